@@ -0,0 +1,143 @@
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use std::collections::BTreeMap;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cell_ref::CellRef;
+use crate::changes::Change;
+use crate::config::Config;
+use crate::query::Predicate;
+use crate::wire::Outgoing;
+
+struct Alert {
+    cell: CellRef,
+    when: Predicate,
+    cooldown: Duration,
+    /// The connection that added the alert, which is told when it fires.
+    owner: String,
+    outbox: Sender<Outgoing>,
+    /// Whether the value is on the near side of the threshold, so that
+    /// crossing it fires the alert.
+    armed: bool,
+    fired: Option<Instant>,
+}
+
+impl Alert {
+    /// Whether the alert fires on its cell's value becoming `value`. It
+    /// fires when the value crosses the threshold, not again until the
+    /// value has come back, and never within `cooldown` of firing last.
+    fn crossed(&mut self, value: &CellValue, now: Instant) -> bool {
+        if !self.when.matches(value) {
+            self.armed = true;
+            return false;
+        }
+        if !self.armed {
+            return false;
+        }
+        self.armed = false;
+        let cooled = self
+            .fired
+            .is_none_or(|fired| now.duration_since(fired) >= self.cooldown);
+        if cooled {
+            self.fired = Some(now);
+        }
+        cooled
+    }
+}
+
+/// Thresholds on cells' values, each reported to the connection that set
+/// it when the value crosses it.
+#[derive(Default)]
+pub struct Alerts {
+    alerts: Mutex<(u64, BTreeMap<u64, Alert>)>,
+}
+
+impl Alerts {
+    /// Starts alerting `owner` through `outbox` when `cell`'s value, now
+    /// `current`, crosses `when`. A value already past the threshold has
+    /// to come back before the alert can fire. Returns the alert's id.
+    pub fn add(
+        &self,
+        cell: CellRef,
+        when: Predicate,
+        cooldown: Duration,
+        owner: &str,
+        outbox: Sender<Outgoing>,
+        current: &CellValue,
+    ) -> u64 {
+        let armed = !when.matches(current);
+        let mut alerts = self.alerts.lock().unwrap();
+        alerts.0 += 1;
+        let id = alerts.0;
+        alerts.1.insert(
+            id,
+            Alert {
+                cell,
+                when,
+                cooldown,
+                owner: owner.to_string(),
+                outbox,
+                armed,
+                fired: None,
+            },
+        );
+        id
+    }
+
+    /// Removes `owner`'s alert `id`.
+    pub fn remove(&self, id: u64, owner: &str) -> bool {
+        let mut alerts = self.alerts.lock().unwrap();
+        match alerts.1.get(&id) {
+            Some(alert) if alert.owner == owner => alerts.1.remove(&id).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Each of `owner`'s alerts as `<id> <cell> <when> cooldown <seconds>s`.
+    pub fn list(&self, owner: &str) -> Vec<String> {
+        let alerts = self.alerts.lock().unwrap();
+        alerts
+            .1
+            .iter()
+            .filter(|(_, alert)| alert.owner == owner)
+            .map(|(id, alert)| {
+                format!(
+                    "{id} {} {} cooldown {}s",
+                    alert.cell,
+                    alert.when,
+                    alert.cooldown.as_secs()
+                )
+            })
+            .collect()
+    }
+
+    /// Drops every alert `connection` added.
+    pub fn leave(&self, connection: &str) {
+        self.alerts
+            .lock()
+            .unwrap()
+            .1
+            .retain(|_, alert| alert.owner != connection);
+    }
+
+    /// Fires every alert on `change`'s cell that the change crosses.
+    pub fn dispatch(&self, change: &Change, config: &Config) {
+        let Ok(cell) = CellRef::parse(&change.cell, config) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut alerts = self.alerts.lock().unwrap();
+        alerts.1.retain(|id, alert| {
+            if alert.cell != cell || !alert.crossed(&change.new, now) {
+                return true;
+            }
+            let label = format!("alert {id} {cell} {}", alert.when);
+            alert
+                .outbox
+                .send(Reply::Value(label, change.new.clone()).into())
+                .is_ok()
+        });
+    }
+}
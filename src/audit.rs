@@ -0,0 +1,192 @@
+use log::info;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::cell_ref::{CellRange, CellRef};
+use crate::config::Config;
+use crate::diff::{describe, Contents};
+
+/// One cell a write changed, with what it held before and after.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Counts up from 1 across every entry recorded.
+    pub seq: u64,
+    /// When the write happened, in milliseconds since the Unix epoch.
+    pub millis: u64,
+    pub connection: String,
+    pub command: &'static str,
+    pub cell: CellRef,
+    pub before: String,
+    pub after: String,
+}
+
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "at={} connection={:?} command={} cell={} before={:?} after={:?}",
+            self.millis, self.connection, self.command, self.cell, self.before, self.after
+        )
+    }
+}
+
+impl AuditEntry {
+    pub fn to_json(&self) -> String {
+        json!({
+            "seq": self.seq,
+            "at": self.millis,
+            "connection": self.connection,
+            "command": self.command,
+            "cell": self.cell.to_string(),
+            "before": self.before,
+            "after": self.after,
+        })
+        .to_string()
+    }
+}
+
+/// When an export file is rotated: once it has reached `max_bytes`, it is
+/// renamed with a `.1` suffix, and older files' suffixes go up by one, with
+/// at most `keep` of them kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub keep: u32,
+}
+
+/// `path` with `.<n>` added.
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+impl Rotation {
+    /// Rotates `path` if it has grown to the limit.
+    fn apply(&self, path: &Path) -> io::Result<()> {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.len() >= self.max_bytes => {}
+            Ok(_) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        if self.keep == 0 {
+            return fs::remove_file(path);
+        }
+        let _ = fs::remove_file(rotated(path, self.keep));
+        for n in (1..self.keep).rev() {
+            match fs::rename(rotated(path, n), rotated(path, n + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        fs::rename(path, rotated(path, 1))
+    }
+}
+
+/// The most recent cell changes, who made them, and the expression and
+/// computed value on either side, for reconstructing how a figure came to
+/// be what it is.
+pub struct AuditLog {
+    limit: usize,
+    entries: Mutex<VecDeque<AuditEntry>>,
+    /// The last entry recorded, and the last exported.
+    seqs: Mutex<(u64, u64)>,
+}
+
+impl AuditLog {
+    /// Keeps up to `limit` entries; audit mode is off if it is zero.
+    pub fn new(limit: usize) -> Self {
+        AuditLog {
+            limit,
+            entries: Mutex::new(VecDeque::new()),
+            seqs: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    /// Records every cell whose expression or value differs between
+    /// `before` and `after`, which are the written cells as `command` from
+    /// `connection` found and left them.
+    pub fn record(
+        &self,
+        connection: &str,
+        command: &'static str,
+        before: &Contents,
+        after: &Contents,
+        millis: u64,
+        config: &Config,
+    ) {
+        let mut cells: Vec<CellRef> = before
+            .keys()
+            .chain(after.keys())
+            .filter_map(|name| CellRef::parse(name, config).ok())
+            .collect();
+        cells.sort();
+        cells.dedup();
+        let mut entries = self.entries.lock().unwrap();
+        let mut seqs = self.seqs.lock().unwrap();
+        for cell in cells {
+            let name = cell.to_string();
+            let (old, new) = (before.get(&name), after.get(&name));
+            if old == new {
+                continue;
+            }
+            seqs.0 += 1;
+            let entry = AuditEntry {
+                seq: seqs.0,
+                millis,
+                connection: connection.to_string(),
+                command,
+                cell,
+                before: describe(old),
+                after: describe(new),
+            };
+            info!("audit {entry}");
+            if entries.len() == self.limit {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+    }
+
+    /// The kept entries for cells in `range` from `since` on, oldest first.
+    pub fn entries(&self, range: CellRange, since: u64) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|entry| entry.millis >= since && range.contains(entry.cell))
+            .cloned()
+            .collect()
+    }
+
+    /// Appends every kept entry not exported before to `path` as JSON
+    /// lines, rotating the file first if `rotation` says it is due. Returns
+    /// how many entries were written. Entries that fell out of the log
+    /// before an export are not in any.
+    pub fn export(&self, path: &Path, rotation: Option<Rotation>) -> io::Result<usize> {
+        let entries = self.entries.lock().unwrap();
+        let mut seqs = self.seqs.lock().unwrap();
+        if let Some(rotation) = rotation {
+            rotation.apply(path)?;
+        }
+        let lines: Vec<String> = entries
+            .iter()
+            .filter(|entry| entry.seq > seqs.1)
+            .map(|entry| entry.to_json() + "\n")
+            .collect();
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(lines.concat().as_bytes())?;
+        file.sync_data()?;
+        seqs.1 = seqs.0;
+        Ok(lines.len())
+    }
+}
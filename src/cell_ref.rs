@@ -0,0 +1,490 @@
+use rsheet_lib::cells::column_number_to_name;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::config::Config;
+use crate::functions::{self, Placement};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CellRef {
+    /// Zero indexed column.
+    pub col: u32,
+    /// One indexed row.
+    pub row: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CellRefError {
+    Empty,
+    NonAscii(String),
+    Malformed(String),
+    MalformedRange(String),
+    MalformedColumn(String),
+    ColumnOutOfRange { column: String, max: String },
+    RowOutOfRange { row: String, max: u32 },
+}
+
+impl Display for CellRefError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CellRefError::Empty => write!(f, "Missing cell name"),
+            CellRefError::NonAscii(name) => {
+                write!(
+                    f,
+                    "Invalid cell name {name:?}: only ASCII A-Z columns are allowed"
+                )
+            }
+            CellRefError::Malformed(name) => write!(
+                f,
+                "Invalid cell name {name:?}: expected column letters A-Z followed by a row number"
+            ),
+            CellRefError::MalformedRange(name) => write!(
+                f,
+                "Invalid range {name:?}: expected two cells joined by an underscore, like A1_B2"
+            ),
+            CellRefError::MalformedColumn(name) => {
+                write!(f, "Invalid column {name:?}: expected column letters A-Z")
+            }
+            CellRefError::ColumnOutOfRange { column, max } => {
+                write!(f, "Column {column} is beyond the last column {max}")
+            }
+            CellRefError::RowOutOfRange { row, max } => {
+                write!(f, "Row {row} is outside the allowed rows 1 to {max}")
+            }
+        }
+    }
+}
+
+impl Error for CellRefError {}
+
+/// Converts column letters to a zero indexed column number, returning `None`
+/// instead of overflowing on absurdly long names.
+fn column_number(column: &str) -> Option<u32> {
+    let mut col_num: u32 = 0;
+    for c in column.bytes() {
+        col_num = col_num
+            .checked_mul(26)?
+            .checked_add((c - b'A') as u32 + 1)?;
+    }
+    col_num.checked_sub(1)
+}
+
+/// Parses bare column letters such as `B` into a zero indexed column.
+pub fn parse_column(column: &str, config: &Config) -> Result<u32, CellRefError> {
+    if config.case_insensitive_cells && column.bytes().any(|b| b.is_ascii_lowercase()) {
+        return parse_column(&column.to_ascii_uppercase(), config);
+    }
+    if column.is_empty() || !column.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Err(CellRefError::MalformedColumn(column.to_string()));
+    }
+    column_number(column)
+        .filter(|col| *col <= config.max_column)
+        .ok_or_else(|| CellRefError::ColumnOutOfRange {
+            column: column.to_string(),
+            max: column_number_to_name(config.max_column),
+        })
+}
+
+impl CellRef {
+    /// Leading zeros in the row are always dropped, so `A01` is the same
+    /// cell as `A1` wherever a cell name is accepted. Lowercase columns are
+    /// only read as uppercase when cell names are case insensitive, and
+    /// are otherwise malformed.
+    pub fn parse(name: &str, config: &Config) -> Result<Self, CellRefError> {
+        if name.is_empty() {
+            return Err(CellRefError::Empty);
+        }
+        if !name.is_ascii() {
+            return Err(CellRefError::NonAscii(name.to_string()));
+        }
+        if config.case_insensitive_cells && name.bytes().any(|b| b.is_ascii_lowercase()) {
+            return CellRef::parse(&name.to_ascii_uppercase(), config);
+        }
+
+        let split = name
+            .find(|c: char| !c.is_ascii_uppercase())
+            .unwrap_or(name.len());
+        let (column, row) = name.split_at(split);
+        if column.is_empty() || row.is_empty() || !row.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(CellRefError::Malformed(name.to_string()));
+        }
+
+        let col = parse_column(column, config)?;
+        let row = row
+            .parse::<u32>()
+            .ok()
+            .filter(|row| (1..=config.max_row).contains(row))
+            .ok_or_else(|| CellRefError::RowOutOfRange {
+                row: row.to_string(),
+                max: config.max_row,
+            })?;
+
+        Ok(CellRef { col, row })
+    }
+}
+
+impl CellRef {
+    /// Parses an `R<row>C<column>` name such as `R3C2` (which is `B3`),
+    /// with one indexed rows and columns.
+    pub fn parse_r1c1(name: &str, config: &Config) -> Option<Self> {
+        let name = if config.case_insensitive_cells {
+            name.to_ascii_uppercase()
+        } else {
+            name.to_string()
+        };
+        let (row, col) = name.strip_prefix('R')?.split_once('C')?;
+        let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if !digits(row) || !digits(col) {
+            return None;
+        }
+        let row = row
+            .parse()
+            .ok()
+            .filter(|row| (1..=config.max_row).contains(row))?;
+        let col = col
+            .parse::<u32>()
+            .ok()?
+            .checked_sub(1)
+            .filter(|col| *col <= config.max_column)?;
+        Some(CellRef { col, row })
+    }
+
+    pub fn to_r1c1(&self) -> String {
+        format!("R{}C{}", self.row, self.col + 1)
+    }
+}
+
+/// Which way a connection writes cell references.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RefStyle {
+    #[default]
+    A1,
+    R1C1,
+}
+
+impl FromStr for RefStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "a1" => Ok(RefStyle::A1),
+            "r1c1" => Ok(RefStyle::R1C1),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+/// Rewrites every R1C1 cell and range reference in `message`, such as
+/// `R1C1_R3C2`, in A1 style. Anything else is left alone.
+pub fn r1c1_to_a1(message: &str, config: &Config) -> Result<String, String> {
+    let a1 = |name: &str| CellRef::parse_r1c1(name, config).map(|cell| cell.to_string());
+    functions::replace_identifiers(message, |name| match name.split_once('_') {
+        Some((start, end)) => Some(format!("{}_{}", a1(start)?, a1(end)?)),
+        None => a1(name),
+    })
+}
+
+/// Where a reference relative to `anchor` points: `+R+C`, with either sign
+/// on each part, is R rows down and C columns right of it, and `.B` is
+/// column B in its row.
+fn relative_cell(name: &str, anchor: CellRef, config: &Config) -> Option<CellRef> {
+    if let Some(column) = name.strip_prefix('.') {
+        let col = parse_column(column, config).ok()?;
+        return Some(CellRef {
+            col,
+            row: anchor.row,
+        });
+    }
+    if !name.starts_with(['+', '-']) {
+        return None;
+    }
+    let split = name[1..].find(['+', '-'])? + 1;
+    let offset = |part: &str| -> Option<i64> {
+        let digits = &part[1..];
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        part.parse().ok()
+    };
+    let row = i64::from(anchor.row) + offset(&name[..split])?;
+    let col = i64::from(anchor.col) + offset(&name[split..])?;
+    Some(CellRef {
+        col: u32::try_from(col)
+            .ok()
+            .filter(|col| *col <= config.max_column)?,
+        row: u32::try_from(row)
+            .ok()
+            .filter(|row| (1..=config.max_row).contains(row))?,
+    })
+}
+
+/// Rewrites every word of `message` that is a reference relative to
+/// `anchor`, like `+1+0` or `.B`, or a range joining two of them with `_`,
+/// in A1 style. String literals and anything else are left alone, as are
+/// references that would fall off the sheet.
+pub fn relative_to_a1(message: &str, anchor: CellRef, config: &Config) -> String {
+    let rewrite = |word: &str| -> Option<String> {
+        match word.split_once('_') {
+            Some((start, end)) => Some(format!(
+                "{}_{}",
+                relative_cell(start, anchor, config)?,
+                relative_cell(end, anchor, config)?
+            )),
+            None => relative_cell(word, anchor, config).map(|cell| cell.to_string()),
+        }
+    };
+    let bytes = message.as_bytes();
+    let mut rewritten = String::with_capacity(message.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let start = index;
+        if functions::is_quote(bytes[index]) {
+            index = functions::skip_string(message, index).unwrap_or(bytes.len());
+        } else if bytes[index].is_ascii_whitespace() {
+            index += 1;
+        } else {
+            while index < bytes.len()
+                && !bytes[index].is_ascii_whitespace()
+                && !functions::is_quote(bytes[index])
+            {
+                index += 1;
+            }
+            if let Some(reference) = rewrite(&message[start..index]) {
+                rewritten.push_str(&reference);
+                continue;
+            }
+        }
+        rewritten.push_str(&message[start..index]);
+    }
+    rewritten
+}
+
+impl Display for CellRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", column_number_to_name(self.col), self.row)
+    }
+}
+
+/// A rectangular block of cells, normalised so `start` is the top left
+/// corner and `end` the bottom right, whichever order they were written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CellRange {
+    pub start: CellRef,
+    pub end: CellRef,
+}
+
+impl CellRange {
+    pub fn new(a: CellRef, b: CellRef) -> Self {
+        CellRange {
+            start: CellRef {
+                col: a.col.min(b.col),
+                row: a.row.min(b.row),
+            },
+            end: CellRef {
+                col: a.col.max(b.col),
+                row: a.row.max(b.row),
+            },
+        }
+    }
+
+    pub fn parse(name: &str, config: &Config) -> Result<Self, CellRefError> {
+        let (start, end) = name
+            .split_once('_')
+            .ok_or_else(|| CellRefError::MalformedRange(name.to_string()))?;
+        Ok(CellRange::new(
+            CellRef::parse(start, config)?,
+            CellRef::parse(end, config)?,
+        ))
+    }
+
+    pub fn contains(&self, cell: CellRef) -> bool {
+        (self.start.col..=self.end.col).contains(&cell.col)
+            && (self.start.row..=self.end.row).contains(&cell.row)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.end.col - self.start.col + 1
+    }
+
+    pub fn height(&self) -> u32 {
+        self.end.row - self.start.row + 1
+    }
+
+    pub fn cell_count(&self) -> u64 {
+        self.width() as u64 * self.height() as u64
+    }
+
+    /// The cells of each row, top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = Vec<CellRef>> + '_ {
+        (self.start.row..=self.end.row).map(move |row| {
+            (self.start.col..=self.end.col)
+                .map(|col| CellRef { col, row })
+                .collect()
+        })
+    }
+}
+
+/// A reference to whole columns, like `A_C`, or whole rows, like `2_5`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WholeReference {
+    Columns(u32, u32),
+    Rows(u32, u32),
+}
+
+impl WholeReference {
+    pub fn parse(name: &str, config: &Config) -> Option<Self> {
+        let (start, end) = name.split_once('_')?;
+        if let (Ok(start), Ok(end)) = (parse_column(start, config), parse_column(end, config)) {
+            return Some(WholeReference::Columns(start.min(end), start.max(end)));
+        }
+        let row = |row: &str| {
+            row.bytes()
+                .all(|b| b.is_ascii_digit())
+                .then(|| row.parse::<u32>().ok())
+                .flatten()
+                .filter(|row| (1..=config.max_row).contains(row))
+        };
+        let (start, end) = (row(start)?, row(end)?);
+        Some(WholeReference::Rows(start.min(end), start.max(end)))
+    }
+
+    /// The cells covered when the sheet extends as far as `last`.
+    pub fn range(&self, last: CellRef) -> CellRange {
+        let (start, end) = match *self {
+            WholeReference::Columns(start, end) => (
+                CellRef { col: start, row: 1 },
+                CellRef {
+                    col: end,
+                    row: last.row,
+                },
+            ),
+            WholeReference::Rows(start, end) => (
+                CellRef { col: 0, row: start },
+                CellRef {
+                    col: last.col,
+                    row: end,
+                },
+            ),
+        };
+        CellRange { start, end }
+    }
+}
+
+impl Display for WholeReference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WholeReference::Columns(start, end) => write!(
+                f,
+                "{}_{}",
+                column_number_to_name(*start),
+                column_number_to_name(*end)
+            ),
+            WholeReference::Rows(start, end) => write!(f, "{start}_{end}"),
+        }
+    }
+}
+
+/// The whole column or row reference `name` makes where it sits. Rows are
+/// only read as a reference when they make up a whole argument, like the
+/// `2_5` in `sum(2_5)`, since anywhere else Rhai reads `12_345` as a number.
+fn whole_reference(name: &str, placement: Placement, config: &Config) -> Option<WholeReference> {
+    WholeReference::parse(name, config)
+        .filter(|whole| placement.argument || matches!(whole, WholeReference::Columns(..)))
+}
+
+/// Rewrites whole column and row references as the ranges they cover when
+/// the sheet extends as far as the cell `last` returns. With the sheet
+/// reaching C9, `A_B` becomes `A1_B9` and `2_2` becomes `A2_C2`. `last` is
+/// only called if there is something to rewrite.
+pub fn expand_whole_references(
+    expression: &str,
+    last: impl FnOnce() -> CellRef,
+    config: &Config,
+) -> String {
+    let mut last = Some(last);
+    let mut bound = None;
+    functions::replace_placed(expression, |name, placement| {
+        let whole = whole_reference(name, placement, config)?;
+        let bound = *bound.get_or_insert_with(|| (last.take().unwrap())());
+        Some(whole.range(bound).to_string())
+    })
+    .unwrap_or_else(|_| expression.to_string())
+}
+
+/// Rewrites the cell and range references in `expression` to their
+/// canonical spelling, so `a1 + B01_B3` is stored as `A1 + B1_B3` (the
+/// lowercase name only when cell names are case insensitive). Anything that
+/// is not a valid reference, and anything inside a string literal, is left
+/// alone.
+pub fn canonical_expression(expression: &str, config: &Config) -> String {
+    let canonical_cell = |name: &str| {
+        CellRef::parse(name, config)
+            .ok()
+            .map(|cell| cell.to_string())
+    };
+    functions::replace_placed(expression, |name, placement| {
+        let canonical = match name.split_once('_') {
+            Some((start, end)) => match (canonical_cell(start), canonical_cell(end)) {
+                (Some(start), Some(end)) => format!("{start}_{end}"),
+                _ => whole_reference(name, placement, config)?.to_string(),
+            },
+            None => canonical_cell(name)?,
+        };
+        (canonical != name).then_some(canonical)
+    })
+    .unwrap_or_else(|_| expression.to_string())
+}
+
+impl Display for CellRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.start, self.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A1: CellRef = CellRef { col: 0, row: 1 };
+
+    fn config(case_insensitive_cells: bool) -> Config {
+        Config {
+            case_insensitive_cells,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn leading_zeros_name_the_same_cell() {
+        for case_insensitive in [true, false] {
+            let config = config(case_insensitive);
+            assert_eq!(CellRef::parse("A01", &config), Ok(A1));
+            assert_eq!(CellRef::parse("A001", &config), Ok(A1));
+        }
+    }
+
+    #[test]
+    fn lowercase_names_follow_the_setting() {
+        assert_eq!(CellRef::parse("a1", &config(true)), Ok(A1));
+        assert_eq!(CellRef::parse("a01", &config(true)), Ok(A1));
+        assert_eq!(
+            CellRef::parse("a1", &config(false)),
+            Err(CellRefError::Malformed("a1".to_string()))
+        );
+    }
+
+    #[test]
+    fn expressions_keep_lowercase_names_when_case_sensitive() {
+        assert_eq!(canonical_expression("a1 + A01", &config(true)), "A1 + A1");
+        assert_eq!(canonical_expression("a1 + A01", &config(false)), "a1 + A1");
+    }
+
+    #[test]
+    fn row_zero_is_out_of_range() {
+        assert!(matches!(
+            CellRef::parse("A00", &config(true)),
+            Err(CellRefError::RowOutOfRange { .. })
+        ));
+    }
+}
@@ -0,0 +1,68 @@
+use rsheet_lib::cell_value::CellValue;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+/// A cell's computed value changing. `seq` numbers changes in the order
+/// they were applied, starting from 1 each time the sheet is loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub seq: u64,
+    pub cell: String,
+    pub old: CellValue,
+    pub new: CellValue,
+}
+
+#[derive(Default)]
+struct Feed {
+    seq: u64,
+    /// Each subscriber, with the connection it belongs to if any.
+    subscribers: Vec<(Option<String>, Sender<Change>)>,
+}
+
+/// Fans every value change out to any number of subscribers.
+#[derive(Default)]
+pub struct ChangeFeed {
+    feed: Mutex<Feed>,
+}
+
+impl ChangeFeed {
+    /// Every change published after this call, in order.
+    pub fn subscribe(&self) -> Receiver<Change> {
+        let (sender, receiver) = channel();
+        self.feed.lock().unwrap().subscribers.push((None, sender));
+        receiver
+    }
+
+    /// Like `subscribe`, but the stream ends when `connection` leaves.
+    pub fn watch(&self, connection: &str) -> Receiver<Change> {
+        let (sender, receiver) = channel();
+        self.feed
+            .lock()
+            .unwrap()
+            .subscribers
+            .push((Some(connection.to_string()), sender));
+        receiver
+    }
+
+    pub fn leave(&self, connection: &str) {
+        self.feed
+            .lock()
+            .unwrap()
+            .subscribers
+            .retain(|(owner, _)| owner.as_deref() != Some(connection));
+    }
+
+    /// Publishes `old` becoming `new`.
+    pub fn publish(&self, cell: &str, old: CellValue, new: CellValue) {
+        let mut feed = self.feed.lock().unwrap();
+        feed.seq += 1;
+        let change = Change {
+            seq: feed.seq,
+            cell: cell.to_string(),
+            old,
+            new,
+        };
+        feed.subscribers
+            .retain(|(_, subscriber)| subscriber.send(change.clone()).is_ok());
+    }
+}
@@ -0,0 +1,101 @@
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::cells::column_number_to_name;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// The kind of value every cell in a column must compute to. Empty cells
+/// and errors are always allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Int,
+    String,
+    /// Exact fixed-point numbers, held as text like `12.50` and read by
+    /// expressions as decimals, so sums of them don't pick up binary
+    /// rounding. Integers are allowed too.
+    Decimal,
+}
+
+impl FromStr for ColumnType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "int" => Ok(ColumnType::Int),
+            "string" => Ok(ColumnType::String),
+            "decimal" => Ok(ColumnType::Decimal),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+impl Display for ColumnType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ColumnType::Int => write!(f, "int"),
+            ColumnType::String => write!(f, "string"),
+            ColumnType::Decimal => write!(f, "decimal"),
+        }
+    }
+}
+
+fn kind(value: &CellValue) -> &'static str {
+    match value {
+        CellValue::Int(_) => "int",
+        CellValue::String(_) => "string",
+        CellValue::Error(_) => "error",
+        CellValue::None => "none",
+    }
+}
+
+#[derive(Default)]
+pub struct ColumnTypes {
+    declared: Mutex<HashMap<u32, ColumnType>>,
+}
+
+impl ColumnTypes {
+    /// Declares the type of `col`, or with `None` drops its declaration.
+    pub fn declare(&self, col: u32, column_type: Option<ColumnType>) {
+        let mut declared = self.declared.lock().unwrap();
+        match column_type {
+            Some(column_type) => declared.insert(col, column_type),
+            None => declared.remove(&col),
+        };
+    }
+
+    /// Every declaration, ordered by column.
+    pub fn declarations(&self) -> Vec<(u32, ColumnType)> {
+        let mut declarations: Vec<(u32, ColumnType)> = self
+            .declared
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(col, column_type)| (*col, *column_type))
+            .collect();
+        declarations.sort_by_key(|(col, _)| *col);
+        declarations
+    }
+
+    pub fn is_decimal(&self, col: u32) -> bool {
+        self.declared.lock().unwrap().get(&col) == Some(&ColumnType::Decimal)
+    }
+
+    /// Explains why `value` can't go in `col`, if it can't.
+    pub fn mismatch(&self, col: u32, value: &CellValue) -> Option<String> {
+        let expected = *self.declared.lock().unwrap().get(&col)?;
+        match (expected, value) {
+            (_, CellValue::None | CellValue::Error(_))
+            | (ColumnType::Int, CellValue::Int(_))
+            | (ColumnType::String, CellValue::String(_))
+            | (ColumnType::Decimal, CellValue::Int(_)) => None,
+            (ColumnType::Decimal, CellValue::String(s)) if Decimal::from_str(s).is_ok() => None,
+            _ => Some(format!(
+                "Column {} holds {expected} values, not {}",
+                column_number_to_name(col),
+                kind(value)
+            )),
+        }
+    }
+}
@@ -0,0 +1,436 @@
+/// How a command is written, for `help` and for resolving aliases.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub syntax: &'static str,
+    pub summary: &'static str,
+}
+
+/// Every command the parser accepts, in the order `help` lists them.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "get",
+        aliases: &["g"],
+        syntax: "get <cell>[@<version>]",
+        summary: "Read a cell's value, or one of its recent versions",
+    },
+    CommandSpec {
+        name: "set",
+        aliases: &["s"],
+        syntax: "set [@<stamp>] <cell> <expression>",
+        summary: "Set a cell's expression",
+    },
+    CommandSpec {
+        name: "getrange",
+        aliases: &[],
+        syntax: "getrange <range>",
+        summary: "Read every value in a range",
+    },
+    CommandSpec {
+        name: "series",
+        aliases: &[],
+        syntax: "series <range> <range>",
+        summary: "Pair up the numbers in two rows or columns as JSON for plotting",
+    },
+    CommandSpec {
+        name: "setmany",
+        aliases: &[],
+        syntax: "setmany <range> <number>...",
+        summary: "Set every cell in a range to a number, row by row",
+    },
+    CommandSpec {
+        name: "cas",
+        aliases: &[],
+        syntax: "cas <cell> <revision> <expression>",
+        summary: "Set a cell only if it hasn't changed since a revision",
+    },
+    CommandSpec {
+        name: "eval",
+        aliases: &[],
+        syntax: "eval <cell> <expression>",
+        summary: "Work out what setting a cell would give it, without setting it",
+    },
+    CommandSpec {
+        name: "calc",
+        aliases: &[],
+        syntax: "calc <expression>",
+        summary: "Work out an expression over the sheet without putting it in a cell",
+    },
+    CommandSpec {
+        name: "append",
+        aliases: &[],
+        syntax: "append <column> <expression> | append <row range> <expression>...",
+        summary: "Write to the next empty row",
+    },
+    CommandSpec {
+        name: "delete",
+        aliases: &[],
+        syntax: "delete [@<stamp>] <cell>",
+        summary: "Remove a cell's expression",
+    },
+    CommandSpec {
+        name: "restore",
+        aliases: &[],
+        syntax: "restore <cell>",
+        summary: "Bring back a recently deleted cell",
+    },
+    CommandSpec {
+        name: "derive",
+        aliases: &[],
+        syntax: "derive <column> = <expression> for rows <start>..[<end>]",
+        summary: "Install a formula down a column, following appended rows",
+    },
+    CommandSpec {
+        name: "underive",
+        aliases: &[],
+        syntax: "underive <column>",
+        summary: "Stop deriving a column",
+    },
+    CommandSpec {
+        name: "define",
+        aliases: &[],
+        syntax: "define <name>(<params>) = <body>",
+        summary: "Define a macro",
+    },
+    CommandSpec {
+        name: "undefine",
+        aliases: &[],
+        syntax: "undefine <name>",
+        summary: "Remove a macro",
+    },
+    CommandSpec {
+        name: "coltype",
+        aliases: &[],
+        syntax: "coltype <column> int|string|decimal|any",
+        summary: "Constrain the values in a column",
+    },
+    CommandSpec {
+        name: "show",
+        aliases: &[],
+        syntax: "show <range>",
+        summary: "Render a range as a table",
+    },
+    CommandSpec {
+        name: "sort",
+        aliases: &[],
+        syntax: "sort <range> by <column> [asc|desc], ...",
+        summary: "Reorder the rows of a range",
+    },
+    CommandSpec {
+        name: "filter",
+        aliases: &[],
+        syntax: "filter <range> where <column> <op> <value>",
+        summary: "List the rows of a range matching a condition",
+    },
+    CommandSpec {
+        name: "groupby",
+        aliases: &[],
+        syntax: "groupby <range> key=<column> agg=<sum|count|min|max>(<column>)",
+        summary: "Aggregate a range by key",
+    },
+    CommandSpec {
+        name: "extent",
+        aliases: &[],
+        syntax: "extent",
+        summary: "Report the populated part of the sheet",
+    },
+    CommandSpec {
+        name: "list",
+        aliases: &[],
+        syntax: "list [order=row|col] [offset=<n>] [limit=<n>]",
+        summary: "List a page of the populated cells and their values",
+    },
+    CommandSpec {
+        name: "scenario",
+        aliases: &[],
+        syntax: "scenario create <name> | scenario set <name> <cell> <expression> | scenario drop <name> | scenario list",
+        summary: "Keep what-if overrides, read with get <cell> scenario=<name>",
+    },
+    CommandSpec {
+        name: "config",
+        aliases: &[],
+        syntax: "config reload",
+        summary: "Re-read the config file, applying limits, tokens and log verbosity without a restart",
+    },
+    CommandSpec {
+        name: "health",
+        aliases: &[],
+        syntax: "health",
+        summary: "Check the recalculation thread is alive and the data directory writable",
+    },
+    CommandSpec {
+        name: "audit",
+        aliases: &[],
+        syntax: "audit <range> [since <millis>]",
+        summary: "List who changed cells in a range, with expressions and values before and after",
+    },
+    CommandSpec {
+        name: "diff",
+        aliases: &[],
+        syntax: "diff [<snapshot|live> [<snapshot|live>]]",
+        summary: "List cells whose expression or value differs between snapshots or the live sheet",
+    },
+    CommandSpec {
+        name: "template",
+        aliases: &[],
+        syntax: "template save <name> <range> | template apply <name> <cell> | template drop <name> | template list",
+        summary: "Stamp a block of expressions out elsewhere, moving references inside it",
+    },
+    CommandSpec {
+        name: "schedule",
+        aliases: &[],
+        syntax: "schedule recalc <range> every <interval> | schedule list | schedule cancel <id>",
+        summary: "Recalculate a range on a timer",
+    },
+    CommandSpec {
+        name: "revision",
+        aliases: &[],
+        syntax: "revision",
+        summary: "Report the sheet's revision",
+    },
+    CommandSpec {
+        name: "cd",
+        aliases: &[],
+        syntax: "cd [<cell>]",
+        summary: "Anchor relative references, +<rows>+<cols> and .<column>, at a cell",
+    },
+    CommandSpec {
+        name: "pwd",
+        aliases: &[],
+        syntax: "pwd",
+        summary: "Report where relative references are anchored",
+    },
+    CommandSpec {
+        name: "refstyle",
+        aliases: &[],
+        syntax: "refstyle a1|r1c1",
+        summary: "Choose how this connection writes cell references",
+    },
+    CommandSpec {
+        name: "merge",
+        aliases: &[],
+        syntax: "merge <range> | merge <snapshot> [base <snapshot>]",
+        summary: "Show a range as one cell holding its top-left value, or bring in a snapshot's changes",
+    },
+    CommandSpec {
+        name: "unmerge",
+        aliases: &[],
+        syntax: "unmerge <range>",
+        summary: "Split a merged range back into its cells",
+    },
+    CommandSpec {
+        name: "merges",
+        aliases: &[],
+        syntax: "merges",
+        summary: "List the merged ranges",
+    },
+    CommandSpec {
+        name: "meta",
+        aliases: &[],
+        syntax: "meta set <key> <value> | meta unset <key> | meta get <key> | meta list",
+        summary: "Share layout like frozen_rows, frozen_cols and width.<column>",
+    },
+    CommandSpec {
+        name: "tag",
+        aliases: &[],
+        syntax: "tag <cell> <tag>",
+        summary: "Tag a cell",
+    },
+    CommandSpec {
+        name: "untag",
+        aliases: &[],
+        syntax: "untag <cell> <tag>",
+        summary: "Remove a tag from a cell",
+    },
+    CommandSpec {
+        name: "cells",
+        aliases: &[],
+        syntax: "cells tagged <tag>",
+        summary: "List the cells carrying a tag",
+    },
+    CommandSpec {
+        name: "protect",
+        aliases: &[],
+        syntax: "protect <range>",
+        summary: "Keep other connections from writing a range",
+    },
+    CommandSpec {
+        name: "unprotect",
+        aliases: &[],
+        syntax: "unprotect <range>",
+        summary: "Release a protected range",
+    },
+    CommandSpec {
+        name: "select",
+        aliases: &[],
+        syntax: "select <cell>",
+        summary: "Tell other connections where you are",
+    },
+    CommandSpec {
+        name: "presence",
+        aliases: &[],
+        syntax: "presence [watch]",
+        summary: "List where every connection is",
+    },
+    CommandSpec {
+        name: "changes",
+        aliases: &[],
+        syntax: "changes watch",
+        summary: "Stream value changes to this connection",
+    },
+    CommandSpec {
+        name: "watch",
+        aliases: &[],
+        syntax: "watch <cell or range> [when <comparison> <literal>]",
+        summary: "Stream changes to some cells, only those whose new value passes a test",
+    },
+    CommandSpec {
+        name: "alert",
+        aliases: &[],
+        syntax: "alert add <cell> <comparison> <literal> [cooldown <interval>] | alert list | alert remove <id>",
+        summary: "Hear once when a cell's value crosses a threshold",
+    },
+    CommandSpec {
+        name: "cycles",
+        aliases: &[],
+        syntax: "cycles",
+        summary: "List circular dependencies",
+    },
+    CommandSpec {
+        name: "goalseek",
+        aliases: &[],
+        syntax: "goalseek <cell>=<goal> by <cell> in [<low>,<high>]",
+        summary: "Find the input value that brings a cell to a goal",
+    },
+    CommandSpec {
+        name: "sweep",
+        aliases: &[],
+        syntax: "sweep <cell> from <start> to <end> step <step> observe <cell>",
+        summary: "Tabulate a cell's value over a range of inputs",
+    },
+    CommandSpec {
+        name: "simulate",
+        aliases: &[],
+        syntax: "simulate <runs> observe <cell> [scenario=<name>]",
+        summary: "Summarize a cell over repeated draws of its random inputs",
+    },
+    CommandSpec {
+        name: "lint",
+        aliases: &[],
+        syntax: "lint [<range>]",
+        summary: "Report suspicious formulas",
+    },
+    CommandSpec {
+        name: "orphans",
+        aliases: &[],
+        syntax: "orphans",
+        summary: "List cells nothing references",
+    },
+    CommandSpec {
+        name: "inputs",
+        aliases: &[],
+        syntax: "inputs",
+        summary: "List referenced cells that reference nothing",
+    },
+    CommandSpec {
+        name: "hotspots",
+        aliases: &[],
+        syntax: "hotspots [<count>]",
+        summary: "List the slowest cells to evaluate",
+    },
+    CommandSpec {
+        name: "memstats",
+        aliases: &[],
+        syntax: "memstats",
+        summary: "Estimate the memory each part of the sheet uses",
+    },
+    CommandSpec {
+        name: "profile",
+        aliases: &[],
+        syntax: "profile dump",
+        summary: "List recent recalculation passes",
+    },
+    CommandSpec {
+        name: "tail",
+        aliases: &[],
+        syntax: "tail [<count>] [follow]",
+        summary: "List recent commands, then optionally stream new ones",
+    },
+    CommandSpec {
+        name: "export",
+        aliases: &[],
+        syntax: "export deps <path> [<range>] | export auditlog <path> [rotate <bytes> [keep <files>]] | export xlsx <path> [values|comments|formulas]",
+        summary: "Write the dependency graph, the audit log or the sheet to a file in the data directory",
+    },
+    #[cfg(feature = "xlsx")]
+    CommandSpec {
+        name: "import",
+        aliases: &[],
+        syntax: "import xlsx <path>",
+        summary: "Load cells from a workbook in the data directory",
+    },
+    #[cfg(feature = "scripting")]
+    CommandSpec {
+        name: "script",
+        aliases: &[],
+        syntax: "script run <path>",
+        summary: "Run a Rhai script from the scripts directory against the sheet",
+    },
+    CommandSpec {
+        name: "save",
+        aliases: &[],
+        syntax: "save",
+        summary: "Write a snapshot",
+    },
+    CommandSpec {
+        name: "compact",
+        aliases: &[],
+        syntax: "compact",
+        summary: "Drop cells that only hold an empty value",
+    },
+    CommandSpec {
+        name: "auth",
+        aliases: &[],
+        syntax: "auth <token>",
+        summary: "Authenticate this connection",
+    },
+    CommandSpec {
+        name: "hello",
+        aliases: &[],
+        syntax: "hello [format=rsheet|json] [frames=text|binary] [compress=none|deflate|gzip] [name=<id>]",
+        summary: "Choose how replies to this connection are encoded, and name it",
+    },
+    CommandSpec {
+        name: "ping",
+        aliases: &[],
+        syntax: "ping",
+        summary: "Check the server is responding",
+    },
+    #[cfg(feature = "metrics")]
+    CommandSpec {
+        name: "metrics",
+        aliases: &[],
+        syntax: "metrics",
+        summary: "Report server metrics",
+    },
+    #[cfg(feature = "webhooks")]
+    CommandSpec {
+        name: "webhook",
+        aliases: &[],
+        syntax: "webhook add <url> on <range> | webhook list | webhook remove <id>",
+        summary: "Post changes in a range to a URL",
+    },
+    CommandSpec {
+        name: "help",
+        aliases: &["?"],
+        syntax: "help [<command>]",
+        summary: "List commands, or describe one",
+    },
+];
+
+/// Finds the command `keyword` names, by its name or an alias.
+pub fn resolve(keyword: &str) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == keyword || spec.aliases.contains(&keyword))
+}
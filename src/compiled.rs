@@ -0,0 +1,337 @@
+use rhai::{ASTNode, Array, Dynamic, Engine, EvalAltResult, Expr, Scope, AST};
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::cells::column_name_to_number;
+use rsheet_lib::command_runner::CellArgument;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cell_ref::CellRef;
+use crate::config::BlankPolicy;
+use crate::eval::EvalContext;
+use crate::memory::{entry_bytes, string_bytes};
+
+/// An expression parsed once, along with the cell references it reads.
+/// Evaluates exactly like `CommandRunner`, which has to re-parse every time
+/// because running it consumes it.
+pub struct Compiled {
+    ast: Result<AST, String>,
+    variables: Vec<String>,
+}
+
+fn is_reference(name: &str) -> bool {
+    let cell = |part: &str| {
+        let split = part
+            .find(|c: char| !c.is_ascii_uppercase())
+            .unwrap_or(part.len());
+        split > 0 && split < part.len() && part[split..].bytes().all(|b| b.is_ascii_digit())
+    };
+    match name.split_once('_') {
+        Some((start, end)) => cell(start) && cell(end),
+        None => cell(name),
+    }
+}
+
+impl Compiled {
+    pub fn new(engine: &Engine, expression: &str) -> Self {
+        let ast = engine
+            .compile_expression(expression)
+            .map_err(|err| err.to_string());
+        let mut variables = Vec::new();
+        if let Ok(ast) = &ast {
+            ast.walk(&mut |nodes| {
+                for node in nodes {
+                    if let ASTNode::Expr(Expr::Variable(variable, _, _)) = node {
+                        let name = variable.3.as_str();
+                        if is_reference(name) && !variables.iter().any(|known| known == name) {
+                            variables.push(name.to_string());
+                        }
+                    }
+                }
+                true
+            });
+        }
+        Compiled { ast, variables }
+    }
+
+    /// The cell and range references the expression reads, like `A1` or
+    /// `A1_B3`, each once, in the order they first appear.
+    pub fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    /// Evaluates the expression for `cell`, if it is any cell's, given the
+    /// exact value of each cell it reads that holds a float. Float and
+    /// boolean results, which `CommandRunner` can't return at all, come back
+    /// as text: floats written in the configured float format, or as a
+    /// decimal in a decimal column, and booleans as `true` or `false`. A
+    /// float written in the float format is returned exactly as well, for
+    /// the cells that read it.
+    pub fn run(
+        &self,
+        variables: &HashMap<String, CellArgument>,
+        floats: &HashMap<String, f64>,
+        context: &EvalContext,
+        cell: Option<CellRef>,
+    ) -> (CellValue, Option<f64>) {
+        let ast = match &self.ast {
+            Ok(ast) => ast,
+            Err(err) => return (CellValue::Error(err.clone()), None),
+        };
+        let mut scope = Scope::new();
+        for (name, value) in variables {
+            match to_rhai(name, value, floats, context) {
+                Ok(value) => {
+                    scope.push(name, value);
+                }
+                Err(_) => {
+                    let err = format!("Unable to convert value {value:?} to Rhai.");
+                    return (CellValue::Error(err), None);
+                }
+            }
+        }
+
+        let value = match context
+            .compiled
+            .engine()
+            .eval_ast_with_scope::<Dynamic>(&mut scope, ast)
+        {
+            Ok(result) if result.is_decimal() => {
+                CellValue::String(result.as_decimal().unwrap_or_default().to_string())
+            }
+            Ok(result) if result.is_bool() => {
+                CellValue::String(result.as_bool().unwrap_or_default().to_string())
+            }
+            Ok(result) if result.is_float() => {
+                let float = result.as_float().unwrap_or_default();
+                match Decimal::try_from(float) {
+                    Ok(decimal)
+                        if cell.is_some_and(|cell| context.column_types.is_decimal(cell.col)) =>
+                    {
+                        CellValue::String(decimal.to_string())
+                    }
+                    _ => {
+                        let rendered = context.config.float_format.render(float);
+                        return (CellValue::String(rendered), Some(float));
+                    }
+                }
+            }
+            Ok(result) => rhai::serde::from_dynamic(&result).unwrap_or_else(|_| {
+                CellValue::Error("Could not cast Rhai return back to Cell Value.".to_string())
+            }),
+            Err(err) => CellValue::Error(err.to_string()),
+        };
+        (value, None)
+    }
+}
+
+/// Where a canonical cell name like `B3` is.
+fn position(cell_name: &str) -> CellRef {
+    let letters = cell_name.trim_end_matches(|c: char| c.is_ascii_digit());
+    CellRef {
+        col: column_name_to_number(letters),
+        row: cell_name[letters.len()..].parse().unwrap_or_default(),
+    }
+}
+
+/// Converts a variable for Rhai. A cell in `floats` is passed as its exact
+/// float. The text `true` or `false` is passed as a boolean. Text that
+/// reads as a number is passed as a decimal in decimal columns, and as a
+/// float in cells with a display format, which only numbers have, or that
+/// the context names as numeric.
+fn to_rhai(
+    name: &str,
+    argument: &CellArgument,
+    floats: &HashMap<String, f64>,
+    context: &EvalContext,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let (start, end) = name.split_once('_').unwrap_or((name, name));
+    let (start, end) = (position(start), position(end));
+    let value = |value: &CellValue, cell: CellRef| {
+        if let CellValue::String(s) = value {
+            if let Some(float) = floats.get(&cell.to_string()) {
+                return Ok(Dynamic::from_float(*float));
+            }
+            if let Ok(boolean) = s.parse() {
+                return Ok(Dynamic::from_bool(boolean));
+            }
+            if context.column_types.is_decimal(cell.col) {
+                if let Ok(decimal) = Decimal::from_str(s) {
+                    return Ok(Dynamic::from_decimal(decimal));
+                }
+            } else if context.formats.get(&cell.to_string()).is_some()
+                || context.numeric.contains(&cell.to_string())
+            {
+                if let Ok(float) = s.parse() {
+                    return Ok(Dynamic::from_float(float));
+                }
+            }
+        }
+        rhai::serde::to_dynamic(value)
+    };
+    let row = |values: &[CellValue], row: u32| -> Result<Dynamic, Box<EvalAltResult>> {
+        values
+            .iter()
+            .zip(start.col..)
+            .map(|(cell, col)| value(cell, CellRef { col, row }))
+            .collect::<Result<Array, _>>()
+            .map(Dynamic::from_array)
+    };
+    match argument {
+        CellArgument::Value(cell) => value(cell, start),
+        CellArgument::Vector(values) if start.col == end.col => values
+            .iter()
+            .zip(start.row..)
+            .map(|(cell, row)| {
+                value(
+                    cell,
+                    CellRef {
+                        col: start.col,
+                        row,
+                    },
+                )
+            })
+            .collect::<Result<Array, _>>()
+            .map(Dynamic::from_array),
+        CellArgument::Vector(values) => row(values, start.row),
+        CellArgument::Matrix(rows) => rows
+            .iter()
+            .zip(start.row..)
+            .map(|(values, r)| row(values, r))
+            .collect::<Result<Array, _>>()
+            .map(Dynamic::from_array),
+    }
+}
+
+/// Adds up numbers in nested lists, as a float if any floats are among
+/// them, or else as a decimal if any decimals are. Empty cells are handled
+/// by `blanks`.
+fn summer(vector: Vec<Dynamic>, blanks: BlankPolicy) -> Result<Dynamic, Box<EvalAltResult>> {
+    let mut total = 0;
+    let mut decimal_total = None;
+    let mut float_total = None;
+    for item in vector {
+        let item = match item.clone().into_array() {
+            Ok(list) => summer(list, blanks)?,
+            Err(_) => item,
+        };
+        if let Ok(i) = item.as_int() {
+            total += i;
+        } else if let Ok(decimal) = item.as_decimal() {
+            *decimal_total.get_or_insert(Decimal::ZERO) += decimal;
+        } else if let Ok(float) = item.as_float() {
+            *float_total.get_or_insert(0.0) += float;
+        } else if item.is_unit() {
+            // Skipped and zero blanks add the same nothing to a sum.
+            if blanks == BlankPolicy::Error {
+                return Err("Empty cell in sum".into());
+            }
+        } else {
+            return Err(format!("Unknown value: {item:?}").into());
+        }
+    }
+    Ok(match (float_total, decimal_total) {
+        (Some(float), decimal) => {
+            let decimal = decimal.and_then(|decimal| decimal.to_f64()).unwrap_or(0.0);
+            Dynamic::from_float(float + decimal + total as f64)
+        }
+        (None, Some(decimal)) => Dynamic::from_decimal(decimal + Decimal::from(total)),
+        (None, None) => Dynamic::from_int(total),
+    })
+}
+
+fn sleep_then(millis: i64, value: Dynamic) -> Dynamic {
+    std::thread::sleep(Duration::from_millis(millis as u64));
+    value
+}
+
+/// Whether `value` came from an empty cell.
+fn isblank(value: Dynamic) -> bool {
+    value.is_unit()
+}
+
+/// A whole number from `low` to `high`, both included.
+fn randbetween(low: i64, high: i64) -> Result<i64, Box<EvalAltResult>> {
+    if low > high {
+        return Err(
+            format!("randbetween() bounds {low} and {high} are the wrong way round").into(),
+        );
+    }
+    Ok(fastrand::i64(low..=high))
+}
+
+/// The engine every expression runs on, with the same functions
+/// `CommandRunner` registers, and `isblank`, `rand` and `randbetween`.
+/// `sum` treats empty cells as `blanks` says, unless called like
+/// `sum(A1_A9, "skip")`.
+pub fn engine(blanks: BlankPolicy) -> Engine {
+    let mut engine = Engine::new();
+    engine.register_fn("sum", move |vector: Vec<Dynamic>| summer(vector, blanks));
+    engine.register_fn(
+        "sum",
+        |vector: Vec<Dynamic>, blanks: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+            summer(vector, blanks.parse()?)
+        },
+    );
+    engine.register_fn("sleep_then", sleep_then);
+    engine.register_fn("isblank", isblank);
+    engine.register_fn("rand", fastrand::f64);
+    engine.register_fn("randbetween", randbetween);
+    engine
+}
+
+/// The compiled form of each cell's expression. An entry is reused while the
+/// expression text it was compiled from is unchanged, so steady state
+/// recalculation never re-parses.
+pub struct CompileCache {
+    engine: Engine,
+    cells: Mutex<HashMap<String, (String, Arc<Compiled>)>>,
+}
+
+impl CompileCache {
+    /// Rough bytes held, counting each compiled expression as its source
+    /// text, which its syntax tree is usually a few times larger than.
+    pub fn memory_bytes(&self) -> usize {
+        self.cells
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, (source, compiled))| {
+                let variables: usize = compiled.variables.iter().map(|v| string_bytes(v)).sum();
+                entry_bytes(name, string_bytes(source) * 4 + variables)
+            })
+            .sum()
+    }
+
+    pub fn new(blanks: BlankPolicy) -> Self {
+        CompileCache {
+            engine: engine(blanks),
+            cells: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn get(&self, cell_name: &str, expression: &str) -> Arc<Compiled> {
+        if let Some((source, compiled)) = self.cells.lock().unwrap().get(cell_name) {
+            if source == expression {
+                return compiled.clone();
+            }
+        }
+        let compiled = Arc::new(Compiled::new(&self.engine, expression));
+        self.cells.lock().unwrap().insert(
+            cell_name.to_string(),
+            (expression.to_string(), compiled.clone()),
+        );
+        compiled
+    }
+
+    pub fn forget(&self, cell_name: &str) {
+        self.cells.lock().unwrap().remove(cell_name);
+    }
+}
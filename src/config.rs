@@ -0,0 +1,263 @@
+use rsheet_lib::cells::column_name_to_number;
+use rust_decimal::Decimal;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::event_log::Verbosity;
+use crate::numbers::{FloatFormat, NumberLocale};
+use crate::persistence::SyncPolicy;
+
+/// Who shares a sheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tenancy {
+    /// Every connection works on the one sheet.
+    Shared,
+    /// Each connection gets its own empty sheet, dropped when it disconnects.
+    PerConnection,
+}
+
+impl FromStr for Tenancy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared" => Ok(Tenancy::Shared),
+            "connection" => Ok(Tenancy::PerConnection),
+            other => Err(format!(
+                "unknown tenancy {other:?}, expected shared or connection"
+            )),
+        }
+    }
+}
+
+/// How concurrent writes to the same cell are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictResolution {
+    /// Whichever write reaches the server last wins.
+    Arrival,
+    /// The write with the latest hybrid logical clock stamp wins. Unstamped
+    /// writes are stamped by the server when they arrive.
+    LastWriterWins,
+}
+
+impl FromStr for ConflictResolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "arrival" => Ok(ConflictResolution::Arrival),
+            "lww" => Ok(ConflictResolution::LastWriterWins),
+            other => Err(format!(
+                "unknown conflict resolution {other:?}, expected arrival or lww"
+            )),
+        }
+    }
+}
+
+/// What happens to a value that doesn't match its column's declared type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnTypePolicy {
+    /// The cell holds an error in place of the value.
+    Error,
+    /// A `set` producing such a value is refused. Values that only stop
+    /// matching when something they depend on changes still become errors.
+    Reject,
+}
+
+impl FromStr for ColumnTypePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(ColumnTypePolicy::Error),
+            "reject" => Ok(ColumnTypePolicy::Reject),
+            other => Err(format!(
+                "unknown column type policy {other:?}, expected error or reject"
+            )),
+        }
+    }
+}
+
+/// What an empty cell inside a range does to a native aggregate like `sum`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlankPolicy {
+    /// The cell is left out.
+    Skip,
+    /// The cell counts as 0.
+    Zero,
+    /// The aggregate fails.
+    Error,
+}
+
+impl FromStr for BlankPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(BlankPolicy::Skip),
+            "zero" => Ok(BlankPolicy::Zero),
+            "error" => Ok(BlankPolicy::Error),
+            other => Err(format!(
+                "unknown blank policy {other:?}, expected skip, zero or error"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The TOML file the settings were read from, if any.
+    pub config_file: Option<PathBuf>,
+    /// Settings given on the command line, which reloading the config file
+    /// leaves alone.
+    pub command_line: Vec<String>,
+    /// Largest accepted column, zero indexed (`ZZZ` by default).
+    pub max_column: u32,
+    /// Largest accepted row, one indexed.
+    pub max_row: u32,
+    /// How often to run `compact` in the background, if at all.
+    pub compact_interval: Option<Duration>,
+    /// Which commands are logged and kept for `tail`.
+    pub log_verbosity: Verbosity,
+    /// Directory holding the snapshot and write-ahead log, if persisting.
+    pub data_dir: Option<PathBuf>,
+    /// Directory `script run` finds scripts in. Scripts can't be run
+    /// without one.
+    pub scripts_dir: Option<PathBuf>,
+    /// When write-ahead log appends are fsynced.
+    pub wal_sync: SyncPolicy,
+    /// How long values fetched by `remote()` are reused before re-fetching.
+    pub remote_refresh: Duration,
+    /// The `host:port` addresses `remote()` may read from. It can't reach
+    /// any other server, so it reads nothing unless some are given.
+    pub remote_hosts: Vec<String>,
+    /// Whether `a1` names the same cell as `A1`. When it doesn't, names are
+    /// left as written and only uppercase ones are cells. Leading zeros in
+    /// the row are dropped either way, so `A01` is always `A1`.
+    pub case_insensitive_cells: bool,
+    /// Whether connections share one sheet or get their own. Only the shared
+    /// sheet is persisted.
+    pub tenancy: Tenancy,
+    /// Tokens accepted by `auth`. When any are set, connections must
+    /// authenticate before running other commands.
+    pub auth_tokens: Vec<String>,
+    /// Tokens that authenticate a connection as an admin, which bypasses
+    /// range protection.
+    pub admin_tokens: Vec<String>,
+    pub conflict_resolution: ConflictResolution,
+    /// Whether recalculation passes are traced for `profile dump`.
+    pub profile: bool,
+    /// Publish the values from a background recalculation all at once when
+    /// it finishes, so reads see the sheet entirely before or entirely after
+    /// it rather than partly updated.
+    pub snapshot_reads: bool,
+    /// Serve `get` and range reads from immutable snapshots of the values,
+    /// so they never wait on writers. Every value is then kept in memory a
+    /// second time, so this can't be combined with `value_cache`.
+    pub lock_free_reads: bool,
+    /// Serve connections from a fixed pool of this many threads instead of
+    /// one thread per connection. Connections beyond the pool wait their turn.
+    pub connection_workers: Option<usize>,
+    /// Refuse connections beyond this many open at once, telling the client
+    /// why. Connections waiting for a worker count as open.
+    pub max_connections: Option<usize>,
+    /// Close connections that send nothing for this long. Clients can send
+    /// `ping` to stay connected.
+    pub idle_timeout: Option<Duration>,
+    /// Longest accepted message, in bytes.
+    pub max_message_len: usize,
+    /// Longest accepted expression, in bytes.
+    pub max_expression_len: usize,
+    /// How many computed values to keep in memory before spilling the least
+    /// recently used to disk, if there is a limit.
+    pub value_cache: Option<usize>,
+    /// How many past values each cell keeps for `get A1@<version>`.
+    pub value_history: usize,
+    /// How many cell changes `audit` can look back over, with zero turning
+    /// audit mode off.
+    pub audit_entries: usize,
+    /// How values that don't match a `coltype` declaration are handled.
+    pub column_type_policy: ColumnTypePolicy,
+    /// How float results are written into cells.
+    pub float_format: FloatFormat,
+    /// How numbers typed as the whole of an expression are written, if not
+    /// the way expressions write them. Persisted expressions are already
+    /// rewritten, so they are always read without it.
+    pub number_locale: Option<NumberLocale>,
+    /// What empty cells in a range do to aggregates that aren't told
+    /// otherwise.
+    pub blank_policy: BlankPolicy,
+    /// Longest chain of cells one evaluation follows before the cell at its
+    /// start becomes an error.
+    pub max_eval_depth: usize,
+    /// Most cells one evaluation evaluates on the way.
+    pub max_eval_reads: usize,
+    /// Whether setting a cell to the expression it already has is skipped,
+    /// rather than logged and recalculated. Off by default, since setting a
+    /// cell that calls `rand` or `remote` again is how it gets a new value.
+    pub skip_unchanged_sets: bool,
+    /// How far a float result may move before it counts as a new value.
+    /// Smaller moves keep the value the cell already shows, and aren't
+    /// published as changes.
+    pub change_epsilon: Decimal,
+    /// How long deleted cells can be brought back with `restore`, if at
+    /// all.
+    pub trash_window: Option<Duration>,
+    /// How long the recalculation thread can go without reporting in before
+    /// `health` calls it stalled.
+    pub health_stall: Duration,
+    /// How close `goalseek` must bring the target cell to the goal.
+    pub goal_seek_tolerance: Decimal,
+    /// Most input values one `sweep` may try.
+    pub max_sweep_steps: usize,
+    /// Most runs one `simulate` may make.
+    pub max_simulation_runs: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            config_file: None,
+            command_line: Vec::new(),
+            max_column: column_name_to_number("ZZZ"),
+            max_row: 1_000_000,
+            compact_interval: None,
+            log_verbosity: Verbosity::All,
+            data_dir: None,
+            scripts_dir: None,
+            wal_sync: SyncPolicy::Always,
+            remote_refresh: Duration::from_secs(30),
+            remote_hosts: Vec::new(),
+            case_insensitive_cells: true,
+            tenancy: Tenancy::Shared,
+            auth_tokens: Vec::new(),
+            admin_tokens: Vec::new(),
+            conflict_resolution: ConflictResolution::Arrival,
+            profile: false,
+            snapshot_reads: false,
+            lock_free_reads: false,
+            connection_workers: None,
+            max_connections: None,
+            idle_timeout: None,
+            max_message_len: 64 * 1024,
+            max_expression_len: 16 * 1024,
+            value_cache: None,
+            value_history: 10,
+            audit_entries: 0,
+            column_type_policy: ColumnTypePolicy::Error,
+            float_format: FloatFormat::default(),
+            number_locale: None,
+            blank_policy: BlankPolicy::Error,
+            max_eval_depth: 10_000,
+            max_eval_reads: 1_000_000,
+            skip_unchanged_sets: false,
+            change_epsilon: Decimal::ZERO,
+            trash_window: None,
+            health_stall: Duration::from_secs(30),
+            goal_seek_tolerance: Decimal::new(1, 6),
+            max_sweep_steps: 10_000,
+            max_simulation_runs: 1_000_000,
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use rsheet_lib::cells::column_name_to_number;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use toml_edit::{DocumentMut, Item, Value};
+
+use crate::config::Config;
+
+/// Environment variables starting with this override the config file, so
+/// `RSHEET_MAX_ROW=500` sets `max_row`.
+pub const ENV_PREFIX: &str = "RSHEET_";
+
+/// The environment variable naming the config file when `--config` doesn't.
+pub const CONFIG_ENV: &str = "RSHEET_CONFIG";
+
+/// The settings `config reload` can change on a running server.
+/// `idle_timeout` and `auth_tokens` only apply to connections made after
+/// the reload, and `max_connections` doesn't close any already open.
+pub const RELOADABLE: [&str; 11] = [
+    "log_verbosity",
+    "max_message_len",
+    "max_expression_len",
+    "auth_tokens",
+    "admin_tokens",
+    "idle_timeout",
+    "max_connections",
+    "compact_interval",
+    "goal_seek_tolerance",
+    "max_sweep_steps",
+    "max_simulation_runs",
+];
+
+/// One setting, named like the command line flag it stands in for, with
+/// underscores for dashes: `max_row` for `--max-row`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub key: String,
+    pub value: SettingValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingValue {
+    One(String),
+    /// A list, for settings like `auth_tokens` that take several.
+    Many(Vec<String>),
+}
+
+/// Reads a column name like `A` or `ZZZ` as its zero indexed number.
+pub fn parse_column(column: &str) -> Result<u32, String> {
+    if column.is_empty() || column.len() > 6 || !column.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Err(format!("{column:?} is not a column name like A or ZZZ"));
+    }
+    Ok(column_name_to_number(column))
+}
+
+/// A TOML value as setting text. Tables and dates aren't settings.
+fn setting_text(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.value().clone()),
+        Value::Integer(n) => Ok(n.value().to_string()),
+        Value::Float(x) => Ok(x.value().to_string()),
+        Value::Boolean(b) => Ok(b.value().to_string()),
+        _ => Err(format!("{key} can't be set to a {}", value.type_name())),
+    }
+}
+
+/// The settings in the TOML file at `path`, in the order they appear.
+pub fn load(path: &Path) -> Result<Vec<Setting>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("Could not read {}: {err}", path.display()))?;
+    let document: DocumentMut = text
+        .parse()
+        .map_err(|err| format!("Could not parse {}: {err}", path.display()))?;
+    document
+        .iter()
+        .map(|(key, item)| {
+            let value = match item {
+                Item::Value(Value::Array(values)) => SettingValue::Many(
+                    values
+                        .iter()
+                        .map(|value| setting_text(key, value))
+                        .collect::<Result<_, _>>()?,
+                ),
+                Item::Value(value) => SettingValue::One(setting_text(key, value)?),
+                _ => return Err(format!("{key} can't be set to a table")),
+            };
+            Ok(Setting {
+                key: key.replace('-', "_"),
+                value,
+            })
+        })
+        .collect()
+}
+
+/// The settings given by `RSHEET_` environment variables, other than
+/// `RSHEET_CONFIG`, which names the config file. Lists are comma separated.
+pub fn from_env() -> Vec<Setting> {
+    let mut settings: Vec<Setting> = std::env::vars()
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(ENV_PREFIX)?.to_ascii_lowercase();
+            if key == "config" {
+                return None;
+            }
+            let value = if key.ends_with("_tokens") {
+                SettingValue::Many(value.split(',').map(str::to_string).collect())
+            } else {
+                SettingValue::One(value)
+            };
+            Some(Setting { key, value })
+        })
+        .collect();
+    settings.sort_by(|a, b| a.key.cmp(&b.key));
+    settings
+}
+
+/// What re-reading the config file would change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reload {
+    /// `current` with every reloadable setting the file or environment
+    /// changes applied.
+    pub config: Config,
+    /// The settings that changed, in the order they were read.
+    pub applied: Vec<String>,
+    /// Settings that changed but only take effect on a restart.
+    pub needs_restart: Vec<String>,
+}
+
+/// Reads `current`'s config file and the environment again. Settings given
+/// on the command line still win, and settings taken out of the file keep
+/// the value they have.
+pub fn reload(current: &Config) -> Result<Reload, String> {
+    let path = current
+        .config_file
+        .as_deref()
+        .ok_or("The server was started without a config file")?;
+    let mut settings = load(path)?;
+    settings.extend(from_env());
+    let mut reload = Reload {
+        config: current.clone(),
+        applied: Vec::new(),
+        needs_restart: Vec::new(),
+    };
+    for setting in settings {
+        if current.command_line.contains(&setting.key) {
+            continue;
+        }
+        let mut changed = reload.config.clone();
+        setting.apply(&mut changed)?;
+        if changed == reload.config {
+            continue;
+        }
+        if RELOADABLE.contains(&setting.key.as_str()) {
+            reload.config = changed;
+            reload.applied.push(setting.key);
+        } else {
+            reload.needs_restart.push(setting.key);
+        }
+    }
+    Ok(reload)
+}
+
+impl Setting {
+    fn text(&self) -> Result<&str, String> {
+        match &self.value {
+            SettingValue::One(text) => Ok(text),
+            SettingValue::Many(_) => Err(format!("{} takes a single value", self.key)),
+        }
+    }
+
+    fn parse<T: FromStr>(&self) -> Result<T, String>
+    where
+        T::Err: Display,
+    {
+        let text = self.text()?;
+        text.parse()
+            .map_err(|err| format!("Invalid {} {text:?}: {err}", self.key))
+    }
+
+    fn seconds(&self) -> Result<Duration, String> {
+        self.parse().map(Duration::from_secs)
+    }
+
+    /// A count that must be at least one.
+    fn positive(&self) -> Result<usize, String> {
+        match self.parse()? {
+            0 => Err(format!("{} must be at least 1", self.key)),
+            n => Ok(n),
+        }
+    }
+
+    fn list(&self) -> Vec<String> {
+        match &self.value {
+            SettingValue::One(text) => vec![text.clone()],
+            SettingValue::Many(values) => values.clone(),
+        }
+    }
+
+    /// Applies the setting to `config`.
+    pub fn apply(&self, config: &mut Config) -> Result<(), String> {
+        match self.key.as_str() {
+            "max_column" => config.max_column = parse_column(self.text()?)?,
+            "max_row" => config.max_row = self.parse()?,
+            "compact_interval" => config.compact_interval = Some(self.seconds()?),
+            "log_verbosity" => config.log_verbosity = self.parse()?,
+            "data_dir" => config.data_dir = Some(PathBuf::from(self.text()?)),
+            "scripts_dir" => config.scripts_dir = Some(PathBuf::from(self.text()?)),
+            "wal_sync" => config.wal_sync = self.parse()?,
+            "remote_refresh" => config.remote_refresh = self.seconds()?,
+            "remote_hosts" => config.remote_hosts = self.list(),
+            "case_sensitive_cells" => config.case_insensitive_cells = !self.parse::<bool>()?,
+            "tenancy" => config.tenancy = self.parse()?,
+            "auth_tokens" => config.auth_tokens = self.list(),
+            "admin_tokens" => config.admin_tokens = self.list(),
+            "conflict_resolution" => config.conflict_resolution = self.parse()?,
+            "profile" => config.profile = self.parse()?,
+            "snapshot_reads" => config.snapshot_reads = self.parse()?,
+            "lock_free_reads" => config.lock_free_reads = self.parse()?,
+            "workers" => config.connection_workers = Some(self.positive()?),
+            "max_connections" => config.max_connections = Some(self.positive()?),
+            "idle_timeout" => config.idle_timeout = Some(self.seconds()?),
+            "max_message_len" => config.max_message_len = self.parse()?,
+            "max_expression_len" => config.max_expression_len = self.parse()?,
+            "value_cache" => config.value_cache = Some(self.positive()?),
+            "value_history" => config.value_history = self.parse()?,
+            "audit_entries" => config.audit_entries = self.parse()?,
+            "column_type_policy" => config.column_type_policy = self.parse()?,
+            "float_digits" => config.float_format.digits = self.parse()?,
+            "float_scientific_exponent" => {
+                config.float_format.scientific_exponent = self.parse()?
+            }
+            "number_locale" => config.number_locale = Some(self.parse()?),
+            "blank_policy" => config.blank_policy = self.parse()?,
+            "max_eval_depth" => config.max_eval_depth = self.parse()?,
+            "max_eval_reads" => config.max_eval_reads = self.parse()?,
+            "skip_unchanged_sets" => config.skip_unchanged_sets = self.parse()?,
+            "change_epsilon" => config.change_epsilon = self.parse()?,
+            "trash_window" => config.trash_window = Some(self.seconds()?),
+            "health_stall" => config.health_stall = self.seconds()?,
+            "goal_seek_tolerance" => config.goal_seek_tolerance = self.parse()?,
+            "max_sweep_steps" => config.max_sweep_steps = self.parse()?,
+            "max_simulation_runs" => config.max_simulation_runs = self.parse()?,
+            other => return Err(format!("Unknown setting {other}")),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::cell_ref::{canonical_expression, parse_column, CellRef};
+use crate::config::Config;
+use crate::fold::fold_constants;
+use crate::functions::replace_names;
+
+/// A formula installed down a column. Bare column names in `template`, such
+/// as `A` in `A * B`, stand for that column's cell in the same row; full
+/// cell references stay as written. Without an `end` the derivation runs to
+/// the last used row and follows rows appended later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Derivation {
+    pub template: String,
+    pub start: u32,
+    pub end: Option<u32>,
+}
+
+impl Derivation {
+    pub fn covers(&self, row: u32) -> bool {
+        row >= self.start && self.end.is_none_or(|end| row <= end)
+    }
+
+    /// The template with every bare column name pointed at `row`, in
+    /// canonical form.
+    pub fn expression(&self, row: u32, config: &Config) -> Result<String, String> {
+        let expression = replace_names(&self.template, |name, called| {
+            if called {
+                return None;
+            }
+            let col = parse_column(name, config).ok()?;
+            Some(CellRef { col, row }.to_string())
+        })?;
+        Ok(fold_constants(&canonical_expression(&expression, config)))
+    }
+}
+
+#[derive(Default)]
+pub struct Derivations {
+    columns: Mutex<HashMap<u32, Derivation>>,
+}
+
+impl Derivations {
+    /// Derives `col`, or with `None` stops deriving it. Returns whether it
+    /// was derived before.
+    pub fn set(&self, col: u32, derivation: Option<Derivation>) -> bool {
+        let mut columns = self.columns.lock().unwrap();
+        match derivation {
+            Some(derivation) => columns.insert(col, derivation).is_some(),
+            None => columns.remove(&col).is_some(),
+        }
+    }
+
+    /// Every derivation, ordered by column.
+    pub fn list(&self) -> Vec<(u32, Derivation)> {
+        let mut derivations: Vec<(u32, Derivation)> = self
+            .columns
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(col, derivation)| (*col, derivation.clone()))
+            .collect();
+        derivations.sort_by_key(|(col, _)| *col);
+        derivations
+    }
+}
@@ -0,0 +1,113 @@
+use rsheet_lib::cell_value::CellValue;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use crate::cell_ref::CellRef;
+use crate::config::Config;
+use crate::functions::string_literal;
+
+/// One side of a diff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffSource {
+    /// The sheet as it is now.
+    Live,
+    /// The sheet a snapshot file in the data directory holds, as `save`
+    /// wrote it, by its name there.
+    Snapshot(PathBuf),
+}
+
+impl DiffSource {
+    /// Reads `live` or a snapshot file's name.
+    pub fn parse(word: &str) -> Self {
+        match word {
+            "live" => DiffSource::Live,
+            path => DiffSource::Snapshot(PathBuf::from(path)),
+        }
+    }
+}
+
+/// Each cell with an expression, and its expression and value.
+pub type Contents = BTreeMap<String, (String, CellValue)>;
+
+/// A cell as one side of a diff shows it.
+pub fn describe(cell: Option<&(String, CellValue)>) -> String {
+    let Some((expression, value)) = cell else {
+        return "empty".to_string();
+    };
+    let value = match value {
+        CellValue::Int(n) => n.to_string(),
+        CellValue::String(s) => string_literal(s),
+        CellValue::Error(err) => format!("error {}", string_literal(err)),
+        CellValue::None => "nothing".to_string(),
+    };
+    format!("{expression} = {value}")
+}
+
+/// Cells to give new expressions, or with `None` to clear.
+pub type Edits = Vec<(String, Option<String>)>;
+
+/// An expression, or `empty` for none.
+fn describe_expression(expression: Option<&String>) -> String {
+    expression.map_or_else(|| "empty".to_string(), String::clone)
+}
+
+/// A three-way merge of `theirs` into `ours`, both descended from `base`,
+/// by expression. Cells only `theirs` changed come back as changes to make
+/// to `ours`, with `None` to clear the cell. Cells both sides changed, to
+/// different expressions, come back as conflicts, with how each side reads.
+pub fn merge(
+    base: &Contents,
+    ours: &HashMap<String, String>,
+    theirs: &Contents,
+    config: &Config,
+) -> (Edits, Vec<(String, String)>) {
+    let mut cells: Vec<CellRef> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .filter_map(|name| CellRef::parse(name, config).ok())
+        .collect();
+    cells.sort();
+    cells.dedup();
+    let (mut changes, mut conflicts) = (Vec::new(), Vec::new());
+    for cell in cells {
+        let name = cell.to_string();
+        let base = base.get(&name).map(|(expression, _)| expression);
+        let theirs = theirs.get(&name).map(|(expression, _)| expression);
+        let ours = ours.get(&name);
+        if theirs == base || theirs == ours {
+            continue;
+        }
+        if ours == base {
+            changes.push((name, theirs.cloned()));
+        } else {
+            let sides = format!(
+                "ours {}, theirs {}",
+                describe_expression(ours),
+                describe_expression(theirs)
+            );
+            conflicts.push((name, sides));
+        }
+    }
+    (changes, conflicts)
+}
+
+/// Every cell whose expression or value differs between `before` and
+/// `after`, in cell order, with how it reads on each side.
+pub fn diff(before: &Contents, after: &Contents, config: &Config) -> Vec<(String, String)> {
+    let mut cells: Vec<CellRef> = before
+        .keys()
+        .chain(after.keys())
+        .filter_map(|name| CellRef::parse(name, config).ok())
+        .collect();
+    cells.sort();
+    cells.dedup();
+    cells
+        .into_iter()
+        .filter_map(|cell| {
+            let name = cell.to_string();
+            let (old, new) = (before.get(&name), after.get(&name));
+            (old != new).then(|| (name, format!("{} -> {}", describe(old), describe(new))))
+        })
+        .collect()
+}
@@ -0,0 +1,604 @@
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::cells::column_number_to_name;
+use rsheet_lib::command_runner::CellArgument;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use crate::cell_ref::{expand_whole_references, CellRange, CellRef};
+use crate::coltype::ColumnTypes;
+use crate::compiled::{CompileCache, Compiled};
+use crate::config::Config;
+use crate::extent::Extent;
+use crate::formats::Formats;
+use crate::functions;
+use crate::health::panic_message;
+use crate::logic;
+use crate::macros::Macros;
+use crate::remote::RemoteCache;
+
+/// Everything evaluation needs beyond the expressions themselves.
+pub struct EvalContext<'a> {
+    pub config: &'a Config,
+    pub remote: &'a RemoteCache,
+    pub compiled: &'a CompileCache,
+    pub macros: &'a Macros,
+    pub extent: &'a Extent,
+    pub column_types: &'a ColumnTypes,
+    pub formats: &'a Formats,
+    /// Cells whose text is read as a number where it holds one, like the
+    /// input `goalseek` fills with floats and the cells it feeds.
+    pub numeric: &'a [String],
+}
+
+/// Expands macros and logical functions, then resolves calls to
+/// server-side functions into literals.
+fn expand_functions(expression: &str, context: &EvalContext) -> Result<String, String> {
+    let expression = logic::expand(&context.macros.expand(expression)?)?;
+    functions::expand(&expression, &["remote"], |call| {
+        match call.args.as_slice() {
+            [address, cell] => match (
+                functions::string_argument(address),
+                functions::string_argument(cell),
+            ) {
+                (Some(address), Some(cell)) => context.remote.get(&address, &cell),
+                _ => CellValue::Error("remote() takes two string arguments".to_string()),
+            },
+            _ => CellValue::Error("remote() takes two string arguments".to_string()),
+        }
+    })
+}
+
+fn get_vector_value(
+    cells: &HashMap<String, CellValue>,
+    col_start: u32,
+    row_start: u32,
+    col_end: u32,
+    row_end: u32,
+) -> Vec<CellValue> {
+    if col_start == col_end {
+        (row_start..=row_end)
+            .map(|row| get_cell_value(cells, col_start, row))
+            .collect()
+    } else {
+        (col_start..=col_end)
+            .map(|col| get_cell_value(cells, col, row_start))
+            .collect()
+    }
+}
+
+fn get_matrix_value(
+    cells: &HashMap<String, CellValue>,
+    col_start: u32,
+    row_start: u32,
+    col_end: u32,
+    row_end: u32,
+) -> Vec<Vec<CellValue>> {
+    (row_start..=row_end)
+        .map(|row| {
+            (col_start..=col_end)
+                .map(|col| get_cell_value(cells, col, row))
+                .collect()
+        })
+        .collect()
+}
+
+fn get_cell_value(cells: &HashMap<String, CellValue>, col: u32, row: u32) -> CellValue {
+    let cell_name = format!("{}{}", column_number_to_name(col), row);
+    cells.get(&cell_name).cloned().unwrap_or(CellValue::None)
+}
+
+/// The values `variables` stand for, given the value of every cell they
+/// read that has an expression.
+fn calculate_variables(
+    variables: &[String],
+    values: &HashMap<String, CellValue>,
+    config: &Config,
+) -> Result<HashMap<String, CellArgument>, String> {
+    variables
+        .iter()
+        .map(|var_name| {
+            let cell_argument = if let Some((start, end)) = var_name.split_once('_') {
+                let start = CellRef::parse(start, config).map_err(|err| err.to_string())?;
+                let end = CellRef::parse(end, config).map_err(|err| err.to_string())?;
+                if start.col == end.col || start.row == end.row {
+                    let value = get_vector_value(values, start.col, start.row, end.col, end.row);
+                    CellArgument::Vector(value)
+                } else {
+                    let value = get_matrix_value(values, start.col, start.row, end.col, end.row);
+                    CellArgument::Matrix(value)
+                }
+            } else {
+                CellRef::parse(var_name, config).map_err(|err| err.to_string())?;
+                let value = values.get(var_name).cloned().unwrap_or(CellValue::None);
+                CellArgument::Value(value)
+            };
+            Ok((var_name.clone(), cell_argument))
+        })
+        .collect()
+}
+
+/// The cells with an expression that `var_name` reads.
+fn cells_read(
+    var_name: &str,
+    expressions: &HashMap<String, String>,
+    config: &Config,
+) -> Result<Vec<String>, String> {
+    let Some((start, end)) = var_name.split_once('_') else {
+        CellRef::parse(var_name, config).map_err(|err| err.to_string())?;
+        return Ok(vec![var_name.to_string()]);
+    };
+    let start = CellRef::parse(start, config).map_err(|err| err.to_string())?;
+    let end = CellRef::parse(end, config).map_err(|err| err.to_string())?;
+    let range = CellRange::new(start, end);
+    Ok(expressions
+        .keys()
+        .filter(|name| CellRef::parse(name, config).is_ok_and(|cell| range.contains(cell)))
+        .cloned()
+        .collect())
+}
+
+/// Functions that see an error in their first argument instead of taking
+/// it on.
+const ERROR_FUNCTIONS: [&str; 2] = ["iferror", "iserror"];
+
+/// Resolves `iferror(value, fallback)` and `iserror(value)` by evaluating
+/// `value` on its own first. `iferror` becomes `value` again, or `fallback`
+/// if it was an error, so the outer expression still computes it natively.
+fn resolve_error_functions(
+    cell: Option<CellRef>,
+    expression: &str,
+    values: &HashMap<String, CellValue>,
+    floats: &HashMap<String, f64>,
+    context: &EvalContext,
+) -> Result<String, String> {
+    functions::replace_calls(expression, &ERROR_FUNCTIONS, |call| {
+        let (value, fallback) = match (call.name, call.args.as_slice()) {
+            ("iferror", [value, fallback]) => (*value, Some(*fallback)),
+            ("iserror", [value]) => (*value, None),
+            ("iferror", _) => return Err("iferror() takes a value and a fallback".to_string()),
+            _ => return Err("iserror() takes one argument".to_string()),
+        };
+        let compiled = Compiled::new(context.compiled.engine(), value);
+        let failed = matches!(
+            evaluate(cell, value, &compiled, values, floats, context),
+            (CellValue::Error(_), _)
+        );
+        match fallback {
+            Some(fallback) => {
+                let chosen = if failed { fallback } else { value };
+                let chosen = resolve_error_functions(cell, chosen, values, floats, context)?;
+                Ok(format!("({chosen})"))
+            }
+            None => Ok(failed.to_string()),
+        }
+    })
+}
+
+/// The first error among the values a variable holds.
+fn first_error(argument: &CellArgument) -> Option<CellValue> {
+    let is_error = |value: &&CellValue| matches!(value, CellValue::Error(_));
+    match argument {
+        CellArgument::Value(value) => Some(value).filter(is_error).cloned(),
+        CellArgument::Vector(values) => values.iter().find(is_error).cloned(),
+        CellArgument::Matrix(rows) => rows.iter().flatten().find(is_error).cloned(),
+    }
+}
+
+/// Evaluates `expression`, compiled as `compiled`, for `cell`, if it is
+/// any cell's, given the values of the cells it reads and the exact value
+/// of those holding floats. Returns the value, and its exact value if it
+/// is a float. Reading a cell holding an error, alone or in a range, makes
+/// the whole expression that error, unless `iferror` or `iserror` reads
+/// it. A panic along the way becomes this cell's error rather than the
+/// calling thread's problem.
+fn evaluate(
+    cell: Option<CellRef>,
+    expression: &str,
+    compiled: &Compiled,
+    values: &HashMap<String, CellValue>,
+    floats: &HashMap<String, f64>,
+    context: &EvalContext,
+) -> (CellValue, Option<f64>) {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        evaluate_unguarded(cell, expression, compiled, values, floats, context)
+    }))
+    .unwrap_or_else(|panic| {
+        let err = format!("evaluation panicked: {}", panic_message(&*panic));
+        (CellValue::Error(err), None)
+    })
+}
+
+fn evaluate_unguarded(
+    cell: Option<CellRef>,
+    expression: &str,
+    compiled: &Compiled,
+    values: &HashMap<String, CellValue>,
+    floats: &HashMap<String, f64>,
+    context: &EvalContext,
+) -> (CellValue, Option<f64>) {
+    let resolved = match resolve_error_functions(cell, expression, values, floats, context) {
+        Ok(resolved) => resolved,
+        Err(err) => return (CellValue::Error(err), None),
+    };
+    let resolved_compiled;
+    let compiled = if resolved == expression {
+        compiled
+    } else {
+        resolved_compiled = Compiled::new(context.compiled.engine(), &resolved);
+        &resolved_compiled
+    };
+    let variables = match calculate_variables(compiled.variables(), values, context.config) {
+        Ok(variables) => variables,
+        Err(err) => return (CellValue::Error(err), None),
+    };
+    if let Some(err) = variables.values().find_map(first_error) {
+        return (err, None);
+    }
+    compiled.run(&variables, floats, context, cell)
+}
+
+/// Cell values already worked out from the current expressions. One is
+/// shared across a whole recalculation wave, so every cell is evaluated at
+/// most once in it however many cells read it.
+#[derive(Default)]
+pub struct Memo {
+    values: HashMap<String, CellValue>,
+    /// The exact value of each cell holding a float, which the cell itself
+    /// holds as text in the float format.
+    floats: HashMap<String, f64>,
+}
+
+impl Memo {
+    pub fn new() -> Self {
+        Memo::default()
+    }
+}
+
+/// A cell whose expression is waiting on the cells it reads.
+struct Frame {
+    cell_name: String,
+    /// `None` for an expression no cell holds.
+    cell: Option<CellRef>,
+    /// The expression with macros and server-side functions expanded.
+    expression: String,
+    compiled: Arc<Compiled>,
+    /// Every cell with an expression that the expression reads, each once.
+    reads: Vec<String>,
+    /// How many of `reads` have been evaluated into `values`.
+    read: usize,
+    values: HashMap<String, CellValue>,
+    /// The exact value of each of `values` that is a float.
+    floats: HashMap<String, f64>,
+}
+
+impl Frame {
+    fn new(
+        expressions: &HashMap<String, String>,
+        cell_name: &str,
+        expression: &str,
+        context: &EvalContext,
+    ) -> Result<Self, String> {
+        let expression = expand(expression, context)?;
+        let cell = CellRef::parse(cell_name, context.config).map_err(|err| err.to_string())?;
+        let compiled = context.compiled.get(cell_name, &expression);
+        Frame::build(
+            expressions,
+            cell_name,
+            Some(cell),
+            expression,
+            compiled,
+            context,
+        )
+    }
+
+    /// A frame for an expression no cell holds. It isn't compiled into the
+    /// cache, which only keeps cells' expressions.
+    fn standalone(
+        expressions: &HashMap<String, String>,
+        expression: &str,
+        context: &EvalContext,
+    ) -> Result<Self, String> {
+        let expression = expand(expression, context)?;
+        let compiled = Arc::new(Compiled::new(context.compiled.engine(), &expression));
+        Frame::build(expressions, "", None, expression, compiled, context)
+    }
+
+    fn build(
+        expressions: &HashMap<String, String>,
+        cell_name: &str,
+        cell: Option<CellRef>,
+        expression: String,
+        compiled: Arc<Compiled>,
+        context: &EvalContext,
+    ) -> Result<Self, String> {
+        let mut reads = Vec::new();
+        let mut seen = HashSet::new();
+        for var_name in compiled.variables() {
+            for read in cells_read(var_name, expressions, context.config)? {
+                if seen.insert(read.clone()) {
+                    reads.push(read);
+                }
+            }
+        }
+        Ok(Frame {
+            cell_name: cell_name.to_string(),
+            cell,
+            expression,
+            compiled,
+            reads,
+            read: 0,
+            values: HashMap::new(),
+            floats: HashMap::new(),
+        })
+    }
+}
+
+/// Expands macros and functions in `expression`, and whole row and column
+/// references to the part of the sheet in use.
+fn expand(expression: &str, context: &EvalContext) -> Result<String, String> {
+    let expression = expand_functions(expression, context)?;
+    Ok(expand_whole_references(
+        &expression,
+        || match context.extent.bounds() {
+            Some(extent) => extent.end,
+            None => CellRef { col: 0, row: 1 },
+        },
+        context.config,
+    ))
+}
+
+/// One cell's evaluation. The cells it is waiting on are kept on an
+/// explicit stack rather than the call stack, so a long dependency chain
+/// can't overflow it, and the evaluation can be stopped between steps and
+/// carried on later.
+pub struct Evaluation<'m> {
+    /// The cell to evaluate, until the first step starts on it.
+    start: Option<String>,
+    /// Each frame is waiting on the one above it.
+    stack: Vec<Frame>,
+    /// The cells on the stack.
+    visiting: HashSet<String>,
+    /// Every cell finished so far, so each is evaluated once however many
+    /// cells read it. Shared with the other evaluations in a wave.
+    finished: &'m mut Memo,
+    /// How many cells have been evaluated.
+    reads: usize,
+}
+
+impl<'m> Evaluation<'m> {
+    pub fn new(cell_name: &str, finished: &'m mut Memo) -> Self {
+        Evaluation {
+            start: Some(cell_name.to_string()),
+            stack: Vec::new(),
+            visiting: HashSet::new(),
+            finished,
+            reads: 0,
+        }
+    }
+
+    /// Moves the evaluation on by one cell, returning the value once it is
+    /// known. `expressions` must not change between steps.
+    pub fn step(
+        &mut self,
+        expressions: &HashMap<String, String>,
+        context: &EvalContext,
+    ) -> Option<CellValue> {
+        if let Some(cell_name) = self.start.take() {
+            return self.enter(expressions, &cell_name, context);
+        }
+        let frame = self.stack.last_mut()?;
+        if let Some(cell_name) = frame.reads.get(frame.read).cloned() {
+            frame.read += 1;
+            if let Some(value) = self.enter(expressions, &cell_name, context) {
+                self.deliver(cell_name, value);
+            }
+            return None;
+        }
+
+        let frame = self.stack.pop()?;
+        self.visiting.remove(&frame.cell_name);
+        let (value, float) = evaluate(
+            frame.cell,
+            &frame.expression,
+            &frame.compiled,
+            &frame.values,
+            &frame.floats,
+            context,
+        );
+        if frame.cell.is_some() {
+            let finished = &mut self.finished;
+            finished
+                .values
+                .insert(frame.cell_name.clone(), value.clone());
+            if let Some(float) = float {
+                finished.floats.insert(frame.cell_name.clone(), float);
+            }
+        }
+        if self.stack.is_empty() {
+            Some(value)
+        } else {
+            self.deliver(frame.cell_name, value);
+            None
+        }
+    }
+
+    /// Hands a finished cell's value to the frame waiting on it.
+    fn deliver(&mut self, cell_name: String, value: CellValue) {
+        if let Some(frame) = self.stack.last_mut() {
+            if let Some(float) = self.finished.floats.get(&cell_name) {
+                frame.floats.insert(cell_name.clone(), *float);
+            }
+            frame.values.insert(cell_name, value);
+        }
+    }
+
+    /// Starts on `cell_name`, or returns its value if that needs no other
+    /// cell.
+    fn enter(
+        &mut self,
+        expressions: &HashMap<String, String>,
+        cell_name: &str,
+        context: &EvalContext,
+    ) -> Option<CellValue> {
+        if self.visiting.contains(cell_name) {
+            return Some(CellValue::Error("Circular dependency detected".to_string()));
+        }
+        if let Some(value) = self.finished.values.get(cell_name) {
+            return Some(value.clone());
+        }
+        self.reads += 1;
+        if self.reads > context.config.max_eval_reads {
+            return Some(CellValue::Error(format!(
+                "Evaluation read more than {} cells",
+                context.config.max_eval_reads
+            )));
+        }
+        let Some(expression) = expressions.get(cell_name) else {
+            return Some(CellValue::None);
+        };
+        if self.visiting.len() >= context.config.max_eval_depth {
+            return Some(CellValue::Error(format!(
+                "Dependency chain deeper than {} cells",
+                context.config.max_eval_depth
+            )));
+        }
+        match Frame::new(expressions, cell_name, expression, context) {
+            Ok(frame) => {
+                self.visiting.insert(cell_name.to_string());
+                self.stack.push(frame);
+                None
+            }
+            Err(err) => Some(CellValue::Error(err)),
+        }
+    }
+}
+
+/// Evaluates `cell_name`, reusing and adding to the values in `memo`.
+pub fn calculate_cell_value(
+    expressions: &HashMap<String, String>,
+    cell_name: &str,
+    memo: &mut Memo,
+    context: &EvalContext,
+) -> CellValue {
+    let mut evaluation = Evaluation::new(cell_name, memo);
+    loop {
+        if let Some(value) = evaluation.step(expressions, context) {
+            return value;
+        }
+    }
+}
+
+/// Evaluates `expression` on its own, as no cell's, reusing and adding to
+/// the values in `memo`.
+pub fn calculate_expression(
+    expressions: &HashMap<String, String>,
+    expression: &str,
+    memo: &mut Memo,
+    context: &EvalContext,
+) -> CellValue {
+    let frame = match Frame::standalone(expressions, expression, context) {
+        Ok(frame) => frame,
+        Err(err) => return CellValue::Error(err),
+    };
+    let mut evaluation = Evaluation {
+        start: None,
+        stack: vec![frame],
+        visiting: HashSet::new(),
+        finished: memo,
+        reads: 0,
+    };
+    loop {
+        if let Some(value) = evaluation.step(expressions, context) {
+            return value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::RemoteCache;
+
+    fn value_of(cells: &[(&str, &str)], cell_name: &str) -> CellValue {
+        let config = Config::default();
+        let remote = RemoteCache::new(config.remote_refresh, Vec::new());
+        let compiled = CompileCache::new(config.blank_policy);
+        let (macros, extent) = (Macros::default(), Extent::default());
+        let (column_types, formats) = (ColumnTypes::default(), Formats::default());
+        let context = EvalContext {
+            config: &config,
+            remote: &remote,
+            compiled: &compiled,
+            macros: &macros,
+            extent: &extent,
+            column_types: &column_types,
+            formats: &formats,
+            numeric: &[],
+        };
+        let expressions = cells
+            .iter()
+            .map(|(name, expression)| (name.to_string(), expression.to_string()))
+            .collect();
+        calculate_cell_value(&expressions, cell_name, &mut Memo::new(), &context)
+    }
+
+    const CELLS: [(&str, &str); 3] = [("A1", "1"), ("A2", "1 / 0"), ("A3", "3")];
+
+    fn with(cell: (&'static str, &'static str)) -> Vec<(&'static str, &'static str)> {
+        let mut cells = CELLS.to_vec();
+        cells.push(cell);
+        cells
+    }
+
+    #[test]
+    fn an_error_in_a_range_is_the_result() {
+        let error = CellValue::Error("Division by zero: 1 / 0".to_string());
+        assert_eq!(value_of(&with(("B1", "sum(A1_A3)")), "B1"), error);
+        assert_eq!(value_of(&with(("B1", "A2 + 1")), "B1"), error);
+    }
+
+    #[test]
+    fn coalesce_takes_the_first_value_that_isnt_empty() {
+        let coalesce = |expression| value_of(&with(("B1", expression)), "B1");
+        assert_eq!(coalesce("coalesce(C1, A3, A1)"), CellValue::Int(3));
+        assert_eq!(
+            coalesce("coalesce(C1, coalesce(C2, A1))"),
+            CellValue::Int(1)
+        );
+        assert_eq!(coalesce("coalesce(C1, C2)"), CellValue::None);
+    }
+
+    #[test]
+    fn iferror_falls_back_only_on_errors() {
+        assert_eq!(
+            value_of(&with(("B1", "iferror(A2, 0)")), "B1"),
+            CellValue::Int(0)
+        );
+        assert_eq!(
+            value_of(&with(("B1", "iferror(A1, 0)")), "B1"),
+            CellValue::Int(1)
+        );
+        assert_eq!(
+            value_of(&with(("B1", "iferror(sum(A1_A3), -1)")), "B1"),
+            CellValue::Int(-1)
+        );
+    }
+
+    #[test]
+    fn iserror_sees_range_members() {
+        let is_error = |expression| value_of(&with(("B1", expression)), "B1");
+        assert_eq!(
+            is_error("iserror(A1_A3)"),
+            CellValue::String("true".to_string())
+        );
+        assert_eq!(
+            is_error("iserror(A2)"),
+            CellValue::String("true".to_string())
+        );
+        assert_eq!(
+            is_error("iserror(A3)"),
+            CellValue::String("false".to_string())
+        );
+    }
+}
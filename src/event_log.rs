@@ -0,0 +1,142 @@
+use log::{info, warn};
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::wire::Outgoing;
+
+/// How many recent events `tail` can look back over.
+const RECENT_EVENTS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Off,
+    Errors,
+    All,
+}
+
+impl FromStr for Verbosity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Verbosity::Off),
+            "errors" => Ok(Verbosity::Errors),
+            "all" => Ok(Verbosity::All),
+            other => Err(format!(
+                "unknown verbosity {other:?}, expected off, errors or all"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEvent {
+    pub connection: String,
+    pub command: &'static str,
+    pub cell: Option<String>,
+    pub duration: Duration,
+    pub outcome: Outcome,
+}
+
+impl Display for LogEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connection={:?} command={} cell={} duration_us={}",
+            self.connection,
+            self.command,
+            self.cell.as_deref().unwrap_or("-"),
+            self.duration.as_micros()
+        )?;
+        match &self.outcome {
+            Outcome::Ok => write!(f, " outcome=ok"),
+            Outcome::Error(err) => write!(f, " outcome=error error={err:?}"),
+        }
+    }
+}
+
+/// How `tail` shows an event.
+pub fn tail_reply(event: &LogEvent) -> Reply {
+    Reply::Value("tail".to_string(), CellValue::String(event.to_string()))
+}
+
+pub struct EventLog {
+    verbosity: Mutex<Verbosity>,
+    recent: Mutex<VecDeque<LogEvent>>,
+    /// Connections following the log with `tail follow`, by id.
+    followers: Mutex<HashMap<String, Sender<Outgoing>>>,
+}
+
+impl EventLog {
+    pub fn new(verbosity: Verbosity) -> Self {
+        EventLog {
+            verbosity: Mutex::new(verbosity),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_EVENTS)),
+            followers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_verbosity(&self, verbosity: Verbosity) {
+        *self.verbosity.lock().unwrap() = verbosity;
+    }
+
+    pub fn record(&self, event: LogEvent) {
+        let wanted = match event.outcome {
+            Outcome::Ok => Verbosity::All,
+            Outcome::Error(_) => Verbosity::Errors,
+        };
+        if *self.verbosity.lock().unwrap() < wanted {
+            return;
+        }
+
+        match event.outcome {
+            Outcome::Ok => info!("{event}"),
+            Outcome::Error(_) => warn!("{event}"),
+        }
+
+        self.followers
+            .lock()
+            .unwrap()
+            .retain(|_, outbox| outbox.send(tail_reply(&event).into()).is_ok());
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(event);
+    }
+
+    /// Sends every event recorded from now on to `outbox`.
+    pub fn follow(&self, connection: &str, outbox: Sender<Outgoing>) {
+        self.followers
+            .lock()
+            .unwrap()
+            .insert(connection.to_string(), outbox);
+    }
+
+    pub fn leave(&self, connection: &str) {
+        self.followers.lock().unwrap().remove(connection);
+    }
+
+    /// The last `count` recorded events, oldest first.
+    pub fn tail(&self, count: usize) -> Vec<LogEvent> {
+        let recent = self.recent.lock().unwrap();
+        recent
+            .iter()
+            .skip(recent.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::cell_ref::{CellRange, CellRef};
+
+/// Which way `list` walks the populated cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListOrder {
+    /// Across each row, top to bottom.
+    Row,
+    /// Down each column, left to right.
+    Column,
+}
+
+impl FromStr for ListOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "row" => Ok(ListOrder::Row),
+            "col" => Ok(ListOrder::Column),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Cells {
+    /// Keyed `(row, col)`.
+    by_row: BTreeSet<(u32, u32)>,
+    /// `CellRef` orders by column first.
+    by_col: BTreeSet<CellRef>,
+}
+
+/// The cells with an expression, kept sorted both ways as cells come and
+/// go, so the populated range and pages of `list` are cheap to find.
+#[derive(Default)]
+pub struct Extent {
+    cells: Mutex<Cells>,
+}
+
+impl Extent {
+    pub fn add(&self, cell: CellRef) {
+        let mut cells = self.cells.lock().unwrap();
+        cells.by_row.insert((cell.row, cell.col));
+        cells.by_col.insert(cell);
+    }
+
+    pub fn remove(&self, cell: CellRef) {
+        let mut cells = self.cells.lock().unwrap();
+        cells.by_row.remove(&(cell.row, cell.col));
+        cells.by_col.remove(&cell);
+    }
+
+    /// The populated range, or `None` for an empty sheet.
+    pub fn bounds(&self) -> Option<CellRange> {
+        let cells = self.cells.lock().unwrap();
+        let (first_row, last_row) = (cells.by_row.first()?.0, cells.by_row.last()?.0);
+        let (first_col, last_col) = (cells.by_col.first()?.col, cells.by_col.last()?.col);
+        Some(CellRange {
+            start: CellRef {
+                col: first_col,
+                row: first_row,
+            },
+            end: CellRef {
+                col: last_col,
+                row: last_row,
+            },
+        })
+    }
+
+    /// Up to `limit` populated cells in `order`, skipping the first
+    /// `offset`, along with how many cells there are in all.
+    pub fn page(&self, order: ListOrder, offset: usize, limit: usize) -> (usize, Vec<CellRef>) {
+        let cells = self.cells.lock().unwrap();
+        let page = match order {
+            ListOrder::Row => cells
+                .by_row
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .map(|&(row, col)| CellRef { col, row })
+                .collect(),
+            ListOrder::Column => cells
+                .by_col
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .copied()
+                .collect(),
+        };
+        (cells.by_col.len(), page)
+    }
+}
@@ -0,0 +1,234 @@
+/// Folds constant integer arithmetic in `expression`, so `2*3+A1` is stored
+/// as `6+A1`. Only expressions made of integers, names, calls, parentheses
+/// and `+ - * / %` are touched; anything else, and any constant that would
+/// overflow or divide by zero, is left for Rhai to evaluate. Rhai's left to
+/// right evaluation is kept, so `A1+2+3` stays as it is: if `A1` held a
+/// string, folding it to `A1+5` would change the result.
+pub fn fold_constants(expression: &str) -> String {
+    let Some(tokens) = tokenize(expression) else {
+        return expression.to_string();
+    };
+    let mut folder = Folder {
+        tokens: &tokens,
+        position: 0,
+        replacements: Vec::new(),
+    };
+    let Some(root) = folder.expr() else {
+        return expression.to_string();
+    };
+    if folder.position != tokens.len() {
+        return expression.to_string();
+    }
+    folder.flush(&root);
+
+    let mut folded = expression.to_string();
+    for (start, end, value) in folder.replacements.into_iter().rev() {
+        let text = if value < 0 {
+            format!("({value})")
+        } else {
+            value.to_string()
+        };
+        folded.replace_range(start..end, &text);
+    }
+    folded
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Int(i64),
+    Name,
+    Op(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token {
+    kind: Kind,
+    start: usize,
+    end: usize,
+}
+
+fn tokenize(expression: &str) -> Option<Vec<Token>> {
+    let bytes = expression.as_bytes();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        let start = index;
+        let byte = bytes[index];
+        let kind = if byte.is_ascii_whitespace() {
+            index += 1;
+            continue;
+        } else if byte.is_ascii_digit() {
+            while index < bytes.len() && bytes[index].is_ascii_digit() {
+                index += 1;
+            }
+            Kind::Int(expression[start..index].parse().ok()?)
+        } else if byte.is_ascii_alphabetic() || byte == b'_' {
+            while index < bytes.len()
+                && (bytes[index].is_ascii_alphanumeric() || bytes[index] == b'_')
+            {
+                index += 1;
+            }
+            Kind::Name
+        } else if b"+-*/%(),".contains(&byte) {
+            index += 1;
+            Kind::Op(byte)
+        } else {
+            return None;
+        };
+        tokens.push(Token {
+            kind,
+            start,
+            end: index,
+        });
+    }
+    Some(tokens)
+}
+
+/// A parsed sub-expression: its source span, and its value if it is
+/// constant. `literal` marks constants already written as a single number.
+struct Node {
+    start: usize,
+    end: usize,
+    value: Option<i64>,
+    literal: bool,
+}
+
+struct Folder<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    replacements: Vec<(usize, usize, i64)>,
+}
+
+impl Folder<'_> {
+    fn peek(&self) -> Option<Kind> {
+        self.tokens.get(self.position).map(|token| token.kind)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = *self.tokens.get(self.position)?;
+        self.position += 1;
+        Some(token)
+    }
+
+    fn expect(&mut self, op: u8) -> Option<Token> {
+        self.next().filter(|token| token.kind == Kind::Op(op))
+    }
+
+    /// Records `node` for folding if it is a constant worth rewriting.
+    fn flush(&mut self, node: &Node) {
+        if let (Some(value), false) = (node.value, node.literal) {
+            self.replacements.push((node.start, node.end, value));
+        }
+    }
+
+    fn binary(&mut self, left: Node, op: u8, right: Node) -> Node {
+        let value = match (left.value, right.value) {
+            (Some(a), Some(b)) => match op {
+                b'+' => a.checked_add(b),
+                b'-' => a.checked_sub(b),
+                b'*' => a.checked_mul(b),
+                b'/' => a.checked_div(b),
+                b'%' => a.checked_rem(b),
+                _ => None,
+            },
+            _ => None,
+        };
+        if value.is_none() {
+            self.flush(&left);
+            self.flush(&right);
+        }
+        Node {
+            start: left.start,
+            end: right.end,
+            value,
+            literal: false,
+        }
+    }
+
+    fn expr(&mut self) -> Option<Node> {
+        let mut left = self.term()?;
+        while let Some(Kind::Op(op @ (b'+' | b'-'))) = self.peek() {
+            self.position += 1;
+            let right = self.term()?;
+            left = self.binary(left, op, right);
+        }
+        Some(left)
+    }
+
+    fn term(&mut self) -> Option<Node> {
+        let mut left = self.unary()?;
+        while let Some(Kind::Op(op @ (b'*' | b'/' | b'%'))) = self.peek() {
+            self.position += 1;
+            let right = self.unary()?;
+            left = self.binary(left, op, right);
+        }
+        Some(left)
+    }
+
+    fn unary(&mut self) -> Option<Node> {
+        if self.peek() != Some(Kind::Op(b'-')) {
+            return self.primary();
+        }
+        let minus = self.next()?;
+        let operand = self.unary()?;
+        let value = operand.value.and_then(i64::checked_neg);
+        if value.is_none() {
+            self.flush(&operand);
+        }
+        Some(Node {
+            start: minus.start,
+            end: operand.end,
+            value,
+            literal: operand.literal,
+        })
+    }
+
+    fn primary(&mut self) -> Option<Node> {
+        let token = self.next()?;
+        match token.kind {
+            Kind::Int(value) => Some(Node {
+                start: token.start,
+                end: token.end,
+                value: Some(value),
+                literal: true,
+            }),
+            Kind::Name if self.peek() == Some(Kind::Op(b'(')) => {
+                self.position += 1;
+                if self.peek() != Some(Kind::Op(b')')) {
+                    loop {
+                        let arg = self.expr()?;
+                        self.flush(&arg);
+                        if self.peek() != Some(Kind::Op(b',')) {
+                            break;
+                        }
+                        self.position += 1;
+                    }
+                }
+                let close = self.expect(b')')?;
+                Some(Node {
+                    start: token.start,
+                    end: close.end,
+                    value: None,
+                    literal: false,
+                })
+            }
+            Kind::Name => Some(Node {
+                start: token.start,
+                end: token.end,
+                value: None,
+                literal: false,
+            }),
+            Kind::Op(b'(') => {
+                let inner = self.expr()?;
+                let close = self.expect(b')')?;
+                Some(Node {
+                    start: token.start,
+                    end: close.end,
+                    value: inner.value,
+                    literal: false,
+                })
+            }
+            Kind::Op(_) => None,
+        }
+    }
+}
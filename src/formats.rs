@@ -0,0 +1,68 @@
+use rsheet_lib::cell_value::CellValue;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::Mutex;
+
+use crate::memory::entry_bytes;
+use crate::numbers::to_percent;
+
+/// How a cell's value is shown, without changing the value itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayFormat {
+    /// A fraction shown as a percentage, so `0.15` reads `15%`.
+    Percent,
+}
+
+impl DisplayFormat {
+    /// `value` as it should be shown.
+    pub fn apply(self, value: CellValue) -> CellValue {
+        match (self, &value) {
+            (DisplayFormat::Percent, CellValue::Int(i)) => {
+                CellValue::String(format!("{}%", i * 100))
+            }
+            (DisplayFormat::Percent, CellValue::String(s)) => match to_percent(s) {
+                Some(percent) => CellValue::String(percent),
+                None => value,
+            },
+            _ => value,
+        }
+    }
+}
+
+/// The display format of each cell that has one. A format is set along with
+/// the cell's expression and goes when it's next written without one.
+#[derive(Default)]
+pub struct Formats {
+    cells: Mutex<HashMap<String, DisplayFormat>>,
+}
+
+impl Formats {
+    pub fn memory_bytes(&self) -> usize {
+        self.cells
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|name| entry_bytes(name, size_of::<DisplayFormat>()))
+            .sum()
+    }
+
+    pub fn set(&self, cell_name: &str, format: Option<DisplayFormat>) {
+        let mut cells = self.cells.lock().unwrap();
+        match format {
+            Some(format) => cells.insert(cell_name.to_string(), format),
+            None => cells.remove(cell_name),
+        };
+    }
+
+    pub fn get(&self, cell_name: &str) -> Option<DisplayFormat> {
+        self.cells.lock().unwrap().get(cell_name).copied()
+    }
+
+    /// `value` as `cell_name` shows it.
+    pub fn display(&self, cell_name: &str, value: CellValue) -> CellValue {
+        match self.get(cell_name) {
+            Some(format) => format.apply(value),
+            None => value,
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use rsheet_lib::cell_value::CellValue;
+
+/// A call to a server-side function, found in an expression before it is
+/// handed to the `CommandRunner`, which only knows `sum` and `sleep_then`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call<'a> {
+    pub name: &'a str,
+    /// Each argument's source text, trimmed.
+    pub args: Vec<&'a str>,
+    start: usize,
+    end: usize,
+}
+
+impl Call<'_> {
+    /// The index just past the call's closing parenthesis.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+}
+
+/// Returns the index just past the string literal starting at `start`.
+pub fn skip_string(expression: &str, start: usize) -> Result<usize, String> {
+    let quote = expression.as_bytes()[start];
+    let mut bytes = expression.bytes().enumerate().skip(start + 1);
+    while let Some((index, byte)) = bytes.next() {
+        if byte == b'\\' && quote != b'`' {
+            bytes.next();
+        } else if byte == quote {
+            return Ok(index + 1);
+        }
+    }
+    Err("Unterminated string".to_string())
+}
+
+pub fn is_quote(byte: u8) -> bool {
+    byte == b'"' || byte == b'\'' || byte == b'`'
+}
+
+fn is_identifier(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+/// The text between the quotes of each string literal in `expression`,
+/// escapes left as written.
+pub fn string_literals(expression: &str) -> Result<Vec<&str>, String> {
+    let bytes = expression.as_bytes();
+    let mut literals = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        if is_quote(bytes[index]) {
+            let end = skip_string(expression, index)?;
+            literals.push(&expression[index + 1..end - 1]);
+            index = end;
+        } else {
+            index += 1;
+        }
+    }
+    Ok(literals)
+}
+
+/// Splits the argument list opening at `open` (a `(`), returning the
+/// arguments and the index just past the closing `)`.
+fn split_args(expression: &str, open: usize) -> Result<(Vec<&str>, usize), String> {
+    let bytes = expression.as_bytes();
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut arg_start = open + 1;
+    let mut index = open + 1;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            byte if is_quote(byte) => {
+                index = skip_string(expression, index)?;
+                continue;
+            }
+            b'(' | b'[' => depth += 1,
+            b')' | b']' if depth > 0 => depth -= 1,
+            b')' => {
+                let last = expression[arg_start..index].trim();
+                if !last.is_empty() || !args.is_empty() {
+                    args.push(last);
+                }
+                return Ok((args, index + 1));
+            }
+            b',' if depth == 0 => {
+                args.push(expression[arg_start..index].trim());
+                arg_start = index + 1;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    Err("Unbalanced parentheses".to_string())
+}
+
+/// Finds the outermost calls to any of `names` in `expression`, ignoring
+/// anything inside string literals. Calls nested in another call's
+/// arguments are left for whoever evaluates those arguments.
+pub fn find_calls<'a>(expression: &'a str, names: &[&str]) -> Result<Vec<Call<'a>>, String> {
+    let bytes = expression.as_bytes();
+    let mut calls = Vec::new();
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let byte = bytes[index];
+        if is_quote(byte) {
+            index = skip_string(expression, index)?;
+        } else if is_identifier(byte) {
+            let start = index;
+            while index < bytes.len() && is_identifier(bytes[index]) {
+                index += 1;
+            }
+            let name = &expression[start..index];
+            let open = index + (expression[index..].len() - expression[index..].trim_start().len());
+            if names.contains(&name) && bytes.get(open) == Some(&b'(') {
+                let (args, end) = split_args(expression, open)?;
+                calls.push(Call {
+                    name,
+                    args,
+                    start,
+                    end,
+                });
+                index = end;
+            }
+        } else {
+            index += 1;
+        }
+    }
+    Ok(calls)
+}
+
+/// Replaces each identifier outside string literals for which `replace`
+/// returns a new spelling. Property names after a `.` are left alone.
+pub fn replace_identifiers(
+    expression: &str,
+    mut replace: impl FnMut(&str) -> Option<String>,
+) -> Result<String, String> {
+    replace_names(expression, |name, _| replace(name))
+}
+
+/// Like `replace_identifiers`, but also tells `replace` whether the
+/// identifier is called as a function.
+pub fn replace_names(
+    expression: &str,
+    mut replace: impl FnMut(&str, bool) -> Option<String>,
+) -> Result<String, String> {
+    replace_placed(expression, |name, placement| {
+        replace(name, placement.called)
+    })
+}
+
+/// Where an identifier sits in an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    /// Whether it is called as a function.
+    pub called: bool,
+    /// Whether it makes up a whole argument of a call, like the `2_5` in
+    /// `sum(2_5)`.
+    pub argument: bool,
+}
+
+/// Like `replace_identifiers`, but also tells `replace` where the
+/// identifier sits.
+pub fn replace_placed(
+    expression: &str,
+    mut replace: impl FnMut(&str, Placement) -> Option<String>,
+) -> Result<String, String> {
+    let bytes = expression.as_bytes();
+    let mut replaced = String::with_capacity(expression.len());
+    let mut last = 0;
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let byte = bytes[index];
+        if is_quote(byte) {
+            index = skip_string(expression, index)?;
+        } else if is_identifier(byte) {
+            let start = index;
+            while index < bytes.len() && is_identifier(bytes[index]) {
+                index += 1;
+            }
+            if start > 0 && bytes[start - 1] == b'.' {
+                continue;
+            }
+            let before = expression[..start].trim_end();
+            let after = expression[index..].trim_start();
+            let opens_call = before
+                .strip_suffix('(')
+                .is_some_and(|callee| callee.trim_end().bytes().last().is_some_and(is_identifier));
+            let placement = Placement {
+                called: after.starts_with('('),
+                argument: (opens_call || before.ends_with(','))
+                    && (after.starts_with(')') || after.starts_with(',')),
+            };
+            if let Some(replacement) = replace(&expression[start..index], placement) {
+                replaced.push_str(&expression[last..start]);
+                replaced.push_str(&replacement);
+                last = index;
+            }
+        } else {
+            index += 1;
+        }
+    }
+    replaced.push_str(&expression[last..]);
+    Ok(replaced)
+}
+
+pub fn string_literal(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+    literal.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Writes `value` as a Rhai literal. Errors have no literal form, so they are
+/// returned as `Err` and the whole expression evaluates to that error.
+pub fn literal(value: &CellValue) -> Result<String, String> {
+    match value {
+        CellValue::Int(i) if *i < 0 => Ok(format!("({i})")),
+        CellValue::Int(i) => Ok(i.to_string()),
+        CellValue::String(s) => Ok(string_literal(s)),
+        CellValue::None => Ok("()".to_string()),
+        CellValue::Error(err) => Err(err.clone()),
+    }
+}
+
+/// Parses a double quoted string argument.
+pub fn string_argument(arg: &str) -> Option<String> {
+    match crate::query::parse_literal(arg)? {
+        CellValue::String(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// Replaces every outermost call to one of `names` with the text `replace`
+/// produces for it.
+pub fn replace_calls(
+    expression: &str,
+    names: &[&str],
+    mut replace: impl FnMut(&Call) -> Result<String, String>,
+) -> Result<String, String> {
+    let calls = find_calls(expression, names)?;
+    if calls.is_empty() {
+        return Ok(expression.to_string());
+    }
+
+    let mut replaced = String::with_capacity(expression.len());
+    let mut last = 0;
+    for call in &calls {
+        replaced.push_str(&expression[last..call.start]);
+        replaced.push_str(&replace(call)?);
+        last = call.end;
+    }
+    replaced.push_str(&expression[last..]);
+    Ok(replaced)
+}
+
+/// Replaces every outermost call to one of `names` with the literal of the
+/// value `resolve` produces for it.
+pub fn expand(
+    expression: &str,
+    names: &[&str],
+    mut resolve: impl FnMut(&Call) -> CellValue,
+) -> Result<String, String> {
+    replace_calls(expression, names, |call| literal(&resolve(call)))
+}
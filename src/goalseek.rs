@@ -0,0 +1,60 @@
+/// Most evaluations of the target a search makes before giving up.
+const MAX_STEPS: usize = 100;
+
+/// Finds an input in `[low, high]` for which `evaluate` comes within
+/// `tolerance` of `goal`, by false position with the Illinois fix, which
+/// converges like the secant method but keeps the answer bracketed like
+/// bisection. The goal must lie between the values at the two bounds.
+pub fn seek(
+    mut evaluate: impl FnMut(f64) -> Result<f64, String>,
+    goal: f64,
+    (mut low, mut high): (f64, f64),
+    tolerance: f64,
+) -> Result<f64, String> {
+    let mut miss = |input: f64| evaluate(input).map(|value| value - goal);
+    let mut low_miss = miss(low)?;
+    if low_miss.abs() <= tolerance {
+        return Ok(low);
+    }
+    let mut high_miss = miss(high)?;
+    if high_miss.abs() <= tolerance {
+        return Ok(high);
+    }
+    if low_miss.signum() == high_miss.signum() {
+        return Err(format!(
+            "The goal isn't between the values at {low} and {high}"
+        ));
+    }
+
+    // Which bound moved last, so a bound stuck twice in a row gets its miss
+    // halved and the estimate pulled towards it.
+    let mut last_moved = 0;
+    for _ in 0..MAX_STEPS {
+        let mut input = high - high_miss * (high - low) / (high_miss - low_miss);
+        if !(input > low.min(high) && input < low.max(high)) {
+            input = (low + high) / 2.0;
+        }
+        let input_miss = miss(input)?;
+        if input_miss.abs() <= tolerance {
+            return Ok(input);
+        }
+        if input_miss.signum() == low_miss.signum() {
+            low = input;
+            low_miss = input_miss;
+            if last_moved == -1 {
+                high_miss /= 2.0;
+            }
+            last_moved = -1;
+        } else {
+            high = input;
+            high_miss = input_miss;
+            if last_moved == 1 {
+                low_miss /= 2.0;
+            }
+            last_moved = 1;
+        }
+    }
+    Err(format!(
+        "No input reached the goal within {MAX_STEPS} steps"
+    ))
+}
@@ -0,0 +1,340 @@
+use rhai::Engine;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::mem::size_of;
+
+use crate::cell_ref::{expand_whole_references, CellRange, CellRef};
+use crate::compiled::Compiled;
+use crate::config::Config;
+use crate::logic;
+use crate::memory::{entry_bytes, string_bytes};
+
+/// Cell dependencies derived from the stored expressions. Range references
+/// only produce edges to cells that actually have an expression.
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    dependencies: HashMap<String, HashSet<String>>,
+    dependents: HashMap<String, HashSet<String>>,
+    /// Every range read, with the cell reading it.
+    ranges: Vec<(CellRange, String)>,
+}
+
+impl DependencyGraph {
+    /// Rough bytes held by every edge and range read.
+    pub fn memory_bytes(&self) -> usize {
+        let edges = |map: &HashMap<String, HashSet<String>>| -> usize {
+            map.iter()
+                .map(|(name, cells)| {
+                    let cells: usize = cells.iter().map(|cell| entry_bytes(cell, 0)).sum();
+                    entry_bytes(name, size_of::<HashSet<String>>() + cells)
+                })
+                .sum()
+        };
+        let ranges: usize = self
+            .ranges
+            .iter()
+            .map(|(_, name)| size_of::<CellRange>() + string_bytes(name))
+            .sum();
+        edges(&self.dependencies) + edges(&self.dependents) + ranges
+    }
+
+    pub fn build(expressions: &HashMap<String, String>, config: &Config) -> Self {
+        let cells: Vec<(&String, CellRef)> = expressions
+            .keys()
+            .filter_map(|name| Some((name, CellRef::parse(name, config).ok()?)))
+            .collect();
+
+        // One bare engine parses every expression; building a full one per
+        // expression, as `CommandRunner` does, dominates on big sheets.
+        let engine = Engine::new_raw();
+        // Whole rows and columns count as reaching the edge of the sheet, so
+        // cells outside the populated part still know who reads them.
+        let edge = CellRef {
+            col: config.max_column,
+            row: config.max_row,
+        };
+        let mut graph = DependencyGraph::default();
+        for (name, expression) in expressions {
+            let mut dependencies = HashSet::new();
+            let expression = logic::expand(expression).unwrap_or_else(|_| expression.clone());
+            let expression = expand_whole_references(&expression, || edge, config);
+            for var_name in Compiled::new(&engine, &expression).variables() {
+                if let Some((start, end)) = var_name.split_once('_') {
+                    let (Ok(start), Ok(end)) =
+                        (CellRef::parse(start, config), CellRef::parse(end, config))
+                    else {
+                        continue;
+                    };
+                    graph
+                        .ranges
+                        .push((CellRange::new(start, end), name.clone()));
+                    for (other, cell) in &cells {
+                        if (start.col..=end.col).contains(&cell.col)
+                            && (start.row..=end.row).contains(&cell.row)
+                        {
+                            dependencies.insert((*other).clone());
+                        }
+                    }
+                } else {
+                    dependencies.insert(var_name.clone());
+                }
+            }
+
+            for dependency in &dependencies {
+                graph
+                    .dependents
+                    .entry(dependency.clone())
+                    .or_default()
+                    .insert(name.clone());
+            }
+            graph.dependencies.insert(name.clone(), dependencies);
+        }
+        graph
+    }
+
+    pub fn dependencies_of(&self, cell_name: &str) -> impl Iterator<Item = &String> {
+        self.dependencies.get(cell_name).into_iter().flatten()
+    }
+
+    pub fn dependents_of(&self, cell_name: &str) -> impl Iterator<Item = &String> {
+        self.dependents.get(cell_name).into_iter().flatten()
+    }
+
+    /// Every cell whose value can change when the `changed` cells do, in the
+    /// order they are reached. Range reads count even for cells that no
+    /// longer have an expression. The changed cells themselves are only
+    /// included if they read one another.
+    pub fn affected_by(&self, changed: &[String], config: &Config) -> Vec<String> {
+        let mut affected = Vec::new();
+        let mut seen: HashSet<&String> = HashSet::new();
+        let mut queue: VecDeque<&String> = changed.iter().collect();
+        while let Some(name) = queue.pop_front() {
+            let cell = CellRef::parse(name, config).ok();
+            let range_readers = self
+                .ranges
+                .iter()
+                .filter(|(range, _)| cell.is_some_and(|cell| range.contains(cell)))
+                .map(|(_, reader)| reader);
+            for reader in self.dependents_of(name).chain(range_readers) {
+                if seen.insert(reader) {
+                    affected.push(reader.clone());
+                    queue.push_back(reader);
+                }
+            }
+        }
+        affected
+    }
+
+    /// The cells with an expression, grouped so that each group only reads
+    /// cells in earlier groups. Cells in a cycle, or reading one, can't be
+    /// ordered and come last, together.
+    pub fn levels(&self) -> Vec<Vec<&String>> {
+        let (mut levels, unordered) = self.ordered_levels();
+        if !unordered.is_empty() {
+            levels.push(unordered);
+        }
+        levels
+    }
+
+    /// How many cells long the longest chain of reads ending at each cell
+    /// is, counting the cell itself. Cells in a cycle, or reading one, have
+    /// no depth.
+    pub fn depths(&self) -> HashMap<&String, usize> {
+        let (levels, _) = self.ordered_levels();
+        levels
+            .into_iter()
+            .enumerate()
+            .flat_map(|(level, cells)| cells.into_iter().map(move |cell| (cell, level + 1)))
+            .collect()
+    }
+
+    /// The groups `levels` orders, and the cells it can't.
+    fn ordered_levels(&self) -> (Vec<Vec<&String>>, Vec<&String>) {
+        let mut waiting: HashMap<&String, usize> = self
+            .dependencies
+            .iter()
+            .map(|(name, dependencies)| {
+                let count = dependencies
+                    .iter()
+                    .filter(|dep| self.dependencies.contains_key(*dep))
+                    .count();
+                (name, count)
+            })
+            .collect();
+
+        let mut levels = Vec::new();
+        let mut ready: Vec<&String> = waiting
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(name, _)| *name)
+            .collect();
+        while !ready.is_empty() {
+            let mut next = Vec::new();
+            for name in &ready {
+                waiting.remove(*name);
+                for dependent in self.dependents_of(name) {
+                    if let Some(count) = waiting.get_mut(dependent) {
+                        *count -= 1;
+                        if *count == 0 {
+                            next.push(dependent);
+                        }
+                    }
+                }
+            }
+            levels.push(std::mem::replace(&mut ready, next));
+        }
+        (levels, waiting.into_keys().collect())
+    }
+
+    /// Cells with an expression that no other expression references.
+    pub fn orphans(&self) -> Vec<&String> {
+        self.dependencies
+            .keys()
+            .filter(|name| self.dependents_of(name).next().is_none())
+            .collect()
+    }
+
+    /// Groups of cells that depend on each other in a loop: every strongly
+    /// connected component with more than one cell, plus any cell that
+    /// references itself.
+    pub fn cycles(&self) -> Vec<Vec<&String>> {
+        let mut names: Vec<&String> = self.dependencies.keys().collect();
+        names.sort();
+
+        let mut tarjan = Tarjan::default();
+        for root in names {
+            if !tarjan.index.contains_key(root) {
+                tarjan.run(self, root);
+            }
+        }
+        tarjan
+            .components
+            .into_iter()
+            .filter(|component| {
+                component.len() > 1
+                    || self
+                        .dependencies_of(component[0])
+                        .any(|dep| dep == component[0])
+            })
+            .collect()
+    }
+
+    /// Renders the graph in Graphviz DOT, with edges pointing from each
+    /// cell to the cells that read it. With a `range`, only edges with at
+    /// least one end inside it are kept, so fan-in from outside still shows.
+    /// Returns the text and the number of cells drawn.
+    pub fn to_dot(
+        &self,
+        range: Option<CellRange>,
+        tags: &HashMap<String, BTreeSet<String>>,
+        config: &Config,
+    ) -> (String, usize) {
+        let in_range = |name: &str| match range {
+            None => true,
+            Some(range) => CellRef::parse(name, config).is_ok_and(|cell| range.contains(cell)),
+        };
+
+        let mut edges: Vec<(&String, &String)> = self
+            .dependencies
+            .iter()
+            .flat_map(|(name, dependencies)| dependencies.iter().map(move |dep| (dep, name)))
+            .filter(|(from, to)| in_range(from) || in_range(to))
+            .collect();
+        edges.sort();
+        let mut nodes: Vec<&String> = self
+            .dependencies
+            .keys()
+            .filter(|name| in_range(name))
+            .chain(edges.iter().flat_map(|(from, to)| [*from, *to]))
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+
+        let mut dot = String::from("digraph deps {\n");
+        for node in &nodes {
+            match tags.get(*node) {
+                Some(tags) => {
+                    let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+                    dot.push_str(&format!(
+                        "  \"{node}\" [label=\"{node} ({})\"];\n",
+                        tags.join(", ")
+                    ));
+                }
+                None => dot.push_str(&format!("  \"{node}\";\n")),
+            }
+        }
+        for (from, to) in &edges {
+            dot.push_str(&format!("  \"{from}\" -> \"{to}\";\n"));
+        }
+        dot.push_str("}\n");
+        (dot, nodes.len())
+    }
+
+    /// Referenced cells whose own expression references nothing.
+    pub fn inputs(&self) -> Vec<&String> {
+        self.dependencies
+            .iter()
+            .filter(|(name, dependencies)| {
+                dependencies.is_empty() && self.dependents_of(name).next().is_some()
+            })
+            .map(|(name, _)| name)
+            .collect()
+    }
+}
+
+/// Tarjan's strongly connected components, walked with an explicit stack so
+/// long dependency chains can't overflow the call stack.
+#[derive(Default)]
+struct Tarjan<'a> {
+    index: HashMap<&'a String, usize>,
+    low_link: HashMap<&'a String, usize>,
+    stack: Vec<&'a String>,
+    on_stack: HashSet<&'a String>,
+    /// Each cell being visited, with the dependencies it has yet to follow.
+    work: Vec<(&'a String, Vec<&'a String>)>,
+    components: Vec<Vec<&'a String>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn discover(&mut self, graph: &'a DependencyGraph, name: &'a String) {
+        let next = self.index.len();
+        self.index.insert(name, next);
+        self.low_link.insert(name, next);
+        self.stack.push(name);
+        self.on_stack.insert(name);
+        self.work
+            .push((name, graph.dependencies_of(name).collect()));
+    }
+
+    fn run(&mut self, graph: &'a DependencyGraph, root: &'a String) {
+        self.discover(graph, root);
+        while let Some((name, pending)) = self.work.last_mut() {
+            let name = *name;
+            if let Some(dependency) = pending.pop() {
+                if !self.index.contains_key(dependency) {
+                    self.discover(graph, dependency);
+                } else if self.on_stack.contains(dependency) {
+                    let low = self.low_link[name].min(self.index[dependency]);
+                    self.low_link.insert(name, low);
+                }
+                continue;
+            }
+
+            self.work.pop();
+            if let Some((parent, _)) = self.work.last() {
+                let low = self.low_link[parent].min(self.low_link[name]);
+                self.low_link.insert(parent, low);
+            }
+            if self.low_link[name] == self.index[name] {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack.remove(member);
+                    component.push(member);
+                    if member == name {
+                        break;
+                    }
+                }
+                self.components.push(component);
+            }
+        }
+    }
+}
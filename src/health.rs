@@ -0,0 +1,135 @@
+use rsheet_lib::cell_value::CellValue;
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::hlc::wall_millis;
+
+/// How often the recalculation thread reports in while it has nothing to
+/// do.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The file `health` writes to check the data directory is writable.
+pub const PROBE_FILE: &str = "health.probe";
+
+/// The recalculation thread's sign of life, and how much work is waiting
+/// for it.
+#[derive(Default)]
+pub struct Heartbeat {
+    /// When the thread last reported in, in milliseconds since the Unix
+    /// epoch.
+    beat: AtomicU64,
+    queued: AtomicI64,
+    /// Recalculation rounds that panicked and were recovered from.
+    panics: AtomicU64,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.beat.store(wall_millis(), Ordering::Relaxed);
+    }
+
+    pub fn pushed(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn popped(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn panicked(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How long since the thread last reported in.
+    fn age(&self) -> Duration {
+        let beat = self.beat.load(Ordering::Relaxed);
+        Duration::from_millis(wall_millis().saturating_sub(beat))
+    }
+}
+
+/// Whether the server is keeping up with its work.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    pub heartbeat_age: Duration,
+    pub queued: i64,
+    pub recalc_panics: u64,
+    /// Whether the data directory takes writes, if there is one.
+    pub persistence: Option<Result<(), String>>,
+    /// How long the recalculation thread can go without reporting in
+    /// before it counts as stalled.
+    pub stall_after: Duration,
+}
+
+/// What a caught panic said, if it said it with a string.
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Writes, syncs and removes a probe file in `dir`.
+fn probe(dir: &Path) -> Result<(), String> {
+    let path = dir.join(PROBE_FILE);
+    let written = File::create(&path)
+        .and_then(|mut file| {
+            file.write_all(b"ok\n")?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::remove_file(&path));
+    written.map_err(|err| err.to_string())
+}
+
+impl Health {
+    pub fn check(heartbeat: &Heartbeat, data_dir: Option<&Path>, stall_after: Duration) -> Self {
+        Health {
+            heartbeat_age: heartbeat.age(),
+            queued: heartbeat.queued.load(Ordering::Relaxed).max(0),
+            recalc_panics: heartbeat.panics.load(Ordering::Relaxed),
+            persistence: data_dir.map(probe),
+            stall_after,
+        }
+    }
+
+    fn stalled(&self) -> bool {
+        self.heartbeat_age >= self.stall_after
+    }
+
+    pub fn healthy(&self) -> bool {
+        !self.stalled() && !matches!(self.persistence, Some(Err(_)))
+    }
+
+    /// Each part of the status, by name, overall health first.
+    pub fn fields(&self) -> Vec<(&'static str, CellValue)> {
+        let text = |s: &str| CellValue::String(s.to_string());
+        let recalc = match (self.stalled(), self.queued) {
+            (false, _) => "alive",
+            (true, 0) => "stalled",
+            (true, _) => "stuck",
+        };
+        let persistence = match &self.persistence {
+            None => text("disabled"),
+            Some(Ok(())) => text("writable"),
+            Some(Err(err)) => text(&format!("failing: {err}")),
+        };
+        vec![
+            (
+                "health",
+                text(if self.healthy() { "ok" } else { "unhealthy" }),
+            ),
+            ("recalc", text(recalc)),
+            (
+                "heartbeat_ms",
+                CellValue::Int(self.heartbeat_age.as_millis() as i64),
+            ),
+            ("queue", CellValue::Int(self.queued)),
+            ("recalc_panics", CellValue::Int(self.recalc_panics as i64)),
+            ("persistence", persistence),
+        ]
+    }
+}
@@ -0,0 +1,79 @@
+use rsheet_lib::cell_value::CellValue;
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of;
+use std::sync::Mutex;
+
+use crate::memory::{entry_bytes, value_bytes};
+
+/// The values a cell has held, newest last. Its first value is version 1.
+#[derive(Default)]
+struct CellHistory {
+    latest: u64,
+    values: VecDeque<CellValue>,
+}
+
+/// The last few values of every cell, for `get A1@<version>`.
+pub struct History {
+    limit: usize,
+    cells: Mutex<HashMap<String, CellHistory>>,
+}
+
+impl History {
+    /// Rough bytes held by every kept version.
+    pub fn memory_bytes(&self) -> usize {
+        self.cells
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, history)| {
+                entry_bytes(
+                    name,
+                    size_of::<CellHistory>()
+                        + history.values.iter().map(value_bytes).sum::<usize>(),
+                )
+            })
+            .sum()
+    }
+
+    /// Keeps up to `limit` versions per cell; none at all if it is zero.
+    pub fn new(limit: usize) -> Self {
+        History {
+            limit,
+            cells: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records `value` as the next version of `cell_name`.
+    pub fn record(&self, cell_name: &str, value: &CellValue) {
+        if self.limit == 0 {
+            return;
+        }
+        let mut cells = self.cells.lock().unwrap();
+        let history = cells.entry(cell_name.to_string()).or_default();
+        history.latest += 1;
+        history.values.push_back(value.clone());
+        if history.values.len() > self.limit {
+            history.values.pop_front();
+        }
+    }
+
+    pub fn get(&self, cell_name: &str, version: u64) -> Result<CellValue, String> {
+        let cells = self.cells.lock().unwrap();
+        let Some(history) = cells.get(cell_name) else {
+            return Err(format!("{cell_name} has no recorded versions"));
+        };
+        let oldest = history.latest + 1 - history.values.len() as u64;
+        if version > history.latest || version == 0 {
+            Err(format!(
+                "{cell_name} has no version {version}, the latest is {}",
+                history.latest
+            ))
+        } else if version < oldest {
+            Err(format!(
+                "Version {version} of {cell_name} is no longer kept, the oldest is {oldest}"
+            ))
+        } else {
+            Ok(history.values[(version - oldest) as usize].clone())
+        }
+    }
+}
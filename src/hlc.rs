@@ -0,0 +1,77 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A hybrid logical clock reading, written `@<millis>.<counter>.<node>`.
+/// Stamps order by time, then counter, then node, so any two writes to a
+/// cell have a single winner whichever order they arrive in.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stamp {
+    pub millis: u64,
+    pub counter: u32,
+    pub node: String,
+}
+
+impl Display for Stamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}.{}.{}", self.millis, self.counter, self.node)
+    }
+}
+
+impl FromStr for Stamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = (|| {
+            let mut parts = s.strip_prefix('@')?.splitn(3, '.');
+            let millis = parts.next()?.parse().ok()?;
+            let counter = parts.next()?.parse().ok()?;
+            let node = parts.next().filter(|node| !node.is_empty())?;
+            Some(Stamp {
+                millis,
+                counter,
+                node: node.to_string(),
+            })
+        })();
+        parsed.ok_or_else(|| s.to_string())
+    }
+}
+
+/// Issues stamps that never go backwards, even if the wall clock does, and
+/// that stay ahead of every stamp seen from clients.
+#[derive(Debug, Default)]
+pub struct HybridClock {
+    millis: u64,
+    counter: u32,
+}
+
+pub fn wall_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+impl HybridClock {
+    pub fn now(&mut self, node: &str) -> Stamp {
+        let wall = wall_millis();
+        if wall > self.millis {
+            self.millis = wall;
+            self.counter = 0;
+        } else {
+            self.counter += 1;
+        }
+        Stamp {
+            millis: self.millis,
+            counter: self.counter,
+            node: node.to_string(),
+        }
+    }
+
+    /// Moves the clock past a stamp received from elsewhere.
+    pub fn observe(&mut self, stamp: &Stamp) {
+        if (stamp.millis, stamp.counter) > (self.millis, self.counter) {
+            self.millis = stamp.millis;
+            self.counter = stamp.counter;
+        }
+    }
+}
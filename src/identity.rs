@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The names connections have given themselves with `hello name=<id>`,
+/// shown in place of their address in presence, protections, the audit log
+/// and log lines.
+#[derive(Default)]
+pub struct Identities {
+    names: Mutex<HashMap<String, String>>,
+}
+
+impl Identities {
+    /// Names `connection`. A connection keeps the name it first gives, and
+    /// no two open connections share one.
+    pub fn claim(&self, connection: &str, name: &str) -> Result<(), String> {
+        let mut names = self.names.lock().unwrap();
+        if let Some(current) = names.get(connection) {
+            if current == name {
+                return Ok(());
+            }
+            return Err(format!("This connection is already named {current}"));
+        }
+        if names.values().any(|other| other == name) {
+            return Err(format!("The name {name} is taken by another connection"));
+        }
+        names.insert(connection.to_string(), name.to_string());
+        Ok(())
+    }
+
+    /// The name `connection` goes by: the one it gave, or its address.
+    pub fn label(&self, connection: &str) -> String {
+        let names = self.names.lock().unwrap();
+        names
+            .get(connection)
+            .cloned()
+            .unwrap_or_else(|| connection.to_string())
+    }
+
+    pub fn leave(&self, connection: &str) {
+        self.names.lock().unwrap().remove(connection);
+    }
+}
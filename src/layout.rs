@@ -0,0 +1,130 @@
+use rsheet_lib::cells::column_number_to_name;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::sync::Mutex;
+
+use crate::cell_ref::{parse_column, CellRange, CellRef};
+use crate::config::Config;
+
+/// A layout setting every client rendering the sheet should agree on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MetaKey {
+    FrozenRows,
+    FrozenColumns,
+    /// The width of a column, in whatever unit the clients share.
+    ColumnWidth(u32),
+}
+
+impl MetaKey {
+    /// Reads `frozen_rows`, `frozen_cols` or `width.<column>`.
+    pub fn parse(key: &str, config: &Config) -> Option<Self> {
+        match key {
+            "frozen_rows" => Some(MetaKey::FrozenRows),
+            "frozen_cols" => Some(MetaKey::FrozenColumns),
+            _ => {
+                let column = key.strip_prefix("width.")?;
+                parse_column(column, config).ok().map(MetaKey::ColumnWidth)
+            }
+        }
+    }
+}
+
+impl Display for MetaKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MetaKey::FrozenRows => write!(f, "frozen_rows"),
+            MetaKey::FrozenColumns => write!(f, "frozen_cols"),
+            MetaKey::ColumnWidth(col) => write!(f, "width.{}", column_number_to_name(*col)),
+        }
+    }
+}
+
+/// The sheet's layout settings. Unset ones are left to each client.
+#[derive(Default)]
+pub struct Layout {
+    settings: Mutex<BTreeMap<MetaKey, u32>>,
+}
+
+impl Layout {
+    /// Sets `key`, or with `None` unsets it.
+    pub fn set(&self, key: MetaKey, value: Option<u32>) {
+        let mut settings = self.settings.lock().unwrap();
+        match value {
+            Some(value) => settings.insert(key, value),
+            None => settings.remove(&key),
+        };
+    }
+
+    pub fn get(&self, key: MetaKey) -> Option<u32> {
+        self.settings.lock().unwrap().get(&key).copied()
+    }
+
+    /// Every setting, in key order.
+    pub fn list(&self) -> Vec<(MetaKey, u32)> {
+        let settings = self.settings.lock().unwrap();
+        settings.iter().map(|(key, value)| (*key, *value)).collect()
+    }
+}
+
+/// Merged regions, each showing one value, its top-left cell's. No two
+/// overlap.
+#[derive(Default)]
+pub struct Merges {
+    regions: Mutex<Vec<CellRange>>,
+}
+
+/// Whether `a` and `b` share a cell.
+fn overlap(a: CellRange, b: CellRange) -> bool {
+    a.start.col <= b.end.col
+        && b.start.col <= a.end.col
+        && a.start.row <= b.end.row
+        && b.start.row <= a.end.row
+}
+
+impl Merges {
+    pub fn merge(&self, range: CellRange) -> Result<(), String> {
+        if range.cell_count() < 2 {
+            return Err(format!("Can't merge the single cell {}", range.start));
+        }
+        let mut regions = self.regions.lock().unwrap();
+        if let Some(region) = regions.iter().find(|region| overlap(**region, range)) {
+            return Err(format!("{range} overlaps the merged region {region}"));
+        }
+        regions.push(range);
+        Ok(())
+    }
+
+    pub fn unmerge(&self, range: CellRange) -> Result<(), String> {
+        let mut regions = self.regions.lock().unwrap();
+        let before = regions.len();
+        regions.retain(|region| *region != range);
+        if regions.len() == before {
+            return Err(format!("{range} isn't merged"));
+        }
+        Ok(())
+    }
+
+    /// Every merged region, in cell order.
+    pub fn list(&self) -> Vec<CellRange> {
+        let mut regions = self.regions.lock().unwrap().clone();
+        regions.sort_by_key(|region| (region.start, region.end));
+        regions
+    }
+
+    /// A cell in `target` hidden inside a merged region, if there is one.
+    /// Top-left cells aren't hidden.
+    pub fn hidden_in(&self, target: CellRange) -> Option<CellRef> {
+        let regions = self.regions.lock().unwrap();
+        regions
+            .iter()
+            .filter(|region| overlap(**region, target))
+            .find_map(|region| {
+                let rows =
+                    region.start.row.max(target.start.row)..=region.end.row.min(target.end.row);
+                let cols =
+                    region.start.col.max(target.start.col)..=region.end.col.min(target.end.col);
+                rows.flat_map(|row| cols.clone().map(move |col| CellRef { col, row }))
+                    .find(|cell| *cell != region.start)
+            })
+    }
+}
@@ -1,260 +1,3382 @@
-use log::info;
+pub mod alert;
+pub mod audit;
+pub mod cell_ref;
+pub mod changes;
+pub mod coltype;
+pub mod commands;
+pub mod compiled;
+pub mod config;
+pub mod config_file;
+pub mod derive;
+pub mod diff;
+pub mod eval;
+pub mod event_log;
+pub mod extent;
+pub mod fold;
+pub mod formats;
+pub mod functions;
+pub mod goalseek;
+pub mod graph;
+pub mod health;
+pub mod history;
+pub mod hlc;
+pub mod identity;
+pub mod layout;
+pub mod lint;
+pub mod logic;
+pub mod macros;
+pub mod memory;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod net;
+pub mod numbers;
+pub mod parser;
+pub mod persistence;
+pub mod presence;
+pub mod profile;
+pub mod protect;
+pub mod query;
+pub mod remote;
+pub mod render;
+pub mod scenario;
+pub mod schedule;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod series;
+pub mod simulate;
+pub mod snapshot;
+pub mod template;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trash;
+pub mod values;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
+pub mod wire;
+#[cfg(feature = "xlsx")]
+pub mod xlsx;
+
+use alert::Alerts;
+use audit::AuditLog;
+use cell_ref::{r1c1_to_a1, relative_to_a1, CellRange, CellRef, CellRefError, RefStyle};
+use changes::{Change, ChangeFeed};
+use coltype::{ColumnType, ColumnTypes};
+use commands::{CommandSpec, COMMANDS};
+use compiled::CompileCache;
+use config::{ColumnTypePolicy, Config, ConflictResolution, Tenancy};
+use derive::{Derivation, Derivations};
+use diff::{Contents, DiffSource};
+use eval::{calculate_cell_value, calculate_expression, EvalContext, Memo};
+use event_log::{tail_reply, EventLog, LogEvent, Outcome};
+use extent::{Extent, ListOrder};
+use formats::{DisplayFormat, Formats};
+use graph::DependencyGraph;
+use health::{panic_message, Health, Heartbeat, HEARTBEAT_INTERVAL};
+use history::History;
+use hlc::{wall_millis, HybridClock, Stamp};
+use identity::Identities;
+use layout::{Layout, Merges, MetaKey};
+use log::{error, info, warn};
+use macros::{Macro, Macros};
+use memory::{entry_bytes, string_bytes, MemoryStats};
+use numbers::{as_number, within_epsilon};
+use parser::{parse_command, parse_frame, Command, ParseError};
+use persistence::{
+    coltype_record, data_file, define_record, delete_record, derive_record, export_file,
+    merge_record, meta_record, read_snapshot, set_record, tag_record, undefine_record, Storage,
+};
+use presence::{presence_reply, Presence};
+use profile::{Pass, Profiler};
+use protect::Protections;
+use query::SortKey;
+use remote::{RemoteCache, RemoteKey};
 use rsheet_lib::cell_value::CellValue;
-use rsheet_lib::cells::{column_name_to_number, column_number_to_name};
-use rsheet_lib::command_runner::{CellArgument, CommandRunner};
-use rsheet_lib::connect::{Manager, Reader, Writer};
+use rsheet_lib::cells::column_number_to_name;
+use rsheet_lib::connect::{ConnectionError, Manager, ReaderWriter};
 use rsheet_lib::replies::Reply;
-use std::collections::{HashMap, HashSet};
+use rust_decimal::Decimal;
+use scenario::{Scenarios, Trial};
+use schedule::Schedules;
+use simulate::{is_random, Summary};
+use snapshot::Published;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error::Error;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+use template::{Template, Templates};
+use trash::Trash;
+use values::Values;
+use wire::{
+    range_frame, write_frame, write_replies, write_reply, Compression, Input, Outgoing,
+    ReplyFormat, WireReader, WireWriter,
+};
+
+/// How many cells the update thread evaluates each time it takes the
+/// expressions lock.
+const UPDATE_CHUNK: usize = 64;
+
+/// Most cells one `getrange` may read.
+const MAX_RANGE_CELLS: u64 = 1 << 20;
+
+/// The expressions, locked.
+type ExpressionsGuard<'a> = MutexGuard<'a, HashMap<String, String>>;
+
+/// The values lock. Releasing it publishes whatever was written under it
+/// to lock-free readers.
+struct ValuesGuard<'a> {
+    values: MutexGuard<'a, Values>,
+    revision: &'a AtomicU64,
+}
+
+impl Deref for ValuesGuard<'_> {
+    type Target = Values;
+
+    fn deref(&self) -> &Values {
+        &self.values
+    }
+}
+
+impl DerefMut for ValuesGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Values {
+        &mut self.values
+    }
+}
+
+impl Drop for ValuesGuard<'_> {
+    fn drop(&mut self) {
+        let revision = self.revision.load(Ordering::SeqCst);
+        self.values.publish(revision);
+    }
+}
+
+/// The fewest cells worth handing to a thread of their own when
+/// recalculating the whole sheet.
+const PARALLEL_CHUNK: usize = 16;
+
+/// How often the scheduler looks for scheduled recalculations that are due.
+const SCHEDULE_TICK: Duration = Duration::from_secs(1);
+
+/// How often the compaction thread checks whether compaction has been
+/// turned on while it is off.
+const COMPACT_POLL: Duration = Duration::from_secs(5);
+
+/// Whole-sheet recalculations of at least this many cells log progress.
+const PROGRESS_CELLS: usize = 10_000;
 
 struct Coordinator {
     expressions: Arc<Mutex<HashMap<String, String>>>,
-    cell_values: Arc<Mutex<HashMap<String, CellValue>>>,
+    cell_values: Arc<Mutex<Values>>,
+    /// Snapshots of `cell_values` for reading without its lock, if enabled.
+    published: Option<Arc<Published>>,
     expression_sender: Sender<String>,
+    event_log: EventLog,
+    audit: AuditLog,
+    heartbeat: Heartbeat,
+    storage: Option<Mutex<Storage>>,
+    remote: Arc<RemoteCache>,
+    compiled: CompileCache,
+    macros: Macros,
+    column_types: ColumnTypes,
+    derivations: Derivations,
+    changes: ChangeFeed,
+    alerts: Alerts,
+    extent: Extent,
+    formats: Formats,
+    trash: Trash,
+    scenarios: Scenarios,
+    schedules: Schedules,
+    templates: Templates,
+    history: History,
+    /// Goes up whenever an expression, value or tag changes.
+    revision: AtomicU64,
+    /// The revision at which each cell's expression or value last changed.
+    changed_at: Mutex<HashMap<String, u64>>,
+    presence: Presence,
+    identities: Identities,
+    protections: Protections,
+    clock: Mutex<HybridClock>,
+    /// The stamp of the last write applied to each cell, deletes included.
+    stamps: Mutex<HashMap<String, Stamp>>,
+    /// How long each cell's last evaluation took.
+    costs: Mutex<HashMap<String, Duration>>,
+    profiler: Profiler,
+    /// Tags on each cell, kept whether or not the cell has an expression.
+    tags: Mutex<HashMap<String, BTreeSet<String>>>,
+    layout: Layout,
+    merges: Merges,
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Metrics,
+    #[cfg(feature = "webhooks")]
+    webhooks: webhook::Webhooks,
+    config: Config,
+    /// `config` as last reloaded, which is where the settings `config
+    /// reload` can change are read from.
+    settings: RwLock<Arc<Config>>,
+    /// How many connections are open, counting those waiting for a worker.
+    connections: Arc<AtomicUsize>,
 }
 
 impl Coordinator {
-    fn new(expression_sender: Sender<String>) -> Self {
+    fn new(expression_sender: Sender<String>, storage: Option<Storage>, config: Config) -> Self {
+        let mut values = Values::new(config.value_cache, config.data_dir.as_deref());
+        let published = config.lock_free_reads.then(|| values.published());
         Coordinator {
             expressions: Arc::new(Mutex::new(HashMap::new())),
-            cell_values: Arc::new(Mutex::new(HashMap::new())),
+            cell_values: Arc::new(Mutex::new(values)),
+            published,
             expression_sender,
+            event_log: EventLog::new(config.log_verbosity),
+            settings: RwLock::new(Arc::new(config.clone())),
+            connections: Arc::new(AtomicUsize::new(0)),
+            audit: AuditLog::new(config.audit_entries),
+            heartbeat: Heartbeat::default(),
+            storage: storage.map(Mutex::new),
+            remote: Arc::new(RemoteCache::new(
+                config.remote_refresh,
+                config.remote_hosts.clone(),
+            )),
+            compiled: CompileCache::new(config.blank_policy),
+            macros: Macros::default(),
+            column_types: ColumnTypes::default(),
+            derivations: Derivations::default(),
+            changes: ChangeFeed::default(),
+            alerts: Alerts::default(),
+            extent: Extent::default(),
+            formats: Formats::default(),
+            trash: Trash::new(config.trash_window),
+            scenarios: Scenarios::default(),
+            schedules: Schedules::default(),
+            templates: Templates::default(),
+            history: History::new(config.value_history),
+            revision: AtomicU64::new(0),
+            changed_at: Mutex::new(HashMap::new()),
+            presence: Presence::default(),
+            identities: Identities::default(),
+            protections: Protections::default(),
+            clock: Mutex::new(HybridClock::default()),
+            stamps: Mutex::new(HashMap::new()),
+            costs: Mutex::new(HashMap::new()),
+            profiler: Profiler::new(config.profile),
+            tags: Mutex::new(HashMap::new()),
+            layout: Layout::default(),
+            merges: Merges::default(),
+            #[cfg(feature = "metrics")]
+            metrics: metrics::Metrics::default(),
+            #[cfg(feature = "webhooks")]
+            webhooks: webhook::Webhooks::default(),
+            config,
         }
     }
 
-    fn get_cell(&self, cell_name: &str) -> CellValue {
-        self.cell_values
+    fn settings(&self) -> Arc<Config> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Counts a new connection as open, or refuses it if that would take
+    /// the server over `max_connections`.
+    fn admit(&self) -> Result<ConnectionSlot, String> {
+        let open = self.connections.fetch_add(1, Ordering::SeqCst);
+        let slot = ConnectionSlot(self.connections.clone());
+        match self.settings().max_connections {
+            Some(max) if open >= max => Err(format!(
+                "Too many connections: the server takes at most {max} at once"
+            )),
+            _ => Ok(slot),
+        }
+    }
+
+    /// Re-reads the config file and applies the settings that can change
+    /// while running. Returns the settings applied and those that need a
+    /// restart.
+    fn reload_config(&self) -> Result<(Vec<String>, Vec<String>), String> {
+        let mut settings = self.settings.write().unwrap();
+        let reload = config_file::reload(&settings)?;
+        self.event_log.set_verbosity(reload.config.log_verbosity);
+        *settings = Arc::new(reload.config);
+        Ok((reload.applied, reload.needs_restart))
+    }
+
+    fn eval_context(&self) -> EvalContext<'_> {
+        EvalContext {
+            config: &self.config,
+            remote: &self.remote,
+            compiled: &self.compiled,
+            macros: &self.macros,
+            extent: &self.extent,
+            column_types: &self.column_types,
+            formats: &self.formats,
+            numeric: &[],
+        }
+    }
+
+    /// Sets a cell's expression, returning the one it replaces. All
+    /// expression changes go through here and `remove_expression` so the
+    /// extent stays current.
+    fn insert_expression(
+        &self,
+        expressions: &mut HashMap<String, String>,
+        cell_name: &str,
+        expression: String,
+    ) -> Option<String> {
+        let previous = expressions.insert(cell_name.to_string(), expression);
+        if previous.is_none() {
+            if let Ok(cell) = CellRef::parse(cell_name, &self.config) {
+                self.extent.add(cell);
+            }
+        }
+        previous
+    }
+
+    fn remove_expression(
+        &self,
+        expressions: &mut HashMap<String, String>,
+        cell_name: &str,
+    ) -> Option<String> {
+        let removed = expressions.remove(cell_name);
+        if removed.is_some() {
+            if let Ok(cell) = CellRef::parse(cell_name, &self.config) {
+                self.extent.remove(cell);
+            }
+        }
+        removed
+    }
+
+    /// Evaluates `cell_name`, recording how long it took, dependencies
+    /// included. Values in `memo` are taken as they are.
+    fn evaluate(
+        &self,
+        expressions: &HashMap<String, String>,
+        cell_name: &str,
+        memo: &mut Memo,
+        pass: &mut Pass,
+    ) -> CellValue {
+        let started = Instant::now();
+        let value = calculate_cell_value(expressions, cell_name, memo, &self.eval_context());
+        let elapsed = started.elapsed();
+        self.costs
             .lock()
             .unwrap()
-            .get(cell_name)
-            .cloned()
-            .unwrap_or(CellValue::None)
+            .insert(cell_name.to_string(), elapsed);
+        pass.record(cell_name, elapsed);
+        value
     }
 
-    fn set_cell(&self, cell_name: &str, expression: &str) {
-        self.expressions
+    /// Estimates the memory held by the sheet's expressions, values,
+    /// metadata and dependency graph.
+    fn memory_stats(&self) -> MemoryStats {
+        let expressions = self.expressions.lock().unwrap();
+        let graph = DependencyGraph::build(&expressions, &self.config);
+        let expression_bytes = expressions
+            .iter()
+            .map(|(name, expression)| entry_bytes(name, string_bytes(expression)))
+            .sum();
+        drop(expressions);
+
+        let revisions: usize = self
+            .changed_at
             .lock()
             .unwrap()
-            .insert(cell_name.to_string(), expression.to_string());
-        let mut visited: HashSet<String> = HashSet::new();
-        let value =
-            calculate_cell_value(&self.expressions.lock().unwrap(), cell_name, &mut visited);
-        self.cell_values
+            .keys()
+            .map(|name| entry_bytes(name, size_of::<u64>()))
+            .sum();
+        let stamps: usize = self
+            .stamps
             .lock()
             .unwrap()
-            .insert(cell_name.to_owned(), value);
-        let _ = self.expression_sender.send(cell_name.to_string());
+            .iter()
+            .map(|(name, stamp)| entry_bytes(name, size_of::<Stamp>() + stamp.node.len()))
+            .sum();
+        let costs: usize = self
+            .costs
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|name| entry_bytes(name, size_of::<Duration>()))
+            .sum();
+        let tags: usize = self
+            .tags
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, tags)| {
+                let tags: usize = tags.iter().map(|tag| entry_bytes(tag, 0)).sum();
+                entry_bytes(name, size_of::<BTreeSet<String>>() + tags)
+            })
+            .sum();
+        MemoryStats {
+            expressions: expression_bytes,
+            values: self.cell_values.lock().unwrap().memory_bytes() + self.history.memory_bytes(),
+            metadata: self.formats.memory_bytes()
+                + self.compiled.memory_bytes()
+                + revisions
+                + stamps
+                + costs
+                + tags,
+            graph: graph.memory_bytes(),
+        }
+    }
+
+    /// The `count` cells whose last evaluation took longest, slowest first.
+    fn hotspots(&self, count: usize) -> Vec<(String, Duration)> {
+        let expressions = self.expressions.lock().unwrap();
+        let mut costs: Vec<(String, Duration)> = self
+            .costs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| expressions.contains_key(*name))
+            .map(|(name, cost)| (name.clone(), *cost))
+            .collect();
+        costs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        costs.truncate(count);
+        costs
+    }
+
+    /// Stores a computed value. If it differs from the one it replaces, it
+    /// becomes the cell's next version and is published to the change feed.
+    /// A float within the change epsilon of the old one isn't stored.
+    fn store_value(&self, cell_values: &mut Values, cell_name: &str, value: CellValue) {
+        let value = match self.type_mismatch(cell_name, &value) {
+            Some(err) => CellValue::Error(err),
+            None => value,
+        };
+        if let (Some(CellValue::String(old)), CellValue::String(new)) =
+            (cell_values.get(cell_name), &value)
+        {
+            if within_epsilon(&old, new, self.config.change_epsilon) {
+                return;
+            }
+        }
+        let old = cell_values
+            .insert(cell_name, value.clone())
+            .unwrap_or(CellValue::None);
+        self.value_changed(cell_name, old, value);
+    }
+
+    fn remove_value(&self, cell_values: &mut Values, cell_name: &str) {
+        if let Some(old) = cell_values.remove(cell_name) {
+            self.value_changed(cell_name, old, CellValue::None);
+        }
+    }
+
+    fn bump_revision(&self) -> u64 {
+        self.revision.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn cell_changed(&self, cell_name: &str) {
+        let revision = self.bump_revision();
+        self.changed_at
+            .lock()
+            .unwrap()
+            .insert(cell_name.to_string(), revision);
+    }
+
+    fn value_changed(&self, cell_name: &str, old: CellValue, new: CellValue) {
+        if old != new {
+            self.cell_changed(cell_name);
+            self.history.record(cell_name, &new);
+            self.changes.publish(cell_name, old, new);
+        }
+    }
+
+    fn lock_values(&self) -> ValuesGuard<'_> {
+        ValuesGuard {
+            values: self.cell_values.lock().unwrap(),
+            revision: &self.revision,
+        }
+    }
+
+    /// Evaluates `cell_name` with scenario `name`'s cells shadowing the
+    /// sheet's. Nothing is stored or published.
+    fn scenario_value(&self, cell_name: &str, name: &str) -> Result<CellValue, String> {
+        let expressions = self
+            .scenarios
+            .apply(name, &self.expressions.lock().unwrap())?;
+        Ok(calculate_cell_value(
+            &expressions,
+            cell_name,
+            &mut Memo::new(),
+            &self.eval_context(),
+        ))
+    }
+
+    /// What `cell_name` would hold with `expression`, given the sheet as it
+    /// is. Nothing is stored, so no other cell sees it.
+    fn dry_run(&self, cell_name: &str, expression: &str) -> CellValue {
+        let mut expressions = self.expressions.lock().unwrap().clone();
+        expressions.insert(cell_name.to_string(), expression.to_string());
+        let value = calculate_cell_value(
+            &expressions,
+            cell_name,
+            &mut Memo::new(),
+            &self.eval_context(),
+        );
+        let rejected = match self.config.column_type_policy {
+            ColumnTypePolicy::Reject => self.type_mismatch(cell_name, &value),
+            ColumnTypePolicy::Error => None,
+        };
+        rejected.map_or(value, CellValue::Error)
     }
 
-    fn update_cell_values(&self, the_cell_name: String) {
+    /// What `expression` works out to over the sheet as it is.
+    fn calculate(&self, expression: &str) -> CellValue {
+        calculate_expression(
+            &self.expressions.lock().unwrap(),
+            expression,
+            &mut Memo::new(),
+            &self.eval_context(),
+        )
+    }
+
+    /// Finds a value for `input` within `bounds` that brings `target` to
+    /// `goal`, evaluating `target` afresh for each value tried. The sheet
+    /// itself isn't changed.
+    fn goal_seek(
+        &self,
+        target: CellRef,
+        goal: Decimal,
+        input: CellRef,
+        bounds: (Decimal, Decimal),
+    ) -> Result<f64, String> {
+        let number = |decimal: Decimal| {
+            f64::try_from(decimal).map_err(|_| format!("{decimal} is out of range"))
+        };
         let expressions = self.expressions.lock().unwrap().clone();
+        let mut trial = Trial::new(expressions, &input.to_string(), &self.config);
+        let target = target.to_string();
+        let context = self.eval_context();
+        goalseek::seek(
+            |value| match trial.evaluate(value, &target, &context) {
+                CellValue::Error(err) => Err(format!("{target} is an error: {err}")),
+                result => as_number(&result).ok_or_else(|| format!("{target} isn't a number")),
+            },
+            number(goal)?,
+            (number(bounds.0)?, number(bounds.1)?),
+            number(self.settings().goal_seek_tolerance)?,
+        )
+    }
 
-        for cell_name in expressions.keys() {
-            if *cell_name != the_cell_name {
-                let mut visited: HashSet<String> = HashSet::new();
-                let value = calculate_cell_value(&expressions, cell_name, &mut visited);
-                self.cell_values
-                    .lock()
-                    .unwrap()
-                    .insert(cell_name.to_owned(), value);
+    /// `observe`'s value with `input` set to each of `from`, `from + step`
+    /// and so on up to `to`, evaluated afresh each time. The sheet itself
+    /// isn't changed.
+    fn sweep(
+        &self,
+        input: CellRef,
+        (from, to, step): (Decimal, Decimal, Decimal),
+        observe: CellRef,
+    ) -> Result<Vec<(Decimal, CellValue)>, String> {
+        let max_sweep_steps = self.settings().max_sweep_steps;
+        let too_many = || format!("Sweep would take more than {max_sweep_steps} steps");
+        let count = to
+            .checked_sub(from)
+            .and_then(|span| span.checked_div(step))
+            .and_then(|steps| steps.floor().checked_add(Decimal::ONE))
+            .ok_or_else(too_many)?;
+        if count > Decimal::from(max_sweep_steps) {
+            return Err(too_many());
+        }
+        let expressions = self.expressions.lock().unwrap().clone();
+        let mut trial = Trial::new(expressions, &input.to_string(), &self.config);
+        let observe = observe.to_string();
+        let context = self.eval_context();
+        let mut table = Vec::new();
+        let mut value = from;
+        while value <= to {
+            let float = f64::try_from(value).map_err(|_| format!("{value} is out of range"))?;
+            table.push((value, trial.evaluate(float, &observe, &context)));
+            match value.checked_add(step) {
+                Some(next) => value = next,
+                None => break,
             }
         }
+        Ok(table)
     }
-}
 
-pub fn start_server<M>(mut manager: M) -> Result<(), Box<dyn Error>>
-where
-    M: Manager,
-{
-    let (expression_sender, expression_update_receiver) = channel();
-    let coordinator = Arc::new(Coordinator::new(expression_sender));
+    /// Evaluates `observe` afresh `runs` times, through scenario `scenario`
+    /// if given, so every call to a random function draws again each run.
+    fn simulate(
+        &self,
+        runs: usize,
+        observe: CellRef,
+        scenario: Option<&str>,
+    ) -> Result<Summary, String> {
+        let max_simulation_runs = self.settings().max_simulation_runs;
+        if runs > max_simulation_runs {
+            return Err(format!(
+                "Simulations are limited to {} runs",
+                max_simulation_runs
+            ));
+        }
+        let expressions = match scenario {
+            Some(name) => self
+                .scenarios
+                .apply(name, &self.expressions.lock().unwrap())?,
+            None => self.expressions.lock().unwrap().clone(),
+        };
+        let random: Vec<String> = expressions
+            .iter()
+            .filter(|(_, expression)| {
+                let expanded = self.macros.expand(expression);
+                is_random(expanded.as_deref().unwrap_or(expression))
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        if random.is_empty() {
+            return Err("No cell calls a random function".to_string());
+        }
+        // Random floats, and whatever is worked out from them, are held as
+        // text on the way to `observe`, so all of them are read as numbers.
+        let mut numeric =
+            DependencyGraph::build(&expressions, &self.config).affected_by(&random, &self.config);
+        numeric.extend(random);
+        let context = EvalContext {
+            numeric: &numeric,
+            ..self.eval_context()
+        };
 
-    let coordinator_clone = coordinator.clone();
-    std::thread::spawn(move || {
-        while let Ok(the_cell_name) = expression_update_receiver.recv() {
-            coordinator_clone.update_cell_values(the_cell_name);
+        let observe = observe.to_string();
+        let mut samples = Vec::with_capacity(runs);
+        let mut failed = 0;
+        for _ in 0..runs {
+            let value = calculate_cell_value(&expressions, &observe, &mut Memo::new(), &context);
+            match as_number(&value) {
+                Some(sample) => samples.push(sample),
+                None => failed += 1,
+            }
         }
-    });
+        Summary::new(samples, failed)
+    }
 
-    std::thread::scope(|s| loop {
-        if let Ok((recv, send)) = manager.accept_new_connection() {
-            let coordinator = coordinator.clone();
+    fn get_cell(&self, cell_name: &str) -> CellValue {
+        if let Some(published) = &self.published {
+            return published.load().get(cell_name);
+        }
+        self.lock_values().get(cell_name).unwrap_or(CellValue::None)
+    }
 
-            s.spawn(move || {
-                let _ = handle_connection(recv, send, coordinator);
-            });
-        } else {
-            return Ok(());
+    /// Each cell's expression and value, as they are now or as loading the
+    /// snapshot at `source` would leave them. A snapshot is loaded into a
+    /// scratch sheet, leaving this one alone.
+    fn contents(&self, source: &DiffSource) -> Result<Contents, String> {
+        match source {
+            DiffSource::Live => {
+                let expressions = self.expressions.lock().unwrap();
+                Ok(expressions
+                    .iter()
+                    .map(|(name, expression)| {
+                        (name.clone(), (expression.clone(), self.get_cell(name)))
+                    })
+                    .collect())
+            }
+            DiffSource::Snapshot(name) => {
+                // Why the file couldn't be read, even whether it exists,
+                // stays with the server.
+                let path = data_file(self.config.data_dir.as_deref(), name)?;
+                let records = read_snapshot(&path)
+                    .map_err(|_| format!("Could not read the snapshot {}", name.display()))?;
+                let scratch = Coordinator::new(
+                    channel().0,
+                    None,
+                    Config {
+                        data_dir: None,
+                        value_cache: None,
+                        ..self.config.clone()
+                    },
+                );
+                scratch.replay(records);
+                scratch.contents(&DiffSource::Live)
+            }
         }
-    })
-}
+    }
 
-fn handle_connection<R, W>(
-    mut recv: R,
-    mut send: W,
-    coordinator: Arc<Coordinator>,
-) -> Result<(), Box<dyn Error>>
-where
-    R: Reader,
-    W: Writer,
-{
-    loop {
-        info!("Just got message");
-        let msg = recv.read_message()?;
-        let parts: Vec<&str> = msg.trim().splitn(2, ' ').collect();
-
-        match parts[0] {
-            "get" => {
-                if parts.len() != 2 {
-                    send.write_message(Reply::Error("Invalid get command".to_string()))?
-                } else {
-                    let cell_name = parts[1];
-                    let cell_value = coordinator.get_cell(cell_name);
-                    match cell_value {
-                        CellValue::String(err) if err == "Runtime error: Unknown value: \"Circular dependency detected\" (line 1, position 1)" => {
-                            send.write_message(Reply::Error("Circular dependency".to_string()))?
-                        }
-                        CellValue::Error(err) if err == "Runtime error: Unknown value: \"Circular dependency detected\" (line 1, position 1)" => {
-                            send.write_message(Reply::Error("Circular dependency".to_string()))?
-                        }
-                        CellValue::String(err) if err == "'this' can only be used in functions (line 1, position 7)" => {
-                            send.write_message(Reply::Error("this err".to_string()))?
-                        }
-                        CellValue::String(err) if err == "Circular dependency detected" => {
-                            send.write_message(Reply::Error("Circular dependency".to_string()))?
+    /// The expression and value of each cell in `range` that has an
+    /// expression.
+    fn cells_in(&self, range: CellRange) -> Contents {
+        let expressions = self.expressions.lock().unwrap();
+        expressions
+            .iter()
+            .filter(|(name, _)| {
+                CellRef::parse(name, &self.config).is_ok_and(|cell| range.contains(cell))
+            })
+            .map(|(name, expression)| (name.clone(), (expression.clone(), self.get_cell(name))))
+            .collect()
+    }
+
+    /// Applies the cells snapshot `theirs` changed since snapshot `base`
+    /// that this sheet hasn't changed since either, unless `writable`
+    /// refuses them. Returns how many cells changed, along with each cell
+    /// left alone because both sides changed it or `writable` refused it.
+    fn merge_snapshot(
+        &self,
+        theirs: &DiffSource,
+        base: &DiffSource,
+        writable: impl Fn(CellRef) -> Result<(), String>,
+    ) -> Result<(usize, Vec<(String, String)>), String> {
+        let (theirs, base) = (self.contents(theirs)?, self.contents(base)?);
+        let mut conflicts = Vec::new();
+        let merged = self
+            .edit_cells(|expressions| {
+                let (mut changes, both_changed) =
+                    diff::merge(&base, expressions, &theirs, &self.config);
+                conflicts = both_changed;
+                changes.retain(|(cell_name, _)| {
+                    let Ok(cell) = CellRef::parse(cell_name, &self.config) else {
+                        return false;
+                    };
+                    match writable(cell) {
+                        Ok(()) => true,
+                        Err(err) => {
+                            conflicts.push((cell_name.clone(), err));
+                            false
                         }
-                        _ => send.write_message(Reply::Value(cell_name.to_string(), cell_value))?,
                     }
-                }
+                });
+                changes
+            })
+            .map_err(|err| format!("Could not log merge: {err}"))?;
+        Ok((merged, conflicts))
+    }
+
+    /// The computed values of `range`, row by row, all read at one moment.
+    fn range_values(&self, range: CellRange) -> Vec<Vec<CellValue>> {
+        self.revisioned_range_values(range).1
+    }
+
+    /// Like `range_values`, along with the revision the values are from.
+    fn revisioned_range_values(&self, range: CellRange) -> (u64, Vec<Vec<CellValue>>) {
+        if let Some(published) = &self.published {
+            let snapshot = published.load();
+            let rows = range
+                .rows()
+                .map(|row| {
+                    row.iter()
+                        .map(|cell| snapshot.get(&cell.to_string()))
+                        .collect()
+                })
+                .collect();
+            return (snapshot.revision, rows);
+        }
+        let mut cell_values = self.lock_values();
+        let revision = self.revision.load(Ordering::SeqCst);
+        let rows = range
+            .rows()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| {
+                        cell_values
+                            .get(&cell.to_string())
+                            .unwrap_or(CellValue::None)
+                    })
+                    .collect()
+            })
+            .collect();
+        (revision, rows)
+    }
+
+    /// A page of the populated cells in `order` with their values, all read
+    /// at one moment, along with how many cells are populated.
+    fn list(
+        &self,
+        order: ListOrder,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> (usize, Vec<(CellRef, CellValue)>) {
+        let (total, cells) = self.extent.page(order, offset, limit.unwrap_or(usize::MAX));
+        if let Some(published) = &self.published {
+            let snapshot = published.load();
+            let page = cells
+                .into_iter()
+                .map(|cell| (cell, snapshot.get(&cell.to_string())))
+                .collect();
+            return (total, page);
+        }
+        let mut cell_values = self.lock_values();
+        let page = cells
+            .into_iter()
+            .map(|cell| {
+                let value = cell_values
+                    .get(&cell.to_string())
+                    .unwrap_or(CellValue::None);
+                (cell, value)
+            })
+            .collect();
+        (total, page)
+    }
+
+    /// Sets the cell and its display format, unless `expected` is given and
+    /// the cell has changed since that revision, or the column's type rejects
+    /// the new value.
+    fn set_cell(
+        &self,
+        cell_name: &str,
+        expression: &str,
+        format: Option<DisplayFormat>,
+        expected: Option<u64>,
+    ) -> Result<(), String> {
+        let mut storage = self.storage.as_ref().map(|storage| storage.lock().unwrap());
+        let mut expressions = self.expressions.lock().unwrap();
+        if let Some(expected) = expected {
+            let changed_at = self.changed_at.lock().unwrap().get(cell_name).copied();
+            if let Some(changed_at) = changed_at.filter(|changed_at| *changed_at > expected) {
+                return Err(format!(
+                    "Conflict: {cell_name} changed at revision {changed_at}, after {expected}"
+                ));
             }
-            "set" => {
-                if parts.len() != 2 {
-                    send.write_message(Reply::Error("Invalid set command".to_string()))?;
-                } else {
-                    let mut parts = parts[1].splitn(2, ' ');
-                    let cell_name = parts.next().unwrap();
-                    let expression = parts.next().unwrap_or("");
-                    if expression.is_empty() {
-                        send.write_message(Reply::Error("Invalid command".to_string()))?
-                    } else {
-                        coordinator.set_cell(cell_name, expression);
-                    }
+        }
+        if self.config.skip_unchanged_sets
+            && expressions.get(cell_name).map(String::as_str) == Some(expression)
+            && self.formats.get(cell_name) == format
+        {
+            return Ok(());
+        }
+
+        let previous = self.insert_expression(&mut expressions, cell_name, expression.to_string());
+        let mut pass = self.profiler.pass(format!("set:{cell_name}"));
+        let value = self.evaluate(&expressions, cell_name, &mut Memo::new(), &mut pass);
+        self.profiler.finish(pass);
+        let rejected = match self.config.column_type_policy {
+            ColumnTypePolicy::Reject => self.type_mismatch(cell_name, &value),
+            ColumnTypePolicy::Error => None,
+        };
+        let logged = match (rejected, storage.as_mut()) {
+            (Some(err), _) => Err(err),
+            (None, Some(storage)) => storage
+                .append(&set_record(cell_name, expression, format))
+                .map_err(|err| format!("Could not log set: {err}")),
+            (None, None) => Ok(()),
+        };
+        if let Err(err) = logged {
+            match previous {
+                Some(previous) => self.insert_expression(&mut expressions, cell_name, previous),
+                None => self.remove_expression(&mut expressions, cell_name),
+            };
+            return Err(err);
+        }
+
+        self.formats.set(cell_name, format);
+        self.cell_changed(cell_name);
+        self.store_value(&mut self.lock_values(), cell_name, value);
+        drop(expressions);
+        self.queue_update(cell_name);
+        Ok(())
+    }
+
+    /// Explains why `value` can't go in `cell_name`'s column, if it can't.
+    fn type_mismatch(&self, cell_name: &str, value: &CellValue) -> Option<String> {
+        let cell = CellRef::parse(cell_name, &self.config).ok()?;
+        self.column_types.mismatch(cell.col, value)
+    }
+
+    /// Runs `write` unless last-writer-wins is on and the cell has already
+    /// seen a write stamped at or after `stamp`. The stamps stay locked
+    /// while writing so two writes to a cell can't apply out of order.
+    fn stamped<T>(
+        &self,
+        cell_name: &str,
+        stamp: Option<&Stamp>,
+        write: impl FnOnce() -> T,
+    ) -> Result<T, String> {
+        if self.config.conflict_resolution != ConflictResolution::LastWriterWins {
+            return Ok(write());
+        }
+
+        let mut stamps = self.stamps.lock().unwrap();
+        let stamp = {
+            let mut clock = self.clock.lock().unwrap();
+            match stamp {
+                Some(stamp) => {
+                    clock.observe(stamp);
+                    stamp.clone()
                 }
+                None => clock.now("server"),
             }
-            _ => send.write_message(Reply::Error("Invalid command".to_string()))?,
         };
+        if let Some(current) = stamps.get(cell_name) {
+            if *current >= stamp {
+                return Err(format!(
+                    "Ignored write to {cell_name} stamped {stamp}, it was last written at {current}"
+                ));
+            }
+        }
+        stamps.insert(cell_name.to_string(), stamp);
+        Ok(write())
     }
-}
 
-fn get_vector_value(
-    cells: &HashMap<String, CellValue>,
-    col_start: u32,
-    row_start: u32,
-    col_end: u32,
-    row_end: u32,
-) -> Vec<CellValue> {
-    if col_start == col_end {
-        (row_start..=row_end)
-            .map(|row| get_cell_value(cells, col_start, row))
-            .collect()
-    } else {
-        (col_start..=col_end)
-            .map(|col| get_cell_value(cells, col, row_start))
-            .collect()
+    fn delete_cell(&self, cell_name: &str) -> io::Result<()> {
+        let mut storage = self.storage.as_ref().map(|storage| storage.lock().unwrap());
+        if let Some(storage) = storage.as_mut() {
+            storage.append(&delete_record(cell_name))?;
+        }
+
+        let format = self.formats.get(cell_name);
+        if let Some(expression) =
+            self.remove_expression(&mut self.expressions.lock().unwrap(), cell_name)
+        {
+            self.trash.put(cell_name, expression, format);
+        }
+        self.formats.set(cell_name, None);
+        self.cell_changed(cell_name);
+        self.remove_value(&mut self.lock_values(), cell_name);
+        self.compiled.forget(cell_name);
+        self.queue_update(cell_name);
+        Ok(())
     }
-}
 
-fn get_matrix_value(
-    cells: &HashMap<String, CellValue>,
-    col_start: u32,
-    row_start: u32,
-    col_end: u32,
-    row_end: u32,
-) -> Vec<Vec<CellValue>> {
-    (row_start..=row_end)
-        .map(|row| {
-            (col_start..=col_end)
-                .map(|col| get_cell_value(cells, col, row))
-                .collect()
-        })
-        .collect()
-}
+    /// Sets a cell deleted within the trash window back to what it was.
+    fn restore(&self, cell_name: &str) -> Result<(), String> {
+        if self.expressions.lock().unwrap().contains_key(cell_name) {
+            return Err(format!("{cell_name} already has an expression"));
+        }
+        let trashed = self.trash.take(cell_name)?;
+        if let Err(err) = self.set_cell(cell_name, &trashed.expression, trashed.format, None) {
+            self.trash.put_back(cell_name, trashed);
+            return Err(err);
+        }
+        Ok(())
+    }
 
-fn get_cell_value(cells: &HashMap<String, CellValue>, col: u32, row: u32) -> CellValue {
-    let cell_name = format!("{}{}", column_number_to_name(col), row);
-    cells.get(&cell_name).cloned().unwrap_or(CellValue::None)
-}
+    /// Rebuilds the sheet from persisted records, then evaluates every cell.
+    fn replay(&self, records: Vec<String>) {
+        let config = Config {
+            number_locale: None,
+            ..self.config.clone()
+        };
+        let mut expressions = self.expressions.lock().unwrap();
+        for record in records {
+            match parse_command(&record, &config) {
+                Ok(Command::Set {
+                    cell,
+                    expression,
+                    format,
+                    ..
+                }) => {
+                    self.insert_expression(&mut expressions, &cell.to_string(), expression);
+                    self.formats.set(&cell.to_string(), format);
+                }
+                Ok(Command::Delete { cell, .. }) => {
+                    self.remove_expression(&mut expressions, &cell.to_string());
+                    self.formats.set(&cell.to_string(), None);
+                }
+                Ok(Command::Define { name, definition }) => self.macros.define(&name, definition),
+                Ok(Command::DeclareColumnType { col, column_type }) => {
+                    self.column_types.declare(col, column_type)
+                }
+                Ok(Command::Undefine { name }) => {
+                    self.macros.undefine(&name);
+                }
+                Ok(Command::Derive { col, derivation }) => {
+                    self.derivations.set(col, Some(derivation));
+                }
+                Ok(Command::Underive { col }) => {
+                    self.derivations.set(col, None);
+                }
+                Ok(Command::Tag { cell, tag }) => self.apply_tag(&cell.to_string(), tag, true),
+                Ok(Command::Untag { cell, tag }) => self.apply_tag(&cell.to_string(), tag, false),
+                Ok(Command::MetaSet { key, value }) => self.layout.set(key, value),
+                Ok(Command::Merge { range }) => {
+                    if let Err(err) = self.merges.merge(range) {
+                        warn!("Skipping persisted merge: {err}");
+                    }
+                }
+                Ok(Command::Unmerge { range }) => {
+                    let _ = self.merges.unmerge(range);
+                }
+                _ => warn!("Skipping unreadable persisted record {record:?}"),
+            }
+        }
 
-fn calculate_variables(
-    expressions: &HashMap<String, String>,
-    expression: &str,
-    visited: &mut HashSet<String>,
-) -> HashMap<String, CellArgument> {
-    let command_runner = CommandRunner::new(expression);
-    command_runner
-        .find_variables()
-        .into_iter()
-        .map(|var_name| {
-            let cell_argument = if var_name.contains('_')
-                && (!var_name.contains("sum") || !var_name.contains("sleep"))
-            {
-                let parts: Vec<&str> = var_name.split('_').collect();
-                let start_col = parts[0]
-                    .chars()
-                    .take_while(|c| c.is_alphabetic())
-                    .collect::<String>();
-                let start_row = parts[0][start_col.len()..].parse::<u32>().unwrap();
-                let end_col = parts[1]
-                    .chars()
-                    .take_while(|c| c.is_alphabetic())
-                    .collect::<String>();
-                let end_row = parts[1][end_col.len()..].parse::<u32>().unwrap();
-                let col_start = column_name_to_number(&start_col);
-                let col_end = column_name_to_number(&end_col);
-                let cells = expressions
+        self.recalculate_all(&expressions);
+        info!("Loaded {} cells from storage", expressions.len());
+    }
+
+    /// Evaluates every cell in dependency order, spreading each level of
+    /// the dependency graph across all cores. Progress is logged for big
+    /// sheets, since loading one can take a while.
+    fn recalculate_all(&self, expressions: &HashMap<String, String>) {
+        let mut pass = self.profiler.pass("recalculate");
+        let graph = DependencyGraph::build(expressions, &self.config);
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let total = expressions.len();
+        let mut done = 0;
+        let mut cell_values = self.lock_values();
+        for level in graph.levels() {
+            let chunk_size = level.len().div_ceil(threads).max(PARALLEL_CHUNK);
+            let evaluate_chunk = |chunk: &[&String]| {
+                let mut pass = self.profiler.pass("recalculate");
+                let mut memo = Memo::new();
+                let values: Vec<(String, CellValue)> = chunk
                     .iter()
-                    .map(|(name, _)| {
-                        (
-                            name.clone(),
-                            calculate_cell_value(expressions, name, visited),
-                        )
+                    .map(|name| {
+                        let value = self.evaluate(expressions, name, &mut memo, &mut pass);
+                        ((*name).clone(), value)
                     })
                     .collect();
-                if col_start == col_end || start_row == end_row {
-                    let value = get_vector_value(&cells, col_start, start_row, col_end, end_row);
-                    CellArgument::Vector(value)
-                } else {
-                    let value = get_matrix_value(&cells, col_start, start_row, col_end, end_row);
-                    CellArgument::Matrix(value)
-                }
+                (values, pass)
+            };
+            let results = if level.len() <= chunk_size {
+                vec![evaluate_chunk(&level)]
             } else {
-                let value = calculate_cell_value(expressions, &var_name, visited);
-                CellArgument::Value(value)
+                std::thread::scope(|scope| {
+                    let workers: Vec<_> = level
+                        .chunks(chunk_size)
+                        .map(|chunk| scope.spawn(move || evaluate_chunk(chunk)))
+                        .collect();
+                    workers
+                        .into_iter()
+                        .map(|worker| worker.join().unwrap())
+                        .collect()
+                })
             };
-            (var_name.clone(), cell_argument)
+            for (values, chunk_pass) in results {
+                pass.evaluations.extend(chunk_pass.evaluations);
+                for (cell_name, value) in values {
+                    self.store_value(&mut cell_values, &cell_name, value);
+                }
+            }
+
+            let before = done * 10 / total;
+            done += level.len();
+            if total >= PROGRESS_CELLS && done * 10 / total > before {
+                info!("Evaluated {done} of {total} cells");
+            }
+        }
+        self.profiler.finish(pass);
+    }
+
+    /// Applies a batch of changes computed by `edit` from the current
+    /// expressions, where `None` deletes a cell. The expressions stay locked
+    /// from planning to applying so the batch is atomic, and the sheet is
+    /// evaluated once at the end rather than once per cell. Returns how many
+    /// cells changed.
+    fn edit_cells(
+        &self,
+        edit: impl FnOnce(&HashMap<String, String>) -> Vec<(String, Option<String>)>,
+    ) -> io::Result<usize> {
+        let (expressions, changed) = self.write_cells(edit)?;
+        self.recalculate_all(&expressions);
+        Ok(changed.len())
+    }
+
+    /// Applies `edit`'s changes as `edit_cells` does, without evaluating
+    /// anything. Returns the expressions, still locked, and the cells that
+    /// changed.
+    fn write_cells(
+        &self,
+        edit: impl FnOnce(&HashMap<String, String>) -> Vec<(String, Option<String>)>,
+    ) -> io::Result<(ExpressionsGuard<'_>, Vec<String>)> {
+        let mut storage = self.storage.as_ref().map(|storage| storage.lock().unwrap());
+        let mut expressions = self.expressions.lock().unwrap();
+        let mut changes = edit(&expressions);
+        if self.config.skip_unchanged_sets {
+            changes.retain(|(cell_name, expression)| {
+                expressions.get(cell_name) != expression.as_ref()
+                    || self.formats.get(cell_name).is_some()
+            });
+        }
+
+        if let Some(storage) = storage.as_mut() {
+            for (cell_name, expression) in &changes {
+                match expression {
+                    Some(expression) => storage.append(&set_record(cell_name, expression, None))?,
+                    None => storage.append(&delete_record(cell_name))?,
+                }
+            }
+        }
+
+        let mut cell_values = self.lock_values();
+        for (cell_name, expression) in &changes {
+            self.formats.set(cell_name, None);
+            match expression {
+                Some(expression) => {
+                    self.insert_expression(&mut expressions, cell_name, expression.clone());
+                }
+                None => {
+                    self.remove_expression(&mut expressions, cell_name);
+                    self.remove_value(&mut cell_values, cell_name);
+                    self.compiled.forget(cell_name);
+                }
+            }
+        }
+        drop(cell_values);
+        for (cell_name, _) in &changes {
+            self.cell_changed(cell_name);
+        }
+        let changed = changes
+            .into_iter()
+            .map(|(cell_name, _)| cell_name)
+            .collect();
+        Ok((expressions, changed))
+    }
+
+    /// Writes `values` into the row after the last one used in `range`'s
+    /// columns, returning that row, or `None` if the sheet has no rows left.
+    /// Only the new row is evaluated, as `set` evaluates its cell, and the
+    /// cells reading it are queued, so appending stays cheap however big the
+    /// sheet is.
+    fn append(&self, range: CellRange, values: &[String]) -> io::Result<Option<u32>> {
+        let mut appended = None;
+        let (expressions, changed) = self.write_cells(|expressions| {
+            let last_used = expressions
+                .keys()
+                .filter_map(|name| CellRef::parse(name, &self.config).ok())
+                .filter(|cell| {
+                    (range.start.col..=range.end.col).contains(&cell.col)
+                        && cell.row >= range.start.row
+                })
+                .map(|cell| cell.row)
+                .max();
+            let row = last_used.map_or(range.start.row, |row| row + 1);
+            if row > self.config.max_row {
+                return Vec::new();
+            }
+            appended = Some(row);
+            let mut changes: Vec<(String, Option<String>)> = (range.start.col..=range.end.col)
+                .zip(values)
+                .map(|(col, value)| (CellRef { col, row }.to_string(), Some(value.clone())))
+                .collect();
+            for (col, derivation) in self.derivations.list() {
+                if !derivation.covers(row) || (range.start.col..=range.end.col).contains(&col) {
+                    continue;
+                }
+                let cell_name = CellRef { col, row }.to_string();
+                if let Ok(expression) = derivation.expression(row, &self.config) {
+                    if expressions.get(&cell_name) != Some(&expression) {
+                        changes.push((cell_name, Some(expression)));
+                    }
+                }
+            }
+            changes
+        })?;
+
+        let mut pass = self.profiler.pass("append");
+        let mut memo = Memo::new();
+        let values: Vec<(&String, CellValue)> = changed
+            .iter()
+            .map(|cell_name| {
+                let value = self.evaluate(&expressions, cell_name, &mut memo, &mut pass);
+                (cell_name, value)
+            })
+            .collect();
+        self.profiler.finish(pass);
+        let mut cell_values = self.lock_values();
+        for (cell_name, value) in values {
+            self.store_value(&mut cell_values, cell_name, value);
+        }
+        drop(cell_values);
+        drop(expressions);
+        for cell_name in &changed {
+            self.queue_update(cell_name);
+        }
+        Ok(appended)
+    }
+
+    /// Sets each cell of `range`, row by row, to the matching number.
+    fn set_many(&self, range: CellRange, values: &[i64]) -> io::Result<usize> {
+        self.edit_cells(|_| {
+            range
+                .rows()
+                .flatten()
+                .zip(values)
+                .map(|(cell, value)| (cell.to_string(), Some(value.to_string())))
+                .collect()
         })
-        .collect()
-}
+    }
 
-fn calculate_cell_value(
-    expressions: &HashMap<String, String>,
-    cell_name: &str,
-    visited: &mut HashSet<String>,
-) -> CellValue {
-    if visited.contains(cell_name) {
-        return CellValue::Error("Circular dependency detected".to_string());
+    /// Saves the expressions in `block` as template `name`.
+    fn save_template(&self, name: &str, block: CellRange) {
+        let template = Template::new(block, &self.expressions.lock().unwrap());
+        self.templates.save(name, template);
     }
 
-    if let Some(expression) = expressions.get(cell_name) {
-        visited.insert(cell_name.to_string());
-        let variables = calculate_variables(expressions, expression, visited);
-        visited.remove(cell_name);
+    /// Stamps template `name` out with its top-left cell at `at`. Returns
+    /// how many cells changed.
+    fn apply_template(&self, name: &str, at: CellRef) -> Result<usize, String> {
+        let changes = self.templates.get(name)?.stamp(at, &self.config)?;
+        self.edit_cells(|_| changes)
+            .map_err(|err| format!("Could not log template: {err}"))
+    }
 
-        let command_runner = CommandRunner::new(expression);
-        command_runner.run(&variables)
-    } else {
-        CellValue::None
+    /// Installs `derivation` in every row of `col` it covers and keeps it
+    /// applied to rows appended later. Returns how many cells were set.
+    fn derive(&self, col: u32, derivation: Derivation) -> io::Result<usize> {
+        if let Some(storage) = self.storage.as_ref() {
+            storage
+                .lock()
+                .unwrap()
+                .append(&derive_record(col, Some(&derivation)))?;
+        }
+        self.derivations.set(col, Some(derivation.clone()));
+        self.edit_cells(|expressions| {
+            let end = derivation.end.unwrap_or_else(|| {
+                expressions
+                    .keys()
+                    .filter_map(|name| CellRef::parse(name, &self.config).ok())
+                    .map(|cell| cell.row)
+                    .max()
+                    .unwrap_or(0)
+            });
+            (derivation.start..=end)
+                .filter_map(|row| {
+                    let expression = derivation.expression(row, &self.config).ok()?;
+                    Some((CellRef { col, row }.to_string(), Some(expression)))
+                })
+                .collect()
+        })
+    }
+
+    /// Stops deriving `col`. The formulas already installed stay.
+    fn underive(&self, col: u32) -> Result<(), String> {
+        if let Some(storage) = self.storage.as_ref() {
+            storage
+                .lock()
+                .unwrap()
+                .append(&derive_record(col, None))
+                .map_err(|err| format!("Could not log underive: {err}"))?;
+        }
+        if self.derivations.set(col, None) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Column {} is not derived",
+                column_number_to_name(col)
+            ))
+        }
+    }
+
+    /// Reorders the rows of `range` by the computed values in the key
+    /// columns. Expressions move with their row, and their references to
+    /// the range follow the rows they name, as `query::move_references`
+    /// describes. References from outside the range are not adjusted.
+    fn sort(&self, range: CellRange, keys: &[SortKey]) -> Result<usize, String> {
+        let mut refused = None;
+        let (expressions, changed) = self
+            .write_cells(|expressions| {
+                let cells: Vec<Vec<CellRef>> = range.rows().collect();
+                let values = self.range_values(range);
+                let order = query::sort_order(&values, range.start.col, keys);
+                let mut moved_to = vec![0; order.len()];
+                for (target, &source) in order.iter().enumerate() {
+                    moved_to[source] = target as u32;
+                }
+
+                let mut changes = Vec::new();
+                for (target, source) in order.into_iter().enumerate() {
+                    for (target, source) in cells[target].iter().zip(&cells[source]) {
+                        let target = target.to_string();
+                        let expression = match expressions.get(&source.to_string()) {
+                            Some(expression) => {
+                                match query::move_references(
+                                    expression,
+                                    range,
+                                    &moved_to,
+                                    &self.config,
+                                ) {
+                                    Ok(expression) => Some(expression),
+                                    Err(err) => {
+                                        refused = Some(err);
+                                        return Vec::new();
+                                    }
+                                }
+                            }
+                            None => None,
+                        };
+                        if expressions.get(&target) != expression.as_ref() {
+                            changes.push((target, expression));
+                        }
+                    }
+                }
+                changes
+            })
+            .map_err(|err| format!("Could not log sort: {err}"))?;
+        if let Some(err) = refused {
+            return Err(err);
+        }
+        self.recalculate_all(&expressions);
+        Ok(changed.len())
+    }
+
+    #[cfg(feature = "xlsx")]
+    fn export_xlsx(
+        &self,
+        path: &std::path::Path,
+        mode: xlsx::ExpressionExport,
+    ) -> Result<usize, String> {
+        let expressions = self.expressions.lock().unwrap();
+        let cells: Vec<xlsx::ExportCell> = expressions
+            .iter()
+            .filter_map(|(name, expression)| {
+                Some(xlsx::ExportCell {
+                    cell: CellRef::parse(name, &self.config).ok()?,
+                    expression,
+                    value: self.get_cell(name),
+                    format: self.formats.get(name),
+                })
+            })
+            .collect();
+        let tags = self.tags.lock().unwrap();
+        let mut tagged: Vec<(CellRef, Vec<&str>)> = tags
+            .iter()
+            .filter_map(|(name, tags)| {
+                let cell = CellRef::parse(name, &self.config).ok()?;
+                Some((cell, tags.iter().map(String::as_str).collect()))
+            })
+            .collect();
+        tagged.sort();
+        xlsx::export(path, &cells, &tagged, &self.merges.list(), mode)?;
+        Ok(cells.len())
+    }
+
+    #[cfg(feature = "xlsx")]
+    fn import_xlsx(&self, path: &std::path::Path) -> Result<usize, String> {
+        let cells = xlsx::import(path)?
+            .into_iter()
+            .map(|(cell, expression)| {
+                if cell.col > self.config.max_column || cell.row > self.config.max_row {
+                    Err(format!("{cell} is outside the sheet"))
+                } else {
+                    Ok((cell.to_string(), expression))
+                }
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let imported = cells.len();
+        self.edit_cells(|_| {
+            cells
+                .into_iter()
+                .map(|(cell_name, expression)| (cell_name, Some(expression)))
+                .collect()
+        })
+        .map_err(|err| format!("Could not log import: {err}"))?;
+        Ok(imported)
+    }
+
+    /// Writes a snapshot of every expression, returning how many were saved.
+    fn save(&self) -> Result<usize, String> {
+        let mut storage = self
+            .storage
+            .as_ref()
+            .ok_or_else(|| "Persistence is not enabled".to_string())?
+            .lock()
+            .unwrap();
+        let expressions = self.expressions.lock().unwrap();
+
+        let tags = self.tags.lock().unwrap();
+
+        let mut cell_names: Vec<&String> = expressions.keys().collect();
+        cell_names.sort();
+        let mut tagged: Vec<(&String, &BTreeSet<String>)> = tags.iter().collect();
+        tagged.sort();
+        storage
+            .save_snapshot(
+                self.macros
+                    .definitions()
+                    .iter()
+                    .map(|(name, definition)| define_record(name, definition))
+                    .chain(
+                        self.column_types
+                            .declarations()
+                            .into_iter()
+                            .map(|(col, column_type)| coltype_record(col, Some(column_type))),
+                    )
+                    .chain(
+                        self.derivations
+                            .list()
+                            .into_iter()
+                            .map(|(col, derivation)| derive_record(col, Some(&derivation))),
+                    )
+                    .chain(
+                        cell_names.iter().map(|name| {
+                            set_record(name, &expressions[*name], self.formats.get(name))
+                        }),
+                    )
+                    .chain(tagged.into_iter().flat_map(|(name, tags)| {
+                        tags.iter().map(move |tag| tag_record(name, tag, true))
+                    }))
+                    .chain(
+                        self.layout
+                            .list()
+                            .into_iter()
+                            .map(|(key, value)| meta_record(key, Some(value))),
+                    )
+                    .chain(
+                        self.merges
+                            .list()
+                            .into_iter()
+                            .map(|range| merge_record(range, true)),
+                    ),
+            )
+            .map_err(|err| format!("Could not save snapshot: {err}"))?;
+        Ok(cell_names.len())
     }
+
+    /// Defines, or with `None` removes, a macro and recalculates every cell,
+    /// since any of them may call it.
+    fn define(&self, name: &str, definition: Option<Macro>) -> Result<(), String> {
+        let mut storage = self.storage.as_ref().map(|storage| storage.lock().unwrap());
+        let expressions = self.expressions.lock().unwrap();
+        let record = match &definition {
+            Some(definition) => define_record(name, definition),
+            None => undefine_record(name),
+        };
+        if let Some(storage) = storage.as_mut() {
+            storage
+                .append(&record)
+                .map_err(|err| format!("Could not log define: {err}"))?;
+        }
+        match definition {
+            Some(definition) => self.macros.define(name, definition),
+            None if self.macros.undefine(name) => {}
+            None => return Err(format!("{name} is not defined")),
+        }
+        self.bump_revision();
+        self.recalculate_all(&expressions);
+        Ok(())
+    }
+
+    /// Declares or drops a column's type and rechecks the whole sheet
+    /// against it.
+    fn declare_column_type(&self, col: u32, column_type: Option<ColumnType>) -> io::Result<()> {
+        let mut storage = self.storage.as_ref().map(|storage| storage.lock().unwrap());
+        let expressions = self.expressions.lock().unwrap();
+        if let Some(storage) = storage.as_mut() {
+            storage.append(&coltype_record(col, column_type))?;
+        }
+        self.column_types.declare(col, column_type);
+        self.bump_revision();
+        self.recalculate_all(&expressions);
+        Ok(())
+    }
+
+    fn apply_tag(&self, cell_name: &str, tag: String, tagged: bool) {
+        let mut tags = self.tags.lock().unwrap();
+        if tagged {
+            tags.entry(cell_name.to_string()).or_default().insert(tag);
+        } else if let Some(cell_tags) = tags.get_mut(cell_name) {
+            cell_tags.remove(&tag);
+            if cell_tags.is_empty() {
+                tags.remove(cell_name);
+            }
+        }
+    }
+
+    /// Adds or removes a tag, logging it first when persistence is on.
+    fn tag(&self, cell_name: &str, tag: &str, tagged: bool) -> io::Result<()> {
+        let mut storage = self.storage.as_ref().map(|storage| storage.lock().unwrap());
+        if let Some(storage) = storage.as_mut() {
+            storage.append(&tag_record(cell_name, tag, tagged))?;
+        }
+        self.apply_tag(cell_name, tag.to_string(), tagged);
+        self.bump_revision();
+        Ok(())
+    }
+
+    /// Sets or unsets a layout setting, logging it first when persistence
+    /// is on.
+    fn set_meta(&self, key: MetaKey, value: Option<u32>) -> io::Result<()> {
+        let mut storage = self.storage.as_ref().map(|storage| storage.lock().unwrap());
+        if let Some(storage) = storage.as_mut() {
+            storage.append(&meta_record(key, value))?;
+        }
+        self.layout.set(key, value);
+        self.bump_revision();
+        Ok(())
+    }
+
+    /// Merges `range`, which must only have an expression in its top-left
+    /// cell, or unmerges it, logging it first when persistence is on.
+    fn merge(&self, range: CellRange, merged: bool) -> Result<(), String> {
+        let mut storage = self.storage.as_ref().map(|storage| storage.lock().unwrap());
+        let expressions = self.expressions.lock().unwrap();
+        if merged {
+            let mut filled: Vec<CellRef> = expressions
+                .keys()
+                .filter_map(|name| CellRef::parse(name, &self.config).ok())
+                .filter(|cell| range.contains(*cell) && *cell != range.start)
+                .collect();
+            filled.sort();
+            if let Some(cell) = filled.first() {
+                return Err(format!("Can't merge {range}, {cell} isn't empty"));
+            }
+            self.merges.merge(range)?;
+        } else {
+            self.merges.unmerge(range)?;
+        }
+        if let Some(storage) = storage.as_mut() {
+            if let Err(err) = storage.append(&merge_record(range, merged)) {
+                // Put the merge back the way the log still has it.
+                let _ = if merged {
+                    self.merges.unmerge(range)
+                } else {
+                    self.merges.merge(range)
+                };
+                return Err(format!("Could not log merge: {err}"));
+            }
+        }
+        self.bump_revision();
+        Ok(())
+    }
+
+    fn tagged(&self, tag: &str) -> Vec<String> {
+        self.tags
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, tags)| tags.contains(tag))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Puts things right after a recalculation round over `changed`
+    /// panicked: the locks it may have held are usable again, and its cells
+    /// are queued once more if `retry`.
+    fn recover_recalculation(&self, changed: Vec<String>, message: &str, retry: bool) {
+        self.expressions.clear_poison();
+        self.cell_values.clear_poison();
+        self.changed_at.clear_poison();
+        self.heartbeat.panicked();
+        #[cfg(feature = "metrics")]
+        self.metrics.record_recalculation_panic();
+        if retry {
+            error!(
+                "Recalculation of {} cells panicked, retrying: {message}",
+                changed.len()
+            );
+            for cell_name in &changed {
+                self.queue_update(cell_name);
+            }
+        } else {
+            error!(
+                "Recalculation of {} cells panicked again, giving up on them: {message}",
+                changed.len()
+            );
+        }
+    }
+
+    fn queue_update(&self, cell_name: &str) {
+        self.heartbeat.pushed();
+        #[cfg(feature = "metrics")]
+        self.metrics.queue_pushed();
+        let _ = self.expression_sender.send(cell_name.to_string());
+    }
+
+    /// The values on other servers that `expression` reads, macros
+    /// included.
+    fn remote_references(&self, expression: &str) -> Vec<RemoteKey> {
+        match self.macros.expand(expression) {
+            Ok(expanded) => remote::references(&expanded),
+            Err(_) => remote::references(expression),
+        }
+    }
+
+    /// The values on other servers any cell reads.
+    fn all_remote_references(&self) -> HashSet<RemoteKey> {
+        let expressions = self.expressions.lock().unwrap();
+        expressions
+            .values()
+            .flat_map(|expression| self.remote_references(expression))
+            .collect()
+    }
+
+    /// The cells reading any of the values on other servers in `keys`.
+    fn cells_reading(&self, keys: &[RemoteKey]) -> Vec<String> {
+        let expressions = self.expressions.lock().unwrap();
+        expressions
+            .iter()
+            .filter(|(_, expression)| {
+                self.remote_references(expression)
+                    .iter()
+                    .any(|key| keys.contains(key))
+            })
+            .map(|(cell_name, _)| cell_name.clone())
+            .collect()
+    }
+
+    /// Fetches the values on other servers `cell_name` reads again, outside
+    /// the sheet's locks, then recomputes it and queues its dependents.
+    fn invalidate(&self, cell_name: &str) {
+        let expression = self.expressions.lock().unwrap().get(cell_name).cloned();
+        if let Some(expression) = expression {
+            self.remote.fetch_all(&self.remote_references(&expression));
+        }
+        self.recompute(cell_name);
+    }
+
+    /// Recomputes `cell_name`, then queues its dependents.
+    fn recompute(&self, cell_name: &str) {
+        let expressions = self.expressions.lock().unwrap();
+        if expressions.contains_key(cell_name) {
+            let mut pass = self.profiler.pass(format!("invalidate:{cell_name}"));
+            let value = self.evaluate(&expressions, cell_name, &mut Memo::new(), &mut pass);
+            self.profiler.finish(pass);
+            self.store_value(&mut self.lock_values(), cell_name, value);
+        }
+        drop(expressions);
+        self.queue_update(cell_name);
+    }
+
+    /// Recomputes every cell with an expression in `range`, as
+    /// `invalidate` does.
+    fn recalculate_range(&self, range: CellRange) {
+        let cells: Vec<String> = self
+            .expressions
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|name| {
+                CellRef::parse(name, &self.config).is_ok_and(|cell| range.contains(cell))
+            })
+            .cloned()
+            .collect();
+        for cell_name in cells {
+            self.invalidate(&cell_name);
+        }
+    }
+
+    fn invalidate_all(&self) {
+        let referenced = self.all_remote_references();
+        self.remote.retain(&referenced);
+        self.remote
+            .fetch_all(&referenced.into_iter().collect::<Vec<_>>());
+        let expressions = self.expressions.lock().unwrap();
+        self.recalculate_all(&expressions);
+    }
+
+    fn dependency_graph(&self) -> DependencyGraph {
+        DependencyGraph::build(&self.expressions.lock().unwrap(), &self.config)
+    }
+
+    /// Drops cells that only hold a constant empty value, returning how many
+    /// were removed. Empty strings are kept while something references them,
+    /// since removing them would turn the reference into `None`.
+    fn compact(&self) -> usize {
+        let mut expressions = self.expressions.lock().unwrap();
+        let mut cell_values = self.lock_values();
+        let graph = DependencyGraph::build(&expressions, &self.config);
+
+        let dead: Vec<String> = expressions
+            .keys()
+            .filter(|name| graph.dependencies_of(name).next().is_none())
+            .filter(|name| match cell_values.get(name) {
+                None | Some(CellValue::None) => true,
+                Some(CellValue::String(s)) if s.is_empty() => {
+                    graph.dependents_of(name).next().is_none()
+                }
+                _ => false,
+            })
+            .cloned()
+            .collect();
+
+        for name in &dead {
+            self.remove_expression(&mut expressions, name);
+            self.remove_value(&mut cell_values, name);
+            self.compiled.forget(name);
+        }
+        for name in &dead {
+            self.cell_changed(name);
+        }
+        expressions.shrink_to_fit();
+        cell_values.shrink_to_fit();
+        dead.len()
+    }
+
+    /// Recomputes every cell that depends on the `changed` ones, which were
+    /// evaluated when they were written. The expressions are locked a chunk
+    /// at a time rather than copied, so writers and readers get in between
+    /// chunks of a long recalculation. With snapshot reads the whole round
+    /// is one chunk, so its values appear together.
+    fn update_cell_values(&self, changed: &[String]) {
+        let affected = DependencyGraph::build(&self.expressions.lock().unwrap(), &self.config)
+            .affected_by(changed, &self.config);
+        let chunk_size = if self.config.snapshot_reads {
+            affected.len().max(1)
+        } else {
+            UPDATE_CHUNK
+        };
+
+        let mut pass = self.profiler.pass(match changed {
+            [cell_name] => format!("update:{cell_name}"),
+            _ => format!("update:{}+{}", changed[0], changed.len() - 1),
+        });
+        // Every cell is evaluated at most once in the wave. Expressions set
+        // between chunks queue a wave of their own, which catches up on any
+        // value remembered from before.
+        let mut memo = Memo::new();
+        for chunk in affected.chunks(chunk_size) {
+            let expressions = self.expressions.lock().unwrap();
+            let values: Vec<(&String, CellValue)> = chunk
+                .iter()
+                .filter(|cell_name| expressions.contains_key(*cell_name))
+                .map(|cell_name| {
+                    let value = self.evaluate(&expressions, cell_name, &mut memo, &mut pass);
+                    (cell_name, value)
+                })
+                .collect();
+            let mut cell_values = self.lock_values();
+            for (cell_name, value) in values {
+                self.store_value(&mut cell_values, cell_name, value);
+            }
+        }
+        self.profiler.finish(pass);
+    }
+}
+
+pub fn start_server<M>(manager: M) -> Result<(), Box<dyn Error>>
+where
+    M: Manager,
+    <M::ReaderWriter as ReaderWriter>::Reader: WireReader,
+    <M::ReaderWriter as ReaderWriter>::Writer: WireWriter,
+{
+    start_server_with_config(manager, Config::default())
+}
+
+pub fn start_server_with_config<M>(manager: M, config: Config) -> Result<(), Box<dyn Error>>
+where
+    M: Manager,
+    <M::ReaderWriter as ReaderWriter>::Reader: WireReader,
+    <M::ReaderWriter as ReaderWriter>::Writer: WireWriter,
+{
+    Server::new(config)?.run(manager)
+}
+
+/// A sheet whose storage is loaded and whose background work is running,
+/// but which is not yet accepting connections. Embedders that need to reach
+/// into the sheet while it serves take a [`SheetHandle`] before calling
+/// [`Server::run`].
+pub struct Server {
+    coordinator: Arc<Coordinator>,
+}
+
+/// Lets an embedder act on a running sheet from outside any connection.
+#[derive(Clone)]
+pub struct SheetHandle {
+    coordinator: Arc<Coordinator>,
+}
+
+impl SheetHandle {
+    /// Forces `cell` and everything depending on it to be recomputed, first
+    /// fetching any external values it reads again. Use this when a data
+    /// source behind a function has changed.
+    pub fn invalidate(&self, cell: &str) -> Result<(), CellRefError> {
+        let cell = CellRef::parse(cell, &self.coordinator.config)?;
+        self.coordinator.invalidate(&cell.to_string());
+        Ok(())
+    }
+
+    /// Fetches every external value again and recomputes the whole sheet.
+    pub fn invalidate_all(&self) {
+        self.coordinator.invalidate_all();
+    }
+
+    /// Every change to a computed value from now on, in the order they were
+    /// applied. Each call gets its own stream.
+    pub fn changes(&self) -> std::sync::mpsc::Receiver<Change> {
+        self.coordinator.changes.subscribe()
+    }
+}
+
+/// Opens a sheet's storage, loads it, and starts its background work. The
+/// background threads only hold weak references, so they wind down once the
+/// sheet is dropped.
+fn launch_sheet(config: Config) -> Result<Arc<Coordinator>, Box<dyn Error>> {
+    let (storage, records) = match &config.data_dir {
+        Some(dir) => {
+            let (storage, records) = Storage::open(dir, config.wal_sync)?;
+            (Some(storage), records)
+        }
+        None => (None, Vec::new()),
+    };
+
+    let (expression_sender, expression_update_receiver) = channel();
+    let coordinator = Arc::new(Coordinator::new(expression_sender, storage, config));
+    coordinator.replay(records);
+
+    // Each round takes every update queued so far and recomputes what they
+    // affect once, so a storm of writes is coalesced rather than replayed
+    // one recalculation at a time, and no cell waits behind more than one
+    // round of others. While idle, the thread wakes every heartbeat interval
+    // just to show `health` it is still there. A round that panics is
+    // retried once before its cells are given up on.
+    let weak = Arc::downgrade(&coordinator);
+    coordinator.heartbeat.beat();
+    let mut retried = false;
+    std::thread::spawn(move || loop {
+        let next = expression_update_receiver.recv_timeout(HEARTBEAT_INTERVAL);
+        let Some(coordinator) = weak.upgrade() else {
+            return;
+        };
+        coordinator.heartbeat.beat();
+        let first = match next {
+            Ok(first) => first,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let mut changed = Vec::new();
+        let mut seen = HashSet::new();
+        for cell_name in std::iter::once(first).chain(expression_update_receiver.try_iter()) {
+            coordinator.heartbeat.popped();
+            #[cfg(feature = "metrics")]
+            coordinator.metrics.queue_popped();
+            if seen.insert(cell_name.clone()) {
+                changed.push(cell_name);
+            }
+        }
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let round = panic::catch_unwind(AssertUnwindSafe(|| {
+            coordinator.update_cell_values(&changed);
+        }));
+        #[cfg(feature = "metrics")]
+        coordinator.metrics.record_recalculation(started.elapsed());
+        match round {
+            Ok(()) => retried = false,
+            Err(panic) => {
+                coordinator.recover_recalculation(changed, &panic_message(&*panic), !retried);
+                retried = !retried;
+            }
+        }
+    });
+
+    // The interval is read afresh each time round, since a config reload
+    // can change it or turn compaction on or off.
+    let weak = Arc::downgrade(&coordinator);
+    std::thread::spawn(move || loop {
+        let interval = match weak.upgrade() {
+            Some(coordinator) => coordinator.settings().compact_interval,
+            None => return,
+        };
+        std::thread::sleep(interval.unwrap_or(COMPACT_POLL));
+        let Some(coordinator) = weak.upgrade() else {
+            return;
+        };
+        if interval.is_some() && coordinator.settings().compact_interval.is_some() {
+            let removed = coordinator.compact();
+            info!("Compaction removed {removed} cells");
+        }
+    });
+
+    let weak = Arc::downgrade(&coordinator);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(SCHEDULE_TICK);
+        let Some(coordinator) = weak.upgrade() else {
+            return;
+        };
+        for range in coordinator.schedules.take_due() {
+            coordinator.recalculate_range(range);
+        }
+    });
+
+    {
+        let weak = Arc::downgrade(&coordinator);
+        let changes = coordinator.changes.subscribe();
+        std::thread::spawn(move || {
+            for change in changes {
+                let Some(coordinator) = weak.upgrade() else {
+                    return;
+                };
+                coordinator.alerts.dispatch(&change, &coordinator.config);
+            }
+        });
+    }
+
+    #[cfg(feature = "webhooks")]
+    {
+        let weak = Arc::downgrade(&coordinator);
+        let changes = coordinator.changes.subscribe();
+        std::thread::spawn(move || {
+            for change in changes {
+                let Some(coordinator) = weak.upgrade() else {
+                    return;
+                };
+                coordinator.webhooks.dispatch(&change, &coordinator.config);
+            }
+        });
+    }
+
+    if let Some(window) = coordinator.trash.window() {
+        let weak = Arc::downgrade(&coordinator);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(window);
+            let Some(coordinator) = weak.upgrade() else {
+                return;
+            };
+            coordinator.trash.purge();
+        });
+    }
+
+    // Fetches values on other servers as evaluation asks for them, and
+    // every value still read once per refresh interval, then recomputes
+    // just the cells whose inputs changed.
+    let weak = Arc::downgrade(&coordinator);
+    let remote = coordinator.remote.clone();
+    std::thread::spawn(move || loop {
+        let wanted = remote.wait_for_wanted();
+        let Some(coordinator) = weak.upgrade() else {
+            return;
+        };
+        let keys = wanted.unwrap_or_else(|| {
+            let referenced = coordinator.all_remote_references();
+            remote.retain(&referenced);
+            referenced.into_iter().collect()
+        });
+        let changed = remote.fetch_all(&keys);
+        if !changed.is_empty() {
+            for cell_name in coordinator.cells_reading(&changed) {
+                coordinator.recompute(&cell_name);
+            }
+        }
+    });
+
+    Ok(coordinator)
+}
+
+impl Server {
+    pub fn new(config: Config) -> Result<Server, Box<dyn Error>> {
+        Ok(Server {
+            coordinator: launch_sheet(config)?,
+        })
+    }
+
+    pub fn handle(&self) -> SheetHandle {
+        SheetHandle {
+            coordinator: self.coordinator.clone(),
+        }
+    }
+
+    /// Serves connections from `manager` until it stops accepting them.
+    pub fn run<M>(self, mut manager: M) -> Result<(), Box<dyn Error>>
+    where
+        M: Manager,
+        <M::ReaderWriter as ReaderWriter>::Reader: WireReader,
+        <M::ReaderWriter as ReaderWriter>::Writer: WireWriter,
+    {
+        let shared = self.coordinator;
+        let Some(workers) = shared.config.connection_workers else {
+            return std::thread::scope(|s| loop {
+                let Ok((recv, send)) = manager.accept_new_connection() else {
+                    return Ok(());
+                };
+                let slot = match shared.admit() {
+                    Ok(slot) => slot,
+                    Err(err) => {
+                        refuse(send, err);
+                        continue;
+                    }
+                };
+                let coordinator = coordinator_for_connection(&shared)?;
+                s.spawn(move || {
+                    serve(recv, send, coordinator);
+                    drop(slot);
+                });
+            });
+        };
+
+        // Connections wait in the queue until one of the workers is free.
+        let (queue, ready) = channel();
+        let ready = Mutex::new(ready);
+        std::thread::scope(|s| {
+            for _ in 0..workers {
+                s.spawn(|| loop {
+                    let next = ready.lock().unwrap().recv();
+                    match next {
+                        Ok((recv, send, coordinator, slot)) => {
+                            serve(recv, send, coordinator);
+                            drop(slot);
+                        }
+                        Err(_) => return,
+                    }
+                });
+            }
+
+            let result = loop {
+                let Ok((recv, send)) = manager.accept_new_connection() else {
+                    break Ok(());
+                };
+                let slot = match shared.admit() {
+                    Ok(slot) => slot,
+                    Err(err) => {
+                        refuse(send, err);
+                        continue;
+                    }
+                };
+                match coordinator_for_connection(&shared) {
+                    Ok(coordinator) => {
+                        let _ = queue.send((recv, send, coordinator, slot));
+                    }
+                    Err(err) => break Err(err),
+                }
+            };
+            drop(queue);
+            result
+        })
+    }
+}
+
+/// An open connection, counted until it is dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tells a connection the server won't take it, then closes it.
+fn refuse<W: WireWriter>(mut send: W, err: String) {
+    warn!("Refused a connection: {err}");
+    let _ = write_reply(&mut send, Reply::Error(err), ReplyFormat::Rsheet);
+}
+
+fn coordinator_for_connection(
+    shared: &Arc<Coordinator>,
+) -> Result<Arc<Coordinator>, Box<dyn Error>> {
+    match shared.config.tenancy {
+        Tenancy::Shared => Ok(shared.clone()),
+        Tenancy::PerConnection => launch_sheet(Config {
+            data_dir: None,
+            ..shared.config.clone()
+        }),
+    }
+}
+
+fn serve<R, W>(recv: R, send: W, coordinator: Arc<Coordinator>)
+where
+    R: WireReader + Send + 'static,
+    W: WireWriter + Send,
+{
+    #[cfg(feature = "metrics")]
+    coordinator.metrics.connection_opened();
+    let _ = handle_connection(recv, send, coordinator.clone());
+    #[cfg(feature = "metrics")]
+    coordinator.metrics.connection_closed();
+}
+
+/// The connection a command arrived on. Replies go through `outbox` so that
+/// other connections can push to it too.
+struct Session {
+    id: String,
+    outbox: Sender<Outgoing>,
+    /// Set once the connection authenticates with an admin token, and from
+    /// the start on the server's own terminal.
+    admin: std::cell::Cell<bool>,
+    /// How this connection writes cell references. Commands in R1C1 style
+    /// are rewritten in A1 style before parsing, and cell labels on replies
+    /// are rewritten back.
+    ref_style: std::cell::Cell<RefStyle>,
+    /// The cell relative references like `+1+0` and `.B` are taken from,
+    /// once the connection has picked one with `cd`.
+    anchor: std::cell::Cell<Option<CellRef>>,
+    /// How replies are encoded, as last negotiated with `hello`.
+    format: std::cell::Cell<ReplyFormat>,
+    /// Whether `getrange` replies with a binary frame and `setmany` may
+    /// arrive as one.
+    binary_frames: std::cell::Cell<bool>,
+    /// How large replies are compressed, if the client asked for it.
+    compression: std::cell::Cell<Option<Compression>>,
+}
+
+fn handle_connection<R, W>(
+    recv: R,
+    mut send: W,
+    coordinator: Arc<Coordinator>,
+) -> Result<(), Box<dyn Error>>
+where
+    R: WireReader + Send + 'static,
+    W: WireWriter + Send,
+{
+    let (outbox, inbox) = channel::<Outgoing>();
+    let session = Session {
+        id: recv.id(),
+        outbox,
+        admin: std::cell::Cell::new(recv.local()),
+        ref_style: std::cell::Cell::new(RefStyle::A1),
+        anchor: std::cell::Cell::new(None),
+        format: std::cell::Cell::new(ReplyFormat::Rsheet),
+        binary_frames: std::cell::Cell::new(false),
+        compression: std::cell::Cell::new(None),
+    };
+
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            let mut format = ReplyFormat::Rsheet;
+            let mut compression = None;
+            for outgoing in inbox {
+                let written = match outgoing {
+                    Outgoing::Reply(reply) => write_reply(&mut send, reply, format),
+                    Outgoing::Replies(replies) => {
+                        write_replies(&mut send, replies, format, compression)
+                    }
+                    Outgoing::Format(new_format) => {
+                        format = new_format;
+                        Ok(())
+                    }
+                    Outgoing::Compression(new_compression) => {
+                        compression = new_compression;
+                        Ok(())
+                    }
+                    Outgoing::Frame(frame) => write_frame(&mut send, &frame, compression),
+                };
+                if written.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let result = match coordinator.settings().idle_timeout {
+            None => {
+                let mut recv = recv;
+                serve_connection(|| recv.read_input(), &session, &coordinator)
+            }
+            Some(timeout) => {
+                // Reads block, so they happen on their own thread and the
+                // timeout applies to waiting for the next one. If the
+                // connection is dropped, that thread ends on its next read.
+                let (messages, incoming) = channel();
+                std::thread::spawn(move || {
+                    let mut recv = recv;
+                    loop {
+                        let message = recv.read_input();
+                        let closed = matches!(
+                            message,
+                            Err(ConnectionError::ConnectionClosed | ConnectionError::ConnectionLost)
+                        );
+                        if messages.send(message).is_err() || closed {
+                            break;
+                        }
+                    }
+                });
+                let next_message = || match incoming.recv_timeout(timeout) {
+                    Ok(message) => message,
+                    Err(RecvTimeoutError::Timeout) => {
+                        let _ = session.outbox.send(
+                            Reply::Error(format!(
+                                "Closing connection after {}s idle",
+                                timeout.as_secs()
+                            ))
+                            .into(),
+                        );
+                        Err(ConnectionError::ConnectionClosed)
+                    }
+                    Err(RecvTimeoutError::Disconnected) => Err(ConnectionError::ConnectionClosed),
+                };
+                serve_connection(next_message, &session, &coordinator)
+            }
+        };
+        coordinator.presence.leave(&session.id);
+        coordinator.event_log.leave(&session.id);
+        coordinator.protections.release(&session.id);
+        coordinator.changes.leave(&session.id);
+        coordinator.alerts.leave(&session.id);
+        coordinator.identities.leave(&session.id);
+        drop(session);
+        result
+    })
+}
+
+fn serve_connection(
+    mut next_message: impl FnMut() -> Result<Input, ConnectionError>,
+    session: &Session,
+    coordinator: &Coordinator,
+) -> Result<(), Box<dyn Error>> {
+    let mut authenticated = coordinator.settings().auth_tokens.is_empty();
+    loop {
+        let msg = match next_message() {
+            Ok(msg) => msg,
+            Err(ConnectionError::MessageTooLong) => {
+                session
+                    .outbox
+                    .send(Reply::Error("Message is too long".to_string()).into())?;
+                continue;
+            }
+            Err(ConnectionError::MessageInvalidUtf8) => {
+                session
+                    .outbox
+                    .send(Reply::Error("Message is not valid UTF-8".to_string()).into())?;
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let started = Instant::now();
+        let settings = coordinator.settings();
+        let command = match msg {
+            Input::Line(msg) => {
+                let msg = match session.ref_style.get() {
+                    RefStyle::R1C1 => r1c1_to_a1(&msg, &coordinator.config).unwrap_or(msg),
+                    RefStyle::A1 => msg,
+                };
+                let msg = match session.anchor.get() {
+                    Some(anchor) => relative_to_a1(&msg, anchor, &coordinator.config),
+                    None => msg,
+                };
+                check_message(&msg, &settings).and_then(|()| parse_command(&msg, &settings))
+            }
+            Input::Frame { .. } if !session.binary_frames.get() => Err(ParseError::InvalidFrame(
+                "send hello frames=binary first".to_string(),
+            )),
+            Input::Frame { kind, payload } => parse_frame(kind, &payload, &settings),
+        };
+
+        let replies = match &command {
+            Ok(Command::Auth { token }) => {
+                if token_accepted(&settings.admin_tokens, token) {
+                    authenticated = true;
+                    session.admin.set(true);
+                    vec![]
+                } else if authenticated || token_accepted(&settings.auth_tokens, token) {
+                    authenticated = true;
+                    vec![]
+                } else {
+                    vec![Reply::Error("Invalid token".to_string())]
+                }
+            }
+            Ok(_) if !authenticated => vec![Reply::Error(
+                "Not authenticated: send auth <token> first".to_string(),
+            )],
+            Ok(command) => run_command(command, coordinator, session),
+            Err(err) => vec![Reply::Error(err.to_string())],
+        };
+
+        let outcome = match replies.iter().find_map(|reply| match reply {
+            Reply::Error(err) => Some(err.clone()),
+            _ => None,
+        }) {
+            Some(err) => Outcome::Error(err),
+            None => Outcome::Ok,
+        };
+        #[cfg(feature = "metrics")]
+        coordinator
+            .metrics
+            .record_command(command.as_ref().map_or("invalid", Command::name));
+        coordinator.event_log.record(LogEvent {
+            connection: coordinator.identities.label(&session.id),
+            command: command.as_ref().map_or("invalid", Command::name),
+            cell: command
+                .as_ref()
+                .ok()
+                .and_then(Command::cell)
+                .map(|cell| cell.to_string()),
+            duration: started.elapsed(),
+            outcome,
+        });
+
+        let replies = replies
+            .into_iter()
+            .map(|reply| match (session.ref_style.get(), reply) {
+                (RefStyle::R1C1, Reply::Value(label, value)) => {
+                    match CellRef::parse(&label, &coordinator.config) {
+                        Ok(cell) => Reply::Value(cell.to_r1c1(), value),
+                        Err(_) => Reply::Value(label, value),
+                    }
+                }
+                (_, reply) => reply,
+            })
+            .collect();
+        session.outbox.send(Outgoing::Replies(replies))?;
+    }
+}
+
+/// Rejects messages over the configured length, and control characters,
+/// before anything else looks at them.
+fn check_message(message: &str, config: &Config) -> Result<(), ParseError> {
+    if message.len() > config.max_message_len {
+        return Err(ParseError::TooLong {
+            what: "message",
+            length: message.len(),
+            max: config.max_message_len,
+        });
+    }
+    match message.chars().position(|c| c.is_control() && c != '\t') {
+        Some(position) => Err(ParseError::ControlCharacter { position }),
+        None => Ok(()),
+    }
+}
+
+/// Compares against every token without stopping early, so response times
+/// don't reveal how much of a guess was right.
+fn token_accepted(tokens: &[String], token: &str) -> bool {
+    tokens.iter().fold(false, |accepted, expected| {
+        let same = expected.len() == token.len()
+            && expected
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0;
+        accepted | same
+    })
+}
+
+fn run_command(command: &Command, coordinator: &Coordinator, session: &Session) -> Vec<Reply> {
+    let target = match command {
+        Command::Set { cell, .. }
+        | Command::CompareAndSet { cell, .. }
+        | Command::Delete { cell, .. }
+        | Command::Restore { cell } => Some(CellRange::new(*cell, *cell)),
+        Command::Sort { range, .. } | Command::SetMany { range, .. } => Some(*range),
+        Command::TemplateApply { name, at } => coordinator
+            .templates
+            .get(name)
+            .and_then(|template| template.footprint(*at, &coordinator.config))
+            .ok(),
+        Command::Derive { col, derivation } => Some(CellRange::new(
+            CellRef {
+                col: *col,
+                row: derivation.start,
+            },
+            CellRef {
+                col: *col,
+                row: derivation.end.unwrap_or(coordinator.config.max_row),
+            },
+        )),
+        Command::Append { range, .. } => Some(CellRange::new(
+            range.start,
+            CellRef {
+                col: range.end.col,
+                row: coordinator.config.max_row,
+            },
+        )),
+        _ => None,
+    };
+    if let Some(target) = target {
+        if let Err(err) = coordinator
+            .protections
+            .check(target, &session.id, session.admin.get())
+        {
+            return vec![Reply::Error(err)];
+        }
+        // Deletes and appends only ever find hidden cells empty already.
+        if !matches!(command, Command::Delete { .. } | Command::Append { .. }) {
+            if let Some(cell) = coordinator.merges.hidden_in(target) {
+                return vec![Reply::Error(format!(
+                    "{cell} is hidden inside a merged region"
+                ))];
+            }
+        }
+    }
+
+    if let Command::Set { cell, .. }
+    | Command::CompareAndSet { cell, .. }
+    | Command::Delete { cell, .. }
+    | Command::Select { cell } = command
+    {
+        coordinator.presence.touch(
+            &session.id,
+            &coordinator.identities.label(&session.id),
+            &cell.to_string(),
+        );
+    }
+
+    if let Some(target) = target.filter(|_| coordinator.audit.enabled()) {
+        let before = coordinator.cells_in(target);
+        let replies = execute(command, coordinator, session);
+        coordinator.audit.record(
+            &coordinator.identities.label(&session.id),
+            command.name(),
+            &before,
+            &coordinator.cells_in(target),
+            wall_millis(),
+            &coordinator.config,
+        );
+        return replies;
+    }
+    execute(command, coordinator, session)
+}
+
+/// Fails unless `session` is an admin, for commands that reach the server's
+/// files or settings.
+fn require_admin(session: &Session, what: &str) -> Result<(), String> {
+    if session.admin.get() {
+        Ok(())
+    } else {
+        Err(format!("Only an admin can {what}"))
+    }
+}
+
+/// Runs a command that has passed the checks `run_command` makes.
+fn execute(command: &Command, coordinator: &Coordinator, session: &Session) -> Vec<Reply> {
+    match command {
+        Command::Get {
+            cell,
+            version: None,
+            scenario: Some(scenario),
+        } => match coordinator.scenario_value(&cell.to_string(), scenario) {
+            Ok(value) => vec![Reply::Value(cell.to_string(), value)],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Eval { cell, expression } => vec![Reply::Value(
+            cell.to_string(),
+            coordinator.dry_run(&cell.to_string(), expression),
+        )],
+        Command::Calc { expression } => vec![Reply::Value(
+            expression.clone(),
+            coordinator.calculate(expression),
+        )],
+        Command::Get {
+            cell,
+            version: Some(version),
+            ..
+        } => match coordinator.history.get(&cell.to_string(), *version) {
+            Ok(value) => vec![Reply::Value(format!("{cell}@{version}"), value)],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Get {
+            cell,
+            version: None,
+            scenario: None,
+        } => {
+            let cell = cell.to_string();
+            let cell_value = coordinator.get_cell(&cell);
+            let reply = match cell_value {
+                CellValue::String(err) if err == "Runtime error: Unknown value: \"Circular dependency detected\" (line 1, position 1)" => {
+                    Reply::Error("Circular dependency".to_string())
+                }
+                CellValue::Error(err) if err == "Runtime error: Unknown value: \"Circular dependency detected\" (line 1, position 1)" => {
+                    Reply::Error("Circular dependency".to_string())
+                }
+                CellValue::String(err) if err == "'this' can only be used in functions (line 1, position 7)" => {
+                    Reply::Error("this err".to_string())
+                }
+                CellValue::String(err) if err == "Circular dependency detected" => {
+                    Reply::Error("Circular dependency".to_string())
+                }
+                CellValue::Error(err) if err == "Circular dependency detected" => {
+                    Reply::Error("Circular dependency".to_string())
+                }
+                _ => Reply::Value(cell, cell_value),
+            };
+            vec![reply]
+        }
+        Command::Set {
+            cell,
+            expression,
+            stamp,
+            format,
+        } => {
+            let cell = cell.to_string();
+            match coordinator
+                .stamped(&cell, stamp.as_ref(), || {
+                    coordinator.set_cell(&cell, expression, *format, None)
+                })
+                .and_then(|set| set)
+            {
+                Ok(()) => vec![],
+                Err(err) => vec![Reply::Error(err)],
+            }
+        }
+        Command::CompareAndSet {
+            cell,
+            expected,
+            expression,
+        } => {
+            let cell = cell.to_string();
+            match coordinator
+                .stamped(&cell, None, || {
+                    coordinator.set_cell(&cell, expression, None, Some(*expected))
+                })
+                .and_then(|set| set)
+            {
+                Ok(()) => vec![],
+                Err(err) => vec![Reply::Error(err)],
+            }
+        }
+        Command::Append { range, values } => match coordinator.append(*range, values) {
+            Ok(Some(row)) => vec![Reply::Value(
+                "append".to_string(),
+                CellValue::Int(row as i64),
+            )],
+            Ok(None) => vec![Reply::Error(format!(
+                "No empty row left at or below {}",
+                range.start
+            ))],
+            Err(err) => vec![Reply::Error(format!("Could not log append: {err}"))],
+        },
+        Command::Delete { cell, stamp } => {
+            let cell = cell.to_string();
+            match coordinator.stamped(&cell, stamp.as_ref(), || coordinator.delete_cell(&cell)) {
+                Ok(Ok(())) => vec![],
+                Ok(Err(err)) => vec![Reply::Error(format!("Could not log delete: {err}"))],
+                Err(err) => vec![Reply::Error(err)],
+            }
+        }
+        Command::ScenarioCreate { name } => match coordinator.scenarios.create(name) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::ScenarioSet {
+            name,
+            cell,
+            expression,
+        } => match coordinator
+            .scenarios
+            .set(name, &cell.to_string(), expression)
+        {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::ScenarioDrop { name } => match coordinator.scenarios.drop(name) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::ScenarioList => coordinator
+            .scenarios
+            .list()
+            .into_iter()
+            .map(|(name, cells)| {
+                Reply::Value(
+                    "scenario".to_string(),
+                    CellValue::String(format!("{name} {cells}")),
+                )
+            })
+            .collect(),
+        Command::TemplateSave { name, block } => {
+            if block.cell_count() > MAX_RANGE_CELLS {
+                return vec![Reply::Error(format!(
+                    "Block {block} is too large to save, the limit is {MAX_RANGE_CELLS} cells"
+                ))];
+            }
+            coordinator.save_template(name, *block);
+            vec![]
+        }
+        Command::TemplateApply { name, at } => match coordinator.apply_template(name, *at) {
+            Ok(_) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::TemplateDrop { name } => match coordinator.templates.drop(name) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::TemplateList => coordinator
+            .templates
+            .list()
+            .into_iter()
+            .map(|(name, block)| {
+                Reply::Value(
+                    "template".to_string(),
+                    CellValue::String(format!("{name} {block}")),
+                )
+            })
+            .collect(),
+        Command::ScheduleRecalc { range, every } => match coordinator.schedules.add(*range, *every)
+        {
+            Ok(id) => vec![Reply::Value(
+                "schedule".to_string(),
+                CellValue::Int(id as i64),
+            )],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::ScheduleList => coordinator
+            .schedules
+            .list()
+            .into_iter()
+            .map(|(id, schedule)| {
+                Reply::Value(
+                    "schedule".to_string(),
+                    CellValue::String(format!(
+                        "{id} recalc {} every {}s",
+                        schedule.range,
+                        schedule.every.as_secs()
+                    )),
+                )
+            })
+            .collect(),
+        Command::ScheduleCancel { id } => {
+            if coordinator.schedules.cancel(*id) {
+                vec![]
+            } else {
+                vec![Reply::Error(format!("No schedule {id}"))]
+            }
+        }
+        #[cfg(feature = "webhooks")]
+        Command::WebhookAdd { url, range } => match require_admin(session, "add webhooks")
+            .and_then(|()| webhook::WebhookUrl::parse(url))
+            .and_then(|url| coordinator.webhooks.add(url, *range))
+        {
+            Ok(id) => vec![Reply::Value(
+                "webhook".to_string(),
+                CellValue::Int(id as i64),
+            )],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        #[cfg(feature = "webhooks")]
+        Command::WebhookList => coordinator
+            .webhooks
+            .list()
+            .into_iter()
+            .map(|hook| Reply::Value("webhook".to_string(), CellValue::String(hook)))
+            .collect(),
+        #[cfg(feature = "webhooks")]
+        Command::WebhookRemove { id } => {
+            if let Err(err) = require_admin(session, "remove webhooks") {
+                vec![Reply::Error(err)]
+            } else if coordinator.webhooks.remove(*id) {
+                vec![]
+            } else {
+                vec![Reply::Error(format!("No webhook {id}"))]
+            }
+        }
+        Command::Restore { cell } => {
+            let cell = cell.to_string();
+            match coordinator.stamped(&cell, None, || coordinator.restore(&cell)) {
+                Ok(Ok(())) => vec![],
+                Ok(Err(err)) | Err(err) => vec![Reply::Error(err)],
+            }
+        }
+        // Handled per connection before commands reach here.
+        Command::Auth { .. } => vec![],
+        Command::Save => match coordinator.save() {
+            Ok(saved) => vec![Reply::Value(
+                "save".to_string(),
+                CellValue::Int(saved as i64),
+            )],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        #[cfg(feature = "xlsx")]
+        Command::ExportXlsx { path, mode } => match require_admin(session, "export files")
+            .and_then(|()| export_file(coordinator.config.data_dir.as_deref(), path))
+            .and_then(|path| coordinator.export_xlsx(&path, *mode))
+        {
+            Ok(exported) => vec![Reply::Value(
+                "export".to_string(),
+                CellValue::Int(exported as i64),
+            )],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        #[cfg(feature = "xlsx")]
+        Command::ImportXlsx { path } => match require_admin(session, "import files")
+            .and_then(|()| persistence::data_file(coordinator.config.data_dir.as_deref(), path))
+            .and_then(|path| coordinator.import_xlsx(&path))
+        {
+            Ok(imported) => vec![Reply::Value(
+                "import".to_string(),
+                CellValue::Int(imported as i64),
+            )],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Compact => {
+            let removed = coordinator.compact();
+            vec![Reply::Value(
+                "compact".to_string(),
+                CellValue::Int(removed as i64),
+            )]
+        }
+        Command::Orphans => {
+            let graph = coordinator.dependency_graph();
+            vec![cell_list_reply(
+                "orphans",
+                graph.orphans(),
+                &coordinator.config,
+            )]
+        }
+        Command::Cycles => {
+            let graph = coordinator.dependency_graph();
+            let cycles = graph.cycles();
+            let count = cycles.len() as i64;
+            let mut replies: Vec<Reply> = cycles
+                .into_iter()
+                .map(|cells| cell_list_reply("cycle", cells, &coordinator.config))
+                .collect();
+            replies.sort_by_key(|reply| match reply {
+                Reply::Value(_, value) => value.to_string(),
+                Reply::Error(err) => err.clone(),
+            });
+            replies.push(Reply::Value("cycles".to_string(), CellValue::Int(count)));
+            replies
+        }
+        Command::GoalSeek {
+            target,
+            goal,
+            input,
+            bounds,
+        } => match coordinator.goal_seek(*target, *goal, *input, *bounds) {
+            Ok(found) => vec![Reply::Value(
+                input.to_string(),
+                CellValue::String(coordinator.config.float_format.render(found)),
+            )],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Sweep {
+            input,
+            from,
+            to,
+            step,
+            observe,
+        } => match coordinator.sweep(*input, (*from, *to, *step), *observe) {
+            Ok(table) => table
+                .into_iter()
+                .map(|(value, observed)| {
+                    Reply::Value(format!("{input}={}", value.normalize()), observed)
+                })
+                .collect(),
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Simulate {
+            runs,
+            observe,
+            scenario,
+        } => match coordinator.simulate(*runs, *observe, scenario.as_deref()) {
+            Ok(summary) => summary
+                .lines(&coordinator.config.float_format)
+                .into_iter()
+                .map(|line| Reply::Value("simulate".to_string(), CellValue::String(line)))
+                .collect(),
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Lint { range } => {
+            let expressions = coordinator.expressions.lock().unwrap();
+            let graph = DependencyGraph::build(&expressions, &coordinator.config);
+            let findings = lint::lint(&expressions, &graph, *range, &coordinator.config);
+            let count = findings.len() as i64;
+            let mut replies: Vec<Reply> = findings
+                .into_iter()
+                .map(|finding| Reply::Value("lint".to_string(), CellValue::String(finding)))
+                .collect();
+            replies.push(Reply::Value("lint".to_string(), CellValue::Int(count)));
+            replies
+        }
+        Command::Audit { range, since } => {
+            if !coordinator.audit.enabled() {
+                return vec![Reply::Error(
+                    "Audit mode is off: start the server with --audit-entries".to_string(),
+                )];
+            }
+            coordinator
+                .audit
+                .entries(*range, *since)
+                .into_iter()
+                .map(|entry| {
+                    Reply::Value(
+                        entry.cell.to_string(),
+                        CellValue::String(format!(
+                            "at={} connection={} command={} {} -> {}",
+                            entry.millis,
+                            entry.connection,
+                            entry.command,
+                            entry.before,
+                            entry.after
+                        )),
+                    )
+                })
+                .collect()
+        }
+        Command::Diff { before, after } => {
+            let contents = coordinator
+                .contents(before)
+                .and_then(|before| Ok((before, coordinator.contents(after)?)));
+            let (before, after) = match contents {
+                Ok(contents) => contents,
+                Err(err) => return vec![Reply::Error(err)],
+            };
+            let changed = diff::diff(&before, &after, &coordinator.config);
+            let count = changed.len() as i64;
+            let mut replies: Vec<Reply> = changed
+                .into_iter()
+                .map(|(cell, change)| Reply::Value(cell, CellValue::String(change)))
+                .collect();
+            replies.push(Reply::Value("diff".to_string(), CellValue::Int(count)));
+            replies
+        }
+        Command::MergeSnapshot { theirs, base } => {
+            let writable = |cell: CellRef| {
+                coordinator.protections.check(
+                    CellRange::new(cell, cell),
+                    &session.id,
+                    session.admin.get(),
+                )?;
+                match coordinator.merges.hidden_in(CellRange::new(cell, cell)) {
+                    Some(cell) => Err(format!("{cell} is hidden inside a merged region")),
+                    None => Ok(()),
+                }
+            };
+            match coordinator.merge_snapshot(theirs, base, writable) {
+                Ok((merged, conflicts)) => {
+                    let mut replies: Vec<Reply> = conflicts
+                        .into_iter()
+                        .map(|(cell, conflict)| Reply::Value(cell, CellValue::String(conflict)))
+                        .collect();
+                    replies.push(Reply::Value(
+                        "merged".to_string(),
+                        CellValue::Int(merged as i64),
+                    ));
+                    replies
+                }
+                Err(err) => vec![Reply::Error(err)],
+            }
+        }
+        Command::Inputs => {
+            let graph = coordinator.dependency_graph();
+            vec![cell_list_reply(
+                "inputs",
+                graph.inputs(),
+                &coordinator.config,
+            )]
+        }
+        #[cfg(feature = "metrics")]
+        Command::Metrics => vec![Reply::Value(
+            "metrics".to_string(),
+            CellValue::String(coordinator.metrics.render()),
+        )],
+        Command::Sort { range, keys } => {
+            if range.cell_count() > MAX_RANGE_CELLS {
+                return vec![Reply::Error(format!(
+                    "Range {range} is too large to sort, the limit is {MAX_RANGE_CELLS} cells"
+                ))];
+            }
+            match coordinator.sort(*range, keys) {
+                Ok(_) => vec![],
+                Err(err) => vec![Reply::Error(err)],
+            }
+        }
+        Command::Filter { range, condition } => {
+            if range.cell_count() > MAX_RANGE_CELLS {
+                return vec![Reply::Error(format!(
+                    "Range {range} is too large to filter, the limit is {MAX_RANGE_CELLS} cells"
+                ))];
+            }
+            let mut replies = Vec::new();
+            let mut matched = 0;
+            for (row, values) in range.rows().zip(coordinator.range_values(*range)) {
+                let key = &values[(condition.col - range.start.col) as usize];
+                if condition.matches(key) {
+                    matched += 1;
+                    replies.extend(
+                        row.iter()
+                            .zip(values)
+                            .map(|(cell, value)| Reply::Value(cell.to_string(), value)),
+                    );
+                }
+            }
+            replies.push(Reply::Value("filter".to_string(), CellValue::Int(matched)));
+            replies
+        }
+        Command::GroupBy {
+            range,
+            key,
+            aggregate,
+            value,
+        } => {
+            if range.cell_count() > MAX_RANGE_CELLS {
+                return vec![Reply::Error(format!(
+                    "Range {range} is too large to group, the limit is {MAX_RANGE_CELLS} cells"
+                ))];
+            }
+            let rows = coordinator.range_values(*range);
+            let groups = query::group_by(
+                &rows,
+                (key - range.start.col) as usize,
+                (value - range.start.col) as usize,
+                *aggregate,
+            );
+            let count = groups.len() as i64;
+            let mut replies: Vec<Reply> = groups
+                .into_iter()
+                .map(|(key, result)| Reply::Value(key.to_string(), result))
+                .collect();
+            replies.push(Reply::Value("groupby".to_string(), CellValue::Int(count)));
+            replies
+        }
+        Command::Show { range } => {
+            if range.cell_count() > render::MAX_RENDERED_CELLS {
+                return vec![Reply::Error(format!(
+                    "Range {range} is too large to show, the limit is {} cells",
+                    render::MAX_RENDERED_CELLS
+                ))];
+            }
+            let (revision, rows) = coordinator.revisioned_range_values(*range);
+            let table = render::table(*range, |cell| {
+                coordinator.formats.display(
+                    &cell.to_string(),
+                    rows[(cell.row - range.start.row) as usize]
+                        [(cell.col - range.start.col) as usize]
+                        .clone(),
+                )
+            });
+            vec![Reply::Value(
+                format!("show@{revision}"),
+                CellValue::String(table),
+            )]
+        }
+        Command::GetRange { range } => {
+            if range.cell_count() > MAX_RANGE_CELLS {
+                return vec![Reply::Error(format!(
+                    "Range {range} is too large to get, the limit is {MAX_RANGE_CELLS} cells"
+                ))];
+            }
+            let (revision, rows) = coordinator.revisioned_range_values(*range);
+            if session.binary_frames.get() {
+                let _ = session
+                    .outbox
+                    .send(Outgoing::Frame(range_frame(*range, revision, &rows)));
+                return vec![];
+            }
+            let mut replies: Vec<Reply> = range
+                .rows()
+                .zip(rows)
+                .flat_map(|(cells, values)| {
+                    cells
+                        .into_iter()
+                        .zip(values)
+                        .map(|(cell, value)| Reply::Value(cell.to_string(), value))
+                })
+                .collect();
+            replies.push(Reply::Value(
+                format!("getrange@{revision}"),
+                CellValue::Int(range.cell_count() as i64),
+            ));
+            replies
+        }
+        Command::Series { x, y } => {
+            if let Some(range) = [x, y]
+                .into_iter()
+                .find(|range| range.width() > 1 && range.height() > 1)
+            {
+                return vec![Reply::Error(format!(
+                    "Range {range} isn't a single row or column"
+                ))];
+            }
+            if x.cell_count() != y.cell_count() {
+                return vec![Reply::Error(format!(
+                    "Ranges {x} and {y} aren't the same length"
+                ))];
+            }
+            if x.cell_count() > MAX_RANGE_CELLS {
+                return vec![Reply::Error(format!(
+                    "Range {x} is too large to plot, the limit is {MAX_RANGE_CELLS} cells"
+                ))];
+            }
+            let values = |range: CellRange| -> Vec<CellValue> {
+                coordinator
+                    .range_values(range)
+                    .into_iter()
+                    .flatten()
+                    .collect()
+            };
+            vec![Reply::Value(
+                "series".to_string(),
+                CellValue::String(series::to_json(&values(*x), &values(*y))),
+            )]
+        }
+        Command::SetMany { range, values } => match coordinator.set_many(*range, values) {
+            Ok(_) => vec![],
+            Err(err) => vec![Reply::Error(format!("Could not log setmany: {err}"))],
+        },
+        Command::Select { .. } => vec![],
+        Command::Merge { range } => match coordinator.merge(*range, true) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Unmerge { range } => match coordinator.merge(*range, false) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Merges => coordinator
+            .merges
+            .list()
+            .into_iter()
+            .map(|range| Reply::Value("merge".to_string(), CellValue::String(range.to_string())))
+            .collect(),
+        Command::MetaSet { key, value } => match coordinator.set_meta(*key, *value) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(format!("Could not log meta: {err}"))],
+        },
+        Command::MetaGet { key } => vec![Reply::Value(
+            key.to_string(),
+            coordinator
+                .layout
+                .get(*key)
+                .map_or(CellValue::None, |value| CellValue::Int(value as i64)),
+        )],
+        Command::MetaList => coordinator
+            .layout
+            .list()
+            .into_iter()
+            .map(|(key, value)| Reply::Value(key.to_string(), CellValue::Int(value as i64)))
+            .collect(),
+        Command::Tag { cell, tag } => match coordinator.tag(&cell.to_string(), tag, true) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(format!("Could not log tag: {err}"))],
+        },
+        Command::Untag { cell, tag } => match coordinator.tag(&cell.to_string(), tag, false) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(format!("Could not log untag: {err}"))],
+        },
+        Command::Tagged { tag } => {
+            let cells = coordinator.tagged(tag);
+            vec![cell_list_reply(
+                "cells",
+                cells.iter().collect(),
+                &coordinator.config,
+            )]
+        }
+        #[cfg(feature = "scripting")]
+        Command::RunScript { path } => match require_admin(session, "run scripts")
+            .and_then(|()| {
+                persistence::file_inside(coordinator.config.scripts_dir.as_deref(), path, "scripts")
+            })
+            .and_then(|path| {
+                script::run(&path, &coordinator.config, |command| {
+                    run_command(command, coordinator, session)
+                })
+            }) {
+            Ok(value) => vec![Reply::Value("script".to_string(), value)],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Define { name, definition } => {
+            match coordinator.define(name, Some(definition.clone())) {
+                Ok(()) => vec![],
+                Err(err) => vec![Reply::Error(err)],
+            }
+        }
+        Command::DeclareColumnType { col, column_type } => {
+            match coordinator.declare_column_type(*col, *column_type) {
+                Ok(()) => vec![],
+                Err(err) => vec![Reply::Error(format!("Could not log coltype: {err}"))],
+            }
+        }
+        Command::Derive { col, derivation } => match coordinator.derive(*col, derivation.clone()) {
+            Ok(_) => vec![],
+            Err(err) => vec![Reply::Error(format!("Could not log derive: {err}"))],
+        },
+        Command::Underive { col } => match coordinator.underive(*col) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Undefine { name } => match coordinator.define(name, None) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Protect { range } => match coordinator.protections.protect(
+            *range,
+            &session.id,
+            &coordinator.identities.label(&session.id),
+        ) {
+            Ok(()) => vec![],
+            Err(err) => vec![Reply::Error(err)],
+        },
+        Command::Unprotect { range } => {
+            match coordinator
+                .protections
+                .unprotect(*range, &session.id, session.admin.get())
+            {
+                Ok(()) => vec![],
+                Err(err) => vec![Reply::Error(err)],
+            }
+        }
+        Command::SetRefStyle { style } => {
+            session.ref_style.set(*style);
+            vec![]
+        }
+        Command::ChangeAnchor { cell } => {
+            session.anchor.set(*cell);
+            vec![]
+        }
+        Command::ShowAnchor => vec![Reply::Value(
+            "pwd".to_string(),
+            session
+                .anchor
+                .get()
+                .map_or(CellValue::None, |cell| CellValue::String(cell.to_string())),
+        )],
+        Command::Help { command } => {
+            let describe = |spec: &CommandSpec| {
+                Reply::Value(
+                    spec.name.to_string(),
+                    CellValue::String(match spec.aliases {
+                        [] => format!("{}: {}", spec.syntax, spec.summary),
+                        aliases => format!(
+                            "{}: {} (also {})",
+                            spec.syntax,
+                            spec.summary,
+                            aliases.join(", ")
+                        ),
+                    }),
+                )
+            };
+            match command.and_then(commands::resolve) {
+                Some(spec) => vec![describe(spec)],
+                None => {
+                    let mut replies: Vec<Reply> = COMMANDS.iter().map(describe).collect();
+                    replies.push(Reply::Value(
+                        "help".to_string(),
+                        CellValue::Int(COMMANDS.len() as i64),
+                    ));
+                    replies
+                }
+            }
+        }
+        Command::Extent => vec![Reply::Value(
+            "extent".to_string(),
+            match coordinator.extent.bounds() {
+                Some(range) => CellValue::String(format!("{}:{}", range.start, range.end)),
+                None => CellValue::None,
+            },
+        )],
+        Command::List {
+            order,
+            offset,
+            limit,
+        } => {
+            let (total, page) = coordinator.list(*order, *offset, *limit);
+            let mut replies: Vec<Reply> = page
+                .into_iter()
+                .map(|(cell, value)| Reply::Value(cell.to_string(), value))
+                .collect();
+            replies.push(Reply::Value(
+                "list".to_string(),
+                CellValue::Int(total as i64),
+            ));
+            replies
+        }
+        Command::Revision => vec![Reply::Value(
+            "revision".to_string(),
+            CellValue::Int(coordinator.revision.load(Ordering::SeqCst) as i64),
+        )],
+        Command::Hello {
+            format,
+            frames,
+            compression,
+            name,
+        } => {
+            if let Some(name) = name {
+                if let Err(err) = coordinator.identities.claim(&session.id, name) {
+                    return vec![Reply::Error(err)];
+                }
+            }
+            if let Some(format) = *format {
+                session.format.set(format);
+                let _ = session.outbox.send(Outgoing::Format(format));
+            }
+            if let Some(frames) = *frames {
+                session.binary_frames.set(frames);
+            }
+            if let Some(compression) = *compression {
+                session.compression.set(compression);
+                let _ = session.outbox.send(Outgoing::Compression(compression));
+            }
+            let compression = session
+                .compression
+                .get()
+                .map_or("none".to_string(), |compression| compression.to_string());
+            let frames = if session.binary_frames.get() {
+                "binary"
+            } else {
+                "text"
+            };
+            vec![Reply::Value(
+                "hello".to_string(),
+                CellValue::String(format!(
+                    "format={} frames={frames} compress={compression} name={}",
+                    session.format.get(),
+                    coordinator.identities.label(&session.id)
+                )),
+            )]
+        }
+        Command::Ping => vec![Reply::Value(
+            "ping".to_string(),
+            CellValue::String("pong".to_string()),
+        )],
+        Command::Presence { watch } => {
+            if *watch {
+                coordinator
+                    .presence
+                    .watch(&session.id, session.outbox.clone());
+            }
+            let positions = coordinator.presence.list();
+            let count = positions.len() as i64;
+            let mut replies: Vec<Reply> = positions
+                .iter()
+                .map(|(connection, cell)| presence_reply(connection, Some(cell)))
+                .collect();
+            replies.push(Reply::Value("presence".to_string(), CellValue::Int(count)));
+            replies
+        }
+        Command::WatchChanges => {
+            let changes = coordinator.changes.watch(&session.id);
+            let outbox = session.outbox.clone();
+            std::thread::spawn(move || {
+                for change in changes {
+                    let label = format!("change {} {} {}", change.seq, change.cell, change.old);
+                    if outbox.send(Reply::Value(label, change.new).into()).is_err() {
+                        break;
+                    }
+                }
+            });
+            vec![]
+        }
+        Command::Watch { range, when } => {
+            let changes = coordinator.changes.watch(&session.id);
+            let outbox = session.outbox.clone();
+            let (range, when) = (*range, when.clone());
+            let config = coordinator.config.clone();
+            std::thread::spawn(move || {
+                for change in changes {
+                    let watched = CellRef::parse(&change.cell, &config)
+                        .is_ok_and(|cell| range.contains(cell));
+                    if !watched || when.as_ref().is_some_and(|when| !when.matches(&change.new)) {
+                        continue;
+                    }
+                    let label = format!("watch {} {} {}", change.seq, change.cell, change.old);
+                    if outbox.send(Reply::Value(label, change.new).into()).is_err() {
+                        break;
+                    }
+                }
+            });
+            vec![]
+        }
+        Command::AlertAdd {
+            cell,
+            when,
+            cooldown,
+        } => {
+            let current = coordinator.get_cell(&cell.to_string());
+            let id = coordinator.alerts.add(
+                *cell,
+                when.clone(),
+                *cooldown,
+                &session.id,
+                session.outbox.clone(),
+                &current,
+            );
+            vec![Reply::Value("alert".to_string(), CellValue::Int(id as i64))]
+        }
+        Command::AlertList => coordinator
+            .alerts
+            .list(&session.id)
+            .into_iter()
+            .map(|alert| Reply::Value("alert".to_string(), CellValue::String(alert)))
+            .collect(),
+        Command::AlertRemove { id } => {
+            if coordinator.alerts.remove(*id, &session.id) {
+                vec![]
+            } else {
+                vec![Reply::Error(format!("No alert {id}"))]
+            }
+        }
+        Command::ReloadConfig => {
+            match require_admin(session, "reload the config")
+                .and_then(|()| coordinator.reload_config())
+            {
+                Ok((applied, needs_restart)) => applied
+                    .into_iter()
+                    .map(|key| Reply::Value("reloaded".to_string(), CellValue::String(key)))
+                    .chain(needs_restart.into_iter().map(|key| {
+                        Reply::Value("needs_restart".to_string(), CellValue::String(key))
+                    }))
+                    .collect(),
+                Err(err) => vec![Reply::Error(err)],
+            }
+        }
+        Command::Health => Health::check(
+            &coordinator.heartbeat,
+            coordinator.config.data_dir.as_deref(),
+            coordinator.config.health_stall,
+        )
+        .fields()
+        .into_iter()
+        .map(|(name, value)| Reply::Value(name.to_string(), value))
+        .collect(),
+        Command::MemStats => coordinator
+            .memory_stats()
+            .lines()
+            .into_iter()
+            .map(|line| Reply::Value("memstats".to_string(), CellValue::String(line)))
+            .collect(),
+        Command::Hotspots { count } => coordinator
+            .hotspots(*count)
+            .into_iter()
+            .map(|(cell, cost)| {
+                Reply::Value(
+                    "hotspots".to_string(),
+                    CellValue::String(format!("{cell} {}us", cost.as_micros())),
+                )
+            })
+            .collect(),
+        Command::ExportDeps { path, range } => {
+            let path = match require_admin(session, "export files")
+                .and_then(|()| export_file(coordinator.config.data_dir.as_deref(), path))
+            {
+                Ok(path) => path,
+                Err(err) => return vec![Reply::Error(err)],
+            };
+            let (dot, cells) = coordinator.dependency_graph().to_dot(
+                *range,
+                &coordinator.tags.lock().unwrap(),
+                &coordinator.config,
+            );
+            match std::fs::write(&path, dot) {
+                Ok(()) => vec![Reply::Value(
+                    "export".to_string(),
+                    CellValue::Int(cells as i64),
+                )],
+                Err(err) => vec![Reply::Error(format!(
+                    "Could not write {}: {err}",
+                    path.display()
+                ))],
+            }
+        }
+        Command::ExportAuditLog { path, rotation } => {
+            if !coordinator.audit.enabled() {
+                return vec![Reply::Error(
+                    "Audit mode is off: start the server with --audit-entries".to_string(),
+                )];
+            }
+            let path = match require_admin(session, "export files")
+                .and_then(|()| export_file(coordinator.config.data_dir.as_deref(), path))
+            {
+                Ok(path) => path,
+                Err(err) => return vec![Reply::Error(err)],
+            };
+            match coordinator.audit.export(&path, *rotation) {
+                Ok(exported) => vec![Reply::Value(
+                    "export".to_string(),
+                    CellValue::Int(exported as i64),
+                )],
+                Err(err) => vec![Reply::Error(format!(
+                    "Could not write {}: {err}",
+                    path.display()
+                ))],
+            }
+        }
+        Command::ProfileDump => {
+            if !coordinator.profiler.enabled() {
+                return vec![Reply::Error("Profiling is not enabled".to_string())];
+            }
+            coordinator
+                .profiler
+                .dump()
+                .into_iter()
+                .map(|pass| {
+                    Reply::Value("profile".to_string(), CellValue::String(pass.to_string()))
+                })
+                .collect()
+        }
+        Command::Tail { count, follow } => {
+            if let Err(err) = require_admin(session, "tail the log") {
+                return vec![Reply::Error(err)];
+            }
+            if *follow {
+                coordinator
+                    .event_log
+                    .follow(&session.id, session.outbox.clone());
+            }
+            coordinator
+                .event_log
+                .tail(*count)
+                .iter()
+                .map(tail_reply)
+                .collect()
+        }
+    }
+}
+
+fn cell_list_reply(label: &str, cells: Vec<&String>, config: &Config) -> Reply {
+    let mut cells: Vec<CellRef> = cells
+        .into_iter()
+        .filter_map(|name| CellRef::parse(name, config).ok())
+        .collect();
+    cells.sort();
+    let cells: Vec<String> = cells.iter().map(CellRef::to_string).collect();
+    Reply::Value(label.to_string(), CellValue::String(cells.join(", ")))
 }
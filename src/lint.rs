@@ -0,0 +1,82 @@
+use rhai::Engine;
+use std::collections::HashMap;
+
+use crate::cell_ref::{expand_whole_references, CellRange, CellRef};
+use crate::compiled::Compiled;
+use crate::config::Config;
+use crate::functions;
+use crate::graph::DependencyGraph;
+use crate::logic;
+
+/// Chains of reads longer than this many cells are reported where they end.
+const DEEP_CHAIN: usize = 50;
+
+/// Suspicious formulas among the cells in `range`, or the whole sheet,
+/// each as `<cell> <what looks wrong>`, in cell order. Nothing is
+/// evaluated; the findings come from the stored expressions and `graph`.
+pub fn lint(
+    expressions: &HashMap<String, String>,
+    graph: &DependencyGraph,
+    range: Option<CellRange>,
+    config: &Config,
+) -> Vec<String> {
+    let engine = Engine::new_raw();
+    let edge = CellRef {
+        col: config.max_column,
+        row: config.max_row,
+    };
+    let depths = graph.depths();
+
+    let mut findings: Vec<(CellRef, String)> = Vec::new();
+    for (name, expression) in expressions {
+        let Ok(cell) = CellRef::parse(name, config) else {
+            continue;
+        };
+        if range.is_some_and(|range| !range.contains(cell)) {
+            continue;
+        }
+        let mut report = |finding: String| findings.push((cell, format!("{name} {finding}")));
+
+        let expanded = logic::expand(expression).unwrap_or_else(|_| expression.clone());
+        let expanded = expand_whole_references(&expanded, || edge, config);
+        for variable in Compiled::new(&engine, &expanded).variables() {
+            match variable.split_once('_') {
+                Some((start, end)) => {
+                    let (Ok(start), Ok(end)) =
+                        (CellRef::parse(start, config), CellRef::parse(end, config))
+                    else {
+                        continue;
+                    };
+                    let read = CellRange::new(start, end);
+                    if read.contains(cell) {
+                        report(format!("reads {read}, which contains itself"));
+                    }
+                }
+                None => {
+                    if CellRef::parse(variable, config).is_ok()
+                        && !expressions.contains_key(variable)
+                    {
+                        report(format!("reads empty cell {variable}"));
+                    }
+                }
+            }
+        }
+
+        for literal in functions::string_literals(expression).unwrap_or_default() {
+            if CellRef::parse(literal, config).is_ok() || CellRange::parse(literal, config).is_ok()
+            {
+                report(format!(
+                    "has the constant \"{literal}\", which looks like a cell reference"
+                ));
+            }
+        }
+
+        if let Some(depth) = depths.get(name).filter(|depth| **depth > DEEP_CHAIN) {
+            if graph.dependents_of(name).next().is_none() {
+                report(format!("ends a chain of reads {depth} cells deep"));
+            }
+        }
+    }
+    findings.sort();
+    findings.into_iter().map(|(_, finding)| finding).collect()
+}
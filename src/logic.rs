@@ -0,0 +1,50 @@
+use crate::functions::{self, Call};
+
+/// Logical functions the runner lacks, rewritten into the Rhai operators
+/// they stand for so they short-circuit the same way.
+const LOGICAL_FUNCTIONS: [&str; 5] = ["if", "and", "or", "not", "coalesce"];
+
+/// Rewrites `if(cond, a, b)`, `and(..)`, `or(..)`, `not(x)` and
+/// `coalesce(..)` into Rhai, and `TRUE` and `FALSE` into its boolean
+/// literals. A Rhai `if` written with a block after its condition is left
+/// as it is.
+pub fn expand(expression: &str) -> Result<String, String> {
+    let expression = functions::replace_identifiers(expression, |name| match name {
+        "TRUE" => Some("true".to_string()),
+        "FALSE" => Some("false".to_string()),
+        _ => None,
+    })?;
+    functions::replace_calls(&expression, &LOGICAL_FUNCTIONS, |call| {
+        rewrite(call, &expression)
+    })
+}
+
+fn rewrite(call: &Call, expression: &str) -> Result<String, String> {
+    let args = call
+        .args
+        .iter()
+        .map(|arg| expand(arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    let joined = |operator: &str| {
+        args.iter()
+            .map(|arg| format!("({arg})"))
+            .collect::<Vec<_>>()
+            .join(operator)
+    };
+    match (call.name, args.as_slice()) {
+        ("if", _) if expression[call.end()..].trim_start().starts_with('{') => {
+            Ok(format!("if ({})", args.join(", ")))
+        }
+        ("if", [condition, then, otherwise]) => Ok(format!(
+            "(if ({condition}) {{ {then} }} else {{ {otherwise} }})"
+        )),
+        ("if", [condition, then]) => Ok(format!("(if ({condition}) {{ {then} }})")),
+        ("and", [_, ..]) => Ok(format!("({})", joined(" && "))),
+        ("or", [_, ..]) => Ok(format!("({})", joined(" || "))),
+        ("not", [value]) => Ok(format!("(!({value}))")),
+        ("coalesce", [_, ..]) => Ok(format!("({})", joined(" ?? "))),
+        ("if", _) => Err("if() takes a condition and one or two values".to_string()),
+        ("not", _) => Err("not() takes one argument".to_string()),
+        (name, _) => Err(format!("{name}() takes at least one argument")),
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::functions;
+
+/// How many times calls may expand into further calls before expansion
+/// gives up, which is what stops a macro that calls itself.
+const MAX_EXPANSIONS: usize = 32;
+
+/// A formula defined with `define name(params) = body`. Bodies only refer to
+/// their parameters, so a cell's dependencies are all in its own expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub params: Vec<String>,
+    pub body: String,
+}
+
+#[derive(Default)]
+pub struct Macros {
+    defined: Mutex<HashMap<String, Macro>>,
+}
+
+impl Macros {
+    pub fn define(&self, name: &str, definition: Macro) {
+        self.defined
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), definition);
+    }
+
+    /// Returns whether `name` was defined.
+    pub fn undefine(&self, name: &str) -> bool {
+        self.defined.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Every definition, sorted by name.
+    pub fn definitions(&self) -> Vec<(String, Macro)> {
+        let mut definitions: Vec<(String, Macro)> = self
+            .defined
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, definition)| (name.clone(), definition.clone()))
+            .collect();
+        definitions.sort_by(|a, b| a.0.cmp(&b.0));
+        definitions
+    }
+
+    /// Replaces calls to defined macros with their bodies, each argument
+    /// parenthesized in place of its parameter.
+    pub fn expand(&self, expression: &str) -> Result<String, String> {
+        let defined = self.defined.lock().unwrap();
+        if defined.is_empty() {
+            return Ok(expression.to_string());
+        }
+        let names: Vec<&str> = defined.keys().map(String::as_str).collect();
+
+        let mut expression = expression.to_string();
+        for _ in 0..MAX_EXPANSIONS {
+            let mut expanded_any = false;
+            let expanded = functions::replace_calls(&expression, &names, |call| {
+                expanded_any = true;
+                let definition = &defined[call.name];
+                if call.args.len() != definition.params.len() {
+                    return Err(format!(
+                        "{}() takes {} arguments",
+                        call.name,
+                        definition.params.len()
+                    ));
+                }
+                let body = functions::replace_identifiers(&definition.body, |name| {
+                    let index = definition.params.iter().position(|param| param == name)?;
+                    Some(format!("({})", call.args[index]))
+                })?;
+                Ok(format!("({body})"))
+            })?;
+            if !expanded_any {
+                return Ok(expression);
+            }
+            expression = expanded;
+        }
+        Err("Macros expand too deeply".to_string())
+    }
+}
@@ -1,30 +1,295 @@
 use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::Parser;
-use rsheet::start_server;
-use rsheet_lib::connect::{resolve_address, ConnectionManager, TerminalManager};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use rsheet::config::{BlankPolicy, ColumnTypePolicy, Config, ConflictResolution, Tenancy};
+use rsheet::config_file::{self, parse_column, CONFIG_ENV};
+use rsheet::event_log::Verbosity;
+use rsheet::net::TcpManager;
+use rsheet::numbers::{FloatFormat, NumberLocale};
+use rsheet::persistence::SyncPolicy;
+use rsheet::start_server_with_config;
+use rsheet_lib::connect::{resolve_address, TerminalManager};
+use rust_decimal::Decimal;
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// Address to listen on
+    /// Address to listen on. Connections are plain TCP unless the server
+    /// is built with the `tls` feature and given --tls-cert and --tls-key
     addr: Option<String>,
 
+    /// PEM certificate chain to serve TLS with, alongside --tls-key
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
     /// Hides the contents of error messages
     #[arg(short, long, default_value_t = false)]
     mark_mode: bool,
+
+    /// TOML file of settings, named like these flags with underscores for
+    /// dashes. Flags given here win over RSHEET_* environment variables,
+    /// which win over the file
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Last column cells may use
+    #[arg(long, default_value = "ZZZ", value_parser = parse_column)]
+    max_column: u32,
+
+    /// Last row cells may use
+    #[arg(long, default_value_t = Config::default().max_row)]
+    max_row: u32,
+
+    /// Seconds between background compactions of empty cells
+    #[arg(long)]
+    compact_interval: Option<u64>,
+
+    /// Which commands to log: off, errors or all
+    #[arg(long, default_value = "all")]
+    log_verbosity: Verbosity,
+
+    /// Directory to persist the sheet in
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Directory holding the scripts `script run` may run
+    #[arg(long)]
+    scripts_dir: Option<PathBuf>,
+
+    /// When to fsync the write-ahead log: always, never or every N writes
+    #[arg(long, default_value = "always")]
+    wal_sync: SyncPolicy,
+
+    /// Seconds to reuse values fetched from other sheet servers
+    #[arg(long, default_value_t = Config::default().remote_refresh.as_secs())]
+    remote_refresh: u64,
+
+    /// A `host:port` that `remote()` may read from; may be repeated
+    #[arg(long = "remote-host")]
+    remote_hosts: Vec<String>,
+
+    /// Treat `a1` and `A1` as different names, leaving lowercase ones alone
+    #[arg(long, default_value_t = false)]
+    case_sensitive_cells: bool,
+
+    /// Whether connections share one sheet or each get their own: shared or connection
+    #[arg(long, default_value = "shared")]
+    tenancy: Tenancy,
+
+    /// Token clients must send with `auth` before other commands; may be repeated
+    #[arg(long = "auth-token")]
+    auth_tokens: Vec<String>,
+
+    /// Token that authenticates as an admin, who may write to and unprotect
+    /// any protected range and run commands that reach the server's files;
+    /// may be repeated
+    #[arg(long = "admin-token")]
+    admin_tokens: Vec<String>,
+
+    /// How concurrent writes to a cell are ordered: arrival or lww
+    #[arg(long, default_value = "arrival")]
+    conflict_resolution: ConflictResolution,
+
+    /// Record recalculation traces for `profile dump`
+    #[arg(long, default_value_t = false)]
+    profile: bool,
+
+    /// Show recalculated values only once the whole recalculation finishes
+    #[arg(long, default_value_t = false)]
+    snapshot_reads: bool,
+
+    /// Serve reads from immutable snapshots that never wait on writers
+    #[arg(long, default_value_t = false, conflicts_with = "value_cache")]
+    lock_free_reads: bool,
+
+    /// Serve connections from a pool of this many threads
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    workers: Option<u16>,
+
+    /// Refuse connections beyond this many open at once
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    max_connections: Option<u16>,
+
+    /// Seconds a connection may stay silent before it is closed
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+
+    /// Longest accepted message, in bytes
+    #[arg(long, default_value_t = Config::default().max_message_len)]
+    max_message_len: usize,
+
+    /// Longest accepted expression, in bytes
+    #[arg(long, default_value_t = Config::default().max_expression_len)]
+    max_expression_len: usize,
+
+    /// Keep at most this many computed values in memory, spilling the rest
+    /// to disk
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    value_cache: Option<u64>,
+
+    /// How many past values each cell keeps for `get A1@<version>`
+    #[arg(long, default_value_t = Config::default().value_history)]
+    value_history: usize,
+
+    /// Keep this many cell changes, with who made them and the values
+    /// either side, for `audit`
+    #[arg(long, default_value_t = Config::default().audit_entries)]
+    audit_entries: usize,
+
+    /// What a value that doesn't fit its column's type does: error or reject
+    #[arg(long, default_value = "error")]
+    column_type_policy: ColumnTypePolicy,
+
+    /// Significant digits kept when a float result is written into a cell
+    #[arg(long, default_value_t = FloatFormat::default().digits)]
+    float_digits: u32,
+
+    /// Write floats with a decimal exponent at least this large, either way,
+    /// in scientific notation
+    #[arg(long, default_value_t = FloatFormat::default().scientific_exponent)]
+    float_scientific_exponent: u32,
+
+    /// Read numbers set on their own in this locale's style, like `1.234,56`
+    /// for de: en or de
+    #[arg(long)]
+    number_locale: Option<NumberLocale>,
+
+    /// What empty cells in a range do to `sum` when the call doesn't say:
+    /// skip, zero or error
+    #[arg(long, default_value = "error")]
+    blank_policy: BlankPolicy,
+
+    /// Longest chain of references one evaluation follows
+    #[arg(long, default_value_t = Config::default().max_eval_depth)]
+    max_eval_depth: usize,
+
+    /// Most cells one evaluation evaluates on the way
+    #[arg(long, default_value_t = Config::default().max_eval_reads)]
+    max_eval_reads: usize,
+
+    /// Skip sets that don't change a cell's expression, rather than logging
+    /// and recalculating them
+    #[arg(long, default_value_t = false)]
+    skip_unchanged_sets: bool,
+
+    /// Float results that move less than this keep their old value and
+    /// aren't published as changes
+    #[arg(long, default_value_t = Config::default().change_epsilon)]
+    change_epsilon: Decimal,
+
+    /// Seconds a deleted cell can be brought back with `restore`
+    #[arg(long)]
+    trash_window: Option<u64>,
+
+    /// Seconds the recalculation thread can go without reporting in before
+    /// `health` calls it stalled
+    #[arg(long, default_value_t = Config::default().health_stall.as_secs())]
+    health_stall: u64,
+
+    /// How close goalseek must bring the target cell to the goal
+    #[arg(long, default_value_t = Config::default().goal_seek_tolerance)]
+    goal_seek_tolerance: Decimal,
+
+    /// Most input values one sweep may try
+    #[arg(long, default_value_t = Config::default().max_sweep_steps)]
+    max_sweep_steps: usize,
+
+    /// Most runs one simulate may make
+    #[arg(long, default_value_t = Config::default().max_simulation_runs)]
+    max_simulation_runs: usize,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches)?;
+    let config_file = args
+        .config
+        .clone()
+        .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from));
+    let mut config = Config {
+        config_file: config_file.clone(),
+        command_line: matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect(),
+        max_column: args.max_column,
+        max_row: args.max_row,
+        compact_interval: args.compact_interval.map(Duration::from_secs),
+        log_verbosity: args.log_verbosity,
+        data_dir: args.data_dir,
+        scripts_dir: args.scripts_dir,
+        wal_sync: args.wal_sync,
+        remote_refresh: Duration::from_secs(args.remote_refresh),
+        remote_hosts: args.remote_hosts,
+        case_insensitive_cells: !args.case_sensitive_cells,
+        tenancy: args.tenancy,
+        auth_tokens: args.auth_tokens,
+        admin_tokens: args.admin_tokens,
+        conflict_resolution: args.conflict_resolution,
+        profile: args.profile,
+        snapshot_reads: args.snapshot_reads,
+        lock_free_reads: args.lock_free_reads,
+        connection_workers: args.workers.map(usize::from),
+        max_connections: args.max_connections.map(usize::from),
+        idle_timeout: args.idle_timeout.map(Duration::from_secs),
+        max_message_len: args.max_message_len,
+        max_expression_len: args.max_expression_len,
+        value_cache: args.value_cache.map(|n| n as usize),
+        value_history: args.value_history,
+        audit_entries: args.audit_entries,
+        column_type_policy: args.column_type_policy,
+        float_format: FloatFormat {
+            digits: args.float_digits,
+            scientific_exponent: args.float_scientific_exponent,
+        },
+        number_locale: args.number_locale,
+        blank_policy: args.blank_policy,
+        max_eval_depth: args.max_eval_depth,
+        max_eval_reads: args.max_eval_reads,
+        skip_unchanged_sets: args.skip_unchanged_sets,
+        change_epsilon: args.change_epsilon,
+        trash_window: args.trash_window.map(Duration::from_secs),
+        health_stall: Duration::from_secs(args.health_stall),
+        goal_seek_tolerance: args.goal_seek_tolerance,
+        max_sweep_steps: args.max_sweep_steps,
+        max_simulation_runs: args.max_simulation_runs,
+    };
+
+    let mut settings = match &config_file {
+        Some(path) => config_file::load(path)?,
+        None => Vec::new(),
+    };
+    settings.extend(config_file::from_env());
+    for setting in settings {
+        let on_command_line = matches.ids().any(|id| id.as_str() == setting.key)
+            && matches.value_source(&setting.key) == Some(ValueSource::CommandLine);
+        if !on_command_line {
+            setting.apply(&mut config)?;
+        }
+    }
 
     if let Some(addr) = args.addr {
         let addr = resolve_address(&addr)?;
-        let manager = ConnectionManager::launch(addr.ip(), addr.port());
-        start_server(manager)
+        let manager = TcpManager::launch(addr)?;
+        #[cfg(feature = "tls")]
+        let manager = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => manager.with_tls(cert, key)?,
+            _ => manager,
+        };
+        start_server_with_config(manager, config)
     } else {
         let manager = TerminalManager::launch(args.mark_mode);
-        start_server(manager)
+        start_server_with_config(manager, config)
     }
 }
@@ -0,0 +1,58 @@
+use rsheet_lib::cell_value::CellValue;
+use std::mem::size_of;
+
+/// What a hash map or set spends on each entry beyond the entry itself:
+/// its control byte and a share of the spare capacity it keeps.
+const ENTRY_OVERHEAD: usize = 8;
+
+/// Rough bytes held by a string, its heap text included.
+pub fn string_bytes(s: &str) -> usize {
+    size_of::<String>() + s.len()
+}
+
+/// Rough bytes held by a cell value, its heap text included.
+pub fn value_bytes(value: &CellValue) -> usize {
+    size_of::<CellValue>()
+        + match value {
+            CellValue::String(s) | CellValue::Error(s) => s.len(),
+            CellValue::Int(_) | CellValue::None => 0,
+        }
+}
+
+/// Rough bytes held by a map entry keyed by `key`, given the bytes of its
+/// value.
+pub fn entry_bytes(key: &str, value: usize) -> usize {
+    string_bytes(key) + value + ENTRY_OVERHEAD
+}
+
+/// Estimated bytes held by each part of a sheet, for `memstats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub expressions: usize,
+    /// Computed values kept in memory, past versions included.
+    pub values: usize,
+    /// Formats, tags, revisions, write stamps, timings and compiled
+    /// expressions.
+    pub metadata: usize,
+    pub graph: usize,
+}
+
+impl MemoryStats {
+    pub fn total(&self) -> usize {
+        self.expressions + self.values + self.metadata + self.graph
+    }
+
+    /// Each part as a `name bytes` line, then the total.
+    pub fn lines(&self) -> Vec<String> {
+        [
+            ("expressions", self.expressions),
+            ("values", self.values),
+            ("metadata", self.metadata),
+            ("graph", self.graph),
+            ("total", self.total()),
+        ]
+        .into_iter()
+        .map(|(name, bytes)| format!("{name} {bytes}"))
+        .collect()
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the recalculation duration histogram buckets.
+const RECALCULATION_BUCKETS: [f64; 7] = [0.0001, 0.001, 0.01, 0.1, 1.0, 10.0, 60.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+    recalculation_buckets: [AtomicU64; RECALCULATION_BUCKETS.len()],
+    recalculation_count: AtomicU64,
+    recalculation_micros: AtomicU64,
+    recalculation_panics: AtomicU64,
+    queue_depth: AtomicI64,
+    active_connections: AtomicI64,
+}
+
+impl Metrics {
+    pub fn record_command(&self, command: &'static str) {
+        *self.commands.lock().unwrap().entry(command).or_default() += 1;
+    }
+
+    pub fn record_recalculation(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.recalculation_buckets.iter().zip(RECALCULATION_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.recalculation_count.fetch_add(1, Ordering::Relaxed);
+        self.recalculation_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_recalculation_panic(&self) {
+        self.recalculation_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn queue_pushed(&self) {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn queue_popped(&self) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP rsheet_commands_total Commands received, by command.\n");
+        out.push_str("# TYPE rsheet_commands_total counter\n");
+        for (command, count) in self.commands.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "rsheet_commands_total{{command=\"{command}\"}} {count}"
+            );
+        }
+
+        out.push_str(
+            "# HELP rsheet_recalculation_seconds Time spent recalculating dependent cells.\n",
+        );
+        out.push_str("# TYPE rsheet_recalculation_seconds histogram\n");
+        for (bucket, bound) in self.recalculation_buckets.iter().zip(RECALCULATION_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "rsheet_recalculation_seconds_bucket{{le=\"{bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.recalculation_count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "rsheet_recalculation_seconds_bucket{{le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(
+            out,
+            "rsheet_recalculation_seconds_sum {}",
+            self.recalculation_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "rsheet_recalculation_seconds_count {count}");
+
+        out.push_str(
+            "# HELP rsheet_recalculation_panics_total Recalculation rounds that panicked and were recovered from.\n",
+        );
+        out.push_str("# TYPE rsheet_recalculation_panics_total counter\n");
+        let _ = writeln!(
+            out,
+            "rsheet_recalculation_panics_total {}",
+            self.recalculation_panics.load(Ordering::Relaxed)
+        );
+
+        out.push_str(
+            "# HELP rsheet_update_queue_depth Updates waiting for the recalculation thread.\n",
+        );
+        out.push_str("# TYPE rsheet_update_queue_depth gauge\n");
+        let _ = writeln!(
+            out,
+            "rsheet_update_queue_depth {}",
+            self.queue_depth.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP rsheet_active_connections Currently open client connections.\n");
+        out.push_str("# TYPE rsheet_active_connections gauge\n");
+        let _ = writeln!(
+            out,
+            "rsheet_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
@@ -0,0 +1,233 @@
+use rsheet_lib::connect::{ConnectionError, Manager, Reader, ReaderWriter, Writer};
+use rsheet_lib::replies::Reply;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(feature = "tls")]
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsStream};
+use crate::wire::{Input, WireReader, WireWriter, FRAME_MARKER, MAX_FRAME};
+
+/// Longest line a connection may send. Anything over the configured message
+/// length but under this gets a proper error from the parser.
+const MAX_LINE: u64 = 1 << 20;
+
+/// Accepts TCP connections that speak rsheet_lib's protocol by default and
+/// can switch to the other formats in `wire`.
+pub struct TcpManager {
+    listener: TcpListener,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl TcpManager {
+    pub fn launch(addr: SocketAddr) -> io::Result<Self> {
+        Ok(TcpManager {
+            listener: TcpListener::bind(addr)?,
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    /// Serves every connection over TLS, with the PEM certificate chain
+    /// at `cert` and private key at `key`.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(self, cert: &Path, key: &Path) -> io::Result<Self> {
+        Ok(TcpManager {
+            tls: Some(tls::server_config(cert, key)?),
+            ..self
+        })
+    }
+
+    /// The reading and writing sides of a newly accepted `socket`.
+    fn split(&self, socket: TcpStream) -> io::Result<(Socket, Socket)> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let (reading, writing) = TlsStream::accept(socket, config)?;
+            return Ok((Socket::Tls(reading), Socket::Tls(writing)));
+        }
+        Ok((Socket::Plain(socket.try_clone()?), Socket::Plain(socket)))
+    }
+}
+
+/// A connection's socket, encrypted when the listener serves TLS.
+enum Socket {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(TlsStream),
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Plain(socket) => socket.read(buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(socket) => socket.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Plain(socket) => socket.write(buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Plain(socket) => socket.flush(),
+            #[cfg(feature = "tls")]
+            Socket::Tls(socket) => socket.flush(),
+        }
+    }
+}
+
+pub struct TcpReaderWriter;
+
+impl ReaderWriter for TcpReaderWriter {
+    type Reader = TcpReader;
+    type Writer = TcpWriter;
+}
+
+impl Manager for TcpManager {
+    type ReaderWriter = TcpReaderWriter;
+
+    fn accept_new_connection(&mut self) -> Result<(TcpReader, TcpWriter), ()> {
+        let (socket, addr) = self.listener.accept().map_err(|_| ())?;
+        let (reading, writing) = self.split(socket).map_err(|_| ())?;
+        let reader = TcpReader {
+            socket: BufReader::new(reading),
+            addr,
+        };
+        Ok((
+            reader,
+            TcpWriter {
+                socket: writing,
+                addr,
+            },
+        ))
+    }
+}
+
+pub struct TcpReader {
+    socket: BufReader<Socket>,
+    addr: SocketAddr,
+}
+
+impl TcpReader {
+    /// Skips the rest of the line, a buffer at a time and keeping none of
+    /// it, so the next read starts fresh however long the line goes on.
+    fn skip_line(&mut self) {
+        loop {
+            let Ok(buffer) = self.socket.fill_buf() else {
+                return;
+            };
+            if buffer.is_empty() {
+                return;
+            }
+            match buffer.iter().position(|b| *b == b'\n') {
+                Some(end) => {
+                    self.socket.consume(end + 1);
+                    return;
+                }
+                None => {
+                    let len = buffer.len();
+                    self.socket.consume(len);
+                }
+            }
+        }
+    }
+}
+
+impl Reader for TcpReader {
+    fn read_message(&mut self) -> Result<String, ConnectionError> {
+        let mut line = Vec::new();
+        let read = (&mut self.socket)
+            .take(MAX_LINE)
+            .read_until(b'\n', &mut line)
+            .map_err(|_| ConnectionError::ConnectionLost)?;
+        if read == 0 {
+            return Err(ConnectionError::ConnectionClosed);
+        }
+        if line.pop() != Some(b'\n') {
+            if read as u64 == MAX_LINE {
+                self.skip_line();
+                return Err(ConnectionError::MessageTooLong);
+            }
+            return Err(ConnectionError::ConnectionClosed);
+        }
+        String::from_utf8(line).map_err(|_| ConnectionError::MessageInvalidUtf8)
+    }
+
+    fn id(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl WireReader for TcpReader {
+    fn read_input(&mut self) -> Result<Input, ConnectionError> {
+        let starts_frame = self
+            .socket
+            .fill_buf()
+            .map_err(|_| ConnectionError::ConnectionLost)?
+            .first()
+            == Some(&FRAME_MARKER);
+        if !starts_frame {
+            return self.read_message().map(Input::Line);
+        }
+
+        let mut header = [0; 6];
+        self.socket
+            .read_exact(&mut header)
+            .map_err(|_| ConnectionError::ConnectionClosed)?;
+        let kind = header[1];
+        let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+        if len > MAX_FRAME {
+            // Skip the payload so the next read starts at the next message.
+            let _ = io::copy(&mut (&mut self.socket).take(len as u64), &mut io::sink());
+            return Err(ConnectionError::MessageTooLong);
+        }
+        let mut payload = vec![0; len as usize];
+        self.socket
+            .read_exact(&mut payload)
+            .map_err(|_| ConnectionError::ConnectionClosed)?;
+        Ok(Input::Frame { kind, payload })
+    }
+}
+
+pub struct TcpWriter {
+    socket: Socket,
+    addr: SocketAddr,
+}
+
+impl Writer for TcpWriter {
+    fn write_message(&mut self, message: Reply) -> Result<(), ConnectionError> {
+        let line =
+            serde_json::to_string(&message).map_err(|_| ConnectionError::CouldNotConvertToJson)?;
+        self.write_line(&line)
+    }
+
+    fn id(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl WireWriter for TcpWriter {
+    fn write_line(&mut self, line: &str) -> Result<(), ConnectionError> {
+        self.write_frame(format!("{line}\n").as_bytes())
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), ConnectionError> {
+        self.socket
+            .write_all(frame)
+            .map_err(|_| ConnectionError::ConnectionClosed)?;
+        let _ = self.socket.flush();
+        Ok(())
+    }
+}
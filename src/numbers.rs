@@ -0,0 +1,187 @@
+use rsheet_lib::cell_value::CellValue;
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+/// How expressions that evaluate to a float are written. Cells only hold
+/// integers and text, so a float result is stored as text rendered this way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloatFormat {
+    /// Significant digits kept.
+    pub digits: u32,
+    /// Floats whose decimal exponent is at least this far from zero are
+    /// written in scientific notation, like `1.5e12`.
+    pub scientific_exponent: u32,
+}
+
+impl Default for FloatFormat {
+    fn default() -> Self {
+        FloatFormat {
+            digits: 15,
+            scientific_exponent: 12,
+        }
+    }
+}
+
+impl FloatFormat {
+    pub fn render(&self, value: f64) -> String {
+        if !value.is_finite() || value == 0.0 {
+            return value.to_string();
+        }
+        let digits = self.digits.max(1) as usize;
+        let exponent = value.abs().log10().floor() as i32;
+        if exponent.unsigned_abs() >= self.scientific_exponent {
+            let rendered = format!("{:.*e}", digits - 1, value);
+            let (mantissa, exponent) = rendered.split_once('e').unwrap_or((&rendered, "0"));
+            return format!("{}e{exponent}", trim_zeros(mantissa));
+        }
+        let decimals = (digits as i32 - 1 - exponent).max(0) as usize;
+        trim_zeros(&format!("{value:.decimals$}")).to_string()
+    }
+}
+
+/// Drops trailing zeros after a decimal point, and the point if nothing is
+/// left after it.
+fn trim_zeros(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+/// How numbers typed as a whole expression are written, when they aren't
+/// written the way expressions are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberLocale {
+    /// `1,234.56`
+    En,
+    /// `1.234,56`
+    De,
+}
+
+impl FromStr for NumberLocale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "en" => Ok(NumberLocale::En),
+            "de" => Ok(NumberLocale::De),
+            other => Err(format!(
+                "unknown number locale {other:?}, expected en or de"
+            )),
+        }
+    }
+}
+
+impl NumberLocale {
+    /// The decimal separator, then the thousands separator.
+    fn separators(self) -> (char, char) {
+        match self {
+            NumberLocale::En => ('.', ','),
+            NumberLocale::De => (',', '.'),
+        }
+    }
+
+    /// Rewrites `expression` as an expression literal if all of it is a
+    /// number written in this locale that expressions would read
+    /// differently. Anything else is left to the expression parser, since
+    /// commas also separate function arguments.
+    pub fn canonical_number(self, expression: &str) -> Option<String> {
+        let (decimal, group) = self.separators();
+        let (sign, number) = match expression.strip_prefix('-') {
+            Some(number) => ("-", number),
+            None => ("", expression.strip_prefix('+').unwrap_or(expression)),
+        };
+        let (whole, fraction) = match number.split_once(decimal) {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (number, None),
+        };
+        let all_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if fraction.is_some_and(|fraction| !all_digits(fraction)) {
+            return None;
+        }
+        let groups: Vec<&str> = whole.split(group).collect();
+        let grouped = groups.len() > 1;
+        let valid_groups = groups.iter().enumerate().all(|(i, part)| {
+            all_digits(part)
+                && (!grouped
+                    || if i == 0 {
+                        part.len() <= 3
+                    } else {
+                        part.len() == 3
+                    })
+        });
+        if !valid_groups || (!grouped && (fraction.is_none() || decimal == '.')) {
+            return None;
+        }
+        let mut canonical = format!("{sign}{}", groups.concat());
+        if let Some(fraction) = fraction {
+            canonical.push('.');
+            canonical.push_str(fraction);
+        }
+        Some(canonical)
+    }
+}
+
+/// Reads a percentage like `15%` as the fraction it stands for, `0.15`,
+/// exactly. The number may be written in `locale`.
+pub fn percent_literal(expression: &str, locale: Option<NumberLocale>) -> Option<String> {
+    let number = expression.strip_suffix('%')?;
+    let number = locale
+        .and_then(|locale| locale.canonical_number(number))
+        .unwrap_or_else(|| number.to_string());
+    let percent = Decimal::from_str(&number).ok()?;
+    Some((percent / Decimal::ONE_HUNDRED).normalize().to_string())
+}
+
+/// Writes a fraction like `0.15` as a percentage, `15%`.
+pub fn to_percent(fraction: &str) -> Option<String> {
+    let fraction = Decimal::from_str(fraction).ok()?;
+    Some(format!(
+        "{}%",
+        (fraction * Decimal::ONE_HUNDRED).normalize()
+    ))
+}
+
+/// Whether two numbers written as text are within `epsilon` of each other,
+/// so a change from one to the other isn't worth telling anyone about.
+/// With no tolerance, nothing but identical text is the same.
+pub fn within_epsilon(old: &str, new: &str, epsilon: Decimal) -> bool {
+    if epsilon.is_zero() {
+        return old == new;
+    }
+    match (
+        old.parse::<f64>(),
+        new.parse::<f64>(),
+        f64::try_from(epsilon),
+    ) {
+        (Ok(old), Ok(new), Ok(epsilon)) => (old - new).abs() <= epsilon,
+        _ => false,
+    }
+}
+
+/// Reads a cell value as a number, whether it holds an integer or a float
+/// written as text.
+pub fn as_number(value: &CellValue) -> Option<f64> {
+    match value {
+        CellValue::Int(n) => Some(*n as f64),
+        CellValue::String(s) => s.parse().ok().filter(|n: &f64| n.is_finite()),
+        CellValue::Error(_) | CellValue::None => None,
+    }
+}
+
+/// Writes `value` as an expression that evaluates to that float, never in
+/// scientific notation.
+pub fn float_expression(value: f64) -> String {
+    let literal = value.to_string();
+    let literal = if literal.contains('.') {
+        literal
+    } else {
+        format!("{literal}.0")
+    };
+    if value < 0.0 {
+        format!("({literal})")
+    } else {
+        literal
+    }
+}
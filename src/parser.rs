@@ -0,0 +1,2286 @@
+use rust_decimal::Decimal;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::audit::Rotation;
+use crate::cell_ref::{
+    canonical_expression, parse_column, CellRange, CellRef, CellRefError, RefStyle,
+};
+use crate::coltype::ColumnType;
+use crate::commands;
+use crate::config::Config;
+use crate::derive::Derivation;
+use crate::diff::DiffSource;
+use crate::extent::ListOrder;
+use crate::fold::fold_constants;
+use crate::formats::DisplayFormat;
+use crate::functions::replace_identifiers;
+use crate::hlc::Stamp;
+use crate::layout::MetaKey;
+use crate::macros::Macro;
+use crate::numbers::percent_literal;
+use crate::persistence::SNAPSHOT_FILE;
+use crate::query::{parse_literal, Aggregate, Comparison, Condition, Predicate, SortKey};
+use crate::schedule::parse_interval;
+use crate::wire::{split_setmany, Compression, ReplyFormat, SETMANY_FRAME};
+#[cfg(feature = "xlsx")]
+use crate::xlsx::ExpressionExport;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Get {
+        cell: CellRef,
+        /// A past version of the value to read, if not the current one.
+        version: Option<u64>,
+        /// The scenario to read the cell through, if any.
+        scenario: Option<String>,
+    },
+    /// `stamp` orders the write under last-writer-wins conflict resolution.
+    Set {
+        cell: CellRef,
+        expression: String,
+        stamp: Option<Stamp>,
+        /// How the value is shown, if the expression was written in a way
+        /// that implies one, like `15%`.
+        format: Option<DisplayFormat>,
+    },
+    /// Sets `cell` only if it hasn't changed since revision `expected`.
+    CompareAndSet {
+        cell: CellRef,
+        expected: u64,
+        expression: String,
+    },
+    /// Evaluates `expression` as if it were `cell`'s, without storing it.
+    Eval {
+        cell: CellRef,
+        expression: String,
+    },
+    /// Evaluates `expression` on its own, as no cell's.
+    Calc {
+        expression: String,
+    },
+    /// Writes `values` across the first row of `range`'s columns, at or
+    /// below its row, that is empty in all of them.
+    Append {
+        range: CellRange,
+        values: Vec<String>,
+    },
+    Delete {
+        cell: CellRef,
+        stamp: Option<Stamp>,
+    },
+    Restore {
+        cell: CellRef,
+    },
+    ScenarioCreate {
+        name: String,
+    },
+    ScenarioSet {
+        name: String,
+        cell: CellRef,
+        expression: String,
+    },
+    ScenarioDrop {
+        name: String,
+    },
+    ScenarioList,
+    /// Saves the expressions in `block` to stamp out with `TemplateApply`.
+    TemplateSave {
+        name: String,
+        block: CellRange,
+    },
+    TemplateApply {
+        name: String,
+        at: CellRef,
+    },
+    TemplateDrop {
+        name: String,
+    },
+    TemplateList,
+    /// Brings in what snapshot `theirs` changed since `base`.
+    MergeSnapshot {
+        theirs: DiffSource,
+        base: DiffSource,
+    },
+    /// Appends the audit entries not exported yet to `path`.
+    ExportAuditLog {
+        path: PathBuf,
+        rotation: Option<Rotation>,
+    },
+    Health,
+    /// Re-reads the config file, applying what can change while running.
+    ReloadConfig,
+    /// Lists the recorded changes to `range` from `since`, in milliseconds
+    /// since the Unix epoch, on.
+    Audit {
+        range: CellRange,
+        since: u64,
+    },
+    /// Lists the cells that differ between two versions of the sheet.
+    Diff {
+        before: DiffSource,
+        after: DiffSource,
+    },
+    ScheduleRecalc {
+        range: CellRange,
+        every: Duration,
+    },
+    ScheduleList,
+    ScheduleCancel {
+        id: u64,
+    },
+    Save,
+    Auth {
+        token: String,
+    },
+    #[cfg(feature = "xlsx")]
+    ExportXlsx {
+        path: PathBuf,
+        mode: ExpressionExport,
+    },
+    #[cfg(feature = "xlsx")]
+    ImportXlsx {
+        path: PathBuf,
+    },
+    #[cfg(feature = "scripting")]
+    RunScript {
+        path: PathBuf,
+    },
+    Compact,
+    Orphans,
+    Inputs,
+    Cycles,
+    Lint {
+        range: Option<CellRange>,
+    },
+    /// Searches `bounds` for a value of `input` that brings `target` to
+    /// `goal`, without changing the sheet.
+    GoalSeek {
+        target: CellRef,
+        goal: Decimal,
+        input: CellRef,
+        bounds: (Decimal, Decimal),
+    },
+    /// Reads `observe` `runs` times, each time drawing every random
+    /// function afresh, optionally through a scenario.
+    Simulate {
+        runs: usize,
+        observe: CellRef,
+        scenario: Option<String>,
+    },
+    /// Reads `observe` with `input` set to each value from `from` to `to`,
+    /// `step` apart, without changing the sheet.
+    Sweep {
+        input: CellRef,
+        from: Decimal,
+        to: Decimal,
+        step: Decimal,
+        observe: CellRef,
+    },
+    /// Lists the last `count` logged events, then with `follow` streams
+    /// each one logged after.
+    Tail {
+        count: usize,
+        follow: bool,
+    },
+    Select {
+        cell: CellRef,
+    },
+    Protect {
+        range: CellRange,
+    },
+    /// Constrains the values in column `col`, or with `None` lifts the
+    /// constraint.
+    DeclareColumnType {
+        col: u32,
+        column_type: Option<ColumnType>,
+    },
+    Define {
+        name: String,
+        definition: Macro,
+    },
+    /// Installs a formula down column `col` and keeps it there.
+    Derive {
+        col: u32,
+        derivation: Derivation,
+    },
+    /// Stops deriving `col`, leaving its formulas in place.
+    Underive {
+        col: u32,
+    },
+    Undefine {
+        name: String,
+    },
+    Unprotect {
+        range: CellRange,
+    },
+    Hotspots {
+        count: usize,
+    },
+    MemStats,
+    ProfileDump,
+    Ping,
+    Revision,
+    /// Lists the commands, or with a name, describes that one.
+    Help {
+        command: Option<&'static str>,
+    },
+    /// Reports the bounding box of the cells with an expression.
+    Extent,
+    /// Lists a page of the cells with an expression and their values.
+    List {
+        order: ListOrder,
+        offset: usize,
+        limit: Option<usize>,
+    },
+    /// Negotiates how this connection is spoken to. Options left out keep
+    /// their current setting.
+    Hello {
+        format: Option<ReplyFormat>,
+        /// Whether bulk commands use binary frames.
+        frames: Option<bool>,
+        /// How large replies are compressed, where `Some(None)` turns
+        /// compression off.
+        compression: Option<Option<Compression>>,
+        /// The name the connection goes by in place of its address.
+        name: Option<String>,
+    },
+    /// Switches how this connection writes cell references.
+    SetRefStyle {
+        style: RefStyle,
+    },
+    /// Anchors this connection's relative references at `cell`, or with
+    /// `None` stops reading them.
+    ChangeAnchor {
+        cell: Option<CellRef>,
+    },
+    ShowAnchor,
+    Tag {
+        cell: CellRef,
+        tag: String,
+    },
+    Untag {
+        cell: CellRef,
+        tag: String,
+    },
+    /// Shows `range` as one cell holding its top-left cell's value.
+    Merge {
+        range: CellRange,
+    },
+    Unmerge {
+        range: CellRange,
+    },
+    /// Lists the merged regions.
+    Merges,
+    /// Sets a layout setting, or with `None` unsets it.
+    MetaSet {
+        key: MetaKey,
+        value: Option<u32>,
+    },
+    MetaGet {
+        key: MetaKey,
+    },
+    MetaList,
+    /// Lists the cells carrying `tag`.
+    Tagged {
+        tag: String,
+    },
+    ExportDeps {
+        path: PathBuf,
+        range: Option<CellRange>,
+    },
+    /// Lists where every connection is, and with `watch`, keeps reporting
+    /// changes to this connection.
+    Presence {
+        watch: bool,
+    },
+    /// Streams value changes to the connection.
+    WatchChanges,
+    /// Streams changes to cells in `range` to the connection, only those
+    /// whose new value passes `when` if given.
+    Watch {
+        range: CellRange,
+        when: Option<Predicate>,
+    },
+    /// Tells the connection when `cell`'s value crosses into `when`, at
+    /// most once per `cooldown`.
+    AlertAdd {
+        cell: CellRef,
+        when: Predicate,
+        cooldown: Duration,
+    },
+    AlertList,
+    AlertRemove {
+        id: u64,
+    },
+    Show {
+        range: CellRange,
+    },
+    /// Reads every value in a range.
+    GetRange {
+        range: CellRange,
+    },
+    /// Pairs up the numbers in two rows or columns for plotting.
+    Series {
+        x: CellRange,
+        y: CellRange,
+    },
+    /// Sets every cell in a range to a number, row by row.
+    SetMany {
+        range: CellRange,
+        values: Vec<i64>,
+    },
+    Sort {
+        range: CellRange,
+        keys: Vec<SortKey>,
+    },
+    Filter {
+        range: CellRange,
+        condition: Condition,
+    },
+    GroupBy {
+        range: CellRange,
+        key: u32,
+        aggregate: Aggregate,
+        value: u32,
+    },
+    #[cfg(feature = "metrics")]
+    Metrics,
+    #[cfg(feature = "webhooks")]
+    WebhookAdd {
+        url: String,
+        range: CellRange,
+    },
+    #[cfg(feature = "webhooks")]
+    WebhookList,
+    #[cfg(feature = "webhooks")]
+    WebhookRemove {
+        id: u64,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get { .. } => "get",
+            Command::Set { .. } => "set",
+            Command::CompareAndSet { .. } => "cas",
+            Command::Eval { .. } => "eval",
+            Command::Calc { .. } => "calc",
+            Command::Append { .. } => "append",
+            Command::Delete { .. } => "delete",
+            Command::Restore { .. } => "restore",
+            Command::ScenarioCreate { .. }
+            | Command::ScenarioSet { .. }
+            | Command::ScenarioDrop { .. }
+            | Command::ScenarioList => "scenario",
+            Command::TemplateSave { .. }
+            | Command::TemplateApply { .. }
+            | Command::TemplateDrop { .. }
+            | Command::TemplateList => "template",
+            Command::Diff { .. } => "diff",
+            Command::Audit { .. } => "audit",
+            Command::Health => "health",
+            Command::ReloadConfig => "config",
+            Command::MergeSnapshot { .. } => "merge_snapshot",
+            Command::ScheduleRecalc { .. }
+            | Command::ScheduleList
+            | Command::ScheduleCancel { .. } => "schedule",
+            Command::Save => "save",
+            Command::Auth { .. } => "auth",
+            #[cfg(feature = "xlsx")]
+            Command::ExportXlsx { .. } => "export",
+            #[cfg(feature = "xlsx")]
+            Command::ImportXlsx { .. } => "import",
+            #[cfg(feature = "scripting")]
+            Command::RunScript { .. } => "script",
+            Command::Compact => "compact",
+            Command::Orphans => "orphans",
+            Command::Inputs => "inputs",
+            Command::Cycles => "cycles",
+            Command::Lint { .. } => "lint",
+            Command::GoalSeek { .. } => "goalseek",
+            Command::Sweep { .. } => "sweep",
+            Command::Simulate { .. } => "simulate",
+            Command::Tail { .. } => "tail",
+            Command::Select { .. } => "select",
+            Command::Protect { .. } => "protect",
+            Command::DeclareColumnType { .. } => "coltype",
+            Command::Define { .. } => "define",
+            Command::Derive { .. } => "derive",
+            Command::Underive { .. } => "underive",
+            Command::Undefine { .. } => "undefine",
+            Command::Unprotect { .. } => "unprotect",
+            Command::Hotspots { .. } => "hotspots",
+            Command::MemStats => "memstats",
+            Command::ProfileDump => "profile",
+            Command::Ping => "ping",
+            Command::Revision => "revision",
+            Command::Help { .. } => "help",
+            Command::Extent => "extent",
+            Command::List { .. } => "list",
+            Command::Hello { .. } => "hello",
+            Command::SetRefStyle { .. } => "refstyle",
+            Command::ChangeAnchor { .. } => "cd",
+            Command::ShowAnchor => "pwd",
+            Command::Tag { .. } => "tag",
+            Command::Untag { .. } => "untag",
+            Command::Tagged { .. } => "cells",
+            Command::ExportDeps { .. } | Command::ExportAuditLog { .. } => "export",
+            Command::Presence { .. } => "presence",
+            Command::WatchChanges => "changes",
+            Command::Watch { .. } => "watch",
+            Command::AlertAdd { .. } | Command::AlertList | Command::AlertRemove { .. } => "alert",
+            Command::Show { .. } => "show",
+            Command::GetRange { .. } => "getrange",
+            Command::Series { .. } => "series",
+            Command::MetaSet { .. } | Command::MetaGet { .. } | Command::MetaList => "meta",
+            Command::Merge { .. } => "merge",
+            Command::Unmerge { .. } => "unmerge",
+            Command::Merges => "merges",
+            Command::SetMany { .. } => "setmany",
+            Command::Sort { .. } => "sort",
+            Command::Filter { .. } => "filter",
+            Command::GroupBy { .. } => "groupby",
+            #[cfg(feature = "metrics")]
+            Command::Metrics => "metrics",
+            #[cfg(feature = "webhooks")]
+            Command::WebhookAdd { .. } | Command::WebhookList | Command::WebhookRemove { .. } => {
+                "webhook"
+            }
+        }
+    }
+
+    pub fn cell(&self) -> Option<CellRef> {
+        match self {
+            Command::Get { cell, .. }
+            | Command::Set { cell, .. }
+            | Command::CompareAndSet { cell, .. }
+            | Command::Delete { cell, .. }
+            | Command::Select { cell }
+            | Command::Tag { cell, .. }
+            | Command::Untag { cell, .. } => Some(*cell),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    UnknownCommand(String),
+    MissingArgument {
+        command: &'static str,
+        argument: &'static str,
+    },
+    UnexpectedArgument {
+        command: &'static str,
+        argument: String,
+    },
+    InvalidArgument {
+        command: &'static str,
+        argument: String,
+    },
+    UnterminatedString {
+        position: usize,
+    },
+    InvalidCell(CellRefError),
+    TooLong {
+        what: &'static str,
+        length: usize,
+        max: usize,
+    },
+    ControlCharacter {
+        position: usize,
+    },
+    InvalidFrame(String),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "Empty command"),
+            ParseError::UnknownCommand(command) => write!(f, "Invalid command: {command}"),
+            ParseError::MissingArgument { command, argument } => {
+                write!(f, "Invalid {command} command: missing {argument}")
+            }
+            ParseError::UnexpectedArgument { command, argument } => {
+                write!(
+                    f,
+                    "Invalid {command} command: unexpected argument {argument}"
+                )
+            }
+            ParseError::InvalidArgument { command, argument } => {
+                write!(f, "Invalid {command} command: bad argument {argument}")
+            }
+            ParseError::UnterminatedString { position } => {
+                write!(f, "Unterminated string starting at position {position}")
+            }
+            ParseError::InvalidCell(err) => write!(f, "{err}"),
+            ParseError::TooLong { what, length, max } => {
+                write!(f, "The {what} is {length} bytes, the limit is {max}")
+            }
+            ParseError::ControlCharacter { position } => {
+                write!(f, "Control character at position {position}")
+            }
+            ParseError::InvalidFrame(reason) => write!(f, "Invalid frame: {reason}"),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<CellRefError> for ParseError {
+    fn from(err: CellRefError) -> Self {
+        ParseError::InvalidCell(err)
+    }
+}
+
+/// Splits off the first whitespace-delimited word, returning it along with
+/// the untouched remainder so expressions keep their internal spacing.
+fn next_word(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start();
+    if input.is_empty() {
+        return None;
+    }
+    let end = input.find(char::is_whitespace).unwrap_or(input.len());
+    Some((&input[..end], &input[end..]))
+}
+
+fn expect_end(command: &'static str, rest: &str) -> Result<(), ParseError> {
+    match next_word(rest) {
+        Some((argument, _)) => Err(ParseError::UnexpectedArgument {
+            command,
+            argument: argument.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn single_range(
+    command: &'static str,
+    rest: &str,
+    config: &Config,
+) -> Result<CellRange, ParseError> {
+    let (range, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+        command,
+        argument: "range",
+    })?;
+    expect_end(command, rest)?;
+    Ok(CellRange::parse(range, config)?)
+}
+
+fn single_cell(command: &'static str, rest: &str, config: &Config) -> Result<CellRef, ParseError> {
+    let (cell, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+        command,
+        argument: "cell",
+    })?;
+    expect_end(command, rest)?;
+    Ok(CellRef::parse(cell, config)?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Text(&'a str),
+    Quoted(&'a str),
+}
+
+impl<'a> Token<'a> {
+    fn as_str(&self) -> &'a str {
+        match self {
+            Token::Text(text) | Token::Quoted(text) => text,
+        }
+    }
+}
+
+/// Splits `input` into runs of plain text and quoted strings. Whitespace is
+/// only a separator outside of quotes, and every token borrows straight from
+/// `input`, so quoted strings (escapes included) are never rewritten.
+fn tokenize(input: &str) -> Result<Vec<Token<'_>>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' || c == '\'' || c == '`' {
+            chars.next();
+            let mut end = None;
+            while let Some((index, next)) = chars.next() {
+                if next == '\\' && c != '`' {
+                    chars.next();
+                } else if next == c {
+                    end = Some(index + next.len_utf8());
+                    break;
+                }
+            }
+            let end = end.ok_or(ParseError::UnterminatedString { position: start })?;
+            tokens.push(Token::Quoted(&input[start..end]));
+        } else {
+            let mut end = input.len();
+            while let Some(&(index, next)) = chars.peek() {
+                if next.is_whitespace() || next == '"' || next == '\'' || next == '`' {
+                    end = index;
+                    break;
+                }
+                chars.next();
+            }
+            tokens.push(Token::Text(&input[start..end]));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Returns the expression covered by `tokens`, sliced from the original input
+/// so spacing between and inside tokens is kept exactly as the client sent it.
+fn expression_span<'a>(input: &'a str, tokens: &[Token<'a>]) -> Option<&'a str> {
+    let first = tokens.first()?.as_str();
+    let last = tokens.last()?.as_str();
+    let start = first.as_ptr() as usize - input.as_ptr() as usize;
+    let end = last.as_ptr() as usize - input.as_ptr() as usize + last.len();
+    Some(&input[start..end])
+}
+
+/// Parses the rest of `message`, from `rest` on, as an expression and puts it
+/// in canonical form.
+fn parse_expression(
+    command: &'static str,
+    message: &str,
+    rest: &str,
+    config: &Config,
+) -> Result<String, ParseError> {
+    let offset = rest.as_ptr() as usize - message.as_ptr() as usize;
+    let tokens = tokenize(rest).map_err(|err| match err {
+        ParseError::UnterminatedString { position } => ParseError::UnterminatedString {
+            position: offset + position,
+        },
+        err => err,
+    })?;
+    let expression = expression_span(rest, &tokens).ok_or(ParseError::MissingArgument {
+        command,
+        argument: "expression",
+    })?;
+    checked_expression(expression, config)
+}
+
+fn checked_expression(expression: &str, config: &Config) -> Result<String, ParseError> {
+    if expression.len() > config.max_expression_len {
+        return Err(ParseError::TooLong {
+            what: "expression",
+            length: expression.len(),
+            max: config.max_expression_len,
+        });
+    }
+    let number = config
+        .number_locale
+        .and_then(|locale| locale.canonical_number(expression));
+    let expression = number.as_deref().unwrap_or(expression);
+    Ok(fold_constants(&canonical_expression(expression, config)))
+}
+
+/// Parses `<column> <expression>` or `<row range> <expression>...`, with one
+/// expression per column of the range.
+fn parse_append(message: &str, rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (target, rest) = required("append", "column", rest)?;
+    let range = match parse_column(target, config) {
+        Ok(col) => {
+            let cell = CellRef { col, row: 1 };
+            CellRange::new(cell, cell)
+        }
+        Err(_) => CellRange::parse(target, config)?,
+    };
+    if range.start.row != range.end.row {
+        return Err(ParseError::InvalidArgument {
+            command: "append",
+            argument: target.to_string(),
+        });
+    }
+    if range.width() == 1 {
+        return Ok(Command::Append {
+            range,
+            values: vec![parse_expression("append", message, rest, config)?],
+        });
+    }
+
+    let tokens = tokenize(rest)?;
+    if let Some(extra) = tokens.get(range.width() as usize) {
+        return Err(ParseError::UnexpectedArgument {
+            command: "append",
+            argument: extra.as_str().to_string(),
+        });
+    }
+    if tokens.len() < range.width() as usize {
+        return Err(ParseError::MissingArgument {
+            command: "append",
+            argument: "value",
+        });
+    }
+    let values = tokens
+        .iter()
+        .map(|token| checked_expression(token.as_str(), config))
+        .collect::<Result<_, _>>()?;
+    Ok(Command::Append { range, values })
+}
+
+fn required<'a>(
+    command: &'static str,
+    argument: &'static str,
+    rest: &'a str,
+) -> Result<(&'a str, &'a str), ParseError> {
+    next_word(rest).ok_or(ParseError::MissingArgument { command, argument })
+}
+
+/// How many rotated audit log exports are kept when `keep` isn't given.
+const DEFAULT_ROTATIONS_KEPT: u32 = 5;
+
+/// Parses an optional `rotate <bytes> [keep <files>]`.
+fn parse_rotation(rest: &str) -> Result<Option<Rotation>, ParseError> {
+    let invalid = |argument: &str| ParseError::InvalidArgument {
+        command: "export",
+        argument: argument.to_string(),
+    };
+    let rest = match next_word(rest) {
+        None => return Ok(None),
+        Some(("rotate", rest)) => rest,
+        Some((other, _)) => return Err(invalid(other)),
+    };
+    let (max_bytes, rest) = required("export", "bytes", rest)?;
+    let max_bytes = max_bytes
+        .parse()
+        .ok()
+        .filter(|bytes| *bytes > 0)
+        .ok_or_else(|| invalid(max_bytes))?;
+    let keep = match next_word(rest) {
+        None => DEFAULT_ROTATIONS_KEPT,
+        Some(("keep", rest)) => {
+            let (keep, rest) = required("export", "keep", rest)?;
+            expect_end("export", rest)?;
+            keep.parse().map_err(|_| invalid(keep))?
+        }
+        Some((other, _)) => return Err(invalid(other)),
+    };
+    Ok(Some(Rotation { max_bytes, keep }))
+}
+
+fn parse_export(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (format, rest) = required("export", "format", rest)?;
+    match format {
+        "deps" => {
+            let (path, rest) = required("export", "path", rest)?;
+            let range = match next_word(rest) {
+                None => None,
+                Some((range, rest)) => {
+                    expect_end("export", rest)?;
+                    Some(CellRange::parse(range, config)?)
+                }
+            };
+            Ok(Command::ExportDeps {
+                path: PathBuf::from(path),
+                range,
+            })
+        }
+        "auditlog" => {
+            let (path, rest) = required("export", "path", rest)?;
+            Ok(Command::ExportAuditLog {
+                path: PathBuf::from(path),
+                rotation: parse_rotation(rest)?,
+            })
+        }
+        #[cfg(feature = "xlsx")]
+        "xlsx" => {
+            let (path, rest) = required("export", "path", rest)?;
+            let mode = match next_word(rest) {
+                None => ExpressionExport::Values,
+                Some((mode, rest)) => {
+                    expect_end("export", rest)?;
+                    mode.parse()
+                        .map_err(|argument| ParseError::InvalidArgument {
+                            command: "export",
+                            argument,
+                        })?
+                }
+            };
+            Ok(Command::ExportXlsx {
+                path: PathBuf::from(path),
+                mode,
+            })
+        }
+        other => Err(ParseError::InvalidArgument {
+            command: "export",
+            argument: other.to_string(),
+        }),
+    }
+}
+
+#[cfg(feature = "xlsx")]
+fn parse_import(rest: &str) -> Result<Command, ParseError> {
+    let (format, rest) = required("import", "format", rest)?;
+    match format {
+        "xlsx" => {
+            let (path, rest) = required("import", "path", rest)?;
+            expect_end("import", rest)?;
+            Ok(Command::ImportXlsx {
+                path: PathBuf::from(path),
+            })
+        }
+        other => Err(ParseError::InvalidArgument {
+            command: "import",
+            argument: other.to_string(),
+        }),
+    }
+}
+
+/// Parses `<range> by <column> [asc|desc], ...`.
+fn parse_sort(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (range, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+        command: "sort",
+        argument: "range",
+    })?;
+    let range = CellRange::parse(range, config)?;
+    let rest = match next_word(rest) {
+        Some(("by", rest)) => rest,
+        Some((other, _)) => {
+            return Err(ParseError::UnexpectedArgument {
+                command: "sort",
+                argument: other.to_string(),
+            })
+        }
+        None => {
+            return Err(ParseError::MissingArgument {
+                command: "sort",
+                argument: "sort keys",
+            })
+        }
+    };
+
+    let keys = rest
+        .split(',')
+        .map(|key| {
+            let invalid = || ParseError::InvalidArgument {
+                command: "sort",
+                argument: key.trim().to_string(),
+            };
+            let (column, rest) = next_word(key).ok_or_else(invalid)?;
+            let col = parse_column(column, config)?;
+            let descending = match next_word(rest) {
+                None => false,
+                Some((direction, rest)) => {
+                    expect_end("sort", rest)?;
+                    match direction {
+                        "asc" => false,
+                        "desc" => true,
+                        _ => return Err(invalid()),
+                    }
+                }
+            };
+            if !(range.start.col..=range.end.col).contains(&col) {
+                return Err(invalid());
+            }
+            Ok(SortKey { col, descending })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Command::Sort { range, keys })
+}
+
+/// Parses `<range> where <column> <op> <literal>`, where spaces around the
+/// operator are optional.
+fn parse_filter(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (range, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+        command: "filter",
+        argument: "range",
+    })?;
+    let range = CellRange::parse(range, config)?;
+    let condition = match next_word(rest) {
+        Some(("where", rest)) => rest.trim(),
+        Some((other, _)) => {
+            return Err(ParseError::UnexpectedArgument {
+                command: "filter",
+                argument: other.to_string(),
+            })
+        }
+        None => {
+            return Err(ParseError::MissingArgument {
+                command: "filter",
+                argument: "condition",
+            })
+        }
+    };
+
+    let invalid = || ParseError::InvalidArgument {
+        command: "filter",
+        argument: condition.to_string(),
+    };
+    let split = condition
+        .find(|c: char| !c.is_ascii_uppercase())
+        .unwrap_or(condition.len());
+    let col = parse_column(&condition[..split], config)?;
+    let (comparison, literal) =
+        Comparison::split_prefix(condition[split..].trim_start()).ok_or_else(invalid)?;
+    let value = parse_literal(literal).ok_or_else(invalid)?;
+    if !(range.start.col..=range.end.col).contains(&col) {
+        return Err(invalid());
+    }
+
+    Ok(Command::Filter {
+        range,
+        condition: Condition {
+            col,
+            comparison,
+            value,
+        },
+    })
+}
+
+/// Parses `<range> key=<column> agg=<aggregate>(<column>)`, with the two
+/// parameters in either order.
+fn parse_group_by(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (range, mut rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+        command: "groupby",
+        argument: "range",
+    })?;
+    let range = CellRange::parse(range, config)?;
+    let in_range = |col: u32| (range.start.col..=range.end.col).contains(&col);
+
+    let mut key = None;
+    let mut aggregation = None;
+    while let Some((parameter, remainder)) = next_word(rest) {
+        rest = remainder;
+        let invalid = || ParseError::InvalidArgument {
+            command: "groupby",
+            argument: parameter.to_string(),
+        };
+        match parameter.split_once('=') {
+            Some(("key", column)) => {
+                let col = parse_column(column, config)?;
+                key = Some(in_range(col).then_some(col).ok_or_else(invalid)?);
+            }
+            Some(("agg", call)) => {
+                let (aggregate, column) = call
+                    .strip_suffix(')')
+                    .and_then(|call| call.split_once('('))
+                    .ok_or_else(invalid)?;
+                let aggregate: Aggregate = aggregate.parse().map_err(|_| invalid())?;
+                let col = parse_column(column, config)?;
+                aggregation = Some((aggregate, in_range(col).then_some(col).ok_or_else(invalid)?));
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    let key = key.ok_or(ParseError::MissingArgument {
+        command: "groupby",
+        argument: "key",
+    })?;
+    let (aggregate, value) = aggregation.ok_or(ParseError::MissingArgument {
+        command: "groupby",
+        argument: "agg",
+    })?;
+    Ok(Command::GroupBy {
+        range,
+        key,
+        aggregate,
+        value,
+    })
+}
+
+/// Parses a tag name: letters, digits, `_` and `-`.
+fn single_tag(command: &'static str, rest: &str) -> Result<String, ParseError> {
+    let (tag, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+        command,
+        argument: "tag",
+    })?;
+    expect_end(command, rest)?;
+    if !tag
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(ParseError::InvalidArgument {
+            command,
+            argument: tag.to_string(),
+        });
+    }
+    Ok(tag.to_string())
+}
+
+/// Parses an optional trailing count, defaulting to 10.
+fn optional_count(command: &'static str, rest: &str) -> Result<usize, ParseError> {
+    match next_word(rest) {
+        None => Ok(10),
+        Some((count, rest)) => {
+            expect_end(command, rest)?;
+            count.parse().map_err(|_| ParseError::InvalidArgument {
+                command,
+                argument: count.to_string(),
+            })
+        }
+    }
+}
+
+/// Names that would shadow functions every expression can already call.
+const BUILTIN_FUNCTIONS: [&str; 13] = [
+    "sum",
+    "sleep_then",
+    "remote",
+    "if",
+    "and",
+    "or",
+    "not",
+    "iferror",
+    "iserror",
+    "isblank",
+    "coalesce",
+    "rand",
+    "randbetween",
+];
+
+/// Checks that `name` can name a macro or one of its parameters: an
+/// identifier that isn't a cell reference.
+fn macro_identifier(name: &str, config: &Config) -> Result<String, ParseError> {
+    let valid = name
+        .bytes()
+        .next()
+        .is_some_and(|b| b.is_ascii_alphabetic() || b == b'_')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        && CellRef::parse(name, config).is_err()
+        && CellRange::parse(name, config).is_err();
+    if valid {
+        Ok(name.to_string())
+    } else {
+        Err(ParseError::InvalidArgument {
+            command: "define",
+            argument: name.to_string(),
+        })
+    }
+}
+
+/// Parses `<name>(<params>) = <body>`. The body may only refer to cells
+/// through its parameters.
+fn parse_define(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (header, body) = rest.split_once('=').ok_or(ParseError::MissingArgument {
+        command: "define",
+        argument: "body",
+    })?;
+    let (name, params) = header
+        .trim()
+        .strip_suffix(')')
+        .and_then(|header| header.split_once('('))
+        .ok_or(ParseError::MissingArgument {
+            command: "define",
+            argument: "parameters",
+        })?;
+    let name = macro_identifier(name.trim(), config)?;
+    if BUILTIN_FUNCTIONS.contains(&name.as_str()) {
+        return Err(ParseError::InvalidArgument {
+            command: "define",
+            argument: name,
+        });
+    }
+
+    let mut parsed: Vec<String> = Vec::new();
+    if !params.trim().is_empty() {
+        for param in params.split(',') {
+            let param = macro_identifier(param.trim(), config)?;
+            if parsed.contains(&param) {
+                return Err(ParseError::InvalidArgument {
+                    command: "define",
+                    argument: param,
+                });
+            }
+            parsed.push(param);
+        }
+    }
+
+    let body = body.trim();
+    if body.is_empty() {
+        return Err(ParseError::MissingArgument {
+            command: "define",
+            argument: "body",
+        });
+    }
+    if body.len() > config.max_expression_len {
+        return Err(ParseError::TooLong {
+            what: "expression",
+            length: body.len(),
+            max: config.max_expression_len,
+        });
+    }
+    let mut reference = None;
+    replace_identifiers(body, |identifier| {
+        if reference.is_none()
+            && (CellRef::parse(identifier, config).is_ok()
+                || CellRange::parse(identifier, config).is_ok())
+        {
+            reference = Some(identifier.to_string());
+        }
+        None
+    })
+    .map_err(|argument| ParseError::InvalidArgument {
+        command: "define",
+        argument,
+    })?;
+    if let Some(argument) = reference {
+        return Err(ParseError::InvalidArgument {
+            command: "define",
+            argument,
+        });
+    }
+
+    Ok(Command::Define {
+        name,
+        definition: Macro {
+            params: parsed,
+            body: body.to_string(),
+        },
+    })
+}
+
+/// Parses `list` options, each written `<key>=<value>`.
+fn parse_list(mut rest: &str) -> Result<Command, ParseError> {
+    let mut order = ListOrder::Row;
+    let mut offset = 0;
+    let mut limit = None;
+    while let Some((option, remaining)) = next_word(rest) {
+        rest = remaining;
+        let invalid = || ParseError::InvalidArgument {
+            command: "list",
+            argument: option.to_string(),
+        };
+        match option.split_once('=') {
+            Some(("order", value)) => order = value.parse().map_err(|_| invalid())?,
+            Some(("offset", value)) => offset = value.parse().map_err(|_| invalid())?,
+            Some(("limit", value)) => limit = Some(value.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(Command::List {
+        order,
+        offset,
+        limit,
+    })
+}
+
+/// Parses `<target>=<goal> by <input> in [<low>,<high>]`. The bounds may
+/// be spaced out.
+fn parse_goal_seek(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let invalid = |argument: &str| ParseError::InvalidArgument {
+        command: "goalseek",
+        argument: argument.to_string(),
+    };
+    let number = |text: &str| Decimal::from_str(text.trim()).map_err(|_| invalid(text.trim()));
+    let (goal, rest) = required("goalseek", "goal", rest)?;
+    let (target, value) = goal.split_once('=').ok_or_else(|| invalid(goal))?;
+    let (by, rest) = required("goalseek", "by", rest)?;
+    if by != "by" {
+        return Err(invalid(by));
+    }
+    let (input, rest) = required("goalseek", "input", rest)?;
+    let (within, rest) = required("goalseek", "in", rest)?;
+    if within != "in" {
+        return Err(invalid(within));
+    }
+    let bounds = rest.trim();
+    if bounds.is_empty() {
+        return Err(ParseError::MissingArgument {
+            command: "goalseek",
+            argument: "bounds",
+        });
+    }
+    let (low, high) = bounds
+        .strip_prefix('[')
+        .and_then(|bounds| bounds.strip_suffix(']'))
+        .and_then(|bounds| bounds.split_once(','))
+        .ok_or_else(|| invalid(bounds))?;
+    Ok(Command::GoalSeek {
+        target: CellRef::parse(target, config)?,
+        goal: number(value)?,
+        input: CellRef::parse(input, config)?,
+        bounds: (number(low)?, number(high)?),
+    })
+}
+
+/// Parses `set <key> <value>`, `unset <key>`, `get <key>` or `list`.
+fn parse_meta(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let invalid = |argument: &str| ParseError::InvalidArgument {
+        command: "meta",
+        argument: argument.to_string(),
+    };
+    let (action, rest) = required("meta", "action", rest)?;
+    if action == "list" {
+        expect_end("meta", rest)?;
+        return Ok(Command::MetaList);
+    }
+    let (key, rest) = required("meta", "key", rest)?;
+    let key = MetaKey::parse(key, config).ok_or_else(|| invalid(key))?;
+    match action {
+        "set" => {
+            let (value, rest) = required("meta", "value", rest)?;
+            expect_end("meta", rest)?;
+            Ok(Command::MetaSet {
+                key,
+                value: Some(value.parse().map_err(|_| invalid(value))?),
+            })
+        }
+        "unset" => {
+            expect_end("meta", rest)?;
+            Ok(Command::MetaSet { key, value: None })
+        }
+        "get" => {
+            expect_end("meta", rest)?;
+            Ok(Command::MetaGet { key })
+        }
+        other => Err(invalid(other)),
+    }
+}
+
+/// Parses `<runs> observe <cell> [scenario=<name>]`.
+fn parse_simulate(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let invalid = |argument: &str| ParseError::InvalidArgument {
+        command: "simulate",
+        argument: argument.to_string(),
+    };
+    let (runs, rest) = required("simulate", "runs", rest)?;
+    let runs = runs
+        .parse()
+        .ok()
+        .filter(|runs| *runs > 0)
+        .ok_or_else(|| invalid(runs))?;
+    let (observe, rest) = required("simulate", "observe", rest)?;
+    if observe != "observe" {
+        return Err(invalid(observe));
+    }
+    let (cell, rest) = required("simulate", "cell", rest)?;
+    let scenario = match next_word(rest) {
+        None => None,
+        Some((option, rest)) => {
+            expect_end("simulate", rest)?;
+            match option.strip_prefix("scenario=") {
+                Some(name) if !name.is_empty() => Some(name.to_string()),
+                _ => return Err(invalid(option)),
+            }
+        }
+    };
+    Ok(Command::Simulate {
+        runs,
+        observe: CellRef::parse(cell, config)?,
+        scenario,
+    })
+}
+
+/// Parses `<input> from <start> to <end> step <step> observe <cell>`.
+fn parse_sweep(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let invalid = |argument: &str| ParseError::InvalidArgument {
+        command: "sweep",
+        argument: argument.to_string(),
+    };
+    let (input, mut rest) = required("sweep", "input", rest)?;
+    let mut values = Vec::new();
+    for keyword in ["from", "to", "step", "observe"] {
+        let (word, remaining) = required("sweep", keyword, rest)?;
+        if word != keyword {
+            return Err(invalid(word));
+        }
+        let (value, remaining) = required("sweep", keyword, remaining)?;
+        values.push(value);
+        rest = remaining;
+    }
+    expect_end("sweep", rest)?;
+    let number = |text: &str| Decimal::from_str(text).map_err(|_| invalid(text));
+    let (from, to, step) = (number(values[0])?, number(values[1])?, number(values[2])?);
+    // A step too small to divide the span by can't be swept.
+    let steps = to.checked_sub(from).and_then(|span| span.checked_div(step));
+    if step <= Decimal::ZERO || steps.is_none() {
+        return Err(invalid(values[2]));
+    }
+    Ok(Command::Sweep {
+        input: CellRef::parse(input, config)?,
+        from,
+        to,
+        step,
+        observe: CellRef::parse(values[3], config)?,
+    })
+}
+
+/// Parses `create <name>`, `set <name> <cell> <expression>`, `drop <name>`
+/// or `list`.
+fn parse_scenario(message: &str, rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (action, rest) = required("scenario", "action", rest)?;
+    match action {
+        "create" | "drop" => {
+            let (name, rest) = required("scenario", "name", rest)?;
+            expect_end("scenario", rest)?;
+            let name = name.to_string();
+            Ok(if action == "create" {
+                Command::ScenarioCreate { name }
+            } else {
+                Command::ScenarioDrop { name }
+            })
+        }
+        "set" => {
+            let (name, rest) = required("scenario", "name", rest)?;
+            let (cell, rest) = required("scenario", "cell", rest)?;
+            Ok(Command::ScenarioSet {
+                name: name.to_string(),
+                cell: CellRef::parse(cell, config)?,
+                expression: parse_expression("scenario", message, rest, config)?,
+            })
+        }
+        "list" => {
+            expect_end("scenario", rest)?;
+            Ok(Command::ScenarioList)
+        }
+        other => Err(ParseError::InvalidArgument {
+            command: "scenario",
+            argument: other.to_string(),
+        }),
+    }
+}
+
+/// Parses `save <name> <range>`, `apply <name> <cell>`, `drop <name>` or
+/// `list`.
+fn parse_template(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (action, rest) = required("template", "action", rest)?;
+    match action {
+        "save" | "apply" => {
+            let (name, rest) = required("template", "name", rest)?;
+            let name = name.to_string();
+            Ok(if action == "save" {
+                Command::TemplateSave {
+                    name,
+                    block: single_range("template", rest, config)?,
+                }
+            } else {
+                Command::TemplateApply {
+                    name,
+                    at: single_cell("template", rest, config)?,
+                }
+            })
+        }
+        "drop" => {
+            let (name, rest) = required("template", "name", rest)?;
+            expect_end("template", rest)?;
+            Ok(Command::TemplateDrop {
+                name: name.to_string(),
+            })
+        }
+        "list" => {
+            expect_end("template", rest)?;
+            Ok(Command::TemplateList)
+        }
+        other => Err(ParseError::InvalidArgument {
+            command: "template",
+            argument: other.to_string(),
+        }),
+    }
+}
+
+/// Reads a cell as the range holding just it, or a range.
+fn cell_or_range(target: &str, config: &Config) -> Result<CellRange, ParseError> {
+    match CellRef::parse(target, config) {
+        Ok(cell) => Ok(CellRange::new(cell, cell)),
+        Err(_) => Ok(CellRange::parse(target, config)?),
+    }
+}
+
+/// Parses `<range> [since <millis>]`. The range may be a single cell.
+fn parse_audit(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (target, rest) = required("audit", "range", rest)?;
+    let range = cell_or_range(target, config)?;
+    let since = match next_word(rest) {
+        Some(("since", rest)) => {
+            let (since, rest) = required("audit", "since", rest)?;
+            expect_end("audit", rest)?;
+            since.parse().map_err(|_| ParseError::InvalidArgument {
+                command: "audit",
+                argument: since.to_string(),
+            })?
+        }
+        Some((other, _)) => {
+            return Err(ParseError::InvalidArgument {
+                command: "audit",
+                argument: other.to_string(),
+            })
+        }
+        None => 0,
+    };
+    Ok(Command::Audit { range, since })
+}
+
+/// Parses `<cell or range> [when <comparison> <literal>]`.
+fn parse_watch(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (target, rest) = required("watch", "range", rest)?;
+    let range = cell_or_range(target, config)?;
+    let when = match next_word(rest) {
+        Some(("when", predicate)) => {
+            Some(
+                Predicate::parse(predicate).ok_or_else(|| ParseError::InvalidArgument {
+                    command: "watch",
+                    argument: predicate.trim().to_string(),
+                })?,
+            )
+        }
+        Some((other, _)) => {
+            return Err(ParseError::InvalidArgument {
+                command: "watch",
+                argument: other.to_string(),
+            })
+        }
+        None => None,
+    };
+    Ok(Command::Watch { range, when })
+}
+
+/// Parses `[<before> [<after>]]`, each `live` or the name of a snapshot file
+/// in the data directory. The sheet is compared against the data
+/// directory's own snapshot when neither is given, and against `before`
+/// when only that is.
+fn parse_diff(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let Some((before, rest)) = next_word(rest) else {
+        if config.data_dir.is_none() {
+            return Err(ParseError::MissingArgument {
+                command: "diff",
+                argument: "snapshot",
+            });
+        }
+        return Ok(Command::Diff {
+            before: DiffSource::Snapshot(PathBuf::from(SNAPSHOT_FILE)),
+            after: DiffSource::Live,
+        });
+    };
+    let after = match next_word(rest) {
+        Some((after, rest)) => {
+            expect_end("diff", rest)?;
+            DiffSource::parse(after)
+        }
+        None => DiffSource::Live,
+    };
+    Ok(Command::Diff {
+        before: DiffSource::parse(before),
+        after,
+    })
+}
+
+/// Parses `<range>`, merging cells, or `<snapshot> [base <snapshot>]`,
+/// merging in a snapshot's changes since `base`, the data directory's
+/// snapshot if not given. Snapshots are named inside the data directory, and
+/// a name that reads as a range needs a `./` in front.
+fn parse_merge(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (theirs, rest) = required("merge", "range", rest)?;
+    if next_word(rest).is_none() {
+        if let Ok(range) = CellRange::parse(theirs, config) {
+            return Ok(Command::Merge { range });
+        }
+    }
+    let base = match next_word(rest) {
+        Some(("base", rest)) => {
+            let (base, rest) = required("merge", "base", rest)?;
+            expect_end("merge", rest)?;
+            DiffSource::parse(base)
+        }
+        Some((other, _)) => {
+            return Err(ParseError::InvalidArgument {
+                command: "merge",
+                argument: other.to_string(),
+            })
+        }
+        None => {
+            if config.data_dir.is_none() {
+                return Err(ParseError::MissingArgument {
+                    command: "merge",
+                    argument: "base",
+                });
+            }
+            DiffSource::Snapshot(PathBuf::from(SNAPSHOT_FILE))
+        }
+    };
+    Ok(Command::MergeSnapshot {
+        theirs: DiffSource::parse(theirs),
+        base,
+    })
+}
+
+/// Parses `recalc <range> every <interval>`, `list` or `cancel <id>`. The
+/// range may be a single cell.
+fn parse_schedule(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let invalid = |argument: &str| ParseError::InvalidArgument {
+        command: "schedule",
+        argument: argument.to_string(),
+    };
+    let (action, rest) = required("schedule", "action", rest)?;
+    match action {
+        "recalc" => {
+            let (target, rest) = required("schedule", "range", rest)?;
+            let range = cell_or_range(target, config)?;
+            let (every, rest) = required("schedule", "every", rest)?;
+            if every != "every" {
+                return Err(invalid(every));
+            }
+            let (interval, rest) = required("schedule", "interval", rest)?;
+            expect_end("schedule", rest)?;
+            Ok(Command::ScheduleRecalc {
+                range,
+                every: parse_interval(interval).ok_or_else(|| invalid(interval))?,
+            })
+        }
+        "list" => {
+            expect_end("schedule", rest)?;
+            Ok(Command::ScheduleList)
+        }
+        "cancel" => {
+            let (id, rest) = required("schedule", "id", rest)?;
+            expect_end("schedule", rest)?;
+            Ok(Command::ScheduleCancel {
+                id: id.parse().map_err(|_| invalid(id))?,
+            })
+        }
+        other => Err(invalid(other)),
+    }
+}
+
+/// Parses `webhook add <url> on <range>`, `webhook list` and
+/// `webhook remove <id>`. The range may be a single cell.
+#[cfg(feature = "webhooks")]
+fn parse_webhook(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let invalid = |argument: &str| ParseError::InvalidArgument {
+        command: "webhook",
+        argument: argument.to_string(),
+    };
+    let (action, rest) = required("webhook", "action", rest)?;
+    match action {
+        "add" => {
+            let (url, rest) = required("webhook", "url", rest)?;
+            let (on, rest) = required("webhook", "on", rest)?;
+            if on != "on" {
+                return Err(invalid(on));
+            }
+            let (target, rest) = required("webhook", "range", rest)?;
+            expect_end("webhook", rest)?;
+            let range = cell_or_range(target, config)?;
+            Ok(Command::WebhookAdd {
+                url: url.to_string(),
+                range,
+            })
+        }
+        "list" => {
+            expect_end("webhook", rest)?;
+            Ok(Command::WebhookList)
+        }
+        "remove" => {
+            let (id, rest) = required("webhook", "id", rest)?;
+            expect_end("webhook", rest)?;
+            Ok(Command::WebhookRemove {
+                id: id.parse().map_err(|_| invalid(id))?,
+            })
+        }
+        other => Err(invalid(other)),
+    }
+}
+
+/// Parses `alert add <cell> <comparison> <literal> [cooldown <interval>]`,
+/// `alert list` and `alert remove <id>`.
+fn parse_alert(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let invalid = |argument: &str| ParseError::InvalidArgument {
+        command: "alert",
+        argument: argument.trim().to_string(),
+    };
+    let (action, rest) = required("alert", "action", rest)?;
+    match action {
+        "add" => {
+            let (cell, rest) = required("alert", "cell", rest)?;
+            let cell = CellRef::parse(cell, config)?;
+            let (when, cooldown) = match rest.trim_end().rsplit_once(char::is_whitespace) {
+                Some((when, interval)) if when.trim_end().ends_with(" cooldown") => (
+                    when.trim_end().strip_suffix(" cooldown").unwrap_or(when),
+                    parse_interval(interval).ok_or_else(|| invalid(interval))?,
+                ),
+                _ => (rest, Duration::ZERO),
+            };
+            if when.trim().is_empty() {
+                return Err(ParseError::MissingArgument {
+                    command: "alert",
+                    argument: "threshold",
+                });
+            }
+            Ok(Command::AlertAdd {
+                cell,
+                when: Predicate::parse(when).ok_or_else(|| invalid(when))?,
+                cooldown,
+            })
+        }
+        "list" => {
+            expect_end("alert", rest)?;
+            Ok(Command::AlertList)
+        }
+        "remove" => {
+            let (id, rest) = required("alert", "id", rest)?;
+            expect_end("alert", rest)?;
+            Ok(Command::AlertRemove {
+                id: id.parse().map_err(|_| invalid(id))?,
+            })
+        }
+        other => Err(invalid(other)),
+    }
+}
+
+/// Parses `hello` options, each written `<key>=<value>`.
+fn parse_hello(mut rest: &str) -> Result<Command, ParseError> {
+    let mut format = None;
+    let mut frames = None;
+    let mut compression = None;
+    let mut name = None;
+    while let Some((option, remaining)) = next_word(rest) {
+        rest = remaining;
+        let invalid = || ParseError::InvalidArgument {
+            command: "hello",
+            argument: option.to_string(),
+        };
+        match option.split_once('=') {
+            Some(("format", value)) => format = Some(value.parse().map_err(|_| invalid())?),
+            Some(("frames", "binary")) => frames = Some(true),
+            Some(("frames", "text")) => frames = Some(false),
+            Some(("compress", "none")) => compression = Some(None),
+            Some(("compress", value)) => {
+                compression = Some(Some(value.parse().map_err(|_| invalid())?))
+            }
+            Some(("name", value)) if !value.is_empty() => name = Some(value.to_string()),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(Command::Hello {
+        format,
+        frames,
+        compression,
+        name,
+    })
+}
+
+/// Parses `<range> <number>...`, one number for each cell.
+fn parse_setmany(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (range, mut rest) = required("setmany", "range", rest)?;
+    let range = CellRange::parse(range, config)?;
+    let mut values = Vec::new();
+    while let Some((value, remaining)) = next_word(rest) {
+        rest = remaining;
+        values.push(value.parse().map_err(|_| ParseError::InvalidArgument {
+            command: "setmany",
+            argument: value.to_string(),
+        })?);
+    }
+    setmany(range, values)
+}
+
+fn setmany(range: CellRange, values: Vec<i64>) -> Result<Command, ParseError> {
+    if values.len() as u64 != range.cell_count() {
+        return Err(ParseError::InvalidArgument {
+            command: "setmany",
+            argument: format!(
+                "{} values for the {} cells of {range}",
+                values.len(),
+                range.cell_count()
+            ),
+        });
+    }
+    Ok(Command::SetMany { range, values })
+}
+
+/// Parses `<column> = <template> for rows <start>..[<end>]`.
+fn parse_derive(rest: &str, config: &Config) -> Result<Command, ParseError> {
+    let (column, rest) = required("derive", "column", rest)?;
+    let col = parse_column(column, config)?;
+    let template = rest
+        .trim_start()
+        .strip_prefix('=')
+        .ok_or(ParseError::MissingArgument {
+            command: "derive",
+            argument: "=",
+        })?;
+    let (template, rows) =
+        template
+            .rsplit_once(" for rows ")
+            .ok_or(ParseError::MissingArgument {
+                command: "derive",
+                argument: "rows",
+            })?;
+    let (rows, rest) = required("derive", "rows", rows)?;
+    expect_end("derive", rest)?;
+
+    let invalid_rows = || ParseError::InvalidArgument {
+        command: "derive",
+        argument: rows.to_string(),
+    };
+    let (start, end) = rows.split_once("..").ok_or_else(invalid_rows)?;
+    let row = |row: &str| match row.parse() {
+        Ok(row) if row >= 1 && row <= config.max_row => Ok(row),
+        _ => Err(invalid_rows()),
+    };
+    let start = row(start)?;
+    let end = match end {
+        "" => None,
+        end => match row(end)? {
+            end if end >= start => Some(end),
+            _ => return Err(invalid_rows()),
+        },
+    };
+
+    let template = template.trim();
+    if template.is_empty() {
+        return Err(ParseError::MissingArgument {
+            command: "derive",
+            argument: "expression",
+        });
+    }
+    let derivation = Derivation {
+        template: template.to_string(),
+        start,
+        end,
+    };
+    let expression =
+        derivation
+            .expression(start, config)
+            .map_err(|argument| ParseError::InvalidArgument {
+                command: "derive",
+                argument,
+            })?;
+    checked_expression(&expression, config)?;
+    Ok(Command::Derive { col, derivation })
+}
+
+/// Splits off a leading `@<millis>.<counter>.<node>` clock stamp, if any.
+fn optional_stamp<'a>(
+    command: &'static str,
+    rest: &'a str,
+) -> Result<(Option<Stamp>, &'a str), ParseError> {
+    match next_word(rest) {
+        Some((word, after)) if word.starts_with('@') => {
+            let stamp = word
+                .parse()
+                .map_err(|argument| ParseError::InvalidArgument { command, argument })?;
+            Ok((Some(stamp), after))
+        }
+        _ => Ok((None, rest)),
+    }
+}
+
+/// Parses a binary frame, which only bulk commands are sent as.
+pub fn parse_frame(kind: u8, payload: &[u8], config: &Config) -> Result<Command, ParseError> {
+    match kind {
+        SETMANY_FRAME => {
+            let (range, values) = split_setmany(payload).map_err(ParseError::InvalidFrame)?;
+            setmany(CellRange::parse(range, config)?, values)
+        }
+        other => Err(ParseError::InvalidFrame(format!("unknown kind {other}"))),
+    }
+}
+
+pub fn parse_command(message: &str, config: &Config) -> Result<Command, ParseError> {
+    let (keyword, rest) = next_word(message).ok_or(ParseError::Empty)?;
+    let keyword = commands::resolve(keyword).map_or(keyword, |spec| spec.name);
+
+    match keyword {
+        "get" => {
+            let (cell, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+                command: "get",
+                argument: "cell",
+            })?;
+            let scenario = match next_word(rest) {
+                None => None,
+                Some((option, rest)) => {
+                    expect_end("get", rest)?;
+                    match option.strip_prefix("scenario=") {
+                        Some(name) if !name.is_empty() && !cell.contains('@') => {
+                            Some(name.to_string())
+                        }
+                        _ => {
+                            return Err(ParseError::InvalidArgument {
+                                command: "get",
+                                argument: option.to_string(),
+                            })
+                        }
+                    }
+                }
+            };
+            let (cell, version) = match cell.split_once('@') {
+                Some((cell, version)) => {
+                    let version = version.parse().map_err(|_| ParseError::InvalidArgument {
+                        command: "get",
+                        argument: version.to_string(),
+                    })?;
+                    (cell, Some(version))
+                }
+                None => (cell, None),
+            };
+            Ok(Command::Get {
+                cell: CellRef::parse(cell, config)?,
+                version,
+                scenario,
+            })
+        }
+        "set" => {
+            let (stamp, rest) = optional_stamp("set", rest)?;
+            let (cell, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+                command: "set",
+                argument: "cell",
+            })?;
+            let cell = CellRef::parse(cell, config)?;
+            let (expression, format) = match percent_literal(rest.trim(), config.number_locale) {
+                Some(fraction) => (fraction, Some(DisplayFormat::Percent)),
+                None => (parse_expression("set", message, rest, config)?, None),
+            };
+            Ok(Command::Set {
+                cell,
+                expression,
+                stamp,
+                format,
+            })
+        }
+        "append" => parse_append(message, rest, config),
+        "cas" => {
+            let (cell, rest) = required("cas", "cell", rest)?;
+            let cell = CellRef::parse(cell, config)?;
+            let (revision, rest) = required("cas", "revision", rest)?;
+            let expected = revision.parse().map_err(|_| ParseError::InvalidArgument {
+                command: "cas",
+                argument: revision.to_string(),
+            })?;
+            Ok(Command::CompareAndSet {
+                cell,
+                expected,
+                expression: parse_expression("cas", message, rest, config)?,
+            })
+        }
+        "eval" => {
+            let (cell, rest) = required("eval", "cell", rest)?;
+            Ok(Command::Eval {
+                cell: CellRef::parse(cell, config)?,
+                expression: parse_expression("eval", message, rest, config)?,
+            })
+        }
+        "calc" => Ok(Command::Calc {
+            expression: parse_expression("calc", message, rest, config)?,
+        }),
+        "delete" => {
+            let (stamp, rest) = optional_stamp("delete", rest)?;
+            Ok(Command::Delete {
+                cell: single_cell("delete", rest, config)?,
+                stamp,
+            })
+        }
+        "restore" => Ok(Command::Restore {
+            cell: single_cell("restore", rest, config)?,
+        }),
+        "scenario" => parse_scenario(message, rest, config),
+        "template" => parse_template(rest, config),
+        "diff" => parse_diff(rest, config),
+        "audit" => parse_audit(rest, config),
+        "watch" => parse_watch(rest, config),
+        "alert" => parse_alert(rest, config),
+        "config" => {
+            let (action, rest) = required("config", "action", rest)?;
+            if action != "reload" {
+                return Err(ParseError::InvalidArgument {
+                    command: "config",
+                    argument: action.to_string(),
+                });
+            }
+            expect_end("config", rest)?;
+            Ok(Command::ReloadConfig)
+        }
+        "health" => {
+            expect_end("health", rest)?;
+            Ok(Command::Health)
+        }
+        "schedule" => parse_schedule(rest, config),
+        #[cfg(feature = "webhooks")]
+        "webhook" => parse_webhook(rest, config),
+        "save" => {
+            expect_end("save", rest)?;
+            Ok(Command::Save)
+        }
+        "export" => parse_export(rest, config),
+        #[cfg(feature = "xlsx")]
+        "import" => parse_import(rest),
+        #[cfg(feature = "scripting")]
+        "script" => match next_word(rest) {
+            Some(("run", rest)) => {
+                let (path, rest) = required("script", "path", rest)?;
+                expect_end("script", rest)?;
+                Ok(Command::RunScript {
+                    path: PathBuf::from(path),
+                })
+            }
+            Some((argument, _)) => Err(ParseError::InvalidArgument {
+                command: "script",
+                argument: argument.to_string(),
+            }),
+            None => Err(ParseError::MissingArgument {
+                command: "script",
+                argument: "run",
+            }),
+        },
+        "auth" => {
+            let (token, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+                command: "auth",
+                argument: "token",
+            })?;
+            expect_end("auth", rest)?;
+            Ok(Command::Auth {
+                token: token.to_string(),
+            })
+        }
+        "compact" => {
+            expect_end("compact", rest)?;
+            Ok(Command::Compact)
+        }
+        "goalseek" => parse_goal_seek(rest, config),
+        "sweep" => parse_sweep(rest, config),
+        "simulate" => parse_simulate(rest, config),
+        "lint" => {
+            let range = match next_word(rest) {
+                None => None,
+                Some((target, rest)) => {
+                    expect_end("lint", rest)?;
+                    Some(cell_or_range(target, config)?)
+                }
+            };
+            Ok(Command::Lint { range })
+        }
+        "orphans" => {
+            expect_end("orphans", rest)?;
+            Ok(Command::Orphans)
+        }
+        "inputs" => {
+            expect_end("inputs", rest)?;
+            Ok(Command::Inputs)
+        }
+        "meta" => parse_meta(rest, config),
+        "merge" => parse_merge(rest, config),
+        "unmerge" => Ok(Command::Unmerge {
+            range: single_range("unmerge", rest, config)?,
+        }),
+        "merges" => {
+            expect_end("merges", rest)?;
+            Ok(Command::Merges)
+        }
+        "tag" | "untag" => {
+            let command = if keyword == "tag" { "tag" } else { "untag" };
+            let (cell, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+                command,
+                argument: "cell",
+            })?;
+            let cell = CellRef::parse(cell, config)?;
+            let tag = single_tag(command, rest)?;
+            Ok(if command == "tag" {
+                Command::Tag { cell, tag }
+            } else {
+                Command::Untag { cell, tag }
+            })
+        }
+        "cells" => match next_word(rest) {
+            Some(("tagged", rest)) => Ok(Command::Tagged {
+                tag: single_tag("cells", rest)?,
+            }),
+            Some((argument, _)) => Err(ParseError::InvalidArgument {
+                command: "cells",
+                argument: argument.to_string(),
+            }),
+            None => Err(ParseError::MissingArgument {
+                command: "cells",
+                argument: "tagged",
+            }),
+        },
+        "revision" => {
+            expect_end("revision", rest)?;
+            Ok(Command::Revision)
+        }
+        "refstyle" => {
+            let (style, rest) = required("refstyle", "style", rest)?;
+            expect_end("refstyle", rest)?;
+            let style = style
+                .parse()
+                .map_err(|argument| ParseError::InvalidArgument {
+                    command: "refstyle",
+                    argument,
+                })?;
+            Ok(Command::SetRefStyle { style })
+        }
+        "cd" => Ok(Command::ChangeAnchor {
+            cell: match next_word(rest) {
+                None => None,
+                Some(_) => Some(single_cell("cd", rest, config)?),
+            },
+        }),
+        "pwd" => {
+            expect_end("pwd", rest)?;
+            Ok(Command::ShowAnchor)
+        }
+        "hello" => parse_hello(rest),
+        "help" => match next_word(rest) {
+            None => Ok(Command::Help { command: None }),
+            Some((name, rest)) => {
+                expect_end("help", rest)?;
+                let spec = commands::resolve(name).ok_or(ParseError::InvalidArgument {
+                    command: "help",
+                    argument: name.to_string(),
+                })?;
+                Ok(Command::Help {
+                    command: Some(spec.name),
+                })
+            }
+        },
+        "extent" => {
+            expect_end("extent", rest)?;
+            Ok(Command::Extent)
+        }
+        "list" => parse_list(rest),
+        "ping" => {
+            expect_end("ping", rest)?;
+            Ok(Command::Ping)
+        }
+        "cycles" => {
+            expect_end("cycles", rest)?;
+            Ok(Command::Cycles)
+        }
+        "sort" => parse_sort(rest, config),
+        "filter" => parse_filter(rest, config),
+        "groupby" => parse_group_by(rest, config),
+        "show" => Ok(Command::Show {
+            range: single_range("show", rest, config)?,
+        }),
+        "getrange" => Ok(Command::GetRange {
+            range: single_range("getrange", rest, config)?,
+        }),
+        "series" => {
+            let (x, rest) = required("series", "x", rest)?;
+            Ok(Command::Series {
+                x: CellRange::parse(x, config)?,
+                y: single_range("series", rest, config)?,
+            })
+        }
+        "setmany" => parse_setmany(rest, config),
+        "coltype" => {
+            let (column, rest) = required("coltype", "column", rest)?;
+            let col = parse_column(column, config)?;
+            let (column_type, rest) = required("coltype", "type", rest)?;
+            expect_end("coltype", rest)?;
+            let column_type = match column_type {
+                "any" => None,
+                other => Some(
+                    other
+                        .parse()
+                        .map_err(|argument| ParseError::InvalidArgument {
+                            command: "coltype",
+                            argument,
+                        })?,
+                ),
+            };
+            Ok(Command::DeclareColumnType { col, column_type })
+        }
+        "define" => parse_define(rest, config),
+        "derive" => parse_derive(rest, config),
+        "underive" => {
+            let (column, rest) = required("underive", "column", rest)?;
+            let col = parse_column(column, config)?;
+            expect_end("underive", rest)?;
+            Ok(Command::Underive { col })
+        }
+        "undefine" => {
+            let (name, rest) = next_word(rest).ok_or(ParseError::MissingArgument {
+                command: "undefine",
+                argument: "name",
+            })?;
+            expect_end("undefine", rest)?;
+            Ok(Command::Undefine {
+                name: name.to_string(),
+            })
+        }
+        "protect" => Ok(Command::Protect {
+            range: single_range("protect", rest, config)?,
+        }),
+        "unprotect" => Ok(Command::Unprotect {
+            range: single_range("unprotect", rest, config)?,
+        }),
+        "select" => Ok(Command::Select {
+            cell: single_cell("select", rest, config)?,
+        }),
+        "changes" => match next_word(rest) {
+            Some(("watch", rest)) => {
+                expect_end("changes", rest)?;
+                Ok(Command::WatchChanges)
+            }
+            Some((argument, _)) => Err(ParseError::InvalidArgument {
+                command: "changes",
+                argument: argument.to_string(),
+            }),
+            None => Err(ParseError::MissingArgument {
+                command: "changes",
+                argument: "watch",
+            }),
+        },
+        "presence" => match next_word(rest) {
+            None => Ok(Command::Presence { watch: false }),
+            Some(("watch", rest)) => {
+                expect_end("presence", rest)?;
+                Ok(Command::Presence { watch: true })
+            }
+            Some((argument, _)) => Err(ParseError::InvalidArgument {
+                command: "presence",
+                argument: argument.to_string(),
+            }),
+        },
+        "tail" => {
+            let (rest, follow) = match rest.trim_end().strip_suffix("follow") {
+                Some(rest) if rest.is_empty() || rest.ends_with(char::is_whitespace) => {
+                    (rest, true)
+                }
+                _ => (rest, false),
+            };
+            Ok(Command::Tail {
+                count: optional_count("tail", rest)?,
+                follow,
+            })
+        }
+        "profile" => match next_word(rest) {
+            Some(("dump", rest)) => {
+                expect_end("profile", rest)?;
+                Ok(Command::ProfileDump)
+            }
+            Some((argument, _)) => Err(ParseError::InvalidArgument {
+                command: "profile",
+                argument: argument.to_string(),
+            }),
+            None => Err(ParseError::MissingArgument {
+                command: "profile",
+                argument: "dump",
+            }),
+        },
+        "hotspots" => Ok(Command::Hotspots {
+            count: optional_count("hotspots", rest)?,
+        }),
+        "memstats" => {
+            expect_end("memstats", rest)?;
+            Ok(Command::MemStats)
+        }
+        #[cfg(feature = "metrics")]
+        "metrics" => {
+            expect_end("metrics", rest)?;
+            Ok(Command::Metrics)
+        }
+        other => Err(ParseError::UnknownCommand(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(message: &str) -> Result<Command, ParseError> {
+        parse_command(message, &Config::default())
+    }
+
+    fn set(expression: &str) -> Command {
+        Command::Set {
+            cell: CellRef { col: 0, row: 1 },
+            expression: expression.to_string(),
+            stamp: None,
+            format: None,
+        }
+    }
+
+    #[test]
+    fn whitespace_around_words_is_ignored() {
+        let get = Command::Get {
+            cell: CellRef { col: 0, row: 1 },
+            version: None,
+            scenario: None,
+        };
+        assert_eq!(parse("get A1"), Ok(get.clone()));
+        assert_eq!(parse("  get   A1  "), Ok(get.clone()));
+        assert_eq!(parse("\tget\tA1\n"), Ok(get));
+        assert_eq!(parse("set A1   B1 +  C1  "), Ok(set("B1 +  C1")));
+    }
+
+    #[test]
+    fn blank_messages_are_empty() {
+        assert_eq!(parse(""), Err(ParseError::Empty));
+        assert_eq!(parse(" \t "), Err(ParseError::Empty));
+    }
+
+    #[test]
+    fn quoted_expressions_keep_their_whitespace() {
+        assert_eq!(
+            parse("set A1 \"hello  world\""),
+            Ok(set("\"hello  world\""))
+        );
+        assert_eq!(parse("set   A1\t\"tab\there\""), Ok(set("\"tab\there\"")));
+        assert_eq!(
+            parse("set A1 \"x\" + \"  y \""),
+            Ok(set("\"x\" + \"  y \""))
+        );
+    }
+
+    #[test]
+    fn unterminated_strings_report_where_they_start() {
+        assert_eq!(
+            parse("set A1 \"a b"),
+            Err(ParseError::UnterminatedString { position: 7 })
+        );
+        assert_eq!(
+            parse("set A1 \"a b").unwrap_err().to_string(),
+            "Unterminated string starting at position 7"
+        );
+    }
+
+    #[test]
+    fn unknown_commands_are_named() {
+        assert_eq!(
+            parse("frobnicate A1"),
+            Err(ParseError::UnknownCommand("frobnicate".to_string()))
+        );
+        assert_eq!(
+            parse("frobnicate").unwrap_err().to_string(),
+            "Invalid command: frobnicate"
+        );
+    }
+
+    #[test]
+    fn argument_errors_name_the_command() {
+        assert_eq!(
+            parse("get"),
+            Err(ParseError::MissingArgument {
+                command: "get",
+                argument: "cell",
+            })
+        );
+        assert_eq!(
+            parse("set A1"),
+            Err(ParseError::MissingArgument {
+                command: "set",
+                argument: "expression",
+            })
+        );
+        assert_eq!(
+            parse("delete A1 B2"),
+            Err(ParseError::UnexpectedArgument {
+                command: "delete",
+                argument: "B2".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("get A1 B1"),
+            Err(ParseError::InvalidArgument {
+                command: "get",
+                argument: "B1".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("get 1A"),
+            Err(ParseError::InvalidCell(CellRefError::Malformed(
+                "1A".to_string()
+            )))
+        );
+    }
+}
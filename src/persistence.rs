@@ -0,0 +1,295 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use rsheet_lib::cells::column_number_to_name;
+
+use crate::cell_ref::CellRange;
+use crate::coltype::ColumnType;
+use crate::derive::Derivation;
+use crate::formats::DisplayFormat;
+use crate::health::PROBE_FILE;
+use crate::layout::MetaKey;
+use crate::macros::Macro;
+use crate::numbers::to_percent;
+use crate::values::SPILL_FILE;
+
+pub const SNAPSHOT_FILE: &str = "snapshot";
+const WAL_FILE: &str = "wal";
+
+/// When appends to the write-ahead log are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// fsync after every record.
+    Always,
+    /// fsync after every `n` records.
+    Every(u32),
+    /// Leave flushing to the operating system.
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            n => match n.parse() {
+                Ok(0) | Err(_) => Err(format!(
+                    "unknown sync policy {n:?}, expected always, never or a record count"
+                )),
+                Ok(n) => Ok(SyncPolicy::Every(n)),
+            },
+        }
+    }
+}
+
+/// A snapshot file plus a write-ahead log of every command applied since.
+/// Both hold one command per line in the same syntax clients send.
+pub struct Storage {
+    dir: PathBuf,
+    wal: File,
+    sync: SyncPolicy,
+    unsynced: u32,
+}
+
+/// Reads the complete lines of `path`, along with their length in bytes. A
+/// trailing partial line is what a crash mid-append leaves behind, so it is
+/// not part of either.
+fn read_records(path: &Path) -> io::Result<(Vec<String>, u64)> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(err) => return Err(err),
+    };
+    let complete = contents.rfind('\n').map_or("", |end| &contents[..=end]);
+    let records = complete
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    Ok((records, complete.len() as u64))
+}
+
+impl Storage {
+    /// Opens the storage in `dir`, returning it along with the records to
+    /// replay: the last snapshot followed by the write-ahead log.
+    pub fn open(dir: &Path, sync: SyncPolicy) -> io::Result<(Self, Vec<String>)> {
+        fs::create_dir_all(dir)?;
+        let (mut records, _) = read_records(&dir.join(SNAPSHOT_FILE))?;
+        let (wal_records, wal_len) = read_records(&dir.join(WAL_FILE))?;
+        records.extend(wal_records);
+
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(WAL_FILE))?;
+        if wal.metadata()?.len() > wal_len {
+            wal.set_len(wal_len)?;
+        }
+        let storage = Storage {
+            dir: dir.to_path_buf(),
+            wal,
+            sync,
+            unsynced: 0,
+        };
+        Ok((storage, records))
+    }
+
+    pub fn append(&mut self, record: &str) -> io::Result<()> {
+        self.wal.write_all(format!("{record}\n").as_bytes())?;
+        self.unsynced += 1;
+        let due = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(n) => self.unsynced >= n,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.wal.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// Atomically replaces the snapshot with `records`, then empties the
+    /// write-ahead log. A crash in between only means replaying commands the
+    /// snapshot already contains, which is harmless.
+    pub fn save_snapshot(&mut self, records: impl Iterator<Item = String>) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+        let mut tmp = File::create(&tmp_path)?;
+        for record in records {
+            tmp.write_all(format!("{record}\n").as_bytes())?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+        if let Ok(dir) = File::open(&self.dir) {
+            let _ = dir.sync_all();
+        }
+
+        self.wal.set_len(0)?;
+        self.wal.sync_all()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// Resolves `name`, a file a client named, inside `dir`, the server's
+/// `kind` directory. Absolute paths and `..` are refused, so clients can't
+/// reach files anywhere else on the server; `./` is allowed.
+pub fn file_inside(dir: Option<&Path>, name: &Path, kind: &str) -> Result<PathBuf, String> {
+    let dir = dir.ok_or_else(|| format!("The server was started without a {kind} directory"))?;
+    let plain = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    let named = name
+        .components()
+        .any(|component| matches!(component, Component::Normal(_)));
+    if !plain || !named {
+        return Err(format!(
+            "{} is not a file name inside the {kind} directory",
+            name.display()
+        ));
+    }
+    Ok(dir.join(name))
+}
+
+/// A file a client named inside the data directory `dir`.
+pub fn data_file(dir: Option<&Path>, name: &Path) -> Result<PathBuf, String> {
+    file_inside(dir, name, "data")
+}
+
+/// Like `data_file`, for a file to write directly in the data directory,
+/// which may not be one of the files the server keeps there itself or the
+/// temporary file it writes one through.
+pub fn export_file(dir: Option<&Path>, name: &Path) -> Result<PathBuf, String> {
+    let path = data_file(dir, name)?;
+    let mut parts = name
+        .components()
+        .filter(|component| *component != Component::CurDir);
+    let file_name = match (parts.next(), parts.next()) {
+        (Some(Component::Normal(file_name)), None) => file_name,
+        _ => {
+            return Err(format!(
+                "{} is not a file name inside the data directory",
+                name.display()
+            ))
+        }
+    };
+    let own = [SNAPSHOT_FILE, WAL_FILE, SPILL_FILE, PROBE_FILE];
+    let kept = own
+        .iter()
+        .any(|own| file_name == *own || file_name == format!("{own}.tmp").as_str());
+    if kept {
+        return Err(format!("{} is kept by the server", name.display()));
+    }
+    Ok(path.with_file_name(file_name))
+}
+
+/// The records of the snapshot file at `path`, which unlike a data
+/// directory's own snapshot has to exist.
+pub fn read_snapshot(path: &Path) -> io::Result<Vec<String>> {
+    if !path.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+    }
+    read_records(path).map(|(records, _)| records)
+}
+
+/// A percentage is written back the way it was entered, so the cell gets
+/// its display format again when the record is replayed.
+pub fn set_record(cell_name: &str, expression: &str, format: Option<DisplayFormat>) -> String {
+    match format.and_then(|DisplayFormat::Percent| to_percent(expression)) {
+        Some(percent) => format!("set {cell_name} {percent}"),
+        None => format!("set {cell_name} {expression}"),
+    }
+}
+
+pub fn delete_record(cell_name: &str) -> String {
+    format!("delete {cell_name}")
+}
+
+pub fn define_record(name: &str, definition: &Macro) -> String {
+    format!(
+        "define {name}({}) = {}",
+        definition.params.join(", "),
+        definition.body
+    )
+}
+
+pub fn undefine_record(name: &str) -> String {
+    format!("undefine {name}")
+}
+
+pub fn coltype_record(col: u32, column_type: Option<ColumnType>) -> String {
+    let column = column_number_to_name(col);
+    match column_type {
+        Some(column_type) => format!("coltype {column} {column_type}"),
+        None => format!("coltype {column} any"),
+    }
+}
+
+pub fn derive_record(col: u32, derivation: Option<&Derivation>) -> String {
+    let column = column_number_to_name(col);
+    match derivation {
+        Some(derivation) => format!(
+            "derive {column} = {} for rows {}..{}",
+            derivation.template,
+            derivation.start,
+            derivation.end.map_or(String::new(), |end| end.to_string())
+        ),
+        None => format!("underive {column}"),
+    }
+}
+
+pub fn tag_record(cell_name: &str, tag: &str, tagged: bool) -> String {
+    let command = if tagged { "tag" } else { "untag" };
+    format!("{command} {cell_name} {tag}")
+}
+
+pub fn meta_record(key: MetaKey, value: Option<u32>) -> String {
+    match value {
+        Some(value) => format!("meta set {key} {value}"),
+        None => format!("meta unset {key}"),
+    }
+}
+
+pub fn merge_record(range: CellRange, merged: bool) -> String {
+    let command = if merged { "merge" } else { "unmerge" };
+    format!("{command} {range}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(name: &str) -> Result<PathBuf, String> {
+        export_file(Some(Path::new("data")), Path::new(name))
+    }
+
+    #[test]
+    fn exports_stay_in_the_data_directory() {
+        assert_eq!(export("out.csv"), Ok(PathBuf::from("data/out.csv")));
+        assert_eq!(export("./out.csv"), Ok(PathBuf::from("data/out.csv")));
+        assert!(export("/tmp/out.csv").is_err());
+        assert!(export("../out.csv").is_err());
+        assert!(export("sub/out.csv").is_err());
+        assert!(export(".").is_err());
+    }
+
+    #[test]
+    fn exports_leave_the_servers_own_files_alone() {
+        for name in [
+            "wal",
+            "./wal",
+            "snapshot",
+            "./snapshot",
+            "snapshot.tmp",
+            "values",
+            "health.probe",
+        ] {
+            assert_eq!(export(name), Err(format!("{name} is kept by the server")));
+        }
+        assert!(export("wal.csv").is_ok());
+    }
+}
@@ -0,0 +1,67 @@
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+
+use crate::wire::Outgoing;
+
+/// Which cell each connection last selected or edited, and which
+/// connections want to hear when that changes.
+#[derive(Default)]
+pub struct Presence {
+    /// The name each connection goes by, and its cell.
+    cells: Mutex<HashMap<String, (String, String)>>,
+    watchers: Mutex<HashMap<String, Sender<Outgoing>>>,
+}
+
+/// A connection's position, or `None` once it has left.
+pub fn presence_reply(connection: &str, cell: Option<&str>) -> Reply {
+    Reply::Value(
+        format!("presence {connection}"),
+        cell.map_or(CellValue::None, |cell| CellValue::String(cell.to_string())),
+    )
+}
+
+impl Presence {
+    /// Moves `connection`, which goes by `who`, to `cell`.
+    pub fn touch(&self, connection: &str, who: &str, cell: &str) {
+        let previous = self
+            .cells
+            .lock()
+            .unwrap()
+            .insert(connection.to_string(), (who.to_string(), cell.to_string()));
+        if previous.is_none_or(|(_, previous)| previous != cell) {
+            self.broadcast(connection, presence_reply(who, Some(cell)));
+        }
+    }
+
+    /// Sends every later change made by other connections to `outbox`.
+    pub fn watch(&self, connection: &str, outbox: Sender<Outgoing>) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .insert(connection.to_string(), outbox);
+    }
+
+    pub fn leave(&self, connection: &str) {
+        self.watchers.lock().unwrap().remove(connection);
+        if let Some((who, _)) = self.cells.lock().unwrap().remove(connection) {
+            self.broadcast(connection, presence_reply(&who, None));
+        }
+    }
+
+    /// Every connection's position, by the name it goes by, in name order.
+    pub fn list(&self) -> Vec<(String, String)> {
+        let mut cells: Vec<(String, String)> =
+            self.cells.lock().unwrap().values().cloned().collect();
+        cells.sort();
+        cells
+    }
+
+    fn broadcast(&self, from: &str, reply: Reply) {
+        self.watchers.lock().unwrap().retain(|connection, outbox| {
+            connection == from || outbox.send(reply.clone().into()).is_ok()
+        });
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How many recalculation passes `profile dump` can look back over.
+const RECENT_PASSES: usize = 64;
+
+/// The cells one recalculation pass evaluated, in order, with how long each
+/// took. Passes from a profiler that is switched off record nothing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pass {
+    pub trigger: String,
+    pub evaluations: Vec<(String, Duration)>,
+    enabled: bool,
+}
+
+impl Pass {
+    pub fn record(&mut self, cell_name: &str, duration: Duration) {
+        if self.enabled {
+            self.evaluations.push((cell_name.to_string(), duration));
+        }
+    }
+}
+
+impl Display for Pass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let total: Duration = self.evaluations.iter().map(|(_, duration)| *duration).sum();
+        write!(
+            f,
+            "trigger={} cells={} duration_us={}",
+            self.trigger,
+            self.evaluations.len(),
+            total.as_micros()
+        )?;
+        for (cell_name, duration) in &self.evaluations {
+            write!(f, " {cell_name}={}", duration.as_micros())?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Profiler {
+    enabled: bool,
+    passes: Mutex<VecDeque<Pass>>,
+}
+
+impl Profiler {
+    pub fn new(enabled: bool) -> Self {
+        Profiler {
+            enabled,
+            passes: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn pass(&self, trigger: impl Into<String>) -> Pass {
+        Pass {
+            trigger: if self.enabled {
+                trigger.into()
+            } else {
+                String::new()
+            },
+            evaluations: Vec::new(),
+            enabled: self.enabled,
+        }
+    }
+
+    pub fn finish(&self, pass: Pass) {
+        if !pass.enabled {
+            return;
+        }
+        let mut passes = self.passes.lock().unwrap();
+        if passes.len() == RECENT_PASSES {
+            passes.pop_front();
+        }
+        passes.push_back(pass);
+    }
+
+    /// The recorded passes, oldest first.
+    pub fn dump(&self) -> Vec<Pass> {
+        self.passes.lock().unwrap().iter().cloned().collect()
+    }
+}
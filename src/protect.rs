@@ -0,0 +1,96 @@
+use std::sync::Mutex;
+
+use crate::cell_ref::CellRange;
+
+/// Ranges that only the connection that protected them, or an admin, may
+/// write to. Protections last until they are released or their owner
+/// disconnects.
+#[derive(Default)]
+pub struct Protections {
+    ranges: Mutex<Vec<Protection>>,
+}
+
+struct Protection {
+    range: CellRange,
+    owner: String,
+    /// The name the owner goes by, for telling others who holds it.
+    who: String,
+}
+
+fn overlaps(a: CellRange, b: CellRange) -> bool {
+    a.start.col <= b.end.col
+        && b.start.col <= a.end.col
+        && a.start.row <= b.end.row
+        && b.start.row <= a.end.row
+}
+
+impl Protections {
+    /// Protects `range` for `owner`, who goes by `who`. Ranges may not
+    /// overlap one protected by someone else.
+    pub fn protect(&self, range: CellRange, owner: &str, who: &str) -> Result<(), String> {
+        let mut ranges = self.ranges.lock().unwrap();
+        if let Some(other) = ranges
+            .iter()
+            .find(|other| other.owner != owner && overlaps(other.range, range))
+        {
+            return Err(format!(
+                "{} is already protected by {}",
+                other.range, other.who
+            ));
+        }
+        if !ranges
+            .iter()
+            .any(|protection| protection.range == range && protection.owner == owner)
+        {
+            ranges.push(Protection {
+                range,
+                owner: owner.to_string(),
+                who: who.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Releases the protection on exactly `range`.
+    pub fn unprotect(&self, range: CellRange, owner: &str, admin: bool) -> Result<(), String> {
+        let mut ranges = self.ranges.lock().unwrap();
+        let index = ranges
+            .iter()
+            .position(|protection| protection.range == range)
+            .ok_or_else(|| format!("{range} is not protected"))?;
+        if ranges[index].owner != owner && !admin {
+            return Err(format!("{range} is protected by {}", ranges[index].who));
+        }
+        ranges.remove(index);
+        Ok(())
+    }
+
+    /// Fails if any cell in `range` is protected by someone other than
+    /// `writer`.
+    pub fn check(&self, range: CellRange, writer: &str, admin: bool) -> Result<(), String> {
+        if admin {
+            return Ok(());
+        }
+        match self
+            .ranges
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|protection| protection.owner != writer && overlaps(protection.range, range))
+        {
+            Some(protection) => Err(format!(
+                "{} is protected by {}",
+                protection.range, protection.who
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Drops every protection held by a connection that has gone.
+    pub fn release(&self, owner: &str) {
+        self.ranges
+            .lock()
+            .unwrap()
+            .retain(|protection| protection.owner != owner);
+    }
+}
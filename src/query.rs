@@ -0,0 +1,458 @@
+use rsheet_lib::cell_value::CellValue;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::cell_ref::{CellRange, CellRef, WholeReference};
+use crate::config::Config;
+use crate::functions::{self, string_literal};
+use crate::numbers::as_number;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortKey {
+    /// Zero indexed column, which must lie inside the sorted range.
+    pub col: u32,
+    pub descending: bool,
+}
+
+fn type_rank(value: &CellValue) -> u8 {
+    match value {
+        CellValue::Int(_) => 0,
+        CellValue::String(_) => 1,
+        CellValue::Error(_) => 2,
+        CellValue::None => 3,
+    }
+}
+
+/// Orders numbers before strings before errors, comparing like with like.
+pub fn compare_values(a: &CellValue, b: &CellValue) -> Ordering {
+    match (a, b) {
+        (CellValue::Int(a), CellValue::Int(b)) => a.cmp(b),
+        (CellValue::String(a), CellValue::String(b)) => a.cmp(b),
+        (CellValue::Error(a), CellValue::Error(b)) => a.cmp(b),
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+fn compare_for_key(a: &CellValue, b: &CellValue, descending: bool) -> Ordering {
+    match (a, b) {
+        (CellValue::None, CellValue::None) => Ordering::Equal,
+        (CellValue::None, _) => Ordering::Greater,
+        (_, CellValue::None) => Ordering::Less,
+        _ if descending => compare_values(a, b).reverse(),
+        _ => compare_values(a, b),
+    }
+}
+
+/// Computes the order rows should be placed in when sorted by `keys`, as
+/// indices into `rows`. Each row holds the values of the sorted range from
+/// its first column, `first_col`.
+///
+/// Keys are applied in turn, so later keys only break ties left by earlier
+/// ones. The sort is stable: rows that tie on every key keep their original
+/// relative order. Empty cells always sort last, whichever the direction.
+pub fn sort_order(rows: &[Vec<CellValue>], first_col: u32, keys: &[SortKey]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..rows.len()).collect();
+    order.sort_by(|&a, &b| {
+        keys.iter()
+            .map(|key| {
+                let index = (key.col - first_col) as usize;
+                compare_for_key(&rows[a][index], &rows[b][index], key.descending)
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+    order
+}
+
+/// Rewrites the references in `expression` for a sort of `range` that
+/// moves its row `i` to row `moved_to[i]`. A reference to cells in one row
+/// of the range follows that row, while one covering all of the range's
+/// rows, or none of them, is left alone. Any other reference would be
+/// broken up by the sort, so is refused.
+pub fn move_references(
+    expression: &str,
+    range: CellRange,
+    moved_to: &[u32],
+    config: &Config,
+) -> Result<String, String> {
+    let moved = |cell: CellRef| CellRef {
+        row: range.start.row + moved_to[(cell.row - range.start.row) as usize],
+        ..cell
+    };
+    let last = CellRef {
+        col: config.max_column,
+        row: config.max_row,
+    };
+    let mut broken = None;
+    let shifted = functions::replace_identifiers(expression, |name| {
+        let (block, ends) = match name.split_once('_') {
+            Some((start, end)) => {
+                match (CellRef::parse(start, config), CellRef::parse(end, config)) {
+                    (Ok(start), Ok(end)) => (CellRange::new(start, end), Some((start, end))),
+                    _ => (WholeReference::parse(name, config)?.range(last), None),
+                }
+            }
+            None => {
+                let cell = CellRef::parse(name, config).ok()?;
+                (CellRange::new(cell, cell), Some((cell, cell)))
+            }
+        };
+        let overlaps = block.start.row <= range.end.row
+            && block.end.row >= range.start.row
+            && block.start.col <= range.end.col
+            && block.end.col >= range.start.col;
+        let covers_rows = block.start.row <= range.start.row && block.end.row >= range.end.row;
+        if !overlaps || covers_rows {
+            return None;
+        }
+        let in_one_row = block.start.row == block.end.row
+            && block.start.col >= range.start.col
+            && block.end.col <= range.end.col;
+        match ends {
+            Some((start, end)) if in_one_row => Some(if name.contains('_') {
+                format!("{}_{}", moved(start), moved(end))
+            } else {
+                moved(start).to_string()
+            }),
+            _ => {
+                broken.get_or_insert_with(|| name.to_string());
+                None
+            }
+        }
+    })?;
+    match broken {
+        Some(name) => Err(format!(
+            "Can't sort {range}: {name} covers only some of its rows"
+        )),
+        None => Ok(shifted),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    /// Splits a leading comparison operator off `text`.
+    pub fn split_prefix(text: &str) -> Option<(Comparison, &str)> {
+        [
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("=", Comparison::Eq),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ]
+        .into_iter()
+        .find_map(|(op, comparison)| Some((comparison, text.strip_prefix(op)?)))
+    }
+
+    /// Whether a value ordered `ordering` against another passes, where
+    /// `None` means the two can't be compared, which only `!=` passes.
+    fn holds(self, ordering: Option<Ordering>) -> bool {
+        let Some(ordering) = ordering else {
+            return self == Comparison::Ne;
+        };
+        match self {
+            Comparison::Eq => ordering.is_eq(),
+            Comparison::Ne => ordering.is_ne(),
+            Comparison::Lt => ordering.is_lt(),
+            Comparison::Le => ordering.is_le(),
+            Comparison::Gt => ordering.is_gt(),
+            Comparison::Ge => ordering.is_ge(),
+        }
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Comparison::Eq => "=",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        })
+    }
+}
+
+/// A test of one column's computed value against a literal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub col: u32,
+    pub comparison: Comparison,
+    pub value: CellValue,
+}
+
+impl Condition {
+    /// Values of different types are never equal, and never ordered either,
+    /// so `B > 10` skips rows where B holds a string.
+    pub fn matches(&self, value: &CellValue) -> bool {
+        let same_type = type_rank(value) == type_rank(&self.value);
+        self.comparison
+            .holds(same_type.then(|| compare_values(value, &self.value)))
+    }
+}
+
+/// A test of one computed value against a literal, like the `> 100` in
+/// `watch B10 when > 100`. Unlike a filter condition, numbers written as
+/// text, as float results are, compare as numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Predicate {
+    pub comparison: Comparison,
+    pub value: CellValue,
+}
+
+impl Predicate {
+    /// Parses an operator and a literal, like `> 100` or `= "done"`. A
+    /// float literal is kept as text, the way float values are.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (comparison, literal) = Comparison::split_prefix(text.trim())?;
+        let literal = literal.trim();
+        let value = parse_literal(literal).or_else(|| {
+            let float = CellValue::String(literal.to_string());
+            as_number(&float).is_some().then_some(float)
+        })?;
+        Some(Predicate { comparison, value })
+    }
+
+    pub fn matches(&self, value: &CellValue) -> bool {
+        let ordering = match (as_number(value), as_number(&self.value)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => (type_rank(value) == type_rank(&self.value))
+                .then(|| compare_values(value, &self.value)),
+        };
+        self.comparison.holds(ordering)
+    }
+}
+
+impl Display for Predicate {
+    /// Numbers are written bare, since they compare as numbers whether they
+    /// were quoted or not.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            CellValue::Int(n) => write!(f, "{} {n}", self.comparison),
+            CellValue::String(s) if as_number(&self.value).is_some() => {
+                write!(f, "{} {s}", self.comparison)
+            }
+            CellValue::String(s) => write!(f, "{} {}", self.comparison, string_literal(s)),
+            value => write!(f, "{} {value}", self.comparison),
+        }
+    }
+}
+
+/// Parses an integer or a double quoted string (with `\"` and `\\` escapes).
+pub fn parse_literal(text: &str) -> Option<CellValue> {
+    let text = text.trim();
+    let Some(quoted) = text.strip_prefix('"') else {
+        return text.parse().ok().map(CellValue::Int);
+    };
+
+    let mut value = String::new();
+    let mut chars = quoted.chars();
+    loop {
+        match chars.next()? {
+            '"' => break,
+            '\\' => value.push(chars.next()?),
+            c => value.push(c),
+        }
+    }
+    chars
+        .as_str()
+        .is_empty()
+        .then_some(CellValue::String(value))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Sum,
+    Count,
+    Min,
+    Max,
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(Aggregate::Sum),
+            "count" => Ok(Aggregate::Count),
+            "min" => Ok(Aggregate::Min),
+            "max" => Ok(Aggregate::Max),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+impl Aggregate {
+    /// Folds `values`, skipping empty cells. `count` counts everything else;
+    /// the other aggregates only accept integers and produce an error value
+    /// when a group contains anything else.
+    pub fn apply(&self, values: &[CellValue]) -> CellValue {
+        let present = values.iter().filter(|value| **value != CellValue::None);
+        if *self == Aggregate::Count {
+            return CellValue::Int(present.count() as i64);
+        }
+
+        let mut numbers = Vec::new();
+        for value in present {
+            match value {
+                CellValue::Int(i) => numbers.push(*i),
+                other => {
+                    return CellValue::Error(format!("Cannot aggregate non-numeric value {other}"))
+                }
+            }
+        }
+        let result = match self {
+            Aggregate::Sum => numbers
+                .iter()
+                .try_fold(0i64, |total, i| total.checked_add(*i)),
+            Aggregate::Min => numbers.into_iter().min(),
+            Aggregate::Max => numbers.into_iter().max(),
+            Aggregate::Count => unreachable!("handled above"),
+        };
+        match (self, result) {
+            (_, Some(result)) => CellValue::Int(result),
+            (Aggregate::Sum, None) => CellValue::Error("Sum overflowed".to_string()),
+            (_, None) => CellValue::None,
+        }
+    }
+}
+
+/// Groups `rows` by the value at `key_index`, aggregating the values at
+/// `value_index` per group. Groups come back in order of first appearance,
+/// and rows with an empty key are left out.
+pub fn group_by(
+    rows: &[Vec<CellValue>],
+    key_index: usize,
+    value_index: usize,
+    aggregate: Aggregate,
+) -> Vec<(CellValue, CellValue)> {
+    let mut groups: Vec<(CellValue, Vec<CellValue>)> = Vec::new();
+    let mut positions: HashMap<&CellValue, usize> = HashMap::new();
+    for row in rows.iter().filter(|row| row[key_index] != CellValue::None) {
+        let position = *positions.entry(&row[key_index]).or_insert_with(|| {
+            groups.push((row[key_index].clone(), Vec::new()));
+            groups.len() - 1
+        });
+        groups[position].1.push(row[value_index].clone());
+    }
+
+    groups
+        .into_iter()
+        .map(|(key, values)| (key, aggregate.apply(&values)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_command, Command};
+
+    fn int(n: i64) -> CellValue {
+        CellValue::Int(n)
+    }
+
+    fn string(s: &str) -> CellValue {
+        CellValue::String(s.to_string())
+    }
+
+    fn key(col: u32, descending: bool) -> SortKey {
+        SortKey { col, descending }
+    }
+
+    fn sort_keys(message: &str) -> Vec<SortKey> {
+        match parse_command(message, &Config::default()) {
+            Ok(Command::Sort { keys, .. }) => keys,
+            other => panic!("{message:?} parsed as {other:?}"),
+        }
+    }
+
+    #[test]
+    fn later_keys_break_ties_in_their_own_direction() {
+        let rows = vec![
+            vec![int(2), string("b")],
+            vec![int(1), string("a")],
+            vec![int(2), string("c")],
+            vec![int(1), string("d")],
+        ];
+        assert_eq!(
+            sort_order(&rows, 0, &[key(0, false), key(1, true)]),
+            [3, 1, 2, 0]
+        );
+        assert_eq!(
+            sort_order(&rows, 0, &[key(0, true), key(1, false)]),
+            [0, 2, 1, 3]
+        );
+    }
+
+    #[test]
+    fn ties_keep_their_order() {
+        let rows = vec![
+            vec![int(1), int(10)],
+            vec![int(0), int(11)],
+            vec![int(1), int(12)],
+            vec![int(0), int(13)],
+        ];
+        assert_eq!(sort_order(&rows, 0, &[key(0, false)]), [1, 3, 0, 2]);
+        assert_eq!(sort_order(&rows, 0, &[key(0, true)]), [0, 2, 1, 3]);
+    }
+
+    #[test]
+    fn numbers_sort_before_strings_and_empty_cells_last() {
+        let rows = vec![
+            vec![CellValue::None],
+            vec![string("b")],
+            vec![int(5)],
+            vec![string("a")],
+            vec![int(-1)],
+        ];
+        assert_eq!(sort_order(&rows, 3, &[key(3, false)]), [4, 2, 3, 1, 0]);
+        assert_eq!(sort_order(&rows, 3, &[key(3, true)]), [1, 3, 2, 4, 0]);
+    }
+
+    #[test]
+    fn sort_keys_parse_with_ascending_as_the_default() {
+        assert_eq!(sort_keys("sort A1_C3 by B"), [key(1, false)]);
+        assert_eq!(
+            sort_keys("sort A1_C3 by B desc, A asc, C"),
+            [key(1, true), key(0, false), key(2, false)]
+        );
+        let config = Config::default();
+        assert!(parse_command("sort A1_C3 by D", &config).is_err());
+        assert!(parse_command("sort A1_C3 by B down", &config).is_err());
+        assert!(parse_command("sort A1_C3 B", &config).is_err());
+    }
+
+    #[test]
+    fn references_follow_the_rows_they_name() {
+        let config = Config::default();
+        let range = CellRange::parse("A1_C3", &config).unwrap();
+        let moved_to = [2, 0, 1];
+        let moved = |expression| move_references(expression, range, &moved_to, &config);
+        assert_eq!(moved("A1 + B2"), Ok("A3 + B1".to_string()));
+        assert_eq!(moved("sum(A2_C2)"), Ok("sum(A1_C1)".to_string()));
+        assert_eq!(
+            moved("sum(A1_A3) + D1 + A4"),
+            Ok("sum(A1_A3) + D1 + A4".to_string())
+        );
+        assert_eq!(
+            moved("sum(A_B) + \"A1\""),
+            Ok("sum(A_B) + \"A1\"".to_string())
+        );
+        assert!(moved("sum(A1_A2)").is_err());
+        assert!(moved("sum(B2_D2)").is_err());
+        assert!(moved("sum(2_2)").is_err());
+    }
+}
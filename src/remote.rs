@@ -0,0 +1,176 @@
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::functions;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest reply read from another sheet server.
+const MAX_REPLY: u64 = 1 << 20;
+
+/// Most values fetched at once.
+const FETCH_THREADS: usize = 8;
+
+/// A value on another sheet server: its address and the cell.
+pub type RemoteKey = (String, String);
+
+/// Values read from other sheet servers by `remote("host:port", "A1")`.
+/// Only the configured hosts can be read. Each value is reused until it is
+/// older than the refresh interval, and is fetched by a refresher thread
+/// rather than during evaluation, which holds the sheet's locks.
+pub struct RemoteCache {
+    refresh: Duration,
+    hosts: Vec<String>,
+    values: Mutex<HashMap<RemoteKey, (Instant, CellValue)>>,
+    /// Values asked for that aren't cached or have gone stale.
+    wanted: Mutex<HashSet<RemoteKey>>,
+    wake: Condvar,
+}
+
+fn fetch(address: &str, cell: &str) -> Result<CellValue, String> {
+    let address = address
+        .to_socket_addrs()
+        .map_err(|err| format!("Could not resolve {address}: {err}"))?
+        .next()
+        .ok_or_else(|| format!("Could not resolve {address}"))?;
+    let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+        .map_err(|err| format!("Could not reach {address}: {err}"))?;
+    stream
+        .set_read_timeout(Some(READ_TIMEOUT))
+        .map_err(|err| err.to_string())?;
+    stream
+        .write_all(format!("get {cell}\n").as_bytes())
+        .map_err(|err| format!("Could not query {address}: {err}"))?;
+
+    let mut line = String::new();
+    BufReader::new(stream.take(MAX_REPLY))
+        .read_line(&mut line)
+        .map_err(|err| format!("No reply from {address}: {err}"))?;
+    match serde_json::from_str(&line) {
+        Ok(Reply::Value(_, value)) => Ok(value),
+        Ok(Reply::Error(err)) => Err(format!("{address} replied: {err}")),
+        Err(err) => Err(format!("Unreadable reply from {address}: {err}")),
+    }
+}
+
+/// The values `expression`'s `remote()` calls read.
+pub fn references(expression: &str) -> Vec<RemoteKey> {
+    let Ok(calls) = functions::find_calls(expression, &["remote"]) else {
+        return Vec::new();
+    };
+    calls
+        .iter()
+        .filter_map(|call| match call.args.as_slice() {
+            [address, cell] => Some((
+                functions::string_argument(address)?,
+                functions::string_argument(cell)?,
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+impl RemoteCache {
+    pub fn new(refresh: Duration, hosts: Vec<String>) -> Self {
+        RemoteCache {
+            refresh,
+            hosts,
+            values: Mutex::new(HashMap::new()),
+            wanted: Mutex::new(HashSet::new()),
+            wake: Condvar::new(),
+        }
+    }
+
+    fn allows(&self, address: &str) -> bool {
+        self.hosts.iter().any(|host| host == address)
+    }
+
+    /// The cached value of `cell` on the server at `address`. One not
+    /// cached yet, or gone stale, is asked for from the refresher, which
+    /// recomputes the cells reading it once it arrives.
+    pub fn get(&self, address: &str, cell: &str) -> CellValue {
+        if !self.allows(address) {
+            return CellValue::Error(format!("{address} is not an allowed remote host"));
+        }
+        let key = (address.to_string(), cell.to_string());
+        let cached = self.values.lock().unwrap().get(&key).cloned();
+        match cached {
+            Some((fetched, value)) => {
+                if fetched.elapsed() >= self.refresh {
+                    self.want(key);
+                }
+                value
+            }
+            None => {
+                self.want(key);
+                CellValue::Error(format!("Waiting for {cell} from {address}"))
+            }
+        }
+    }
+
+    fn want(&self, key: RemoteKey) {
+        self.wanted.lock().unwrap().insert(key);
+        self.wake.notify_one();
+    }
+
+    /// Waits up to the refresh interval for values to be asked for,
+    /// returning them, or `None` if the interval passed without any.
+    pub fn wait_for_wanted(&self) -> Option<Vec<RemoteKey>> {
+        let wanted = self.wanted.lock().unwrap();
+        let (mut wanted, _) = self
+            .wake
+            .wait_timeout_while(wanted, self.refresh, |wanted| wanted.is_empty())
+            .unwrap();
+        if wanted.is_empty() {
+            return None;
+        }
+        Some(wanted.drain().collect())
+    }
+
+    /// Drops the cached values no expression reads any more.
+    pub fn retain(&self, referenced: &HashSet<RemoteKey>) {
+        self.values
+            .lock()
+            .unwrap()
+            .retain(|key, _| referenced.contains(key));
+    }
+
+    /// Fetches the values of `keys` on allowed hosts, a few at a time and
+    /// without holding any lock while waiting on the network. Returns the
+    /// ones whose value changed.
+    pub fn fetch_all(&self, keys: &[RemoteKey]) -> Vec<RemoteKey> {
+        let keys: Vec<&RemoteKey> = keys.iter().filter(|key| self.allows(&key.0)).collect();
+        let mut changed = Vec::new();
+        for chunk in keys.chunks(FETCH_THREADS) {
+            let fetched: Vec<(&RemoteKey, CellValue)> = std::thread::scope(|scope| {
+                let workers: Vec<_> = chunk
+                    .iter()
+                    .map(|key| {
+                        scope.spawn(move || {
+                            let value = fetch(&key.0, &key.1).unwrap_or_else(CellValue::Error);
+                            (*key, value)
+                        })
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| worker.join().unwrap())
+                    .collect()
+            });
+            let mut values = self.values.lock().unwrap();
+            for (key, value) in fetched {
+                let previous = values.insert(key.clone(), (Instant::now(), value.clone()));
+                if previous.map(|(_, previous)| previous) != Some(value) {
+                    changed.push(key.clone());
+                }
+            }
+        }
+        changed
+    }
+}
@@ -0,0 +1,65 @@
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::cells::column_number_to_name;
+
+use crate::cell_ref::{CellRange, CellRef};
+
+/// Ranges bigger than this are refused rather than rendered.
+pub const MAX_RENDERED_CELLS: u64 = 10_000;
+
+fn cell_text(value: &CellValue) -> String {
+    match value {
+        CellValue::Int(i) => i.to_string(),
+        CellValue::String(s) => s.clone(),
+        CellValue::Error(_) => "#ERROR".to_string(),
+        CellValue::None => String::new(),
+    }
+}
+
+/// Renders `range` as an aligned ASCII table with column letters across the
+/// top and row numbers down the side. Numbers are right aligned, everything
+/// else left aligned.
+pub fn table(range: CellRange, value_of: impl Fn(CellRef) -> CellValue) -> String {
+    let rows: Vec<(u32, Vec<CellValue>)> = range
+        .rows()
+        .map(|cells| (cells[0].row, cells.into_iter().map(&value_of).collect()))
+        .collect();
+    let headers: Vec<String> = (range.start.col..=range.end.col)
+        .map(column_number_to_name)
+        .collect();
+
+    let label_width = range.end.row.to_string().len();
+    let widths: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| {
+            rows.iter()
+                .map(|(_, values)| cell_text(&values[i]).chars().count())
+                .fold(header.len(), usize::max)
+        })
+        .collect();
+
+    let mut out = format!("{:label_width$} |", "");
+    for (header, width) in headers.iter().zip(&widths) {
+        out.push_str(&format!(" {header:<width$} |"));
+    }
+    out.push('\n');
+    out.push_str(&"-".repeat(label_width + 1));
+    out.push('+');
+    for width in &widths {
+        out.push_str(&"-".repeat(width + 2));
+        out.push('+');
+    }
+
+    for (row, values) in &rows {
+        out.push('\n');
+        out.push_str(&format!("{row:>label_width$} |"));
+        for (value, width) in values.iter().zip(&widths) {
+            let text = cell_text(value);
+            match value {
+                CellValue::Int(_) => out.push_str(&format!(" {text:>width$} |")),
+                _ => out.push_str(&format!(" {text:<width$} |")),
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,107 @@
+use rsheet_lib::cell_value::CellValue;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::eval::{calculate_cell_value, EvalContext, Memo};
+use crate::graph::DependencyGraph;
+use crate::numbers::float_expression;
+
+/// Named what-if layers. Each holds expressions that shadow the base
+/// sheet's when a cell is read through the scenario, leaving the base
+/// sheet as it is.
+#[derive(Default)]
+pub struct Scenarios {
+    layers: Mutex<BTreeMap<String, HashMap<String, String>>>,
+}
+
+impl Scenarios {
+    pub fn create(&self, name: &str) -> Result<(), String> {
+        let mut layers = self.layers.lock().unwrap();
+        if layers.contains_key(name) {
+            return Err(format!("Scenario {name} already exists"));
+        }
+        layers.insert(name.to_string(), HashMap::new());
+        Ok(())
+    }
+
+    pub fn drop(&self, name: &str) -> Result<(), String> {
+        match self.layers.lock().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(no_scenario(name)),
+        }
+    }
+
+    /// Shadows `cell_name` with `expression` in scenario `name`.
+    pub fn set(&self, name: &str, cell_name: &str, expression: &str) -> Result<(), String> {
+        let mut layers = self.layers.lock().unwrap();
+        let layer = layers.get_mut(name).ok_or_else(|| no_scenario(name))?;
+        layer.insert(cell_name.to_string(), expression.to_string());
+        Ok(())
+    }
+
+    /// Each scenario's name and how many cells it shadows.
+    pub fn list(&self) -> Vec<(String, usize)> {
+        let layers = self.layers.lock().unwrap();
+        layers
+            .iter()
+            .map(|(name, layer)| (name.clone(), layer.len()))
+            .collect()
+    }
+
+    /// `expressions` with scenario `name`'s cells laid over them.
+    pub fn apply(
+        &self,
+        name: &str,
+        expressions: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, String> {
+        let layers = self.layers.lock().unwrap();
+        let layer = layers.get(name).ok_or_else(|| no_scenario(name))?;
+        let mut shadowed = expressions.clone();
+        shadowed.extend(
+            layer
+                .iter()
+                .map(|(cell, expression)| (cell.clone(), expression.clone())),
+        );
+        Ok(shadowed)
+    }
+}
+
+fn no_scenario(name: &str) -> String {
+    format!("No scenario {name}")
+}
+
+/// A copy of the sheet's expressions with one input cell set to each value
+/// tried in turn, for searches and sweeps that leave the sheet alone.
+pub struct Trial {
+    expressions: HashMap<String, String>,
+    input: String,
+    /// The input and every cell it feeds. Their floats are held as text on
+    /// the way to whatever is observed, so they are read back as numbers.
+    numeric: Vec<String>,
+}
+
+impl Trial {
+    pub fn new(expressions: HashMap<String, String>, input: &str, config: &Config) -> Self {
+        let input = input.to_string();
+        let mut numeric = DependencyGraph::build(&expressions, config)
+            .affected_by(std::slice::from_ref(&input), config);
+        numeric.push(input.clone());
+        Trial {
+            expressions,
+            input,
+            numeric,
+        }
+    }
+
+    /// `cell_name`'s value with the input set to `value`.
+    pub fn evaluate(&mut self, value: f64, cell_name: &str, context: &EvalContext) -> CellValue {
+        self.expressions
+            .insert(self.input.clone(), float_expression(value));
+        let context = EvalContext {
+            numeric: &self.numeric,
+            ..*context
+        };
+        calculate_cell_value(&self.expressions, cell_name, &mut Memo::new(), &context)
+    }
+}
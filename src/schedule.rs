@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cell_ref::CellRange;
+
+/// Reads an interval like `90s`, `5m` or `2h`. A bare number is seconds.
+pub fn parse_interval(interval: &str) -> Option<Duration> {
+    let split = interval
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(interval.len());
+    let count: u64 = interval[..split].parse().ok()?;
+    let unit = match &interval[split..] {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(count.checked_mul(unit)?)).filter(|interval| !interval.is_zero())
+}
+
+/// A range recalculated every `every`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    pub range: CellRange,
+    pub every: Duration,
+    next: Instant,
+}
+
+/// Ranges to recalculate on a timer, for sheets reading volatile or
+/// remote inputs that nothing else would refresh.
+#[derive(Default)]
+pub struct Schedules {
+    entries: Mutex<(u64, BTreeMap<u64, Schedule>)>,
+}
+
+impl Schedules {
+    /// Schedules `range` to be recalculated every `every`, first after one
+    /// interval. Returns the schedule's id, or an error if the interval is
+    /// too long for the clock to reach.
+    pub fn add(&self, range: CellRange, every: Duration) -> Result<u64, String> {
+        let next = Instant::now()
+            .checked_add(every)
+            .ok_or_else(|| format!("An interval of {}s is too long", every.as_secs()))?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.0 += 1;
+        let id = entries.0;
+        entries.1.insert(id, Schedule { range, every, next });
+        Ok(id)
+    }
+
+    pub fn cancel(&self, id: u64) -> bool {
+        self.entries.lock().unwrap().1.remove(&id).is_some()
+    }
+
+    /// Every schedule, by id.
+    pub fn list(&self) -> Vec<(u64, Schedule)> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .1
+            .iter()
+            .map(|(id, schedule)| (*id, *schedule))
+            .collect()
+    }
+
+    /// The ranges due by now, moving each on to its next run. A schedule
+    /// whose next run is past what the clock can reach is dropped.
+    pub fn take_due(&self) -> Vec<CellRange> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        self.entries.lock().unwrap().1.retain(|_, schedule| {
+            if schedule.next > now {
+                return true;
+            }
+            due.push(schedule.range);
+            while schedule.next <= now {
+                match schedule.next.checked_add(schedule.every) {
+                    Some(next) => schedule.next = next,
+                    None => return false,
+                }
+            }
+            true
+        });
+        due
+    }
+}
@@ -0,0 +1,121 @@
+use rhai::{Array, Dynamic, Engine, EvalAltResult};
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+
+use crate::cell_ref::CellRange;
+use crate::config::Config;
+use crate::parser::{parse_command, Command};
+
+/// A command a script wants run, and where to send its replies.
+type Request = (Command, Sender<Vec<Reply>>);
+
+fn to_dynamic(value: CellValue) -> Result<Dynamic, Box<EvalAltResult>> {
+    match value {
+        CellValue::Int(i) => Ok(i.into()),
+        CellValue::String(s) => Ok(s.into()),
+        CellValue::None => Ok(Dynamic::UNIT),
+        CellValue::Error(err) => Err(err.into()),
+    }
+}
+
+/// Parses `message` as a command, has the connection run it, and returns its
+/// replies, failing the script on any error reply.
+fn request(
+    requests: &Sender<Request>,
+    config: &Config,
+    message: &str,
+) -> Result<Vec<Reply>, Box<EvalAltResult>> {
+    let command = parse_command(message, config).map_err(|err| err.to_string())?;
+    let (reply_sender, replies) = channel();
+    requests
+        .send((command, reply_sender))
+        .map_err(|_| "The connection has gone")?;
+    let replies = replies.recv().map_err(|_| "The connection has gone")?;
+    match replies.iter().find_map(|reply| match reply {
+        Reply::Error(err) => Some(err.clone()),
+        _ => None,
+    }) {
+        Some(err) => Err(err.into()),
+        None => Ok(replies),
+    }
+}
+
+fn get(
+    requests: &Sender<Request>,
+    config: &Config,
+    cell: &str,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    match request(requests, config, &format!("get {cell}"))?.pop() {
+        Some(Reply::Value(_, value)) => to_dynamic(value),
+        _ => Ok(Dynamic::UNIT),
+    }
+}
+
+/// Builds an engine whose `get`, `set`, `delete` and `range` functions run
+/// the matching commands through `requests`.
+fn engine(requests: Sender<Request>, config: Config) -> Engine {
+    let mut engine = Engine::new();
+
+    let (sender, sheet) = (requests.clone(), config.clone());
+    engine.register_fn("get", move |cell: &str| get(&sender, &sheet, cell));
+
+    let (sender, sheet) = (requests.clone(), config.clone());
+    engine.register_fn("set", move |cell: &str, expression: &str| {
+        request(&sender, &sheet, &format!("set {cell} {expression}")).map(|_| ())
+    });
+
+    let (sender, sheet) = (requests.clone(), config.clone());
+    engine.register_fn("delete", move |cell: &str| {
+        request(&sender, &sheet, &format!("delete {cell}")).map(|_| ())
+    });
+
+    engine.register_fn("range", move |range: &str| {
+        let range = CellRange::parse(range, &config).map_err(|err| err.to_string())?;
+        range
+            .rows()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| get(&requests, &config, &cell.to_string()))
+                    .collect::<Result<Array, _>>()
+                    .map(Dynamic::from_array)
+            })
+            .collect::<Result<Array, _>>()
+    });
+
+    engine
+}
+
+/// Runs the Rhai script at `path` on its own thread. Every sheet access the
+/// script makes comes back to this thread as a command for `execute`, so
+/// scripts are subject to the same checks as the connection running them.
+/// Returns the script's result.
+pub fn run(
+    path: &Path,
+    config: &Config,
+    mut execute: impl FnMut(&Command) -> Vec<Reply>,
+) -> Result<CellValue, String> {
+    let script = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read {}: {err}", path.display()))?;
+    let (requests, incoming) = channel::<Request>();
+    let engine = engine(requests, config.clone());
+
+    let script = std::thread::spawn(move || {
+        let result = engine.eval::<Dynamic>(&script);
+        drop(engine);
+        result
+            .map_err(|err| err.to_string())
+            .and_then(|result| match result {
+                result if result.is_unit() => Ok(CellValue::None),
+                result => rhai::serde::from_dynamic(&result)
+                    .map_err(|_| "The script returned something other than a value".to_string()),
+            })
+    });
+    for (command, replies) in incoming {
+        let _ = replies.send(execute(&command));
+    }
+    script
+        .join()
+        .unwrap_or_else(|_| Err("The script panicked".to_string()))
+}
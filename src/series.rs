@@ -0,0 +1,23 @@
+use rsheet_lib::cell_value::CellValue;
+use serde_json::{json, Value};
+
+use crate::numbers::as_number;
+
+/// A cell as a JSON number, keeping integers whole, if it holds a number.
+fn number(value: &CellValue) -> Option<Value> {
+    match value {
+        CellValue::Int(n) => Some(json!(n)),
+        value => as_number(value).map(|n| json!(n)),
+    }
+}
+
+/// Pairs `xs` with `ys` position by position as `{"x": [..], "y": [..]}`,
+/// leaving out every pair where either side isn't a number.
+pub fn to_json(xs: &[CellValue], ys: &[CellValue]) -> String {
+    let (x, y): (Vec<Value>, Vec<Value>) = xs
+        .iter()
+        .zip(ys)
+        .filter_map(|(x, y)| Some((number(x)?, number(y)?)))
+        .unzip();
+    json!({ "x": x, "y": y }).to_string()
+}
@@ -0,0 +1,80 @@
+use crate::functions;
+use crate::numbers::FloatFormat;
+
+/// Functions whose result changes every time they run.
+pub const RANDOM_FUNCTIONS: [&str; 2] = ["rand", "randbetween"];
+
+/// The percentiles `simulate` reports.
+const PERCENTILES: [u32; 5] = [5, 25, 50, 75, 95];
+
+/// Whether `expression` calls one of the random functions, so a fresh
+/// evaluation of it draws a fresh sample.
+pub fn is_random(expression: &str) -> bool {
+    let mut random = false;
+    let _ = functions::replace_names(expression, |name, called| {
+        random |= called && RANDOM_FUNCTIONS.contains(&name);
+        None
+    });
+    random
+}
+
+/// What a Monte Carlo run saw of the observed cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    /// Each numeric sample, in order.
+    samples: Vec<f64>,
+    /// Runs where the cell was an error, empty or not a number.
+    failed: usize,
+}
+
+impl Summary {
+    /// Summarizes `samples`, or fails if none of the runs gave a number.
+    pub fn new(mut samples: Vec<f64>, failed: usize) -> Result<Self, String> {
+        if samples.is_empty() {
+            return Err("No run gave a number".to_string());
+        }
+        samples.sort_by(f64::total_cmp);
+        Ok(Summary { samples, failed })
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.samples.iter().sum::<f64>() / self.samples.len() as f64
+    }
+
+    /// The sample standard deviation, zero for a single sample.
+    pub fn stddev(&self) -> f64 {
+        if self.samples.len() < 2 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        let squares: f64 = self.samples.iter().map(|x| (x - mean).powi(2)).sum();
+        (squares / (self.samples.len() - 1) as f64).sqrt()
+    }
+
+    /// The `p`th percentile, by nearest rank.
+    pub fn percentile(&self, p: u32) -> f64 {
+        let rank = (p as f64 / 100.0 * self.samples.len() as f64).ceil() as usize;
+        self.samples[rank.clamp(1, self.samples.len()) - 1]
+    }
+
+    /// Each statistic as a `name value` line.
+    pub fn lines(&self, format: &FloatFormat) -> Vec<String> {
+        let mut lines = vec![
+            format!("runs {}", self.samples.len() + self.failed),
+            format!("failed {}", self.failed),
+            format!("mean {}", format.render(self.mean())),
+            format!("stddev {}", format.render(self.stddev())),
+            format!("min {}", format.render(self.samples[0])),
+        ];
+        lines.extend(
+            PERCENTILES
+                .iter()
+                .map(|p| format!("p{p} {}", format.render(self.percentile(*p)))),
+        );
+        lines.push(format!(
+            "max {}",
+            format.render(self.samples[self.samples.len() - 1])
+        ));
+        lines
+    }
+}
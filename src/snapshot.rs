@@ -0,0 +1,97 @@
+use rsheet_lib::cell_value::CellValue;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock};
+
+const SHARDS: usize = 64;
+
+/// An immutable copy of every computed value, as of `revision`. Values are
+/// split into shards behind `Arc`s, so the next snapshot shares every shard
+/// a write didn't touch.
+#[derive(Clone)]
+pub struct Snapshot {
+    shards: Vec<Arc<HashMap<String, CellValue>>>,
+    pub revision: u64,
+}
+
+fn shard(cell_name: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    cell_name.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+impl Snapshot {
+    fn empty() -> Self {
+        Snapshot {
+            shards: (0..SHARDS).map(|_| Arc::default()).collect(),
+            revision: 0,
+        }
+    }
+
+    pub fn get(&self, cell_name: &str) -> CellValue {
+        self.shards[shard(cell_name)]
+            .get(cell_name)
+            .cloned()
+            .unwrap_or(CellValue::None)
+    }
+}
+
+/// The latest published snapshot. Readers only hold the lock long enough
+/// to clone a pointer, never while values are computed or written.
+pub struct Published {
+    current: RwLock<Arc<Snapshot>>,
+}
+
+impl Published {
+    pub fn load(&self) -> Arc<Snapshot> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// The writer's side: edits go into a draft, which becomes visible to
+/// readers all at once when published.
+pub struct Publisher {
+    draft: Snapshot,
+    dirty: bool,
+    published: Arc<Published>,
+}
+
+impl Publisher {
+    pub fn new() -> (Self, Arc<Published>) {
+        let published = Arc::new(Published {
+            current: RwLock::new(Arc::new(Snapshot::empty())),
+        });
+        let publisher = Publisher {
+            draft: Snapshot::empty(),
+            dirty: false,
+            published: published.clone(),
+        };
+        (publisher, published)
+    }
+
+    /// Only the first edit to a shard since the last publish copies it.
+    pub fn insert(&mut self, cell_name: &str, value: CellValue) {
+        Arc::make_mut(&mut self.draft.shards[shard(cell_name)])
+            .insert(cell_name.to_string(), value);
+        self.dirty = true;
+    }
+
+    pub fn remove(&mut self, cell_name: &str) {
+        let shard = &mut self.draft.shards[shard(cell_name)];
+        if shard.contains_key(cell_name) {
+            Arc::make_mut(shard).remove(cell_name);
+            self.dirty = true;
+        }
+    }
+
+    /// Makes the draft the current snapshot, if anything changed.
+    pub fn publish(&mut self, revision: u64) {
+        if !self.dirty {
+            return;
+        }
+        self.draft.revision = revision;
+        *self.published.current.write().unwrap() = Arc::new(self.draft.clone());
+        self.dirty = false;
+    }
+}
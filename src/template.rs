@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::cell_ref::{CellRange, CellRef};
+use crate::config::Config;
+use crate::functions;
+
+/// A block of expressions saved to be stamped out elsewhere.
+#[derive(Debug, Clone)]
+pub struct Template {
+    /// Where the block was saved from.
+    block: CellRange,
+    /// The expression of each cell in the block that had one.
+    expressions: HashMap<CellRef, String>,
+}
+
+impl Template {
+    /// Copies the expressions in `block` out of `expressions`.
+    pub fn new(block: CellRange, expressions: &HashMap<String, String>) -> Self {
+        let expressions = block
+            .rows()
+            .flatten()
+            .filter_map(|cell| Some((cell, expressions.get(&cell.to_string())?.clone())))
+            .collect();
+        Template { block, expressions }
+    }
+
+    /// The cells stamping the block out at `at` covers, with `at` as its
+    /// top-left cell, or an error if it runs off the sheet.
+    pub fn footprint(&self, at: CellRef, config: &Config) -> Result<CellRange, String> {
+        let end = CellRef {
+            col: at.col + self.block.width() - 1,
+            row: at.row + self.block.height() - 1,
+        };
+        if end.col > config.max_column || end.row > config.max_row {
+            return Err(format!(
+                "A {}x{} template doesn't fit at {at}",
+                self.block.width(),
+                self.block.height()
+            ));
+        }
+        Ok(CellRange::new(at, end))
+    }
+
+    /// Each cell the block covers at `at`, with the expression it gets, or
+    /// `None` where the saved cell was empty. References to cells inside the
+    /// block move with it; references to anything outside stay put, so
+    /// every copy shares them.
+    pub fn stamp(
+        &self,
+        at: CellRef,
+        config: &Config,
+    ) -> Result<Vec<(String, Option<String>)>, String> {
+        let footprint = self.footprint(at, config)?;
+        let moved = |cell: CellRef| CellRef {
+            col: cell.col - self.block.start.col + footprint.start.col,
+            row: cell.row - self.block.start.row + footprint.start.row,
+        };
+        let inside = |name: &str| {
+            CellRef::parse(name, config)
+                .ok()
+                .filter(|cell| self.block.contains(*cell))
+        };
+        let shift = |expression: &str| {
+            functions::replace_identifiers(expression, |name| match name.split_once('_') {
+                Some((start, end)) => {
+                    let (start, end) = (inside(start)?, inside(end)?);
+                    Some(format!("{}_{}", moved(start), moved(end)))
+                }
+                None => inside(name).map(|cell| moved(cell).to_string()),
+            })
+        };
+        self.block
+            .rows()
+            .flatten()
+            .map(|cell| {
+                let expression = match self.expressions.get(&cell) {
+                    Some(expression) => Some(shift(expression)?),
+                    None => None,
+                };
+                Ok((moved(cell).to_string(), expression))
+            })
+            .collect()
+    }
+}
+
+/// Saved templates, by name.
+#[derive(Default)]
+pub struct Templates {
+    templates: Mutex<BTreeMap<String, Template>>,
+}
+
+impl Templates {
+    /// Saves `template` as `name`, replacing any template already called
+    /// that.
+    pub fn save(&self, name: &str, template: Template) {
+        let mut templates = self.templates.lock().unwrap();
+        templates.insert(name.to_string(), template);
+    }
+
+    pub fn get(&self, name: &str) -> Result<Template, String> {
+        let templates = self.templates.lock().unwrap();
+        templates
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("No template {name}"))
+    }
+
+    pub fn drop(&self, name: &str) -> Result<(), String> {
+        match self.templates.lock().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(format!("No template {name}")),
+        }
+    }
+
+    /// Each template's name and the block it was saved from.
+    pub fn list(&self) -> Vec<(String, CellRange)> {
+        let templates = self.templates.lock().unwrap();
+        templates
+            .iter()
+            .map(|(name, template)| (name.clone(), template.block))
+            .collect()
+    }
+}
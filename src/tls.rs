@@ -0,0 +1,121 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Most bytes read off the socket at a time.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Loads a PEM certificate chain and private key to serve TLS with.
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let unreadable = |path: &Path, err: rustls::pki_types::pem::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Could not read {}: {err}", path.display()),
+        )
+    };
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| unreadable(cert, err))?;
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(|err| unreadable(key, err))?;
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(certs, private_key)
+            .map_err(io::Error::other)?;
+    Ok(Arc::new(config))
+}
+
+/// One side of a TLS connection. The reading and writing sides share the
+/// session but each has its own handle on the socket, so the reading side
+/// never holds the session while it waits on the network.
+pub struct TlsStream {
+    socket: TcpStream,
+    session: Arc<Mutex<ServerConnection>>,
+    /// Bytes read off the socket that the session hasn't taken yet.
+    received: Vec<u8>,
+}
+
+impl TlsStream {
+    /// The reading and writing sides of a connection on `socket`. The
+    /// handshake happens as the reading side first reads.
+    pub fn accept(
+        socket: TcpStream,
+        config: &Arc<ServerConfig>,
+    ) -> io::Result<(TlsStream, TlsStream)> {
+        let session = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+        let session = Arc::new(Mutex::new(session));
+        let reading = TlsStream {
+            socket: socket.try_clone()?,
+            session: session.clone(),
+            received: Vec::new(),
+        };
+        let writing = TlsStream {
+            socket,
+            session,
+            received: Vec::new(),
+        };
+        Ok((reading, writing))
+    }
+}
+
+/// Sends whatever `session` has queued for the peer.
+fn send_queued(session: &mut ServerConnection, socket: &mut TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(socket)?;
+    }
+    Ok(())
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                while !self.received.is_empty() {
+                    // Takes nothing while the session's buffers are full,
+                    // until `buf` takes some of its plaintext.
+                    let taken = match session.read_tls(&mut self.received.as_slice()) {
+                        Ok(taken) if taken > 0 => taken,
+                        _ => break,
+                    };
+                    self.received.drain(..taken);
+                    if let Err(err) = session.process_new_packets() {
+                        let _ = send_queued(&mut session, &mut self.socket);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                    }
+                }
+                send_queued(&mut session, &mut self.socket)?;
+                match session.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    read => return read,
+                }
+            }
+
+            let mut chunk = [0; READ_CHUNK];
+            let read = self.socket.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(0);
+            }
+            self.received.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let written = session.writer().write(buf)?;
+        send_queued(&mut session, &mut self.socket)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        send_queued(&mut self.session.lock().unwrap(), &mut self.socket)
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::formats::DisplayFormat;
+
+/// A deleted cell as it was just before the delete.
+pub struct Trashed {
+    pub expression: String,
+    pub format: Option<DisplayFormat>,
+    deleted: Instant,
+}
+
+/// Deleted cells, kept in memory for `restore` until their window runs
+/// out. Without a window nothing is kept and deletes are final.
+pub struct Trash {
+    window: Option<Duration>,
+    cells: Mutex<HashMap<String, Trashed>>,
+}
+
+impl Trash {
+    pub fn new(window: Option<Duration>) -> Self {
+        Trash {
+            window,
+            cells: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn window(&self) -> Option<Duration> {
+        self.window
+    }
+
+    /// Keeps `cell_name`'s last expression, replacing any earlier delete of
+    /// it.
+    pub fn put(&self, cell_name: &str, expression: String, format: Option<DisplayFormat>) {
+        if self.window.is_none() {
+            return;
+        }
+        self.cells.lock().unwrap().insert(
+            cell_name.to_string(),
+            Trashed {
+                expression,
+                format,
+                deleted: Instant::now(),
+            },
+        );
+    }
+
+    /// Takes `cell_name` back out, if it was deleted within the window.
+    pub fn take(&self, cell_name: &str) -> Result<Trashed, String> {
+        let window = self
+            .window
+            .ok_or_else(|| "Deleted cells aren't kept".to_string())?;
+        let mut cells = self.cells.lock().unwrap();
+        match cells.remove(cell_name) {
+            Some(trashed) if trashed.deleted.elapsed() <= window => Ok(trashed),
+            _ => Err(format!(
+                "{cell_name} wasn't deleted in the last {}s",
+                window.as_secs()
+            )),
+        }
+    }
+
+    /// Puts a cell taken by `take` back, for when restoring it failed.
+    pub fn put_back(&self, cell_name: &str, trashed: Trashed) {
+        self.cells
+            .lock()
+            .unwrap()
+            .insert(cell_name.to_string(), trashed);
+    }
+
+    /// Drops every cell deleted longer ago than the window, returning how
+    /// many.
+    pub fn purge(&self) -> usize {
+        let Some(window) = self.window else {
+            return 0;
+        };
+        let mut cells = self.cells.lock().unwrap();
+        let before = cells.len();
+        cells.retain(|_, trashed| trashed.deleted.elapsed() <= window);
+        before - cells.len()
+    }
+}
@@ -0,0 +1,302 @@
+use log::warn;
+use rsheet_lib::cell_value::CellValue;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::memory::{entry_bytes, string_bytes, value_bytes};
+use crate::snapshot::{Published, Publisher};
+
+pub const SPILL_FILE: &str = "values";
+
+/// The spill file is rewritten once it holds this many bytes of replaced
+/// values and they outweigh the live ones.
+const SPILL_GARBAGE: u64 = 1 << 20;
+
+/// Writes a value as a one byte kind followed by its contents. JSON can't
+/// be used because it doesn't tell strings and errors apart.
+fn encode(value: &CellValue) -> Vec<u8> {
+    let (kind, contents) = match value {
+        CellValue::Int(i) => (b'i', i.to_string()),
+        CellValue::String(s) => (b's', s.clone()),
+        CellValue::Error(err) => (b'e', err.clone()),
+        CellValue::None => (b'n', String::new()),
+    };
+    let mut bytes = vec![kind];
+    bytes.extend_from_slice(contents.as_bytes());
+    bytes
+}
+
+fn decode(bytes: Vec<u8>) -> io::Result<CellValue> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupt spilled value");
+    let (&kind, contents) = bytes.split_first().ok_or_else(invalid)?;
+    let contents = std::str::from_utf8(contents).map_err(|_| invalid())?;
+    match kind {
+        b'i' => contents.parse().map(CellValue::Int).map_err(|_| invalid()),
+        b's' => Ok(CellValue::String(contents.to_string())),
+        b'e' => Ok(CellValue::Error(contents.to_string())),
+        b'n' => Ok(CellValue::None),
+        _ => Err(invalid()),
+    }
+}
+
+/// Values evicted from memory, appended to a file and found again
+/// through an in-memory index. The file is a cache: it is emptied on open
+/// and removed when the sheet closes.
+struct Spill {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    live: u64,
+    index: HashMap<String, (u64, u64)>,
+}
+
+impl Spill {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Spill {
+            path,
+            file,
+            len: 0,
+            live: 0,
+            index: HashMap::new(),
+        })
+    }
+
+    fn write(&mut self, cell_name: &str, value: &CellValue) -> io::Result<()> {
+        let bytes = encode(value);
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(&bytes)?;
+        let len = bytes.len() as u64;
+        self.index.insert(cell_name.to_string(), (self.len, len));
+        self.len += len;
+        self.live += len;
+        Ok(())
+    }
+
+    fn take(&mut self, cell_name: &str) -> io::Result<Option<CellValue>> {
+        let Some((offset, len)) = self.index.remove(cell_name) else {
+            return Ok(None);
+        };
+        self.live -= len;
+        let mut bytes = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut bytes)?;
+        let value = decode(bytes)?;
+        if self.len - self.live > SPILL_GARBAGE && self.len - self.live > self.live {
+            self.rewrite()?;
+        }
+        Ok(Some(value))
+    }
+
+    /// Copies the live values to the start of the file and cuts off the rest.
+    fn rewrite(&mut self) -> io::Result<()> {
+        let mut entries: Vec<(String, (u64, u64))> = self.index.drain().collect();
+        entries.sort_by_key(|(_, (offset, _))| *offset);
+        let mut end = 0;
+        for (cell_name, (offset, len)) in entries {
+            let mut bytes = vec![0; len as usize];
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut bytes)?;
+            self.file.seek(SeekFrom::Start(end))?;
+            self.file.write_all(&bytes)?;
+            self.index.insert(cell_name, (end, len));
+            end += len;
+        }
+        self.file.set_len(end)?;
+        self.len = end;
+        self.live = end;
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Computed cell values. Without a capacity they all stay in memory; with
+/// one, the least recently used values beyond it are spilled to disk and
+/// read back when next needed.
+pub struct Values {
+    hot: HashMap<String, (CellValue, u64)>,
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    capacity: Option<usize>,
+    dir: Option<PathBuf>,
+    spill: Option<Spill>,
+    publisher: Option<Publisher>,
+}
+
+fn spill_path(dir: Option<&Path>) -> PathBuf {
+    static SHEETS: AtomicUsize = AtomicUsize::new(0);
+    match dir {
+        Some(dir) => dir.join(SPILL_FILE),
+        None => std::env::temp_dir().join(format!(
+            "rsheet-{}-{}-{SPILL_FILE}",
+            std::process::id(),
+            SHEETS.fetch_add(1, Ordering::Relaxed)
+        )),
+    }
+}
+
+impl Values {
+    /// Rough bytes held in memory, spilled values' index included.
+    pub fn memory_bytes(&self) -> usize {
+        let hot: usize = self
+            .hot
+            .iter()
+            .map(|(name, (value, _))| entry_bytes(name, value_bytes(value) + size_of::<u64>()))
+            .sum();
+        let recency: usize = self
+            .recency
+            .values()
+            .map(|name| string_bytes(name) + size_of::<u64>())
+            .sum();
+        let spilled: usize = self.spill.as_ref().map_or(0, |spill| {
+            spill
+                .index
+                .keys()
+                .map(|name| entry_bytes(name, size_of::<(u64, u64)>()))
+                .sum()
+        });
+        hot + recency + spilled
+    }
+
+    /// Spilled values go in `dir`, or a temporary file without one.
+    pub fn new(capacity: Option<usize>, dir: Option<&Path>) -> Self {
+        Values {
+            hot: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            capacity,
+            dir: dir.map(Path::to_path_buf),
+            spill: None,
+            publisher: None,
+        }
+    }
+
+    /// Starts mirroring every value into snapshots that can be read without
+    /// this store's lock. Call before storing anything.
+    pub fn published(&mut self) -> Arc<Published> {
+        let (publisher, published) = Publisher::new();
+        self.publisher = Some(publisher);
+        published
+    }
+
+    /// Makes the values stored so far visible in the published snapshot.
+    pub fn publish(&mut self, revision: u64) {
+        if let Some(publisher) = self.publisher.as_mut() {
+            publisher.publish(revision);
+        }
+    }
+
+    fn touch(&mut self, cell_name: &str) {
+        if let Some((_, used)) = self.hot.get_mut(cell_name) {
+            self.recency.remove(used);
+            self.tick += 1;
+            *used = self.tick;
+            self.recency.insert(self.tick, cell_name.to_string());
+        }
+    }
+
+    /// Moves a spilled value back into memory.
+    fn load(&mut self, cell_name: &str) -> Option<CellValue> {
+        let value = match self.spill.as_mut()?.take(cell_name) {
+            Ok(value) => value?,
+            Err(err) => CellValue::Error(format!("Could not read spilled value: {err}")),
+        };
+        self.put(cell_name, value.clone());
+        Some(value)
+    }
+
+    fn put(&mut self, cell_name: &str, value: CellValue) -> Option<CellValue> {
+        self.tick += 1;
+        self.recency.insert(self.tick, cell_name.to_string());
+        let old = self.hot.insert(cell_name.to_string(), (value, self.tick));
+        if let Some((_, used)) = &old {
+            self.recency.remove(used);
+        }
+        self.evict();
+        old.map(|(value, _)| value)
+    }
+
+    /// Spills the least recently used values until memory is within the
+    /// capacity. If the spill file can't be written they stay in memory.
+    fn evict(&mut self) {
+        let Some(capacity) = self.capacity else {
+            return;
+        };
+        while self.hot.len() > capacity {
+            if self.spill.is_none() {
+                match Spill::open(spill_path(self.dir.as_deref())) {
+                    Ok(spill) => self.spill = Some(spill),
+                    Err(err) => {
+                        warn!(
+                            "Could not open the value spill file, keeping values in memory: {err}"
+                        );
+                        self.capacity = None;
+                        return;
+                    }
+                }
+            }
+            let Some((&used, _)) = self.recency.iter().next() else {
+                return;
+            };
+            let cell_name = self.recency[&used].clone();
+            let value = &self.hot[&cell_name].0;
+            if let Err(err) = self.spill.as_mut().unwrap().write(&cell_name, value) {
+                warn!("Could not spill values, keeping them in memory: {err}");
+                self.capacity = None;
+                return;
+            }
+            self.recency.remove(&used);
+            self.hot.remove(&cell_name);
+        }
+    }
+
+    pub fn get(&mut self, cell_name: &str) -> Option<CellValue> {
+        if let Some((value, _)) = self.hot.get(cell_name) {
+            let value = value.clone();
+            self.touch(cell_name);
+            return Some(value);
+        }
+        self.load(cell_name)
+    }
+
+    /// Stores `value`, returning the one it replaces.
+    pub fn insert(&mut self, cell_name: &str, value: CellValue) -> Option<CellValue> {
+        if let Some(publisher) = self.publisher.as_mut() {
+            publisher.insert(cell_name, value.clone());
+        }
+        let spilled = self.load(cell_name);
+        self.put(cell_name, value).or(spilled)
+    }
+
+    pub fn remove(&mut self, cell_name: &str) -> Option<CellValue> {
+        if let Some(publisher) = self.publisher.as_mut() {
+            publisher.remove(cell_name);
+        }
+        self.load(cell_name);
+        let (value, used) = self.hot.remove(cell_name)?;
+        self.recency.remove(&used);
+        Some(value)
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.hot.shrink_to_fit();
+        if let Some(spill) = self.spill.as_mut() {
+            spill.index.shrink_to_fit();
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use log::warn;
+use rsheet_lib::cell_value::CellValue;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::cell_ref::{CellRange, CellRef};
+use crate::changes::Change;
+use crate::config::Config;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tries per change before it is dropped.
+const ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry, doubled before each one after.
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Most webhooks at once; each has its own posting thread.
+const MAX_WEBHOOKS: usize = 64;
+
+/// Where a webhook posts to. Only plain `http://` URLs are supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl WebhookUrl {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        if url.starts_with("https://") {
+            return Err("Webhooks can only post to http:// URLs".to_string());
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Invalid webhook URL {url:?}"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("Invalid port in webhook URL {url:?}"))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("Invalid webhook URL {url:?}"));
+        }
+        Ok(WebhookUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Posts `body` as JSON, succeeding on any 2xx reply.
+    fn post(&self, body: &str) -> Result<(), String> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|err| format!("Could not resolve {}: {err}", self.host))?
+            .next()
+            .ok_or_else(|| format!("Could not resolve {}", self.host))?;
+        let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+            .map_err(|err| format!("Could not reach {address}: {err}"))?;
+        stream
+            .set_read_timeout(Some(REPLY_TIMEOUT))
+            .map_err(|err| err.to_string())?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.host,
+            body.len()
+        );
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|err| format!("Could not post to {address}: {err}"))?;
+
+        let mut status = String::new();
+        BufReader::new(stream)
+            .read_line(&mut status)
+            .map_err(|err| format!("No reply from {address}: {err}"))?;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(format!("{address} replied {:?}", status.trim_end())),
+        }
+    }
+}
+
+impl std::fmt::Display for WebhookUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+fn value_json(value: &CellValue) -> Value {
+    match value {
+        CellValue::Int(n) => json!(n),
+        CellValue::String(s) => json!(s),
+        CellValue::Error(err) => json!({ "error": err }),
+        CellValue::None => Value::Null,
+    }
+}
+
+fn payload(change: &Change) -> String {
+    json!({
+        "seq": change.seq,
+        "cell": change.cell,
+        "old": value_json(&change.old),
+        "new": value_json(&change.new),
+    })
+    .to_string()
+}
+
+/// Posts `change`, retrying with exponential backoff before giving up.
+fn deliver(url: &WebhookUrl, change: &Change) {
+    let body = payload(change);
+    let mut backoff = FIRST_BACKOFF;
+    for attempt in 1..=ATTEMPTS {
+        match url.post(&body) {
+            Ok(()) => return,
+            Err(err) if attempt == ATTEMPTS => {
+                warn!("Dropped change {} for webhook {url}: {err}", change.seq);
+            }
+            Err(_) => {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+struct Webhook {
+    url: WebhookUrl,
+    range: CellRange,
+    /// Feeds the thread posting this webhook's changes, one at a time and
+    /// in order. Dropping it ends the thread.
+    queue: Sender<Change>,
+}
+
+/// URLs posted a JSON payload whenever a cell in their range changes
+/// value.
+#[derive(Default)]
+pub struct Webhooks {
+    hooks: Mutex<(u64, BTreeMap<u64, Webhook>)>,
+}
+
+impl Webhooks {
+    /// Starts posting changes in `range` to `url`. Returns the webhook's id.
+    pub fn add(&self, url: WebhookUrl, range: CellRange) -> Result<u64, String> {
+        let mut hooks = self.hooks.lock().unwrap();
+        if hooks.1.len() >= MAX_WEBHOOKS {
+            return Err(format!("There can be at most {MAX_WEBHOOKS} webhooks"));
+        }
+        let (queue, changes) = channel::<Change>();
+        let target = url.clone();
+        std::thread::spawn(move || {
+            for change in changes {
+                deliver(&target, &change);
+            }
+        });
+        hooks.0 += 1;
+        let id = hooks.0;
+        hooks.1.insert(id, Webhook { url, range, queue });
+        Ok(id)
+    }
+
+    pub fn remove(&self, id: u64) -> bool {
+        self.hooks.lock().unwrap().1.remove(&id).is_some()
+    }
+
+    /// Each webhook as `<id> <url> on <range>`.
+    pub fn list(&self) -> Vec<String> {
+        let hooks = self.hooks.lock().unwrap();
+        hooks
+            .1
+            .iter()
+            .map(|(id, hook)| format!("{id} {} on {}", hook.url, hook.range))
+            .collect()
+    }
+
+    /// Queues `change` for every webhook watching its cell.
+    pub fn dispatch(&self, change: &Change, config: &Config) {
+        let Ok(cell) = CellRef::parse(&change.cell, config) else {
+            return;
+        };
+        let hooks = self.hooks.lock().unwrap();
+        for hook in hooks.1.values() {
+            if hook.range.contains(cell) {
+                let _ = hook.queue.send(change.clone());
+            }
+        }
+    }
+}
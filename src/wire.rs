@@ -0,0 +1,308 @@
+use flate2::write::{DeflateEncoder, GzEncoder};
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::connect::{ConnectionError, Reader, TerminalReader, TerminalWriter, Writer};
+use rsheet_lib::replies::Reply;
+use serde_json::json;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::cell_ref::CellRange;
+
+/// Starts a binary frame where a text line would otherwise begin. Frames are
+/// this byte, a kind byte, the payload's length as a big-endian `u32`, then
+/// the payload. Integers in payloads are big-endian too.
+pub const FRAME_MARKER: u8 = 0;
+/// Sent by clients: a `u16`-prefixed range in A1 style, then an `i64` for
+/// each of its cells, row by row.
+pub const SETMANY_FRAME: u8 = 1;
+/// Sent to clients for `getrange`: the `u64` revision, a `u16`-prefixed
+/// range, then each cell row by row as a tag byte (0 empty, 1 int, 2 string,
+/// 3 error) followed by an `i64`, or a `u32`-prefixed UTF-8 string.
+pub const RANGE_FRAME: u8 = 2;
+/// Sent to clients that asked for compression, in place of replies or a
+/// frame whose encoding reaches `COMPRESS_THRESHOLD`: a byte naming the
+/// compression (0 deflate, 1 gzip), then those bytes compressed.
+pub const COMPRESSED_FRAME: u8 = 3;
+/// Smallest encoding, in bytes, worth compressing.
+pub const COMPRESS_THRESHOLD: usize = 4096;
+/// Largest frame payload accepted from a client.
+pub const MAX_FRAME: u32 = 64 << 20;
+
+/// How replies are encoded for a connection, chosen with `hello format=...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyFormat {
+    /// rsheet_lib's own encoding, which its clients expect.
+    Rsheet,
+    /// One flat JSON object per line: `{"label":"A1","value":5}`, with
+    /// `"error"` in place of `"value"` for error values, or just
+    /// `{"error":"..."}` when a command fails.
+    JsonLines,
+}
+
+impl FromStr for ReplyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rsheet" => Ok(ReplyFormat::Rsheet),
+            "json" => Ok(ReplyFormat::JsonLines),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ReplyFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReplyFormat::Rsheet => "rsheet",
+            ReplyFormat::JsonLines => "json",
+        })
+    }
+}
+
+/// How large replies are compressed for a connection, chosen with
+/// `hello compress=...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Deflate,
+    Gzip,
+}
+
+impl Compression {
+    fn compress(self, bytes: &[u8]) -> Vec<u8> {
+        // Writing into a `Vec` can't fail.
+        match self {
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(bytes).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(bytes).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deflate" => Ok(Compression::Deflate),
+            "gzip" => Ok(Compression::Gzip),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Deflate => "deflate",
+            Compression::Gzip => "gzip",
+        })
+    }
+}
+
+/// What a connection's writer thread is sent. Format and compression
+/// changes travel with the replies so that every reply queued before one is written the old way.
+pub enum Outgoing {
+    Reply(Reply),
+    /// Every reply to one command, which may be compressed together.
+    Replies(Vec<Reply>),
+    Format(ReplyFormat),
+    Compression(Option<Compression>),
+    /// A complete binary frame, header included.
+    Frame(Vec<u8>),
+}
+
+impl From<Reply> for Outgoing {
+    fn from(reply: Reply) -> Self {
+        Outgoing::Reply(reply)
+    }
+}
+
+/// A message from a client: a line of text, or a binary frame.
+pub enum Input {
+    Line(String),
+    Frame { kind: u8, payload: Vec<u8> },
+}
+
+/// A connection that may also send binary frames.
+pub trait WireReader: Reader {
+    fn read_input(&mut self) -> Result<Input, ConnectionError>;
+
+    /// Whether the connection is the server's own terminal, whose user
+    /// already has the server's access and so counts as an admin.
+    fn local(&self) -> bool {
+        false
+    }
+}
+
+impl WireReader for TerminalReader {
+    fn read_input(&mut self) -> Result<Input, ConnectionError> {
+        self.read_message().map(Input::Line)
+    }
+
+    fn local(&self) -> bool {
+        true
+    }
+}
+
+/// A connection that can be sent text and frames of our own, for the
+/// formats rsheet_lib's `Writer` doesn't know about.
+pub trait WireWriter: Writer {
+    fn write_line(&mut self, line: &str) -> Result<(), ConnectionError>;
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), ConnectionError>;
+}
+
+/// Frames are printed as hex, since the terminal is for reading.
+impl WireWriter for TerminalWriter {
+    fn write_line(&mut self, line: &str) -> Result<(), ConnectionError> {
+        println!("{line}");
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), ConnectionError> {
+        let hex: String = frame.iter().map(|byte| format!("{byte:02x}")).collect();
+        println!("frame {hex}");
+        Ok(())
+    }
+}
+
+pub fn json_line(reply: &Reply) -> String {
+    let object = match reply {
+        Reply::Value(label, CellValue::Error(err)) => json!({ "label": label, "error": err }),
+        Reply::Value(label, CellValue::Int(n)) => json!({ "label": label, "value": n }),
+        Reply::Value(label, CellValue::String(s)) => json!({ "label": label, "value": s }),
+        Reply::Value(label, CellValue::None) => json!({ "label": label, "value": null }),
+        Reply::Error(err) => json!({ "error": err }),
+    };
+    object.to_string()
+}
+
+pub fn write_reply<W: WireWriter>(
+    send: &mut W,
+    reply: Reply,
+    format: ReplyFormat,
+) -> Result<(), ConnectionError> {
+    match format {
+        ReplyFormat::Rsheet => send.write_message(reply),
+        ReplyFormat::JsonLines => send.write_line(&json_line(&reply)),
+    }
+}
+
+/// Writes `replies` one by one, or as one `COMPRESSED_FRAME` holding the
+/// lines a TCP connection would have been sent if they're large enough.
+pub fn write_replies<W: WireWriter>(
+    send: &mut W,
+    replies: Vec<Reply>,
+    format: ReplyFormat,
+    compression: Option<Compression>,
+) -> Result<(), ConnectionError> {
+    if let Some(compression) = compression {
+        let mut lines = String::new();
+        for reply in &replies {
+            match format {
+                ReplyFormat::Rsheet => lines.push_str(
+                    &serde_json::to_string(reply)
+                        .map_err(|_| ConnectionError::CouldNotConvertToJson)?,
+                ),
+                ReplyFormat::JsonLines => lines.push_str(&json_line(reply)),
+            }
+            lines.push('\n');
+        }
+        if lines.len() >= COMPRESS_THRESHOLD {
+            return send.write_frame(&compressed_frame(compression, lines.as_bytes()));
+        }
+    }
+    replies
+        .into_iter()
+        .try_for_each(|reply| write_reply(send, reply, format))
+}
+
+/// Writes a complete frame, compressed inside a `COMPRESSED_FRAME` if it's
+/// large enough.
+pub fn write_frame<W: WireWriter>(
+    send: &mut W,
+    frame: &[u8],
+    compression: Option<Compression>,
+) -> Result<(), ConnectionError> {
+    match compression {
+        Some(compression) if frame.len() >= COMPRESS_THRESHOLD => {
+            send.write_frame(&compressed_frame(compression, frame))
+        }
+        _ => send.write_frame(frame),
+    }
+}
+
+fn compressed_frame(compression: Compression, bytes: &[u8]) -> Vec<u8> {
+    let mut payload = vec![match compression {
+        Compression::Deflate => 0,
+        Compression::Gzip => 1,
+    }];
+    payload.extend(compression.compress(bytes));
+    frame(COMPRESSED_FRAME, payload)
+}
+
+fn frame(kind: u8, payload: Vec<u8>) -> Vec<u8> {
+    let mut frame = vec![FRAME_MARKER, kind];
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+fn put_text(payload: &mut Vec<u8>, text: &str) {
+    payload.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    payload.extend_from_slice(text.as_bytes());
+}
+
+/// Encodes `getrange`'s result as a `RANGE_FRAME`.
+pub fn range_frame(range: CellRange, revision: u64, rows: &[Vec<CellValue>]) -> Vec<u8> {
+    let mut payload = revision.to_be_bytes().to_vec();
+    let name = range.to_string();
+    payload.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    payload.extend_from_slice(name.as_bytes());
+    for value in rows.iter().flatten() {
+        match value {
+            CellValue::None => payload.push(0),
+            CellValue::Int(n) => {
+                payload.push(1);
+                payload.extend_from_slice(&n.to_be_bytes());
+            }
+            CellValue::String(s) => {
+                payload.push(2);
+                put_text(&mut payload, s);
+            }
+            CellValue::Error(err) => {
+                payload.push(3);
+                put_text(&mut payload, err);
+            }
+        }
+    }
+    frame(RANGE_FRAME, payload)
+}
+
+/// Splits a `SETMANY_FRAME` payload into its range, as written, and values.
+pub fn split_setmany(payload: &[u8]) -> Result<(&str, Vec<i64>), String> {
+    let truncated = || "Frame is truncated".to_string();
+    let (len, rest) = payload.split_first_chunk::<2>().ok_or_else(truncated)?;
+    let len = u16::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    let (range, values) = rest.split_at(len);
+    let range = std::str::from_utf8(range).map_err(|_| "Frame range is not UTF-8".to_string())?;
+    if values.len() % 8 != 0 {
+        return Err(truncated());
+    }
+    let values = values
+        .chunks_exact(8)
+        .map(|value| i64::from_be_bytes(value.try_into().unwrap()))
+        .collect();
+    Ok((range, values))
+}
@@ -0,0 +1,225 @@
+use calamine::{open_workbook_auto, Data, Reader};
+use rsheet_lib::cell_value::CellValue;
+use rust_xlsxwriter::{Format, Formula, Note, Workbook};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::cell_ref::{CellRange, CellRef};
+use crate::formats::DisplayFormat;
+use crate::functions::string_literal;
+
+/// How expressions are carried into an exported workbook. Values are always
+/// written; expressions either go nowhere, into a note on the cell, or are
+/// translated into an Excel formula where that is possible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpressionExport {
+    Values,
+    Comments,
+    Formulas,
+}
+
+impl FromStr for ExpressionExport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "values" => Ok(ExpressionExport::Values),
+            "comments" => Ok(ExpressionExport::Comments),
+            "formulas" => Ok(ExpressionExport::Formulas),
+            other => Err(other.to_string()),
+        }
+    }
+}
+
+pub struct ExportCell<'a> {
+    pub cell: CellRef,
+    pub expression: &'a str,
+    pub value: CellValue,
+    pub format: Option<DisplayFormat>,
+}
+
+fn is_cell_ref(name: &str) -> bool {
+    let split = name
+        .find(|c: char| !c.is_ascii_uppercase())
+        .unwrap_or(name.len());
+    split > 0 && split < name.len() && name[split..].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Translates an expression into an Excel formula, or `None` if it uses
+/// anything beyond arithmetic, cell references, ranges, and `sum`.
+fn excel_formula(expression: &str) -> Option<String> {
+    let mut formula = String::from("=");
+    let mut chars = expression.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                formula.push('"');
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            '"' => formula.push_str("\"\""),
+                            '\\' => formula.push('\\'),
+                            _ => return None,
+                        },
+                        c => formula.push(c),
+                    }
+                }
+                formula.push('"');
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                match name.split_once('_') {
+                    _ if name == "sum" => formula.push_str("SUM"),
+                    Some((start, end)) if is_cell_ref(start) && is_cell_ref(end) => {
+                        formula.push_str(&format!("{start}:{end}"));
+                    }
+                    None if is_cell_ref(&name) => formula.push_str(&name),
+                    _ => return None,
+                }
+            }
+            c if c.is_ascii_digit() || c.is_whitespace() || "+-*/(),".contains(c) => {
+                formula.push(c)
+            }
+            _ => return None,
+        }
+    }
+
+    Some(formula)
+}
+
+/// Writes the cells to the first worksheet of `path`, with `merges` merged,
+/// and any tags to a second `Tags` worksheet listing each tagged cell with
+/// its tags.
+pub fn export(
+    path: &Path,
+    cells: &[ExportCell],
+    tags: &[(CellRef, Vec<&str>)],
+    merges: &[CellRange],
+    mode: ExpressionExport,
+) -> Result<(), String> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    let percent = Format::new().set_num_format("0%");
+
+    // Merging writes an empty top-left cell, which the cells below then
+    // overwrite with their values.
+    for range in merges {
+        let col = |cell: CellRef| {
+            u16::try_from(cell.col).map_err(|_| format!("{cell} is beyond the last xlsx column"))
+        };
+        worksheet
+            .merge_range(
+                range.start.row - 1,
+                col(range.start)?,
+                range.end.row - 1,
+                col(range.end)?,
+                "",
+                &Format::new(),
+            )
+            .map_err(|err| format!("Could not merge {range}: {err}"))?;
+    }
+
+    for export_cell in cells {
+        let row = export_cell.cell.row - 1;
+        let col = u16::try_from(export_cell.cell.col)
+            .map_err(|_| format!("{} is beyond the last xlsx column", export_cell.cell))?;
+
+        let formula = match mode {
+            ExpressionExport::Formulas => excel_formula(export_cell.expression),
+            _ => None,
+        };
+        // Percentages are written as numbers Excel shows as percentages.
+        let percentage = match (export_cell.format, &export_cell.value) {
+            (Some(DisplayFormat::Percent), CellValue::Int(i)) => Some(*i as f64),
+            (Some(DisplayFormat::Percent), CellValue::String(s)) => s.parse().ok(),
+            _ => None,
+        };
+        let result = match (&export_cell.value, formula) {
+            (_, None) if percentage.is_some() => worksheet.write_number_with_format(
+                row,
+                col,
+                percentage.unwrap_or_default(),
+                &percent,
+            ),
+            (value, Some(formula)) => {
+                let cached = match value {
+                    CellValue::Int(i) => i.to_string(),
+                    CellValue::String(s) | CellValue::Error(s) => s.clone(),
+                    CellValue::None => String::new(),
+                };
+                worksheet.write_formula(row, col, Formula::new(formula).set_result(cached))
+            }
+            (CellValue::Int(i), None) => worksheet.write_number(row, col, *i as f64),
+            (CellValue::String(s), None) => worksheet.write_string(row, col, s),
+            (CellValue::Error(e), None) => worksheet.write_string(row, col, format!("#ERROR {e}")),
+            (CellValue::None, None) => Ok(&mut *worksheet),
+        };
+        result.map_err(|err| format!("Could not write {}: {err}", export_cell.cell))?;
+
+        if mode == ExpressionExport::Comments {
+            worksheet
+                .insert_note(row, col, &Note::new(export_cell.expression))
+                .map_err(|err| format!("Could not annotate {}: {err}", export_cell.cell))?;
+        }
+    }
+
+    if !tags.is_empty() {
+        let worksheet = workbook
+            .add_worksheet()
+            .set_name("Tags")
+            .map_err(|err| format!("Could not add the tags worksheet: {err}"))?;
+        worksheet
+            .write_string(0, 0, "Cell")
+            .and_then(|worksheet| worksheet.write_string(0, 1, "Tags"))
+            .map_err(|err| format!("Could not write the tags header: {err}"))?;
+        for (row, (cell, cell_tags)) in (1..).zip(tags) {
+            worksheet
+                .write_string(row, 0, cell.to_string())
+                .and_then(|worksheet| worksheet.write_string(row, 1, cell_tags.join(", ")))
+                .map_err(|err| format!("Could not write the tags of {cell}: {err}"))?;
+        }
+    }
+
+    workbook
+        .save(path)
+        .map_err(|err| format!("Could not save {}: {err}", path.display()))
+}
+
+/// Reads the first worksheet of `path` as `(cell, expression)` pairs. Numbers
+/// that are whole become integers; everything else is imported as a string.
+pub fn import(path: &Path) -> Result<Vec<(CellRef, String)>, String> {
+    let mut workbook = open_workbook_auto(path)
+        .map_err(|err| format!("Could not open {}: {err}", path.display()))?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or_else(|| format!("{} has no worksheets", path.display()))?
+        .map_err(|err| format!("Could not read {}: {err}", path.display()))?;
+    let (start_row, start_col) = range.start().unwrap_or((0, 0));
+
+    Ok(range
+        .used_cells()
+        .map(|(row, col, data)| {
+            let cell = CellRef {
+                col: start_col + col as u32,
+                row: start_row + row as u32 + 1,
+            };
+            let expression = match data {
+                Data::Int(i) => i.to_string(),
+                Data::Float(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => {
+                    (*f as i64).to_string()
+                }
+                other => string_literal(&other.to_string()),
+            };
+            (cell, expression)
+        })
+        .collect())
+}